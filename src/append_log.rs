@@ -5,23 +5,28 @@
 // This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
 // You should have received a copy of the GNU General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
 
-use crate::atomic_store::AtomicStoreLoader;
+use crate::atomic_store::{AtomicStoreLoader, Inconsistency, InconsistencyKind, ReadOnlyLoader};
+use crate::branch::{BranchId, BranchedLog, Branches};
+use crate::config::{AtomicStoreConfig, BackupPolicy};
 use crate::error::{
     PersistenceError, StdIoDirOpsSnafu, StdIoOpenSnafu, StdIoReadSnafu, StdIoSeekSnafu,
     StdIoWriteSnafu,
 };
 use crate::fixed_append_log;
-use crate::fixed_append_log::FixedAppendLog;
-use crate::load_store::{LoadStore, StorageLocationLoadStore};
+use crate::fixed_append_log::{FixedAppendLog, ReadOnlyFixedAppendLog};
+use crate::load_store::{recorded_format, LoadStore, StorageLocationLoadStore};
+use crate::resource_descriptor::{ResourceDescriptor, ResourceKind};
+use crate::storage_backend::{EntryType, OpenMode, StorageBackend, StorageFile};
 use crate::storage_location::{StorageLocation, STORAGE_LOCATION_SERIALIZED_SIZE};
-use crate::utils::unix_timestamp;
+use crate::utils::{copy_prefix, discard_backup, file_len, link_or_copy, sync_dir, unix_timestamp};
 use crate::version_sync::VersionSyncHandle;
 use crate::Result;
 
+use regex::Regex;
 use snafu::ResultExt;
 
-use std::fs;
-use std::fs::{File, OpenOptions};
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
 use std::io::{Read, Seek, SeekFrom, Write};
 use std::path::{Path, PathBuf};
 use std::sync::{Arc, RwLock};
@@ -29,21 +34,40 @@ use std::sync::{Arc, RwLock};
 #[derive(Debug)]
 pub struct AppendLog<ResourceAdaptor: LoadStore> {
     persisted_sync: Arc<RwLock<VersionSyncHandle>>,
+    backend: Arc<dyn StorageBackend>,
+    backups: BackupPolicy,
     file_path: PathBuf,
     file_pattern: String,
     file_fill_size: u64,
-    write_to_file: Option<File>,
+    write_to_file: Option<Box<dyn StorageFile>>,
     write_pos: u64,
     write_file_counter: u32,
     index_log: FixedAppendLog<StorageLocationLoadStore>,
+    branches: Box<Branches>,
+    adaptor: ResourceAdaptor,
+}
+
+/// An `AppendLog` opened from a `ReadOnlyLoader`, which reads the version recorded in the table of
+/// contents it was opened or last refreshed with.
+#[derive(Debug)]
+pub struct ReadOnlyAppendLog<ResourceAdaptor: LoadStore> {
+    persisted_sync: Arc<RwLock<VersionSyncHandle>>,
+    backend: Arc<dyn StorageBackend>,
+    file_path: PathBuf,
+    file_pattern: String,
+    store_pattern: String,
+    file_fill_size: u64,
+    version: Option<u32>,
+    index_log: ReadOnlyFixedAppendLog<StorageLocationLoadStore>,
     adaptor: ResourceAdaptor,
 }
 
 pub struct Iter<'a, ResourceAdaptor: LoadStore> {
     inner_iter: fixed_append_log::Iter<'a, StorageLocationLoadStore>,
+    backend: Arc<dyn StorageBackend>,
     file_path: PathBuf,
     file_pattern: String,
-    read_from_file: Option<File>,
+    read_from_file: Option<Box<dyn StorageFile>>,
     read_from_counter: u32,
     adaptor: &'a ResourceAdaptor,
 }
@@ -52,14 +76,29 @@ fn format_index_file_pattern(file_pattern: &str) -> String {
     format!("{}_index", file_pattern)
 }
 
+/// The kind of the index of an append log, whose index files hold `index_file_size` locations.
+pub(crate) fn index_kind(index_file_size: u64) -> ResourceKind {
+    ResourceKind::FixedAppendLog {
+        resource_size: STORAGE_LOCATION_SERIALIZED_SIZE,
+        file_size: index_file_size,
+    }
+}
+
 fn format_nth_file_path(root_path: &Path, file_pattern: &str, file_count: u32) -> PathBuf {
     let mut buf = root_path.to_path_buf();
     buf.push(format!(".{}_{}", file_pattern, file_count));
     buf
 }
 
+/// Matches the data files of an append log with `file_pattern`, including backups of replaced
+/// files. The log's index is a resource of its own.
+pub(crate) fn resource_file_regex(file_pattern: &str) -> Regex {
+    let pattern = regex::escape(file_pattern);
+    Regex::new(&format!(r"^(\.{pattern}_\d+|{pattern}_\d+\.bak\.-?\d+)$")).unwrap()
+}
+
 fn load_from_file<ResourceAdaptor: LoadStore>(
-    read_file: &mut File,
+    read_file: &mut dyn StorageFile,
     adaptor: &ResourceAdaptor,
     location: &StorageLocation,
 ) -> Result<ResourceAdaptor::ParamType> {
@@ -72,6 +111,162 @@ fn load_from_file<ResourceAdaptor: LoadStore>(
     adaptor.load(&buffer[..])
 }
 
+// Copy the data files of a log committed at `location` from `src_pattern` in `src_path` to
+// `dest_pattern` in `dest_path`; the file containing `location` is truncated just past it. The index
+// is a resource of its own.
+pub(crate) fn checkpoint_files(
+    backend: &dyn StorageBackend,
+    src_path: &Path,
+    src_pattern: &str,
+    dest_path: &Path,
+    dest_pattern: &str,
+    location: &StorageLocation,
+) -> Result<()> {
+    for file_counter in 0..location.file_counter {
+        link_or_copy(
+            backend,
+            &format_nth_file_path(src_path, src_pattern, file_counter),
+            &format_nth_file_path(dest_path, dest_pattern, file_counter),
+        )?;
+    }
+    copy_prefix(
+        backend,
+        &format_nth_file_path(src_path, src_pattern, location.file_counter),
+        &format_nth_file_path(dest_path, dest_pattern, location.file_counter),
+        location.store_start + location.store_length as u64,
+    )
+}
+
+// Check that `location`, recorded by the table of contents or by entry `index_entry` of the index,
+// lies inside a data file of a log with `file_pattern`. Missing files are only reported the first
+// time, as recorded in `file_lens`.
+fn verify_location(
+    backend: &dyn StorageBackend,
+    root_path: &Path,
+    file_pattern: &str,
+    location: &StorageLocation,
+    index_entry: Option<u64>,
+    file_lens: &mut HashMap<u32, Option<u64>>,
+    issues: &mut Vec<Inconsistency>,
+) -> Result<()> {
+    let path = format_nth_file_path(root_path, file_pattern, location.file_counter);
+    let len = match file_lens.entry(location.file_counter) {
+        Entry::Occupied(entry) => *entry.get(),
+        Entry::Vacant(entry) => {
+            let len = file_len(backend, &path)?;
+            if len.is_none() {
+                issues.push(Inconsistency::new(
+                    file_pattern,
+                    &path,
+                    InconsistencyKind::MissingFile,
+                ));
+            }
+            *entry.insert(len)
+        }
+    };
+    if let Some(len) = len {
+        if location.store_start + location.store_length as u64 > len {
+            issues.push(Inconsistency::new(
+                file_pattern,
+                &path,
+                InconsistencyKind::LocationPastEnd {
+                    location: *location,
+                    index_entry,
+                    len,
+                },
+            ));
+        }
+    }
+    Ok(())
+}
+
+// Check the data files of a log with `file_pattern` committed at `location` against the entries of
+// its index, as read by `fixed_append_log::read_committed_entries`, recording anything
+// inconsistent in `issues`. The index itself is checked as a resource of its own.
+pub(crate) fn verify_files(
+    backend: &dyn StorageBackend,
+    root_path: &Path,
+    file_pattern: &str,
+    location: &StorageLocation,
+    index_entries: Option<Vec<fixed_append_log::RawEntry>>,
+    issues: &mut Vec<Inconsistency>,
+) -> Result<()> {
+    let mut file_lens = HashMap::new();
+    verify_location(
+        backend,
+        root_path,
+        file_pattern,
+        location,
+        None,
+        &mut file_lens,
+        issues,
+    )?;
+    let Some(index_entries) = index_entries else {
+        return Ok(());
+    };
+
+    let adaptor = StorageLocationLoadStore::default();
+    let mut last_entry: Option<StorageLocation> = None;
+    for (index, (index_path, serialized)) in index_entries.into_iter().enumerate() {
+        let entry = match adaptor.load(&serialized) {
+            Ok(entry) => entry,
+            Err(err) => {
+                issues.push(Inconsistency::new(
+                    file_pattern,
+                    &index_path,
+                    InconsistencyKind::UnreadableIndexEntry {
+                        index_entry: index as u64,
+                        error: err.to_string(),
+                    },
+                ));
+                last_entry = None;
+                continue;
+            }
+        };
+        if let Some(last_entry) = last_entry {
+            let follows = entry.file_counter > last_entry.file_counter
+                || (entry.file_counter == last_entry.file_counter
+                    && entry.store_start
+                        >= last_entry.store_start + last_entry.store_length as u64);
+            if !follows {
+                issues.push(Inconsistency::new(
+                    file_pattern,
+                    &index_path,
+                    InconsistencyKind::IndexEntryOutOfOrder {
+                        index_entry: index as u64,
+                        location: entry,
+                        previous: last_entry,
+                    },
+                ));
+            }
+        }
+        verify_location(
+            backend,
+            root_path,
+            file_pattern,
+            &entry,
+            Some(index as u64),
+            &mut file_lens,
+            issues,
+        )?;
+        last_entry = Some(entry);
+    }
+    // The index is committed along with the log, so its last entry is the log's location.
+    if let Some(last_entry) = last_entry {
+        if last_entry != *location {
+            issues.push(Inconsistency::new(
+                file_pattern,
+                &format_nth_file_path(root_path, file_pattern, location.file_counter),
+                InconsistencyKind::LastIndexEntryMismatch {
+                    last_entry,
+                    location: *location,
+                },
+            ));
+        }
+    }
+    Ok(())
+}
+
 impl<ResourceAdaptor: LoadStore> AppendLog<ResourceAdaptor> {
     pub(crate) fn open_impl(
         loader: &mut AtomicStoreLoader,
@@ -89,7 +284,7 @@ impl<ResourceAdaptor: LoadStore> AppendLog<ResourceAdaptor> {
                     StorageLocationLoadStore::default(),
                     &index_pattern,
                     STORAGE_LOCATION_SERIALIZED_SIZE,
-                    4096,
+                    loader.config().append_log_index_file_size,
                 )?;
                 let append_point = location.store_start + location.store_length as u64;
                 if append_point < file_fill_size {
@@ -104,7 +299,7 @@ impl<ResourceAdaptor: LoadStore> AppendLog<ResourceAdaptor> {
                     StorageLocationLoadStore::default(),
                     &index_pattern,
                     STORAGE_LOCATION_SERIALIZED_SIZE,
-                    4096,
+                    loader.config().append_log_index_file_size,
                 )?;
                 (0, 0, index_log)
             }
@@ -112,6 +307,8 @@ impl<ResourceAdaptor: LoadStore> AppendLog<ResourceAdaptor> {
 
         Ok(AppendLog {
             persisted_sync: Arc::new(RwLock::new(VersionSyncHandle::new(file_pattern, location))),
+            backend: loader.backend().clone(),
+            backups: loader.config().backups,
             file_path: file_path.to_path_buf(),
             file_pattern: String::from(file_pattern),
             file_fill_size,
@@ -119,6 +316,11 @@ impl<ResourceAdaptor: LoadStore> AppendLog<ResourceAdaptor> {
             write_pos,
             write_file_counter: counter,
             index_log,
+            branches: Box::new(Branches::open(
+                loader.backend().clone(),
+                file_path,
+                file_pattern,
+            )?),
             adaptor,
         })
     }
@@ -129,7 +331,9 @@ impl<ResourceAdaptor: LoadStore> AppendLog<ResourceAdaptor> {
         file_pattern: &str,
         file_fill_size: u64,
     ) -> Result<AppendLog<ResourceAdaptor>> {
-        let resource = loader.look_up_resource(file_pattern);
+        let descriptor = Self::descriptor(&adaptor, file_fill_size);
+        let resource =
+            loader.look_up_resource(file_pattern, &descriptor, adaptor.format_id().is_some())?;
         let path = loader.persistence_path().to_path_buf();
         let created = Self::open_impl(
             loader,
@@ -139,7 +343,7 @@ impl<ResourceAdaptor: LoadStore> AppendLog<ResourceAdaptor> {
             file_pattern,
             file_fill_size,
         )?;
-        loader.add_sync_handle(file_pattern, created.persisted_sync.clone())?;
+        loader.add_sync_handle(file_pattern, created.persisted_sync.clone(), descriptor)?;
         Ok(created)
     }
     pub fn create(
@@ -148,25 +352,66 @@ impl<ResourceAdaptor: LoadStore> AppendLog<ResourceAdaptor> {
         file_pattern: &str,
         file_fill_size: u64,
     ) -> Result<AppendLog<ResourceAdaptor>> {
+        let descriptor = Self::descriptor(&adaptor, file_fill_size);
         let path = loader.persistence_path().to_path_buf();
         let created = Self::open_impl(loader, adaptor, None, &path, file_pattern, file_fill_size)?;
-        loader.add_sync_handle(file_pattern, created.persisted_sync.clone())?;
+        loader.add_sync_handle(file_pattern, created.persisted_sync.clone(), descriptor)?;
         Ok(created)
     }
 
+    /// Open the log for reading at the version recorded in `loader`. Unlike `load`, the log's
+    /// files are not modified, and the log is not registered with the loader.
+    pub fn open_read_only(
+        loader: &ReadOnlyLoader,
+        adaptor: ResourceAdaptor,
+        file_pattern: &str,
+        file_fill_size: u64,
+    ) -> Result<ReadOnlyAppendLog<ResourceAdaptor>> {
+        let descriptor = Self::descriptor(&adaptor, file_fill_size);
+        let location =
+            loader.look_up_resource(file_pattern, &descriptor, adaptor.format_id().is_some())?;
+        let index_log = FixedAppendLog::open_read_only(
+            loader,
+            StorageLocationLoadStore::default(),
+            &format_index_file_pattern(file_pattern),
+            STORAGE_LOCATION_SERIALIZED_SIZE,
+            loader.config().append_log_index_file_size,
+        )?;
+        Ok(ReadOnlyAppendLog {
+            persisted_sync: Arc::new(RwLock::new(VersionSyncHandle::new(file_pattern, location))),
+            backend: loader.backend().clone(),
+            file_path: loader.persistence_path().to_path_buf(),
+            file_pattern: String::from(file_pattern),
+            store_pattern: loader.file_pattern().to_string(),
+            file_fill_size,
+            version: loader.version(),
+            index_log,
+            adaptor,
+        })
+    }
+
+    fn descriptor(adaptor: &ResourceAdaptor, file_fill_size: u64) -> ResourceDescriptor {
+        ResourceDescriptor {
+            kind: ResourceKind::AppendLog { file_fill_size },
+            format: recorded_format(adaptor),
+        }
+    }
+
     fn open_write_file(&mut self) -> Result<()> {
         let out_file_path =
             format_nth_file_path(&self.file_path, &self.file_pattern, self.write_file_counter);
 
-        if out_file_path.exists() {
-            if !out_file_path.is_file() {
+        // Whether the directory entries change, and must be synced once the file is open.
+        let mut dir_changed = !self.backend.exists(&out_file_path);
+        if self.backend.exists(&out_file_path) {
+            if !self.backend.is_file(&out_file_path) {
                 return Err(PersistenceError::InvalidPathToFile {
                     path: out_file_path.to_string_lossy().to_string(),
                 });
             }
 
-            if let Ok(metadata) = fs::metadata(&out_file_path) {
-                if metadata.len() > self.write_pos {
+            if let Ok(Some(EntryType::File { len })) = self.backend.stat(&out_file_path) {
+                if len > self.write_pos {
                     let mut backup_path = self.file_path.clone();
                     backup_path.push(format!(
                         "{}_{}.bak.{}",
@@ -174,22 +419,31 @@ impl<ResourceAdaptor: LoadStore> AppendLog<ResourceAdaptor> {
                         self.write_file_counter,
                         unix_timestamp()
                     ));
+                    // The file is replaced rather than truncated in place, as it may be hard linked
+                    // by a checkpoint.
+                    self.backend
+                        .rename(&out_file_path, &backup_path)
+                        .context(StdIoDirOpsSnafu)?;
                     if self.write_pos > 0 {
-                        fs::copy(&out_file_path, &backup_path).context(StdIoDirOpsSnafu)?;
-                    } else {
-                        fs::rename(&out_file_path, &backup_path).context(StdIoDirOpsSnafu)?;
+                        self.backend
+                            .copy(&backup_path, &out_file_path)
+                            .context(StdIoDirOpsSnafu)?;
+                    }
+                    if self.backups == BackupPolicy::Discard {
+                        discard_backup(self.backend.as_ref(), &backup_path, &out_file_path)?;
                     }
+                    dir_changed = true;
                 }
             }
         }
 
-        #[allow(clippy::suspicious_open_options)]
-        let mut file = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .open(out_file_path)
+        let mut file = self
+            .backend
+            .open(&out_file_path, OpenMode::ReadWrite)
             .context(StdIoOpenSnafu)?;
+        if dir_changed {
+            sync_dir(self.backend.as_ref(), &self.file_path)?;
+        }
         file.seek(SeekFrom::End(0)).context(StdIoSeekSnafu)?;
         if file.stream_position().context(StdIoSeekSnafu)? != self.write_pos {
             file.set_len(self.write_pos).context(StdIoWriteSnafu)?;
@@ -202,20 +456,23 @@ impl<ResourceAdaptor: LoadStore> AppendLog<ResourceAdaptor> {
     }
 
     // Writes out a resource instance; does not update the commit position, but in this version, does advance the pending commit position.
-    // In the future, we may support a queue of commit points, or even entire sequences of pre-written alternative future versions (for chained consensus), which would require a more complex interface.
     pub fn store_resource(
         &mut self,
         resource: &ResourceAdaptor::ParamType,
     ) -> Result<StorageLocation> {
+        let serialized = self.adaptor.store(resource)?;
+        self.store_serialized(&serialized)
+    }
+
+    fn store_serialized(&mut self, serialized: &[u8]) -> Result<StorageLocation> {
         if self.write_to_file.is_none() {
             self.open_write_file()?;
         }
-        let serialized = self.adaptor.store(resource)?;
         let resource_length = serialized.len() as u32;
         self.write_to_file
-            .as_ref()
+            .as_mut()
             .unwrap()
-            .write_all(&serialized)
+            .write_all(serialized)
             .context(StdIoWriteSnafu)?;
 
         let location = StorageLocation {
@@ -227,8 +484,7 @@ impl<ResourceAdaptor: LoadStore> AppendLog<ResourceAdaptor> {
         self.write_pos += resource_length as u64;
         if self.write_pos >= self.file_fill_size {
             if let Some(ref mut file) = self.write_to_file {
-                file.flush().context(StdIoWriteSnafu)?; // drop is not guaranteed to report errors
-                file.sync_all().context(StdIoDirOpsSnafu)?;
+                file.sync().context(StdIoDirOpsSnafu)?; // drop is not guaranteed to report errors
             }
             self.write_pos = 0;
             self.write_file_counter += 1;
@@ -239,16 +495,70 @@ impl<ResourceAdaptor: LoadStore> AppendLog<ResourceAdaptor> {
         Ok(location)
     }
 
-    // This currenty won't have any effect if called again before the atomic store has processed the prior committed version. A more appropriate behavior might be to block. A version that supports queued writes could enqueue the commit points.
+    // If called again before the atomic store has processed the prior committed version, the commit points are queued, and recorded by successive versions of the store.
     pub fn commit_version(&mut self) -> Result<()> {
         if let Some(ref mut file) = self.write_to_file {
-            file.flush().context(StdIoWriteSnafu)?; // in case the latest write isn't flushed
-            file.sync_all().context(StdIoDirOpsSnafu)?;
+            file.sync().context(StdIoDirOpsSnafu)?; // in case the latest write isn't flushed
         }
         self.index_log.commit_version()?;
         self.persisted_sync.write()?.update_version()
     }
 
+    /// Write a resource instance to the speculative `branch`, returning its index within the
+    /// branch. The log itself is unchanged until the branch is committed.
+    pub fn store_branch_resource(
+        &mut self,
+        branch: BranchId,
+        resource: &ResourceAdaptor::ParamType,
+    ) -> Result<u64> {
+        let serialized = self.adaptor.store(resource)?;
+        self.branches.append(branch, &serialized)
+    }
+
+    /// Load the resource instance at `index` of the speculative `branch`.
+    pub fn load_branch_resource(
+        &self,
+        branch: BranchId,
+        index: u64,
+    ) -> Result<ResourceAdaptor::ParamType> {
+        self.adaptor.load(&self.branches.read(branch, index)?)
+    }
+
+    /// The number of resource instances written to `branch`; zero for a branch that has not been
+    /// written to.
+    pub fn branch_len(&self, branch: BranchId) -> u64 {
+        self.branches.len(branch)
+    }
+
+    /// Decide on `branch`: append its resource instances to the log after any stored since the
+    /// last commit, discard every branch, and commit the version. A branch that was never written
+    /// to commits no new instances. To decide on a branch of several logs of a store at once, use
+    /// `AtomicStore::commit_branch`.
+    ///
+    /// If an instance cannot be appended or the version cannot be committed, the log is reverted
+    /// to its last commit, dropping anything stored since, and the branches are kept.
+    pub fn commit_branch(&mut self, branch: BranchId) -> Result<()> {
+        if let Err(err) = self
+            .append_branch(branch)
+            .and_then(|()| self.commit_version())
+        {
+            if let Err(revert_err) = self.revert_version() {
+                tracing::warn!(
+                    err = %revert_err,
+                    key = %self.file_pattern,
+                    "failed to revert log after failing to commit a branch",
+                );
+            }
+            return Err(err);
+        }
+        self.branches.discard()
+    }
+
+    /// Discard every branch without committing any of them.
+    pub fn discard_branches(&mut self) -> Result<()> {
+        self.branches.discard()
+    }
+
     pub fn skip_version(&mut self) -> Result<()> {
         self.index_log.skip_version()?;
         self.persisted_sync.write()?.skip_version()
@@ -273,13 +583,117 @@ impl<ResourceAdaptor: LoadStore> AppendLog<ResourceAdaptor> {
     pub fn load_specified(&self, location: &StorageLocation) -> Result<ResourceAdaptor::ParamType> {
         let read_file_path =
             format_nth_file_path(&self.file_path, &self.file_pattern, location.file_counter);
-        let mut read_file = File::open(read_file_path.as_path()).context(StdIoOpenSnafu)?;
-        load_from_file::<ResourceAdaptor>(&mut read_file, &self.adaptor, location)
+        let mut read_file = self
+            .backend
+            .open(&read_file_path, OpenMode::Read)
+            .context(StdIoOpenSnafu)?;
+        load_from_file::<ResourceAdaptor>(read_file.as_mut(), &self.adaptor, location)
+    }
+
+    /// Load the resource instance at `index`, looking up its location in the index.
+    pub fn load_at(&self, index: u64) -> Result<ResourceAdaptor::ParamType> {
+        self.load_specified(&self.index_log.load_at(index)?)
+    }
+
+    pub fn iter(&self) -> Iter<'_, ResourceAdaptor> {
+        Iter {
+            inner_iter: self.index_log.iter(),
+            backend: self.backend.clone(),
+            file_path: self.file_path.clone(),
+            file_pattern: self.file_pattern.clone(),
+            read_from_file: None,
+            read_from_counter: 0,
+            adaptor: &self.adaptor,
+        }
+    }
+}
+
+impl<ResourceAdaptor: LoadStore> BranchedLog for AppendLog<ResourceAdaptor> {
+    fn resource_key(&self) -> &str {
+        &self.file_pattern
+    }
+
+    fn append_branch(&mut self, branch: BranchId) -> Result<()> {
+        for index in 0..self.branches.len(branch) {
+            let serialized = self.branches.read(branch, index)?;
+            self.store_serialized(&serialized)?;
+        }
+        Ok(())
+    }
+
+    fn commit_version(&mut self) -> Result<()> {
+        AppendLog::commit_version(self)
+    }
+
+    fn revert_version(&mut self) -> Result<()> {
+        AppendLog::revert_version(self)
+    }
+
+    fn discard_branches(&mut self) -> Result<()> {
+        AppendLog::discard_branches(self)
+    }
+}
+
+impl<ResourceAdaptor: LoadStore> ReadOnlyAppendLog<ResourceAdaptor> {
+    /// Pick up the newest committed version of the store. Returns whether a different version of
+    /// the table of contents was read.
+    pub fn refresh(&mut self) -> Result<bool> {
+        let loader = AtomicStoreLoader::open_read_only_with_config(
+            &self.file_path,
+            &self.store_pattern,
+            AtomicStoreConfig::default().backend(self.backend.clone()),
+        )?;
+        if loader.version() == self.version {
+            return Ok(false);
+        }
+        let descriptor = AppendLog::descriptor(&self.adaptor, self.file_fill_size);
+        let location = loader.look_up_resource(
+            &self.file_pattern,
+            &descriptor,
+            self.adaptor.format_id().is_some(),
+        )?;
+        self.index_log.update(&loader)?;
+        self.persisted_sync = Arc::new(RwLock::new(VersionSyncHandle::new(
+            &self.file_pattern,
+            location,
+        )));
+        self.version = loader.version();
+        Ok(true)
+    }
+
+    /// The counter of the table of contents this log was last read from.
+    pub fn version(&self) -> Option<u32> {
+        self.version
+    }
+
+    pub fn load_latest(&self) -> Result<ResourceAdaptor::ParamType> {
+        if let Some(location) = self.persisted_sync.read()?.last_location() {
+            self.load_specified(location)
+        } else {
+            Err(PersistenceError::FailedToFindExpectedResource {
+                key: self.file_pattern.to_string(),
+            })
+        }
+    }
+    pub fn load_specified(&self, location: &StorageLocation) -> Result<ResourceAdaptor::ParamType> {
+        let read_file_path =
+            format_nth_file_path(&self.file_path, &self.file_pattern, location.file_counter);
+        let mut read_file = self
+            .backend
+            .open(&read_file_path, OpenMode::Read)
+            .context(StdIoOpenSnafu)?;
+        load_from_file::<ResourceAdaptor>(read_file.as_mut(), &self.adaptor, location)
+    }
+
+    /// Load the resource instance at `index`, looking up its location in the index.
+    pub fn load_at(&self, index: u64) -> Result<ResourceAdaptor::ParamType> {
+        self.load_specified(&self.index_log.load_at(index)?)
     }
 
-    pub fn iter(&self) -> Iter<ResourceAdaptor> {
+    pub fn iter(&self) -> Iter<'_, ResourceAdaptor> {
         Iter {
             inner_iter: self.index_log.iter(),
+            backend: self.backend.clone(),
             file_path: self.file_path.clone(),
             file_pattern: self.file_pattern.clone(),
             read_from_file: None,
@@ -298,11 +712,14 @@ impl<ResourceAdaptor: LoadStore> Iter<'_, ResourceAdaptor> {
             self.read_from_counter = location.file_counter;
             let read_file_path =
                 format_nth_file_path(&self.file_path, &self.file_pattern, location.file_counter);
-            self.read_from_file =
-                Some(File::open(read_file_path.as_path()).context(StdIoOpenSnafu)?);
+            self.read_from_file = Some(
+                self.backend
+                    .open(&read_file_path, OpenMode::Read)
+                    .context(StdIoOpenSnafu)?,
+            );
         }
         load_from_file::<ResourceAdaptor>(
-            self.read_from_file.as_mut().unwrap(),
+            self.read_from_file.as_mut().unwrap().as_mut(),
             self.adaptor,
             location,
         )
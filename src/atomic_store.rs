@@ -5,12 +5,20 @@
 // This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
 // You should have received a copy of the GNU General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
 
+use crate::append_log;
+use crate::branch::{self, BranchId, BranchedLog};
+use crate::config::{AtomicStoreConfig, ExistingStorePolicy, GarbagePolicy};
 use crate::error::{
     BincodeDeSnafu, BincodeSerSnafu, PersistenceError, StdIoDirOpsSnafu, StdIoOpenSnafu,
     StdIoReadSnafu, StdIoWriteSnafu,
 };
+use crate::fixed_append_log;
+use crate::resource_descriptor::{ResourceDescriptor, ResourceKind};
+use crate::rolling_log;
+use crate::storage_backend::{EntryType, OpenMode, StorageBackend, StorageFile};
 use crate::storage_location::StorageLocation;
-use crate::utils::unix_timestamp;
+use crate::two_phase_commit;
+use crate::utils::{create_dir_all, link_or_copy, remove_dir_all, sync_dir, unix_timestamp};
 use crate::version_sync::VersionSyncHandle;
 use crate::Result;
 
@@ -19,27 +27,186 @@ use serde::{Deserialize, Serialize};
 use snafu::ResultExt;
 
 use std::collections::hash_map::Entry;
-use std::collections::HashMap;
-use std::fs;
-use std::fs::File;
-use std::io::{Read, Write};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs::TryLockError;
+use std::io::{Read, Seek, SeekFrom, Write};
 use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, Receiver, Sender};
 use std::sync::{Arc, RwLock};
-use std::time::Duration;
+use std::time::{Duration, Instant};
+
+/// Marks a table of contents file written in a versioned format; files without it predate
+/// versioning.
+const TOC_MAGIC: [u8; 8] = *b"ATOMSTOC";
+// After the version, the header holds the length of the body and a CRC-32 of it.
+const TOC_FORMAT_VERSION: u32 = 1;
+const TOC_HEADER_SIZE: usize = TOC_MAGIC.len() + 4;
+const TOC_CHECKSUM_HEADER_SIZE: usize = TOC_HEADER_SIZE + 8;
+
+/// A resource recorded in the table of contents.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct ResourceEntry {
+    pub location: StorageLocation,
+    // `None` for resources migrated from the unversioned format; the descriptor is recorded the
+    // next time the resource is loaded and committed.
+    pub descriptor: Option<ResourceDescriptor>,
+}
 
 /// This exists to provide a common type for serializing and deserializing of the atomic store
 /// table of contents, so the prior state can be pre-loaded without sacrificing single point of initialization.
 #[derive(Debug, Serialize, Deserialize)]
 struct AtomicStoreFileContents {
+    pub file_counter: u32,
+    pub resource_files: HashMap<String, ResourceEntry>,
+    // Passed to `commit_version_with_metadata`; empty for versions committed without metadata.
+    pub metadata: Vec<u8>,
+    // When the version was committed, in seconds since the Unix epoch; `None` for versions
+    // committed before timestamps were recorded.
+    pub timestamp: Option<i64>,
+    // Resources that have been removed. Their files are deleted once no retained version refers to
+    // them.
+    pub retired_resources: HashMap<String, RetiredResource>,
+}
+
+/// A removed resource whose files have not been deleted yet.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct RetiredResource {
+    pub first_version_without: u32,
+    // Determines the names of the resource's files; `None` if it had no descriptor when it was
+    // removed, in which case its files are never deleted.
+    pub kind: Option<ResourceKind>,
+}
+
+/// The table of contents as written before the format was versioned.
+#[derive(Debug, Serialize, Deserialize)]
+struct LegacyFileContents {
     pub file_counter: u32,
     pub resource_files: HashMap<String, StorageLocation>,
 }
 
-fn load_state(path: &Path) -> Result<AtomicStoreFileContents> {
-    let mut file = File::open(path).context(StdIoOpenSnafu)?;
+impl From<LegacyFileContents> for AtomicStoreFileContents {
+    fn from(legacy: LegacyFileContents) -> Self {
+        AtomicStoreFileContents {
+            file_counter: legacy.file_counter,
+            resource_files: legacy
+                .resource_files
+                .into_iter()
+                .map(|(key, location)| {
+                    (
+                        key,
+                        ResourceEntry {
+                            location,
+                            descriptor: None,
+                        },
+                    )
+                })
+                .collect(),
+            metadata: Vec::new(),
+            timestamp: None,
+            retired_resources: HashMap::new(),
+        }
+    }
+}
+
+fn decode_state(path: &Path, buf: &[u8]) -> Result<AtomicStoreFileContents> {
+    if buf.len() < TOC_HEADER_SIZE || buf[..TOC_MAGIC.len()] != TOC_MAGIC {
+        let legacy = bincode::deserialize::<LegacyFileContents>(buf).context(BincodeDeSnafu)?;
+        // The legacy format has no checksum, but it must at least account for the entire file.
+        if bincode::serialized_size(&legacy).ok() != Some(buf.len() as u64) {
+            return Err(PersistenceError::InvalidFileContents {
+                note: "trailing data after legacy table of contents".to_string(),
+                path: path.to_string_lossy().to_string(),
+            });
+        }
+        return Ok(legacy.into());
+    }
+    let invalid = |note: String| PersistenceError::InvalidFileContents {
+        note,
+        path: path.to_string_lossy().to_string(),
+    };
+    let read_u32 = |offset: usize| {
+        let mut bytes = [0u8; 4];
+        bytes.copy_from_slice(&buf[offset..offset + 4]);
+        u32::from_le_bytes(bytes)
+    };
+    match read_u32(TOC_MAGIC.len()) {
+        TOC_FORMAT_VERSION => {
+            if buf.len() < TOC_CHECKSUM_HEADER_SIZE {
+                return Err(invalid("truncated table of contents header".to_string()));
+            }
+            let body_length = read_u32(TOC_HEADER_SIZE) as usize;
+            let checksum = read_u32(TOC_HEADER_SIZE + 4);
+            let body = &buf[TOC_CHECKSUM_HEADER_SIZE..];
+            if body.len() != body_length {
+                return Err(invalid(format!(
+                    "table of contents body is {} bytes, expected {}",
+                    body.len(),
+                    body_length
+                )));
+            }
+            if crc32fast::hash(body) != checksum {
+                return Err(invalid("table of contents checksum mismatch".to_string()));
+            }
+            bincode::deserialize::<AtomicStoreFileContents>(body).context(BincodeDeSnafu)
+        }
+        version => Err(invalid(format!(
+            "unsupported table of contents format version {}",
+            version
+        ))),
+    }
+}
+
+fn encode_state(contents: &AtomicStoreFileContents) -> Result<Vec<u8>> {
+    let body = bincode::serialize(contents).context(BincodeSerSnafu)?;
+    let mut buf = TOC_MAGIC.to_vec();
+    buf.extend_from_slice(&TOC_FORMAT_VERSION.to_le_bytes());
+    buf.extend_from_slice(&(body.len() as u32).to_le_bytes());
+    buf.extend_from_slice(&crc32fast::hash(&body).to_le_bytes());
+    buf.extend(body);
+    Ok(buf)
+}
+
+fn load_state(backend: &dyn StorageBackend, path: &Path) -> Result<AtomicStoreFileContents> {
+    let mut file = backend.open(path, OpenMode::Read).context(StdIoOpenSnafu)?;
     let mut buf = Vec::new();
     file.read_to_end(&mut buf).context(StdIoReadSnafu)?;
-    bincode::deserialize::<AtomicStoreFileContents>(&buf[..]).context(BincodeDeSnafu)
+    decode_state(path, &buf)
+}
+
+/// A table of contents file that was skipped while loading because its contents were invalid.
+#[derive(Debug, Clone)]
+pub struct SkippedVersion {
+    pub path: PathBuf,
+    pub reason: String,
+}
+
+/// Load a table of contents, returning `None` and recording it in `skipped` if the file is
+/// corrupted. I/O errors are not treated as corruption, and are returned.
+fn load_valid_state(
+    backend: &dyn StorageBackend,
+    path: &Path,
+    skipped: &mut Vec<SkippedVersion>,
+) -> Result<Option<AtomicStoreFileContents>> {
+    match load_state(backend, path) {
+        Ok(state) => Ok(Some(state)),
+        Err(
+            err @ (PersistenceError::InvalidFileContents { .. }
+            | PersistenceError::BincodeDe { .. }),
+        ) => {
+            tracing::warn!(
+                %err,
+                path = %path.display(),
+                "skipping corrupted table of contents",
+            );
+            skipped.push(SkippedVersion {
+                path: path.to_path_buf(),
+                reason: err.to_string(),
+            });
+            Ok(None)
+        }
+        Err(err) => Err(err),
+    }
 }
 
 fn format_latest_file_path(root_path: &Path, file_pattern: &str) -> PathBuf {
@@ -54,6 +221,31 @@ fn format_archived_file_path(root_path: &Path, file_pattern: &str, counter: u32)
     buf
 }
 
+// The path to quarantine the table of contents of `version` at. A store rolled back more than once
+// commits new versions with the counters of quarantined ones, so if `version` has been quarantined
+// before, a sequence number is added rather than replacing the earlier file.
+fn format_quarantined_file_path(
+    backend: &dyn StorageBackend,
+    root_path: &Path,
+    file_pattern: &str,
+    version: &str,
+) -> PathBuf {
+    let path = root_path.join(format!("{}_quarantined_{}", file_pattern, version));
+    if !backend.exists(&path) {
+        return path;
+    }
+    (1u32..)
+        .map(|seq| root_path.join(format!("{}_quarantined_{}.{}", file_pattern, version, seq)))
+        .find(|path| !backend.exists(path))
+        .unwrap()
+}
+
+fn format_lock_file_path(root_path: &Path, file_pattern: &str) -> PathBuf {
+    let mut buf = root_path.to_path_buf();
+    buf.push(format!(".{}_lock", file_pattern));
+    buf
+}
+
 fn format_working_file_path(root_path: &Path, file_pattern: &str) -> PathBuf {
     let mut buf = root_path.to_path_buf();
     buf.push(format!(".{}_working", file_pattern));
@@ -65,20 +257,17 @@ fn format_working_file_path(root_path: &Path, file_pattern: &str) -> PathBuf {
 /// Yields (in an unspecified order) file paths for each archive file in `root_path`, along with the
 /// file counter associated with each archive file.
 fn archive_files<'a>(
+    backend: &dyn StorageBackend,
     root_path: &'a Path,
     file_pattern: &'a str,
 ) -> Result<impl 'a + Iterator<Item = Result<(PathBuf, u32)>>> {
     let re = Regex::new(&format!("^{file_pattern}_archived_(\\d+)$")).unwrap();
-    Ok(fs::read_dir(root_path)
+    Ok(backend
+        .list(root_path)
         .context(StdIoDirOpsSnafu)?
-        .map(move |de| {
-            let de = de.context(StdIoDirOpsSnafu)?;
-            let os_name = de.file_name();
-            let Some(name) = os_name.to_str() else {
-                // If the file name is not unicode, it cannot match the archive file pattern.
-                return Ok(None);
-            };
-            let Some(captures) = re.captures(name) else {
+        .into_iter()
+        .map(move |name| {
+            let Some(captures) = re.captures(&name) else {
                 return Ok(None);
             };
             // The regex used cannot match without capturing something in the first capture group,
@@ -89,16 +278,20 @@ fn archive_files<'a>(
                 // not an archive file after all.
                 return Ok(None);
             };
-            Ok(Some((root_path.join(name), archive_num)))
+            Ok(Some((root_path.join(&name), archive_num)))
         })
         // Filter out `Ok(None)` entries, which are files that didn't match the archive file
         // pattern.
         .filter_map(Result::transpose))
 }
 
-fn archive_file_exists(root_path: &Path, file_pattern: &str) -> Result<bool> {
+fn archive_file_exists(
+    backend: &dyn StorageBackend,
+    root_path: &Path,
+    file_pattern: &str,
+) -> Result<bool> {
     let mut err = None;
-    for res in archive_files(root_path, file_pattern)? {
+    for res in archive_files(backend, root_path, file_pattern)? {
         match res {
             // If the iterator yields any element successfully, then at least one archive file
             // exists.
@@ -115,358 +308,4587 @@ fn archive_file_exists(root_path: &Path, file_pattern: &str) -> Result<bool> {
     }
 }
 
-fn prune_archives(root_path: &Path, file_pattern: &str, below: u32) -> Result<()> {
-    for res in archive_files(root_path, file_pattern)? {
+/// Move the table of contents of every version newer than `counter` out of the way, so that a store
+/// reopened at an older version can commit new versions on top of it.
+fn quarantine_versions_after(
+    backend: &dyn StorageBackend,
+    root_path: &Path,
+    file_pattern: &str,
+    counter: u32,
+) -> Result<()> {
+    let mut newer_archives = Vec::new();
+    for res in archive_files(backend, root_path, file_pattern)? {
+        let (path, num) = res?;
+        if num > counter {
+            newer_archives.push((path, num));
+        }
+    }
+    for (path, num) in newer_archives {
+        let quarantine_path =
+            format_quarantined_file_path(backend, root_path, file_pattern, &num.to_string());
+        backend
+            .rename(&path, &quarantine_path)
+            .context(StdIoDirOpsSnafu)?;
+        sync_dir(backend, root_path)?;
+    }
+    // Move the latest version last, so that if we are interrupted, loading the store still finds
+    // either the newest version or the one we rolled back to.
+    let latest_file_path = format_latest_file_path(root_path, file_pattern);
+    if backend.exists(&latest_file_path) {
+        let version = match load_valid_state(backend, &latest_file_path, &mut Vec::new())? {
+            Some(state) if state.file_counter == counter => return Ok(()),
+            Some(state) => state.file_counter.to_string(),
+            None => format!("latest.{}", unix_timestamp()),
+        };
+        let quarantine_path =
+            format_quarantined_file_path(backend, root_path, file_pattern, &version);
+        backend
+            .rename(&latest_file_path, &quarantine_path)
+            .context(StdIoDirOpsSnafu)?;
+        sync_dir(backend, root_path)?;
+    }
+    Ok(())
+}
+
+fn prune_archives(
+    backend: &dyn StorageBackend,
+    root_path: &Path,
+    file_pattern: &str,
+    below: u32,
+) -> Result<()> {
+    let mut pruned = false;
+    for res in archive_files(backend, root_path, file_pattern)? {
         let (path, num) = res?;
         if num >= below {
             continue;
         }
-        fs::remove_file(path).context(StdIoDirOpsSnafu)?;
+        backend.remove(&path).context(StdIoDirOpsSnafu)?;
+        pruned = true;
+    }
+    if pruned {
+        sync_dir(backend, root_path)?;
     }
     Ok(())
 }
 
-/// Enables each managed resource storage instance to initialize before creating the AtomicStore.
-pub struct AtomicStoreLoader {
-    file_path: PathBuf,
-    file_pattern: String,
-    file_counter: u32,
-    initial_run: bool,
-    // TODO: type checking on load/store format embedded in StorageLocation?
-    resource_files: HashMap<String, StorageLocation>,
-    resources: HashMap<String, Arc<RwLock<VersionSyncHandle>>>,
-    // How many backup index files to retain at any given time. If `None`, all archives will be
-    // retained.
-    retained_archives: Option<u32>,
+// Write a table of contents to a new file at `path`, and sync it.
+fn write_state(
+    backend: &dyn StorageBackend,
+    path: &Path,
+    contents: &AtomicStoreFileContents,
+) -> Result<()> {
+    let mut file = backend
+        .open(path, OpenMode::Create)
+        .context(StdIoOpenSnafu)?;
+    let serialized = encode_state(contents)?;
+    file.write_all(&serialized).context(StdIoWriteSnafu)?;
+    file.sync().context(StdIoDirOpsSnafu)
 }
 
-impl AtomicStoreLoader {
-    /// Attempt to load the specified atomic state in the specified directory; if no files exist, will initialize a new state
-    pub fn load(storage_path: &Path, file_pattern: &str) -> Result<AtomicStoreLoader> {
-        let file_path = storage_path.to_path_buf();
-        let load_path_buf = format_latest_file_path(storage_path, file_pattern);
-        let alt_path_buf;
-        let load_path = if load_path_buf.as_path().exists() {
-            load_path_buf.as_path()
-        } else {
-            let max_match = if storage_path.exists() {
-                // attempt to use the most recent backup file
-                archive_files(storage_path, file_pattern)?
-                    .max_by_key(|res| Some(res.as_ref().ok()?.1))
-                    .and_then(|res| Some(res.ok()?.0))
-            } else {
-                fs::create_dir_all(storage_path).context(StdIoDirOpsSnafu)?;
-                None
-            };
+// Revert each of `logs` to its last commit after failing to commit a branch. Failures are only
+// logged, so that the error that stopped the commit is the one returned.
+fn revert_branched_logs(logs: &mut [&mut dyn BranchedLog]) {
+    for log in logs.iter_mut() {
+        if let Err(err) = log.revert_version() {
+            tracing::warn!(
+                %err,
+                key = log.resource_key(),
+                "failed to revert log after failing to commit a branch",
+            );
+        }
+    }
+}
 
-            if max_match.is_none() {
-                // start from scratch
-                return Ok(AtomicStoreLoader {
-                    file_path,
-                    file_pattern: String::from(file_pattern),
-                    file_counter: 0,
-                    initial_run: true,
-                    resource_files: HashMap::new(),
-                    resources: HashMap::new(),
-                    retained_archives: None,
-                });
-            }
-            alt_path_buf = max_match.unwrap();
-            alt_path_buf.as_path()
+// Make the table of contents in the working file the latest version, archiving the previous latest
+// version, whose counter is `last_counter` if it is known. The version is durable once this returns.
+fn install_working_state(
+    backend: &dyn StorageBackend,
+    root_path: &Path,
+    file_pattern: &str,
+    last_counter: Option<u32>,
+) -> Result<()> {
+    let latest_file_path = format_latest_file_path(root_path, file_pattern);
+    let temp_file_path = format_working_file_path(root_path, file_pattern);
+    if !backend.exists(&temp_file_path) {
+        // A retry after the working file was installed, but before the directory was synced.
+        return sync_dir(backend, root_path);
+    }
+    if backend.exists(&latest_file_path) {
+        let last_counter = match last_counter {
+            Some(last_counter) => last_counter,
+            None => load_state(backend, &latest_file_path)?.file_counter,
         };
-        if !load_path.is_file() {
-            return Err(PersistenceError::InvalidPathToFile {
-                path: load_path.to_string_lossy().to_string(),
-            });
-        }
-        let loaded_state = load_state(load_path)?;
-        Ok(AtomicStoreLoader {
-            file_path: storage_path.to_path_buf(),
-            file_pattern: String::from(file_pattern),
-            file_counter: loaded_state.file_counter,
-            initial_run: false,
-            resource_files: loaded_state.resource_files,
-            resources: HashMap::new(),
-            retained_archives: None,
-        })
+        let archived_file_path = format_archived_file_path(root_path, file_pattern, last_counter);
+        backend
+            .rename(&latest_file_path, &archived_file_path)
+            .context(StdIoDirOpsSnafu)?;
     }
-    /// Attempt to initialize a new atomic state in the specified directory; if files exist, will back up existing directory before creating
-    pub fn create(storage_path: &Path, file_pattern: &str) -> Result<AtomicStoreLoader> {
-        if !storage_path.exists() {
-            fs::create_dir_all(storage_path).context(StdIoDirOpsSnafu)?;
-        } else if archive_file_exists(storage_path, file_pattern)?
-            || format_latest_file_path(storage_path, file_pattern).exists()
-        {
-            let mut backup_path = storage_path.to_path_buf();
-            let mut temp_path = storage_path.to_path_buf();
-            if !temp_path.pop() {
-                // TODO: maybe use some kind of known location instead?
-                // std::env::temp_dir() is not guaranteed secure, std::env::current_dir() and std::env::home_dir() are unreliable.
-                // Maybe it's better to just require the path to be in a writable parent if you're going to try to create new with existing files.
-                return Err(PersistenceError::FailedToResolvePath {
-                    path: storage_path.to_string_lossy().to_string(),
-                });
-            }
-            temp_path.push("temporary");
-            backup_path.push(format!("backup.{}", unix_timestamp()));
+    backend
+        .rename(&temp_file_path, &latest_file_path)
+        .context(StdIoDirOpsSnafu)?;
+    // The version is not durable until the renames are.
+    sync_dir(backend, root_path)
+}
 
-            fs::rename(storage_path, &temp_path).context(StdIoDirOpsSnafu)?;
-            fs::create_dir(storage_path).context(StdIoDirOpsSnafu)?;
-            fs::rename(&temp_path, &backup_path).context(StdIoDirOpsSnafu)?;
+// Commit or discard a version left prepared by an interrupted `CommitCoordinator::commit`,
+// according to the coordinator's decision.
+fn resolve_prepared_version(
+    backend: &dyn StorageBackend,
+    root_path: &Path,
+    file_pattern: &str,
+) -> Result<()> {
+    let Some(committed) = two_phase_commit::prepared_outcome(backend, root_path, file_pattern)?
+    else {
+        return Ok(());
+    };
+    let temp_file_path = format_working_file_path(root_path, file_pattern);
+    // Without a working file, the version was committed before the marker could be removed.
+    if backend.exists(&temp_file_path) {
+        if committed {
+            install_working_state(backend, root_path, file_pattern, None)?;
+        } else {
+            backend.remove(&temp_file_path).context(StdIoDirOpsSnafu)?;
         }
-        // TODO: sane behavior if files are already present
-        Ok(AtomicStoreLoader {
-            file_path: storage_path.to_path_buf(),
-            file_pattern: String::from(file_pattern),
-            file_counter: 0,
-            initial_run: true,
-            resource_files: HashMap::new(),
-            resources: HashMap::new(),
-            retained_archives: None,
-        })
     }
+    two_phase_commit::remove_prepared_marker(backend, root_path, file_pattern)
+}
 
-    pub fn retain_archives(&mut self, retained_archives: u32) {
-        self.retained_archives = Some(retained_archives);
+// Load the newest valid table of contents: the latest version, or failing that the most recent
+// valid archive. Returns `None` if there are no table of contents files at all.
+fn load_newest_state(
+    backend: &dyn StorageBackend,
+    storage_path: &Path,
+    file_pattern: &str,
+    skipped_versions: &mut Vec<SkippedVersion>,
+) -> Result<Option<AtomicStoreFileContents>> {
+    // Candidates in order of preference: the latest version, then the archives from most to least
+    // recent.
+    let mut candidates = Vec::new();
+    let latest_file_path = format_latest_file_path(storage_path, file_pattern);
+    if backend.exists(&latest_file_path) {
+        if !backend.is_file(&latest_file_path) {
+            return Err(PersistenceError::InvalidPathToFile {
+                path: latest_file_path.to_string_lossy().to_string(),
+            });
+        }
+        candidates.push(latest_file_path);
     }
+    let mut archives =
+        archive_files(backend, storage_path, file_pattern)?.collect::<Result<Vec<_>>>()?;
+    archives.sort_unstable_by_key(|(_, num)| std::cmp::Reverse(*num));
+    candidates.extend(archives.into_iter().map(|(path, _)| path));
 
-    pub(crate) fn persistence_path(&self) -> &Path {
-        self.file_path.as_path()
+    if candidates.is_empty() {
+        return Ok(None);
     }
-    pub(crate) fn look_up_resource(&self, key: &str) -> Option<StorageLocation> {
-        self.resource_files.get(key).copied()
-    }
-    pub(crate) fn add_sync_handle(
-        &mut self,
-        key: &str,
-        handle: Arc<RwLock<VersionSyncHandle>>,
-    ) -> Result<()> {
-        if let Entry::Vacant(insert_point) = self.resources.entry(key.to_string()) {
-            insert_point.insert(handle);
-        } else {
-            return Err(PersistenceError::DuplicateResourceKey {
-                key: key.to_string(),
-            });
+    for candidate in candidates.iter() {
+        if let Some(loaded_state) = load_valid_state(backend, candidate, skipped_versions)? {
+            return Ok(Some(loaded_state));
         }
-        Ok(())
     }
+    Err(PersistenceError::InvalidFileContents {
+        note: format!(
+            "all {} table of contents files are corrupted",
+            candidates.len()
+        ),
+        path: storage_path.to_string_lossy().to_string(),
+    })
 }
 
-/// The central index of an atomic version of truth across multiple persisted data structures;
-/// Guarantees that all managed resources can be loaded in a consistent state across an entire logical entity.
-pub struct AtomicStore {
-    // because there is only one instance per file for the table of contents, we do not keep it open.
-    file_path: PathBuf,
-    file_pattern: String,
-    file_counter: u32,
-    last_counter: Option<u32>,
-    resources: HashMap<String, Arc<RwLock<VersionSyncHandle>>>,
-    // How long `commit_version` will wait for resource versions before returning an error.
-    // defaults to 100 milliseconds
-    commit_timeout: Duration,
-    // How many backup index files to retain at any given time. If `None`, all archives will be
-    // retained.
-    retained_archives: Option<u32>,
+// The regexes matching the names of the files of a resource `key` of `kind`, or of any kind if it
+// is unknown.
+fn resource_file_regexes(key: &str, kind: Option<ResourceKind>) -> Vec<Regex> {
+    let mut regexes = match kind {
+        Some(ResourceKind::AppendLog { .. }) => vec![append_log::resource_file_regex(key)],
+        Some(ResourceKind::FixedAppendLog { .. }) => {
+            vec![fixed_append_log::resource_file_regex(key)]
+        }
+        Some(ResourceKind::RollingLog { .. }) => vec![rolling_log::resource_file_regex(key)],
+        None => vec![
+            append_log::resource_file_regex(key),
+            fixed_append_log::resource_file_regex(key),
+            rolling_log::resource_file_regex(key),
+        ],
+    };
+    regexes.push(branch::branch_file_regex(key));
+    regexes
 }
 
-impl AtomicStore {
-    pub fn open(load_info: AtomicStoreLoader) -> Result<AtomicStore> {
-        let commit_timeout = if std::env::var("ATOMIC_STORE_NO_TIMEOUT").is_ok() {
-            Duration::MAX
-        } else {
-            Duration::from_millis(100)
-        };
+/// Attributes the files in a store's directory to a set of resources, with the regexes for each
+/// resource compiled once.
+///
+/// The names of the files of different keys can overlap: the append log `a_1` backs up its second
+/// data file to `a_1_2.bak.<timestamp>`, as does a fixed append log `a` with one entry per file for
+/// its second range file. A name is attributed to the longest key that matches it, so the files of
+/// a resource are only told apart from those of another resource if both are known. The names of
+/// every kind of log are matched for a resource whose kind is unknown.
+struct ResourceFiles {
+    // Each key, its kind, and the regexes matching its files, longest key first.
+    resources: Vec<(String, Option<ResourceKind>, Vec<Regex>)>,
+}
 
-        if !load_info.initial_run {
-            if let Some(retained_archives) = load_info.retained_archives {
-                // At startup, prune all existing archive files that fall outside the retaining
-                // window. This is necessary in case the `retained_archives` parameter has changed
-                // since the last time the store was opened. Once this is done, we know the number
-                // of archive files on disk is consistent with `retained_archives`, and we only need
-                // to check for at most one old archive each time we commit a new version.
-                prune_archives(
-                    &load_info.file_path,
-                    &load_info.file_pattern,
-                    load_info.file_counter.saturating_sub(retained_archives),
-                )?;
+impl ResourceFiles {
+    fn new(resources: impl IntoIterator<Item = (String, Option<ResourceKind>)>) -> ResourceFiles {
+        // A key may be recorded by several versions, not all of which know its kind.
+        let mut kinds: HashMap<String, Option<ResourceKind>> = HashMap::new();
+        for (key, kind) in resources {
+            let known = kinds.entry(key).or_default();
+            if known.is_none() {
+                *known = kind;
             }
         }
+        let mut resources: Vec<_> = kinds
+            .into_iter()
+            .map(|(key, kind)| {
+                let regexes = resource_file_regexes(&key, kind);
+                (key, kind, regexes)
+            })
+            .collect();
+        resources.sort_by(|(a, _, _), (b, _, _)| b.len().cmp(&a.len()).then_with(|| a.cmp(b)));
+        ResourceFiles { resources }
+    }
 
-        Ok(AtomicStore {
-            file_path: load_info.file_path,
-            file_pattern: load_info.file_pattern,
-            file_counter: if load_info.initial_run {
-                load_info.file_counter
-            } else {
-                load_info.file_counter + 1
-            },
-            last_counter: if load_info.initial_run {
-                None
-            } else {
-                Some(load_info.file_counter)
-            },
-            resources: load_info.resources,
-            commit_timeout,
-            retained_archives: load_info.retained_archives,
-        })
+    /// The key of the resource that the file `name` belongs to, if any.
+    fn owner(&self, name: &str) -> Option<&str> {
+        self.resources
+            .iter()
+            .find(|(_, _, regexes)| regexes.iter().any(|regex| regex.is_match(name)))
+            .map(|(key, _, _)| key.as_str())
     }
 
-    /// Set the commit timeout. By default, `AtomicStore` will wait 100 milliseconds for all logs to be committed.
-    pub fn set_commit_timeout(&mut self, timeout: Duration) {
-        self.commit_timeout = timeout;
+    /// The kind of the resource `key`, if it is known.
+    fn kind(&self, key: &str) -> Option<ResourceKind> {
+        self.resources
+            .iter()
+            .find(|(resource, _, _)| resource == key)
+            .and_then(|(_, kind, _)| *kind)
     }
+}
 
-    /// Commit the version. Note that all logs and stores must call `.commit_version()` or `.skip_version()` before this function is called.
-    ///
-    /// This will timeout after 100 milliseconds (configurable with `set_commit_timeout`). If you want to disable this timeout, set the `ATOMIC_STORE_NO_TIMEOUT` environment variable before calling `AtomicStore::open`.
-    pub fn commit_version(&mut self) -> Result<()> {
-        let mut collected_locations = HashMap::<String, StorageLocation>::new();
-        for (resource_key, resource_store) in self.resources.iter() {
-            {
-                let store_access = resource_store.read()?;
-                store_access.wait_for_version_with_timeout(self.commit_timeout)?;
-                if let Some(location_found) = store_access.last_location() {
-                    collected_locations.insert(resource_key.to_string(), *location_found);
-                }
-            }
-            {
-                let mut store_access = resource_store.write()?;
-                store_access.start_version()?;
-            }
-        }
+// The key and kind of each of a set of resources, for `ResourceFiles::new`.
+type ResourceKinds = Vec<(String, Option<ResourceKind>)>;
 
-        let latest_file_path = format_latest_file_path(&self.file_path, &self.file_pattern);
-        let temp_file_path = format_working_file_path(&self.file_path, &self.file_pattern);
-        let mut temp_file = File::create(&temp_file_path).context(StdIoOpenSnafu)?;
-        let out_state = AtomicStoreFileContents {
-            file_counter: self.file_counter,
-            resource_files: collected_locations,
-        };
-        let serialized = bincode::serialize(&out_state).context(BincodeSerSnafu)?;
-        temp_file.write_all(&serialized).context(StdIoWriteSnafu)?;
-        temp_file.flush().context(StdIoWriteSnafu)?;
-        temp_file.sync_all().context(StdIoDirOpsSnafu)?;
-        if latest_file_path.exists() {
-            let last_counter = if self.last_counter.is_none() {
-                let loaded_state = load_state(latest_file_path.as_path())?;
-                loaded_state.file_counter
-            } else {
-                self.last_counter.unwrap()
-            };
-            let archived_file_path =
-                format_archived_file_path(&self.file_path, &self.file_pattern, last_counter);
+// The keys and kinds of the resources in `resource_files`.
+fn entry_kinds(
+    resource_files: &HashMap<String, ResourceEntry>,
+) -> impl Iterator<Item = (String, Option<ResourceKind>)> + '_ {
+    resource_files.iter().map(|(key, entry)| {
+        let kind = entry.descriptor.as_ref().map(|descriptor| descriptor.kind);
+        (key.clone(), kind)
+    })
+}
+
+// The keys and kinds of the resources in `retired_resources`.
+fn retired_kinds(
+    retired_resources: &HashMap<String, RetiredResource>,
+) -> impl Iterator<Item = (String, Option<ResourceKind>)> + '_ {
+    retired_resources
+        .iter()
+        .map(|(key, retired)| (key.clone(), retired.kind))
+}
+
+// The resources recorded or retired by the tables of contents of one store in a directory.
+#[derive(Debug, Default)]
+struct RecordedStore {
+    // The kind of each resource, if any of the tables of contents knows it.
+    resources: HashMap<String, Option<ResourceKind>>,
+    // Whether a table of contents of the store could not be read, so that its resources are
+    // unknown.
+    unreadable_tables: bool,
+}
 
-            fs::rename(&latest_file_path, archived_file_path).context(StdIoDirOpsSnafu)?;
+impl RecordedStore {
+    fn record(&mut self, key: &str, kind: Option<ResourceKind>) {
+        let recorded = self.resources.entry(key.to_string()).or_default();
+        if recorded.is_none() {
+            *recorded = kind;
         }
-        self.last_counter = Some(self.file_counter);
-        fs::rename(&temp_file_path, &latest_file_path).context(StdIoDirOpsSnafu)?;
+    }
+}
 
-        // Prune an old archive if this commit has just pushed one outside of the retention window.
-        if let Some(retained_archives) = self.retained_archives {
-            if let Some(num) = self.file_counter.checked_sub(retained_archives + 1) {
-                let prune_path =
-                    format_archived_file_path(&self.file_path, &self.file_pattern, num);
-                if prune_path.exists() {
-                    if let Err(err) = fs::remove_file(&prune_path) {
-                        // If we fail to prune the old archive, we have still committed the version
-                        // update, so we shouldn't fail here. Just log a warning.
-                        tracing::warn!(
-                            %err,
-                            path = %prune_path.display(),
-                            "failed to prune old archive file",
-                        );
-                    }
+// The resources recorded or retired by every table of contents in `root_path`, by the pattern of
+// the store they belong to. A store that has been opened, but has not committed a version, is
+// found by its lock file and records no resources.
+fn directory_stores(
+    backend: &dyn StorageBackend,
+    root_path: &Path,
+) -> Result<HashMap<String, RecordedStore>> {
+    let mut stores: HashMap<String, RecordedStore> = HashMap::new();
+    let toc_regex = any_table_of_contents_regex();
+    let lock_regex = any_lock_file_regex();
+    for name in backend.list(root_path).context(StdIoDirOpsSnafu)? {
+        if let Some(captures) = lock_regex.captures(&name) {
+            stores.entry(captures[1].to_string()).or_default();
+            continue;
+        }
+        let Some(captures) = toc_regex.captures(&name) else {
+            continue;
+        };
+        let path = root_path.join(&name);
+        if !backend.is_file(&path) {
+            continue;
+        }
+        let store = stores.entry(captures[1].to_string()).or_default();
+        match load_valid_state(backend, &path, &mut Vec::new())? {
+            Some(state) => {
+                for (key, entry) in &state.resource_files {
+                    let kind = entry.descriptor.as_ref().map(|descriptor| descriptor.kind);
+                    store.record(key, kind);
+                }
+                for (key, retired) in &state.retired_resources {
+                    store.record(key, retired.kind);
                 }
             }
+            // Quarantined tables of contents are often corrupted; that is why they were
+            // quarantined.
+            None if captures[2].starts_with("quarantined_") => {}
+            None => store.unreadable_tables = true,
         }
-
-        self.file_counter += 1; // advance for the next version
-        Ok(())
     }
+    Ok(stores)
 }
 
-#[test]
-fn test_atomic_store_log_timeout() {
-    use crate::load_store::BincodeLoadStore;
+// The keys and kinds of the resources recorded or retired by any table of contents in `root_path`,
+// of this store or any other, and whether every table of contents could be read.
+fn directory_resource_kinds(
+    backend: &dyn StorageBackend,
+    root_path: &Path,
+) -> Result<(ResourceKinds, bool)> {
+    let mut kinds = Vec::new();
+    let mut all_tables_read = true;
+    for store in directory_stores(backend, root_path)?.into_values() {
+        all_tables_read &= !store.unreadable_tables;
+        kinds.extend(store.resources);
+    }
+    Ok((kinds, all_tables_read))
+}
 
-    let prefix = "test_atomic_store_log_timeout";
-    let dir = tempfile::tempdir().expect("Could not create tempdir");
-    let mut loader =
-        AtomicStoreLoader::load(dir.path(), prefix).expect("Could not open an atomic store");
-    let mut log = crate::AppendLog::load(
-        &mut loader,
-        BincodeLoadStore::<u64>::default(),
-        prefix,
-        1024,
-    )
-    .expect("Could not create appendlog");
-    let mut store = AtomicStore::open(loader).expect("Could not open store");
+// Delete the files of a removed resource `key`, whose kind must be known to `files`.
+fn delete_resource_files(
+    backend: &dyn StorageBackend,
+    root_path: &Path,
+    files: &ResourceFiles,
+    key: &str,
+) -> Result<()> {
+    if files.kind(key).is_none() {
+        return Err(PersistenceError::ResourceKindUnknown {
+            key: key.to_string(),
+        });
+    }
+    let mut deleted = false;
+    for name in backend.list(root_path).context(StdIoDirOpsSnafu)? {
+        if files.owner(&name) == Some(key) {
+            backend
+                .remove(&root_path.join(&name))
+                .context(StdIoDirOpsSnafu)?;
+            deleted = true;
+        }
+    }
+    if deleted {
+        sync_dir(backend, root_path)?;
+    }
+    Ok(())
+}
 
-    // oops we forgot to commit log
-    if let Err(crate::error::PersistenceError::TimedOut) = store.commit_version() {
-        // ok
-    } else {
-        panic!("Atomic store should've timed out");
+// The latest table of contents of the store with `file_pattern`, or failing that any archive.
+fn find_table_of_contents(
+    backend: &dyn StorageBackend,
+    root_path: &Path,
+    file_pattern: &str,
+) -> Result<Option<PathBuf>> {
+    let latest_file_path = format_latest_file_path(root_path, file_pattern);
+    if backend.exists(&latest_file_path) {
+        return Ok(Some(latest_file_path));
+    }
+    match archive_files(backend, root_path, file_pattern)?.next() {
+        Some(res) => Ok(Some(res?.0)),
+        None => Ok(None),
     }
+}
 
-    // Committing again should work
-    log.commit_version().expect("Could not commit log");
-    store.commit_version().expect("Could not commit store");
+// Matches the names of backup directories made by `backup_store_directory`, capturing the
+// timestamp and the sequence number of backups made within the same second.
+fn backup_dir_regex() -> Regex {
+    Regex::new(r"^backup\.(\d+)(?:\.(\d+))?$").unwrap()
 }
 
-#[test]
-fn test_archive_pruning() {
-    let dir = tempfile::tempdir().expect("Could not create tempdir");
-    let file_pattern = "test_archive_pruning";
+// The files of the store with `file_pattern` in a directory, other than its lock.
+struct StoreFiles {
+    // Every table of contents, including the working and prepared ones, which may not exist.
+    toc_paths: Vec<PathBuf>,
+    // The resources recorded or retired by any of the tables of contents.
+    keys: Vec<String>,
+    // Attributes files to the resources of every store in the directory.
+    files: ResourceFiles,
+}
 
-    let list_archives = || {
-        let mut versions = archive_files(dir.path(), file_pattern)
-            .unwrap()
-            .map(|res| res.unwrap().1)
-            .collect::<Vec<_>>();
-        versions.sort();
-        versions
-    };
+fn find_store_files(
+    backend: &dyn StorageBackend,
+    root_path: &Path,
+    file_pattern: &str,
+) -> Result<StoreFiles> {
+    let toc_regex = Regex::new(&format!(
+        r"^{}_(latest|archived_\d+|quarantined_.+)$",
+        regex::escape(file_pattern)
+    ))
+    .unwrap();
+    let mut toc_paths = backend
+        .list(root_path)
+        .context(StdIoDirOpsSnafu)?
+        .into_iter()
+        .filter(|name| toc_regex.is_match(name))
+        .map(|name| root_path.join(name))
+        .collect::<Vec<_>>();
+    toc_paths.push(format_working_file_path(root_path, file_pattern));
+    toc_paths.push(two_phase_commit::format_prepared_file_path(
+        root_path,
+        file_pattern,
+    ));
 
-    // First create a store without any pruning and create several archive files.
-    {
-        let loader = AtomicStoreLoader::create(dir.path(), file_pattern)
-            .expect("Could not create an atomic store");
+    let mut kinds = Vec::new();
+    for path in toc_paths.iter().filter(|path| backend.is_file(path)) {
+        if let Some(state) = load_valid_state(backend, path, &mut Vec::new())? {
+            kinds.extend(entry_kinds(&state.resource_files));
+            kinds.extend(retired_kinds(&state.retired_resources));
+        }
+    }
+    let mut keys: Vec<String> = kinds.iter().map(|(key, _)| key.clone()).collect();
+    keys.sort();
+    keys.dedup();
+    kinds.extend(directory_resource_kinds(backend, root_path)?.0);
+    Ok(StoreFiles {
+        toc_paths,
+        keys,
+        files: ResourceFiles::new(kinds),
+    })
+}
+
+// Move the tables of contents of the store with `file_pattern`, along with the files of every
+// resource recorded in any of them, to a new backup directory, and prune the oldest backups outside
+// the retention limit. The files of other stores in the directory are left alone. A resource
+// without a descriptor is backed up with every file named as any kind of log would name its files,
+// unless the file belongs to another resource.
+fn backup_store_directory(
+    backend: &dyn StorageBackend,
+    root_path: &Path,
+    file_pattern: &str,
+    config: &AtomicStoreConfig,
+) -> Result<()> {
+    let store_files = find_store_files(backend, root_path, file_pattern)?;
+    let backup_root = config.backup_dir.as_deref().unwrap_or(root_path);
+    let timestamp = unix_timestamp();
+    let mut backup_path = backup_root.join(format!("backup.{}", timestamp));
+    let mut sequence = 0;
+    while backend.exists(&backup_path) {
+        sequence += 1;
+        backup_path = backup_root.join(format!("backup.{}.{}", timestamp, sequence));
+    }
+    create_dir_all(backend, &backup_path)?;
+
+    // Move the tables of contents first, so that if we are interrupted, loading the store does not
+    // find a version whose files have been moved.
+    for path in store_files
+        .toc_paths
+        .iter()
+        .filter(|path| backend.exists(path))
+    {
+        // Every table of contents is named within `root_path`.
+        let name = path.file_name().unwrap();
+        backend
+            .rename(path, &backup_path.join(name))
+            .context(StdIoDirOpsSnafu)?;
+    }
+    sync_dir(backend, root_path)?;
+    for name in backend.list(root_path).context(StdIoDirOpsSnafu)? {
+        let owned = store_files
+            .files
+            .owner(&name)
+            .is_some_and(|key| store_files.keys.iter().any(|own| own == key));
+        if owned {
+            backend
+                .rename(&root_path.join(&name), &backup_path.join(&name))
+                .context(StdIoDirOpsSnafu)?;
+        }
+    }
+    sync_dir(backend, &backup_path)?;
+    sync_dir(backend, root_path)?;
+
+    if let Some(retained_backups) = config.retained_backups {
+        let backup_regex = backup_dir_regex();
+        let mut backups = backend
+            .list(backup_root)
+            .context(StdIoDirOpsSnafu)?
+            .into_iter()
+            .filter_map(|name| {
+                let captures = backup_regex.captures(&name)?;
+                let timestamp: i64 = captures.get(1)?.as_str().parse().ok()?;
+                let sequence: u32 = match captures.get(2) {
+                    Some(sequence) => sequence.as_str().parse().ok()?,
+                    None => 0,
+                };
+                Some(((timestamp, sequence), backup_root.join(name)))
+            })
+            .filter(|(_, path)| backend.is_dir(path))
+            .collect::<Vec<_>>();
+        backups.sort_unstable_by_key(|(order, _)| std::cmp::Reverse(*order));
+        for (_, path) in backups.into_iter().skip(retained_backups as usize) {
+            remove_dir_all(backend, &path)?;
+        }
+        sync_dir(backend, backup_root)?;
+    }
+    Ok(())
+}
+
+// Delete the tables of contents of the store with `file_pattern`, along with the files of every
+// resource recorded in any of them.
+fn wipe_store_files(
+    backend: &dyn StorageBackend,
+    root_path: &Path,
+    file_pattern: &str,
+) -> Result<()> {
+    let StoreFiles {
+        toc_paths,
+        keys,
+        files,
+    } = find_store_files(backend, root_path, file_pattern)?;
+    // Delete the tables of contents first, so that if we are interrupted, loading the store does
+    // not find a version whose files have been deleted.
+    for path in toc_paths.iter().filter(|path| backend.exists(path)) {
+        backend.remove(path).context(StdIoDirOpsSnafu)?;
+    }
+    sync_dir(backend, root_path)?;
+    for key in keys {
+        // The files of a resource of unknown kind cannot be told apart with certainty, so they are
+        // left for `AtomicStore::collect_garbage`.
+        if files.kind(&key).is_some() {
+            delete_resource_files(backend, root_path, &files, &key)?;
+        }
+    }
+    Ok(())
+}
+
+// Copy the files of the resource `src_key` of `kind` in `src_path`, committed at `location`, to
+// `dest_key` in `dest_path`.
+fn copy_resource_files(
+    backend: &dyn StorageBackend,
+    src_path: &Path,
+    src_key: &str,
+    dest_path: &Path,
+    dest_key: &str,
+    kind: ResourceKind,
+    location: &StorageLocation,
+) -> Result<()> {
+    match kind {
+        ResourceKind::AppendLog { .. } => {
+            append_log::checkpoint_files(backend, src_path, src_key, dest_path, dest_key, location)
+        }
+        ResourceKind::FixedAppendLog {
+            resource_size,
+            file_size,
+        } => fixed_append_log::checkpoint_files(
+            backend,
+            src_path,
+            src_key,
+            dest_path,
+            dest_key,
+            location,
+            resource_size,
+            file_size,
+        ),
+        ResourceKind::RollingLog { .. } => {
+            rolling_log::checkpoint_files(backend, src_path, src_key, dest_path, dest_key, location)
+        }
+    }
+}
+
+// Copy the files of the resource `key`, which has no descriptor, from `src_path` to `dest_path`.
+// Every file attributed to it is linked or copied whole, other than backups and branches, which are
+// never part of a committed version. The resource must not be written while it is copied.
+fn copy_untyped_resource_files(
+    backend: &dyn StorageBackend,
+    src_path: &Path,
+    key: &str,
+    dest_path: &Path,
+    files: &ResourceFiles,
+) -> Result<()> {
+    let uncommitted_regex = Regex::new(r"(\.bak\.-?\d+|\.branch\.\d+)$").unwrap();
+    for name in backend.list(src_path).context(StdIoDirOpsSnafu)? {
+        if files.owner(&name) == Some(key) && !uncommitted_regex.is_match(&name) {
+            link_or_copy(backend, &src_path.join(&name), &dest_path.join(&name))?;
+        }
+    }
+    Ok(())
+}
+
+// Check the files of every resource recorded in `resource_files`, in order of their keys.
+// Resources without a descriptor have no known layout, so they are not checked.
+fn verify_resources(
+    backend: &dyn StorageBackend,
+    root_path: &Path,
+    resource_files: &HashMap<String, ResourceEntry>,
+) -> Result<Vec<Inconsistency>> {
+    let mut keys: Vec<&String> = resource_files.keys().collect();
+    keys.sort_unstable();
+    let mut issues = Vec::new();
+    for key in keys {
+        let entry = &resource_files[key];
+        let Some(descriptor) = &entry.descriptor else {
+            issues.push(Inconsistency::new(
+                key,
+                root_path,
+                InconsistencyKind::Unchecked,
+            ));
+            continue;
+        };
+        match descriptor.kind {
+            ResourceKind::AppendLog { .. } => {
+                // The index is a resource of its own, which is checked under its own key.
+                let index_key = format!("{}_index", key);
+                let index_entries = match resource_files.get(&index_key) {
+                    Some(ResourceEntry {
+                        location,
+                        descriptor:
+                            Some(ResourceDescriptor {
+                                kind:
+                                    ResourceKind::FixedAppendLog {
+                                        resource_size,
+                                        file_size,
+                                    },
+                                ..
+                            }),
+                    }) => fixed_append_log::read_committed_entries(
+                        backend,
+                        root_path,
+                        &index_key,
+                        location,
+                        *resource_size,
+                        *file_size,
+                    )?,
+                    Some(ResourceEntry {
+                        descriptor: None, ..
+                    }) => None,
+                    _ => {
+                        issues.push(Inconsistency::new(
+                            key,
+                            root_path,
+                            InconsistencyKind::MissingIndex { index_key },
+                        ));
+                        None
+                    }
+                };
+                append_log::verify_files(
+                    backend,
+                    root_path,
+                    key,
+                    &entry.location,
+                    index_entries,
+                    &mut issues,
+                )?;
+            }
+            ResourceKind::FixedAppendLog {
+                resource_size,
+                file_size,
+            } => fixed_append_log::verify_files(
+                backend,
+                root_path,
+                key,
+                &entry.location,
+                resource_size,
+                file_size,
+                &mut issues,
+            )?,
+            ResourceKind::RollingLog { .. } => {
+                rolling_log::verify_files(backend, root_path, key, &entry.location, &mut issues)?
+            }
+        }
+    }
+    Ok(issues)
+}
+
+// Load the table of contents of version `counter`, which may be the latest version or an archive.
+fn load_version_state(
+    backend: &dyn StorageBackend,
+    storage_path: &Path,
+    file_pattern: &str,
+    counter: u32,
+    skipped_versions: &mut Vec<SkippedVersion>,
+) -> Result<AtomicStoreFileContents> {
+    let latest_file_path = format_latest_file_path(storage_path, file_pattern);
+    let latest_state = if backend.is_file(&latest_file_path) {
+        load_valid_state(backend, &latest_file_path, skipped_versions)?
+    } else {
+        None
+    };
+    match latest_state {
+        Some(state) if state.file_counter == counter => Ok(state),
+        _ => {
+            let archived_file_path = format_archived_file_path(storage_path, file_pattern, counter);
+            if !backend.is_file(&archived_file_path) {
+                return Err(PersistenceError::VersionNotFound { version: counter });
+            }
+            load_state(backend, &archived_file_path)
+        }
+    }
+}
+
+// The counters of the committed versions of the store at `storage_path` with `file_pattern`, in
+// ascending order.
+fn list_versions(
+    backend: &dyn StorageBackend,
+    storage_path: &Path,
+    file_pattern: &str,
+) -> Result<Vec<u32>> {
+    if !backend.exists(storage_path) {
+        return Ok(Vec::new());
+    }
+    let mut versions = archive_files(backend, storage_path, file_pattern)?
+        .map(|res| res.map(|(_, num)| num))
+        .collect::<Result<Vec<_>>>()?;
+    let latest_file_path = format_latest_file_path(storage_path, file_pattern);
+    if backend.is_file(&latest_file_path) {
+        if let Some(state) = load_valid_state(backend, &latest_file_path, &mut Vec::new())? {
+            versions.push(state.file_counter);
+        }
+    }
+    versions.sort_unstable();
+    versions.dedup();
+    Ok(versions)
+}
+
+// The information of the committed version `counter` of the store at `storage_path` with
+// `file_pattern`.
+fn read_version_info(
+    backend: &dyn StorageBackend,
+    storage_path: &Path,
+    file_pattern: &str,
+    counter: u32,
+) -> Result<VersionInfo> {
+    if !backend.is_dir(storage_path) {
+        return Err(PersistenceError::VersionNotFound { version: counter });
+    }
+    let state = load_version_state(
+        backend,
+        storage_path,
+        file_pattern,
+        counter,
+        &mut Vec::new(),
+    )?;
+    Ok(VersionInfo::from_state(&state))
+}
+
+// Read the information of a version found by `list_versions`, or `None` if its table of contents
+// cannot be read, such as an archive that was pruned or corrupted since it was listed.
+fn read_readable_version_info(
+    backend: &dyn StorageBackend,
+    storage_path: &Path,
+    file_pattern: &str,
+    counter: u32,
+) -> Option<VersionInfo> {
+    match read_version_info(backend, storage_path, file_pattern, counter) {
+        Ok(info) => Some(info),
+        Err(err) => {
+            tracing::warn!(%err, counter, "skipping unreadable version");
+            None
+        }
+    }
+}
+
+// The format is only compared if `check_format`, as the format recorded for an adaptor without a
+// `LoadStore::format_id` is not stable.
+fn look_up_entry(
+    resource_files: &HashMap<String, ResourceEntry>,
+    key: &str,
+    descriptor: &ResourceDescriptor,
+    check_format: bool,
+) -> Result<Option<StorageLocation>> {
+    match resource_files.get(key) {
+        Some(entry) => {
+            if let Some(stored) = &entry.descriptor {
+                if stored.kind != descriptor.kind
+                    || (check_format && stored.format != descriptor.format)
+                {
+                    tracing::error!(
+                        key,
+                        %stored,
+                        expected = %descriptor,
+                        "resource descriptor mismatch",
+                    );
+                    return Err(PersistenceError::ResourceFormatInconsistent {
+                        key: key.to_string(),
+                    });
+                }
+            }
+            Ok(Some(entry.location))
+        }
+        None => Ok(None),
+    }
+}
+
+/// An exclusive advisory lock on the files of one store, held by the loader and then by the
+/// `AtomicStore` it opens. The lock file records the process ID of the holder.
+#[derive(Debug)]
+struct StoreLock {
+    _file: Box<dyn StorageFile>,
+}
+
+impl StoreLock {
+    fn acquire(
+        backend: &dyn StorageBackend,
+        root_path: &Path,
+        file_pattern: &str,
+    ) -> Result<StoreLock> {
+        let lock_file_path = format_lock_file_path(root_path, file_pattern);
+        let mut file = backend
+            .open(&lock_file_path, OpenMode::ReadWrite)
+            .context(StdIoOpenSnafu)?;
+        match file.try_lock() {
+            Ok(()) => {}
+            Err(TryLockError::WouldBlock) => {
+                let mut contents = String::new();
+                let pid = file
+                    .read_to_string(&mut contents)
+                    .ok()
+                    .and_then(|_| contents.trim().parse().ok());
+                return Err(PersistenceError::StoreLocked {
+                    path: lock_file_path.to_string_lossy().to_string(),
+                    pid,
+                });
+            }
+            Err(TryLockError::Error(source)) => {
+                return Err(PersistenceError::StdIoOpen { source });
+            }
+        }
+        file.set_len(0).context(StdIoWriteSnafu)?;
+        file.seek(SeekFrom::Start(0)).context(StdIoWriteSnafu)?;
+        file.write_all(std::process::id().to_string().as_bytes())
+            .context(StdIoWriteSnafu)?;
+        Ok(StoreLock { _file: file })
+    }
+}
+
+/// Determines how `AtomicStore` treats resources that are recorded in the loaded table of
+/// contents, but were not loaded by any log before the store was opened.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub enum UnclaimedResourcePolicy {
+    /// Record the last committed location of each unclaimed resource, unchanged, in every new
+    /// version.
+    #[default]
+    CarryForward,
+    /// Like `CarryForward`, but also log a warning for each unclaimed resource when the store is
+    /// opened.
+    Warn,
+    /// Refuse to open the store while any resource is unclaimed.
+    Fail,
+}
+
+/// Describes a committed version without loading any of its resources.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct VersionInfo {
+    /// The version counter, as used by `AtomicStoreLoader::load_version`.
+    pub counter: u32,
+    /// The metadata passed to `AtomicStore::commit_version_with_metadata`; empty for versions
+    /// committed with `commit_version`.
+    pub metadata: Vec<u8>,
+    /// When the version was committed, in seconds since the Unix epoch; `None` for versions
+    /// committed before timestamps were recorded.
+    pub timestamp: Option<i64>,
+}
+
+impl VersionInfo {
+    fn from_state(state: &AtomicStoreFileContents) -> VersionInfo {
+        VersionInfo {
+            counter: state.file_counter,
+            metadata: state.metadata.clone(),
+            timestamp: state.timestamp,
+        }
+    }
+}
+
+/// Enables each managed resource storage instance to initialize before creating the AtomicStore.
+pub struct AtomicStoreLoader {
+    backend: Arc<dyn StorageBackend>,
+    file_path: PathBuf,
+    file_pattern: String,
+    file_counter: u32,
+    initial_run: bool,
+    // Describes the loaded version; `None` on the initial run.
+    version_info: Option<VersionInfo>,
+    resource_files: HashMap<String, ResourceEntry>,
+    // Removed resources whose files have not been deleted yet, including those removed from this
+    // loader, which are first left out of the version the store commits next.
+    retired_resources: HashMap<String, RetiredResource>,
+    resources: HashMap<String, Arc<RwLock<VersionSyncHandle>>>,
+    resource_descriptors: HashMap<String, ResourceDescriptor>,
+    config: AtomicStoreConfig,
+    // Set when loading a version older than the newest one on disk; newer versions are
+    // quarantined by the first commit.
+    supersedes_newer_versions: bool,
+    skipped_versions: Vec<SkippedVersion>,
+    lock: StoreLock,
+}
+
+impl AtomicStoreLoader {
+    fn from_state(
+        config: AtomicStoreConfig,
+        storage_path: &Path,
+        file_pattern: &str,
+        loaded_state: Option<AtomicStoreFileContents>,
+        lock: StoreLock,
+    ) -> AtomicStoreLoader {
+        let version_info = loaded_state.as_ref().map(VersionInfo::from_state);
+        let (file_counter, initial_run, resource_files, retired_resources) = match loaded_state {
+            Some(state) => (
+                state.file_counter,
+                false,
+                state.resource_files,
+                state.retired_resources,
+            ),
+            None => (0, true, HashMap::new(), HashMap::new()),
+        };
+        AtomicStoreLoader {
+            backend: config.backend.clone(),
+            file_path: storage_path.to_path_buf(),
+            file_pattern: String::from(file_pattern),
+            file_counter,
+            initial_run,
+            version_info,
+            resource_files,
+            retired_resources,
+            resources: HashMap::new(),
+            resource_descriptors: HashMap::new(),
+            config,
+            supersedes_newer_versions: false,
+            skipped_versions: Vec::new(),
+            lock,
+        }
+    }
+
+    /// Attempt to load the specified atomic state in the specified directory; if no files exist, will initialize a new state
+    ///
+    /// If the latest table of contents is corrupted, the most recent valid archive is loaded
+    /// instead, and the corrupted files are reported by `skipped_versions`.
+    pub fn load(storage_path: &Path, file_pattern: &str) -> Result<AtomicStoreLoader> {
+        Self::load_with_config(storage_path, file_pattern, AtomicStoreConfig::default())
+    }
+
+    /// Like `load`, but with the options in `config` rather than the defaults.
+    pub fn load_with_config(
+        storage_path: &Path,
+        file_pattern: &str,
+        config: AtomicStoreConfig,
+    ) -> Result<AtomicStoreLoader> {
+        let config = config.apply_fsync_policy();
+        let backend = config.backend.clone();
+        create_dir_all(backend.as_ref(), storage_path)?;
+        let lock = StoreLock::acquire(backend.as_ref(), storage_path, file_pattern)?;
+        resolve_prepared_version(backend.as_ref(), storage_path, file_pattern)?;
+
+        let mut skipped_versions = Vec::new();
+        let loaded_state = load_newest_state(
+            backend.as_ref(),
+            storage_path,
+            file_pattern,
+            &mut skipped_versions,
+        )?;
+        let mut loader = Self::from_state(config, storage_path, file_pattern, loaded_state, lock);
+        // Anything skipped is newer than the version we loaded, and must not be archived over
+        // older versions by the next commit.
+        loader.supersedes_newer_versions = !skipped_versions.is_empty();
+        loader.skipped_versions = skipped_versions;
+        Ok(loader)
+    }
+
+    /// Open the newest committed version in the specified directory for reading, without taking
+    /// the store's lock or modifying anything in the directory. This can be used alongside a
+    /// process that has the store open for writing.
+    pub fn open_read_only(storage_path: &Path, file_pattern: &str) -> Result<ReadOnlyLoader> {
+        Self::open_read_only_with_config(storage_path, file_pattern, AtomicStoreConfig::default())
+    }
+
+    /// Like `open_read_only`, but with the options in `config` rather than the defaults. Only the
+    /// backend and the layout of the logs are relevant to reading.
+    pub fn open_read_only_with_config(
+        storage_path: &Path,
+        file_pattern: &str,
+        config: AtomicStoreConfig,
+    ) -> Result<ReadOnlyLoader> {
+        let backend = config.backend.clone();
+        if !backend.is_dir(storage_path) {
+            return Err(PersistenceError::FailedToResolvePath {
+                path: storage_path.to_string_lossy().to_string(),
+            });
+        }
+        let loaded_state = load_newest_state(
+            backend.as_ref(),
+            storage_path,
+            file_pattern,
+            &mut Vec::new(),
+        )?;
+        Ok(ReadOnlyLoader::from_state(
+            config,
+            storage_path,
+            file_pattern,
+            loaded_state,
+        ))
+    }
+
+    /// Like `open_read_only`, but opens a specific committed version, which may be the latest
+    /// version or any retained archive.
+    pub fn open_read_only_version(
+        storage_path: &Path,
+        file_pattern: &str,
+        counter: u32,
+    ) -> Result<ReadOnlyLoader> {
+        Self::open_read_only_version_with_config(
+            storage_path,
+            file_pattern,
+            counter,
+            AtomicStoreConfig::default(),
+        )
+    }
+
+    /// Like `open_read_only_version`, but with the options in `config` rather than the defaults.
+    pub fn open_read_only_version_with_config(
+        storage_path: &Path,
+        file_pattern: &str,
+        counter: u32,
+        config: AtomicStoreConfig,
+    ) -> Result<ReadOnlyLoader> {
+        let backend = config.backend.clone();
+        if !backend.is_dir(storage_path) {
+            return Err(PersistenceError::VersionNotFound { version: counter });
+        }
+        let loaded_state = load_version_state(
+            backend.as_ref(),
+            storage_path,
+            file_pattern,
+            counter,
+            &mut Vec::new(),
+        )?;
+        Ok(ReadOnlyLoader::from_state(
+            config,
+            storage_path,
+            file_pattern,
+            Some(loaded_state),
+        ))
+    }
+
+    /// Attempt to load a specific committed version, which may be the latest version or any
+    /// retained archive. All logs reopen at the locations recorded in that version; versions newer
+    /// than it are quarantined by the next `AtomicStore::commit_version`.
+    pub fn load_version(
+        storage_path: &Path,
+        file_pattern: &str,
+        counter: u32,
+    ) -> Result<AtomicStoreLoader> {
+        Self::load_version_with_config(
+            storage_path,
+            file_pattern,
+            counter,
+            AtomicStoreConfig::default(),
+        )
+    }
+
+    /// Like `load_version`, but with the options in `config` rather than the defaults.
+    pub fn load_version_with_config(
+        storage_path: &Path,
+        file_pattern: &str,
+        counter: u32,
+        config: AtomicStoreConfig,
+    ) -> Result<AtomicStoreLoader> {
+        let config = config.apply_fsync_policy();
+        let backend = config.backend.clone();
+        if !backend.is_dir(storage_path) {
+            return Err(PersistenceError::VersionNotFound { version: counter });
+        }
+        let lock = StoreLock::acquire(backend.as_ref(), storage_path, file_pattern)?;
+        resolve_prepared_version(backend.as_ref(), storage_path, file_pattern)?;
+        let mut skipped_versions = Vec::new();
+        let loaded_state = load_version_state(
+            backend.as_ref(),
+            storage_path,
+            file_pattern,
+            counter,
+            &mut skipped_versions,
+        )?;
+        let supersedes_newer_versions = !skipped_versions.is_empty()
+            || list_versions(backend.as_ref(), storage_path, file_pattern)?
+                .last()
+                .is_some_and(|newest| *newest > counter);
+        let mut loader =
+            Self::from_state(config, storage_path, file_pattern, Some(loaded_state), lock);
+        loader.supersedes_newer_versions = supersedes_newer_versions;
+        loader.skipped_versions = skipped_versions;
+        Ok(loader)
+    }
+
+    /// List the counters of all committed versions that can be loaded with `load_version`, in
+    /// ascending order.
+    pub fn list_versions(storage_path: &Path, file_pattern: &str) -> Result<Vec<u32>> {
+        Self::list_versions_with_config(storage_path, file_pattern, AtomicStoreConfig::default())
+    }
+
+    /// Like `list_versions`, but with the options in `config` rather than the defaults. Only the
+    /// backend is relevant.
+    pub fn list_versions_with_config(
+        storage_path: &Path,
+        file_pattern: &str,
+        config: AtomicStoreConfig,
+    ) -> Result<Vec<u32>> {
+        list_versions(config.backend.as_ref(), storage_path, file_pattern)
+    }
+
+    /// Find the newest committed version for which `at_or_before` holds, such as the last version
+    /// at or before some block height recorded in its metadata. `at_or_before` must hold for every
+    /// version older than one it holds for; this allows a binary search over the versions, so only
+    /// a few tables of contents are read. Versions whose tables of contents cannot be read are
+    /// skipped. The counter of the version found can be passed to `load_version`.
+    pub fn find_version(
+        storage_path: &Path,
+        file_pattern: &str,
+        at_or_before: impl FnMut(&VersionInfo) -> bool,
+    ) -> Result<Option<VersionInfo>> {
+        Self::find_version_with_config(
+            storage_path,
+            file_pattern,
+            at_or_before,
+            AtomicStoreConfig::default(),
+        )
+    }
+
+    /// Like `find_version`, but with the options in `config` rather than the defaults. Only the
+    /// backend is relevant.
+    pub fn find_version_with_config(
+        storage_path: &Path,
+        file_pattern: &str,
+        mut at_or_before: impl FnMut(&VersionInfo) -> bool,
+        config: AtomicStoreConfig,
+    ) -> Result<Option<VersionInfo>> {
+        let backend = config.backend.as_ref();
+        let mut versions = list_versions(backend, storage_path, file_pattern)?;
+        let (mut low, mut high) = (0, versions.len());
+        let mut found = None;
+        while low < high {
+            let mid = low + (high - low) / 2;
+            let Some(info) =
+                read_readable_version_info(backend, storage_path, file_pattern, versions[mid])
+            else {
+                versions.remove(mid);
+                high -= 1;
+                continue;
+            };
+            if at_or_before(&info) {
+                low = mid + 1;
+                found = Some(info);
+            } else {
+                high = mid;
+            }
+        }
+        Ok(found)
+    }
+
+    /// Find the newest version committed at or before `timestamp`, in seconds since the Unix
+    /// epoch. Versions committed before timestamps were recorded are treated as older than any
+    /// timestamp, and versions whose tables of contents cannot be read are skipped.
+    ///
+    /// Commit times come from the wall clock, which may have been set back between commits, so
+    /// unlike `find_version` this cannot binary search: it reads versions from the newest back
+    /// until it finds one committed at or before `timestamp`.
+    pub fn find_version_at_time(
+        storage_path: &Path,
+        file_pattern: &str,
+        timestamp: i64,
+    ) -> Result<Option<VersionInfo>> {
+        Self::find_version_at_time_with_config(
+            storage_path,
+            file_pattern,
+            timestamp,
+            AtomicStoreConfig::default(),
+        )
+    }
+
+    /// Like `find_version_at_time`, but with the options in `config` rather than the defaults.
+    /// Only the backend is relevant.
+    pub fn find_version_at_time_with_config(
+        storage_path: &Path,
+        file_pattern: &str,
+        timestamp: i64,
+        config: AtomicStoreConfig,
+    ) -> Result<Option<VersionInfo>> {
+        let backend = config.backend.as_ref();
+        let versions = list_versions(backend, storage_path, file_pattern)?;
+        Ok(versions.into_iter().rev().find_map(|counter| {
+            read_readable_version_info(backend, storage_path, file_pattern, counter)
+                .filter(|info| info.timestamp <= Some(timestamp))
+        }))
+    }
+
+    /// Read the metadata and commit time of a committed version, which may be the latest version
+    /// or any retained archive, without taking the store's lock.
+    pub fn read_version_info(
+        storage_path: &Path,
+        file_pattern: &str,
+        counter: u32,
+    ) -> Result<VersionInfo> {
+        Self::read_version_info_with_config(
+            storage_path,
+            file_pattern,
+            counter,
+            AtomicStoreConfig::default(),
+        )
+    }
+
+    /// Like `read_version_info`, but with the options in `config` rather than the defaults. Only
+    /// the backend is relevant.
+    pub fn read_version_info_with_config(
+        storage_path: &Path,
+        file_pattern: &str,
+        counter: u32,
+        config: AtomicStoreConfig,
+    ) -> Result<VersionInfo> {
+        read_version_info(config.backend.as_ref(), storage_path, file_pattern, counter)
+    }
+    /// Attempt to initialize a new atomic state in the specified directory. If a store with the
+    /// same file pattern already exists there, its tables of contents and the files of its
+    /// resources are first moved to a `backup.<timestamp>` directory within it;
+    /// `AtomicStoreConfig::existing_store` selects other behaviour, and
+    /// `AtomicStoreConfig::backup_dir` another location for backups.
+    pub fn create(storage_path: &Path, file_pattern: &str) -> Result<AtomicStoreLoader> {
+        Self::create_with_config(storage_path, file_pattern, AtomicStoreConfig::default())
+    }
+
+    /// Like `create`, but with the options in `config` rather than the defaults.
+    pub fn create_with_config(
+        storage_path: &Path,
+        file_pattern: &str,
+        config: AtomicStoreConfig,
+    ) -> Result<AtomicStoreLoader> {
+        let config = config.apply_fsync_policy();
+        let backend = config.backend.clone();
+        create_dir_all(backend.as_ref(), storage_path)?;
+        // Make sure nobody is using an existing store before replacing it.
+        let lock = StoreLock::acquire(backend.as_ref(), storage_path, file_pattern)?;
+        if let Some(existing_path) =
+            find_table_of_contents(backend.as_ref(), storage_path, file_pattern)?
+        {
+            match config.existing_store {
+                ExistingStorePolicy::Backup => {
+                    backup_store_directory(backend.as_ref(), storage_path, file_pattern, &config)?
+                }
+                ExistingStorePolicy::Fail => {
+                    return Err(PersistenceError::StoreAlreadyExists {
+                        path: existing_path.to_string_lossy().to_string(),
+                    });
+                }
+                ExistingStorePolicy::Wipe => {
+                    wipe_store_files(backend.as_ref(), storage_path, file_pattern)?
+                }
+            }
+        }
+        Ok(Self::from_state(
+            config,
+            storage_path,
+            file_pattern,
+            None,
+            lock,
+        ))
+    }
+
+    /// Override the archive retention of the config this loader was created with; see
+    /// `AtomicStoreConfig::retain_archives`.
+    #[deprecated(note = "use `AtomicStoreConfig::retain_archives` instead")]
+    pub fn retain_archives(&mut self, retained_archives: u32) {
+        self.config.retained_archives = Some(retained_archives);
+    }
+
+    /// Set how resources in the loaded table of contents that are not loaded before
+    /// `AtomicStore::open` are treated, overriding the config this loader was created with. By
+    /// default, they are carried forward unchanged.
+    pub fn set_unclaimed_resource_policy(&mut self, policy: UnclaimedResourcePolicy) {
+        self.config.unclaimed_resource_policy = policy;
+    }
+
+    /// Corrupted table of contents files that were skipped while loading, newest first.
+    pub fn skipped_versions(&self) -> &[SkippedVersion] {
+        &self.skipped_versions
+    }
+
+    /// Remove a resource from the loaded version, so that it is no longer recorded in versions
+    /// committed by the store. The resource must not be loaded by a log. An append log's index is
+    /// removed along with it.
+    ///
+    /// The resource's files are deleted once no retained archive refers to it; until then, its key
+    /// cannot be reused. The files of a resource without a descriptor cannot be told apart from
+    /// those of other keys with certainty, so they are left for `AtomicStore::collect_garbage`.
+    pub fn remove_resource(&mut self, key: &str) -> Result<()> {
+        let entry = match self.resource_files.get(key) {
+            Some(entry) => entry,
+            None => {
+                return Err(PersistenceError::FailedToFindExpectedResource {
+                    key: key.to_string(),
+                })
+            }
+        };
+        let mut keys = vec![key.to_string()];
+        if let Some(ResourceKind::AppendLog { .. }) = entry.descriptor.as_ref().map(|d| d.kind) {
+            let index_key = format!("{}_index", key);
+            if self.resource_files.contains_key(&index_key) {
+                keys.push(index_key);
+            }
+        }
+        if let Some(key) = keys.iter().find(|key| self.resources.contains_key(*key)) {
+            return Err(PersistenceError::ResourceInUse { key: key.clone() });
+        }
+        for key in keys {
+            let entry = self.resource_files.remove(&key).unwrap();
+            let kind = entry.descriptor.map(|descriptor| descriptor.kind);
+            self.retire_resource(key, kind);
+        }
+        Ok(())
+    }
+
+    /// Rename a resource in the loaded version, so that it can be loaded by a log with the key
+    /// `new`. The resource must not be loaded by a log. An append log's index is renamed along
+    /// with it.
+    ///
+    /// Retained archives still refer to the files of the old key, so they are not renamed in
+    /// place. Instead, the committed files of the resource, and backups of its files, are hard
+    /// linked to the file names of the new key right away; the file that may still be appended
+    /// to, and every file on a backend without hard links, is copied up to the committed
+    /// location. Versions committed by the store then refer to the new key. If the store is not
+    /// committed, the old key is still used when it is next loaded, and the files of the new key
+    /// are replaced by the next rename to it.
+    ///
+    /// The files of the old key are deleted as if it had been removed with `remove_resource`: by
+    /// the first commit once no retained archive refers to it, which is after as many commits as
+    /// `AtomicStoreConfig::retain_archives` allows archives. A store that retains every archive
+    /// never deletes them.
+    ///
+    /// A resource migrated from the unversioned format has no recorded kind, which is needed to
+    /// find its files, so it returns `PersistenceError::ResourceKindUnknown`; rename it with
+    /// `rename_resource_with_kind` instead.
+    pub fn rename_resource(&mut self, old: &str, new: &str) -> Result<()> {
+        self.rename_resource_impl(old, new, None)
+    }
+
+    /// Like `rename_resource`, but for a resource of `kind`, which need not have a recorded kind.
+    /// If it does, it must be `kind`. The index of an append log is assumed to have the layout set
+    /// by `AtomicStoreConfig::append_log_index_file_size`, unless its kind is recorded.
+    pub fn rename_resource_with_kind(
+        &mut self,
+        old: &str,
+        new: &str,
+        kind: ResourceKind,
+    ) -> Result<()> {
+        self.rename_resource_impl(old, new, Some(kind))
+    }
+
+    fn rename_resource_impl(
+        &mut self,
+        old: &str,
+        new: &str,
+        kind: Option<ResourceKind>,
+    ) -> Result<()> {
+        let entry = match self.resource_files.get(old) {
+            Some(entry) => entry,
+            None => {
+                return Err(PersistenceError::FailedToFindExpectedResource {
+                    key: old.to_string(),
+                })
+            }
+        };
+        let recorded_kind = entry.descriptor.as_ref().map(|descriptor| descriptor.kind);
+        let kind = match (recorded_kind, kind) {
+            (Some(recorded), Some(kind)) if recorded != kind => {
+                return Err(PersistenceError::ResourceFormatInconsistent {
+                    key: old.to_string(),
+                })
+            }
+            (Some(kind), _) | (None, Some(kind)) => kind,
+            (None, None) => {
+                return Err(PersistenceError::ResourceKindUnknown {
+                    key: old.to_string(),
+                })
+            }
+        };
+        let mut keys = vec![(old.to_string(), new.to_string(), kind)];
+        if let ResourceKind::AppendLog { .. } = kind {
+            let index_key = format!("{}_index", old);
+            if let Some(index_entry) = self.resource_files.get(&index_key) {
+                let index_kind = match &index_entry.descriptor {
+                    Some(descriptor) => descriptor.kind,
+                    None => append_log::index_kind(self.config.append_log_index_file_size),
+                };
+                keys.push((index_key, format!("{}_index", new), index_kind));
+            }
+        }
+        for (old, new, _) in keys.iter() {
+            if self.resources.contains_key(old) {
+                return Err(PersistenceError::ResourceInUse { key: old.clone() });
+            }
+            if self.resource_files.contains_key(new) || self.resources.contains_key(new) {
+                return Err(PersistenceError::DuplicateResourceKey { key: new.clone() });
+            }
+            if self.retired_resources.contains_key(new) {
+                return Err(PersistenceError::ResourceRetired { key: new.clone() });
+            }
+        }
+
+        let backend = self.backend.as_ref();
+        let mut kinds = directory_resource_kinds(backend, &self.file_path)?.0;
+        kinds.extend(entry_kinds(&self.resource_files));
+        kinds.extend(retired_kinds(&self.retired_resources));
+        for (old, new, kind) in keys.iter() {
+            kinds.push((old.clone(), Some(*kind)));
+            kinds.push((new.clone(), Some(*kind)));
+        }
+        let files = ResourceFiles::new(kinds);
+        let names = backend.list(&self.file_path).context(StdIoDirOpsSnafu)?;
+        let backup_regex = any_backup_file_regex();
+        for (old, new, kind) in keys.iter() {
+            // Anything under the new key is left over from a rename that was never committed.
+            delete_resource_files(backend, &self.file_path, &files, new)?;
+            copy_resource_files(
+                backend,
+                &self.file_path,
+                old,
+                &self.file_path,
+                new,
+                *kind,
+                &self.resource_files[old].location,
+            )?;
+            // Backups are named after the key, followed by the name of the file they replaced.
+            for name in names.iter() {
+                if files.owner(name) == Some(old) && backup_regex.is_match(name) {
+                    link_or_copy(
+                        backend,
+                        &self.file_path.join(name),
+                        &self
+                            .file_path
+                            .join(format!("{}{}", new, &name[old.len()..])),
+                    )?;
+                }
+            }
+        }
+        sync_dir(backend, &self.file_path)?;
+        for (old, new, kind) in keys {
+            let entry = self.resource_files.remove(&old).unwrap();
+            self.retire_resource(old, Some(kind));
+            self.resource_files.insert(new, entry);
+        }
+        Ok(())
+    }
+
+    // Record that the resource `key` of `kind`, if it is known, is left out of the version the
+    // store commits next.
+    fn retire_resource(&mut self, key: String, kind: Option<ResourceKind>) {
+        let retired = RetiredResource {
+            first_version_without: self.file_counter + 1,
+            kind,
+        };
+        self.retired_resources.insert(key, retired);
+    }
+
+    /// Describes the loaded version, or `None` if nothing has been committed yet.
+    pub fn version_info(&self) -> Option<&VersionInfo> {
+        self.version_info.as_ref()
+    }
+
+    pub(crate) fn backend(&self) -> &Arc<dyn StorageBackend> {
+        &self.backend
+    }
+    pub(crate) fn config(&self) -> &AtomicStoreConfig {
+        &self.config
+    }
+    pub(crate) fn persistence_path(&self) -> &Path {
+        self.file_path.as_path()
+    }
+    /// Look up the last committed location of a resource, checking that it was stored in the
+    /// layout described by `descriptor`, and in its format if `check_format`.
+    pub(crate) fn look_up_resource(
+        &self,
+        key: &str,
+        descriptor: &ResourceDescriptor,
+        check_format: bool,
+    ) -> Result<Option<StorageLocation>> {
+        look_up_entry(&self.resource_files, key, descriptor, check_format)
+    }
+    pub(crate) fn add_sync_handle(
+        &mut self,
+        key: &str,
+        handle: Arc<RwLock<VersionSyncHandle>>,
+        descriptor: ResourceDescriptor,
+    ) -> Result<()> {
+        if self.retired_resources.contains_key(key) {
+            return Err(PersistenceError::ResourceRetired {
+                key: key.to_string(),
+            });
+        }
+        if let Entry::Vacant(insert_point) = self.resources.entry(key.to_string()) {
+            insert_point.insert(handle);
+            self.resource_descriptors
+                .insert(key.to_string(), descriptor);
+        } else {
+            return Err(PersistenceError::DuplicateResourceKey {
+                key: key.to_string(),
+            });
+        }
+        Ok(())
+    }
+}
+
+/// A snapshot of the newest table of contents of a store, opened with
+/// `AtomicStoreLoader::open_read_only`. Logs opened from it are read-only, and can each be
+/// refreshed to pick up versions committed later.
+#[derive(Clone, Debug)]
+pub struct ReadOnlyLoader {
+    backend: Arc<dyn StorageBackend>,
+    config: AtomicStoreConfig,
+    file_path: PathBuf,
+    file_pattern: String,
+    version_info: Option<VersionInfo>,
+    resource_files: HashMap<String, ResourceEntry>,
+}
+
+impl ReadOnlyLoader {
+    fn from_state(
+        config: AtomicStoreConfig,
+        storage_path: &Path,
+        file_pattern: &str,
+        loaded_state: Option<AtomicStoreFileContents>,
+    ) -> ReadOnlyLoader {
+        let version_info = loaded_state.as_ref().map(VersionInfo::from_state);
+        let resource_files = match loaded_state {
+            Some(state) => state.resource_files,
+            None => HashMap::new(),
+        };
+        ReadOnlyLoader {
+            backend: config.backend.clone(),
+            config,
+            file_path: storage_path.to_path_buf(),
+            file_pattern: String::from(file_pattern),
+            version_info,
+            resource_files,
+        }
+    }
+
+    /// The counter of the table of contents that was read, or `None` if nothing has been
+    /// committed yet.
+    pub fn version(&self) -> Option<u32> {
+        self.version_info.as_ref().map(|info| info.counter)
+    }
+
+    /// Describes the version that was read, or `None` if nothing has been committed yet.
+    pub fn version_info(&self) -> Option<&VersionInfo> {
+        self.version_info.as_ref()
+    }
+
+    /// The keys of the resources recorded in the version that was read, in sorted order.
+    pub fn resource_keys(&self) -> Vec<&str> {
+        let mut keys: Vec<&str> = self.resource_files.keys().map(String::as_str).collect();
+        keys.sort_unstable();
+        keys
+    }
+
+    /// The location recorded for the resource `key`, if there is one.
+    pub fn resource_location(&self, key: &str) -> Option<StorageLocation> {
+        self.resource_files.get(key).map(|entry| entry.location)
+    }
+
+    /// The log type and format recorded for the resource `key`. This is `None` for resources that
+    /// have not been committed since they were migrated from the unversioned format.
+    pub fn resource_descriptor(&self, key: &str) -> Option<&ResourceDescriptor> {
+        self.resource_files
+            .get(key)
+            .and_then(|entry| entry.descriptor.as_ref())
+    }
+
+    /// The files in the store's directory that belong to the resource `key`, with their sizes in
+    /// bytes, sorted by path. A log's index is a resource of its own. For a resource without a
+    /// descriptor, the files named as any kind of log would name them are listed.
+    pub fn resource_files(&self, key: &str) -> Result<Vec<(PathBuf, u64)>> {
+        let backend = self.backend.as_ref();
+        let mut kinds = directory_resource_kinds(backend, &self.file_path)?.0;
+        kinds.extend(entry_kinds(&self.resource_files));
+        let resource_files = ResourceFiles::new(kinds);
+        let mut files = Vec::new();
+        for name in backend.list(&self.file_path).context(StdIoDirOpsSnafu)? {
+            if resource_files.owner(&name) != Some(key) {
+                continue;
+            }
+            let path = self.file_path.join(name);
+            if let Ok(Some(EntryType::File { len })) = self.backend.stat(&path) {
+                files.push((path, len));
+            }
+        }
+        files.sort();
+        Ok(files)
+    }
+
+    /// Check the files of every resource in the version that was read, as `AtomicStore::verify`
+    /// does.
+    pub fn verify(&self) -> Result<Vec<Inconsistency>> {
+        verify_resources(self.backend.as_ref(), &self.file_path, &self.resource_files)
+    }
+
+    pub(crate) fn backend(&self) -> &Arc<dyn StorageBackend> {
+        &self.backend
+    }
+    pub(crate) fn config(&self) -> &AtomicStoreConfig {
+        &self.config
+    }
+    pub(crate) fn persistence_path(&self) -> &Path {
+        self.file_path.as_path()
+    }
+    pub(crate) fn file_pattern(&self) -> &str {
+        &self.file_pattern
+    }
+    pub(crate) fn look_up_resource(
+        &self,
+        key: &str,
+        descriptor: &ResourceDescriptor,
+        check_format: bool,
+    ) -> Result<Option<StorageLocation>> {
+        look_up_entry(&self.resource_files, key, descriptor, check_format)
+    }
+}
+
+/// A version that has just become durable, as sent to the receivers returned by
+/// `AtomicStore::subscribe`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CommittedVersion {
+    /// The version counter, as used by `AtomicStoreLoader::load_version`.
+    pub counter: u32,
+    /// The committed locations of the resources that changed since the previous version.
+    pub changed: HashMap<String, StorageLocation>,
+    /// The metadata passed to `AtomicStore::commit_version_with_metadata`, if any.
+    pub metadata: Vec<u8>,
+    /// When the version was committed, in seconds since the Unix epoch.
+    pub timestamp: i64,
+}
+
+/// Why a file found by `AtomicStore::collect_garbage` is not reachable from any retained version.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum GarbageKind {
+    /// A `.bak.<timestamp>` backup of the uncommitted tail of a log file.
+    Backup,
+    /// A temporary file left behind by an interrupted commit of a store or of a log's index.
+    Working,
+    /// A file of a resource that no table of contents in the directory records.
+    Orphaned,
+}
+
+/// A file found by `AtomicStore::collect_garbage`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct GarbageFile {
+    pub path: PathBuf,
+    /// The size of the file in bytes.
+    pub len: u64,
+    pub kind: GarbageKind,
+    /// Whether the file was deleted, as allowed by `AtomicStoreConfig::garbage_policy`.
+    pub deleted: bool,
+}
+
+/// A problem found by `AtomicStore::verify` in the files of a resource.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Inconsistency {
+    /// The key of the resource.
+    pub key: String,
+    /// The file that is missing or inconsistent, or the store's directory if the problem is not
+    /// with a single file.
+    pub path: PathBuf,
+    pub kind: InconsistencyKind,
+}
+
+impl Inconsistency {
+    pub(crate) fn new(key: &str, path: &Path, kind: InconsistencyKind) -> Self {
+        Inconsistency {
+            key: key.to_string(),
+            path: path.to_path_buf(),
+            kind,
+        }
+    }
+}
+
+impl fmt::Display for Inconsistency {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}: {}", self.key, self.path.display(), self.kind)
+    }
+}
+
+/// What is wrong with the files of a resource, as found by `AtomicStore::verify`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum InconsistencyKind {
+    /// The table of contents records no layout for the resource, as for one migrated from the
+    /// unversioned format, so its files were not checked.
+    Unchecked,
+    /// The file is missing.
+    MissingFile,
+    /// An `AppendLog` has no index recorded under the key `index_key`.
+    MissingIndex { index_key: String },
+    /// A location lies past the end of its file of `len` bytes. The location is recorded by the
+    /// table of contents if `index_entry` is `None`, and otherwise by that entry of the index.
+    LocationPastEnd {
+        location: StorageLocation,
+        index_entry: Option<u64>,
+        len: u64,
+    },
+    /// The location recorded by the table of contents is not the location of an entry of the file.
+    LocationNotAnEntry { location: StorageLocation },
+    /// An entry of the index of an `AppendLog` cannot be deserialized.
+    UnreadableIndexEntry { index_entry: u64, error: String },
+    /// An entry of the index of an `AppendLog` does not follow the one before it.
+    IndexEntryOutOfOrder {
+        index_entry: u64,
+        location: StorageLocation,
+        previous: StorageLocation,
+    },
+    /// The last entry of the index of an `AppendLog` is not the location recorded by the table of
+    /// contents.
+    LastIndexEntryMismatch {
+        last_entry: StorageLocation,
+        location: StorageLocation,
+    },
+    /// The table of contents records a `FixedAppendLog` with no room for any entry.
+    EmptyLayout,
+    /// The index file of a `FixedAppendLog` is too short for a header.
+    IndexTooShort { len: u64 },
+    /// The index of a `FixedAppendLog` was written on a machine with the opposite byte order.
+    OppositeByteOrder,
+    /// The index of a `FixedAppendLog` has an invalid byte order mark.
+    InvalidByteOrder { byte_order: u32 },
+    /// The header of the index of a `FixedAppendLog` records a different layout than the table of
+    /// contents.
+    LayoutMismatch {
+        index_resource_size: u64,
+        index_file_size: u64,
+        resource_size: u64,
+        file_size: u64,
+    },
+    /// The index of a `FixedAppendLog` was committed up to `committed`, before the location
+    /// recorded by the table of contents.
+    IndexBehindLocation {
+        committed: StorageLocation,
+        location: StorageLocation,
+    },
+    /// The file is `len` bytes, shorter than the `committed` bytes it must hold.
+    FileTooShort { len: u64, committed: u64 },
+    /// A `RollingLog` file is too short for the entry count in its header.
+    MissingHeader,
+    /// A `RollingLog` file ends in an entry that starts at `offset` but is not complete.
+    PartialEntry { offset: u64 },
+    /// The header of a `RollingLog` file records `header` entries, but the file holds `held`. For
+    /// the file of the committed location, `committed` is how many of those are committed.
+    EntryCountMismatch {
+        header: u64,
+        committed: Option<u64>,
+        held: u64,
+    },
+}
+
+impl fmt::Display for InconsistencyKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            InconsistencyKind::Unchecked => write!(
+                f,
+                "the table of contents records no layout, so the files were not checked"
+            ),
+            InconsistencyKind::MissingFile => write!(f, "the file is missing"),
+            InconsistencyKind::MissingIndex { index_key } => write!(
+                f,
+                "the table of contents records no index '{}' for the log",
+                index_key
+            ),
+            InconsistencyKind::LocationPastEnd {
+                location,
+                index_entry,
+                len,
+            } => match index_entry {
+                Some(index_entry) => write!(
+                    f,
+                    "index entry {} at {} is past the end of the file ({} bytes)",
+                    index_entry, location, len
+                ),
+                None => write!(
+                    f,
+                    "the location {} in the table of contents is past the end of the file ({} bytes)",
+                    location, len
+                ),
+            },
+            InconsistencyKind::LocationNotAnEntry { location } => write!(
+                f,
+                "the location {} in the table of contents is not an entry of the file",
+                location
+            ),
+            InconsistencyKind::UnreadableIndexEntry { index_entry, error } => {
+                write!(f, "index entry {} is unreadable: {}", index_entry, error)
+            }
+            InconsistencyKind::IndexEntryOutOfOrder {
+                index_entry,
+                location,
+                previous,
+            } => write!(
+                f,
+                "index entry {} at {} does not follow the previous entry at {}",
+                index_entry, location, previous
+            ),
+            InconsistencyKind::LastIndexEntryMismatch {
+                last_entry,
+                location,
+            } => write!(
+                f,
+                "the last index entry {} is not the location {} in the table of contents",
+                last_entry, location
+            ),
+            InconsistencyKind::EmptyLayout => write!(f, "the recorded layout is empty"),
+            InconsistencyKind::IndexTooShort { len } => write!(
+                f,
+                "the index file is {} bytes, too short for a header",
+                len
+            ),
+            InconsistencyKind::OppositeByteOrder => write!(
+                f,
+                "the index was written with the opposite byte order"
+            ),
+            InconsistencyKind::InvalidByteOrder { byte_order } => {
+                write!(f, "invalid index byte order mark {:#010x}", byte_order)
+            }
+            InconsistencyKind::LayoutMismatch {
+                index_resource_size,
+                index_file_size,
+                resource_size,
+                file_size,
+            } => write!(
+                f,
+                "the index records {} entries of {} bytes per file, but the table of contents records {} of {}",
+                index_file_size, index_resource_size, file_size, resource_size
+            ),
+            InconsistencyKind::IndexBehindLocation {
+                committed,
+                location,
+            } => write!(
+                f,
+                "the index was committed up to {}, before the location {} in the table of contents",
+                committed, location
+            ),
+            InconsistencyKind::FileTooShort { len, committed } => write!(
+                f,
+                "the file is {} bytes, but {} are committed",
+                len, committed
+            ),
+            InconsistencyKind::MissingHeader => {
+                write!(f, "the file is too short for its entry count")
+            }
+            InconsistencyKind::PartialEntry { offset } => {
+                write!(f, "the file ends in a partial entry at {}", offset)
+            }
+            InconsistencyKind::EntryCountMismatch {
+                header,
+                committed,
+                held,
+            } => match committed {
+                Some(committed) => write!(
+                    f,
+                    "the header records {} entries, but {} are committed and the file holds {}",
+                    header, committed, held
+                ),
+                None => write!(
+                    f,
+                    "the header records {} entries, but the file holds {}",
+                    header, held
+                ),
+            },
+        }
+    }
+}
+
+// Matches the tables of contents of a store with any file pattern, capturing the file pattern.
+fn any_table_of_contents_regex() -> Regex {
+    Regex::new(r"^(.+)_(latest|archived_\d+|quarantined_.+)$").unwrap()
+}
+
+// Matches the lock files of stores, whatever their pattern.
+fn any_lock_file_regex() -> Regex {
+    Regex::new(r"^\.(.+)_lock$").unwrap()
+}
+
+// Matches backups of replaced log files, whatever their key and kind.
+fn any_backup_file_regex() -> Regex {
+    Regex::new(r"\.bak\.-?\d+$").unwrap()
+}
+
+// Matches the names of files written by any kind of log, whatever its key, capturing the
+// timestamp of backups.
+fn any_resource_file_regex() -> Regex {
+    Regex::new(
+        r"^(\..+_\d+|.+_\d+(_\d+)?\.bak\.(-?\d+)|.+_\d+_\d+|.+_index|\..+_index_(working|backup)|\..+\.branch\.\d+)$",
+    )
+    .unwrap()
+}
+
+/// The central index of an atomic version of truth across multiple persisted data structures;
+/// Guarantees that all managed resources can be loaded in a consistent state across an entire logical entity.
+pub struct AtomicStore {
+    backend: Arc<dyn StorageBackend>,
+    // because there is only one instance per file for the table of contents, we do not keep it open.
+    file_path: PathBuf,
+    file_pattern: String,
+    file_counter: u32,
+    last_counter: Option<u32>,
+    resources: HashMap<String, Arc<RwLock<VersionSyncHandle>>>,
+    resource_descriptors: HashMap<String, ResourceDescriptor>,
+    // Resources from the loaded table of contents that no log has claimed; their last committed
+    // entries are recorded unchanged in each new version.
+    unclaimed_resources: HashMap<String, ResourceEntry>,
+    // How long `commit_version` will wait for resource versions before returning an error.
+    commit_timeout: Duration,
+    // How many backup index files to retain at any given time. If `None`, all archives will be
+    // retained.
+    retained_archives: Option<u32>,
+    // Whether versions newer than the one this store was opened at must be quarantined before
+    // the next commit.
+    quarantine_pending: bool,
+    // The table of contents of the last committed version, if there is one.
+    committed_state: Option<AtomicStoreFileContents>,
+    // Removed resources whose files have not been deleted yet.
+    retired_resources: HashMap<String, RetiredResource>,
+    // Notified of each committed version; dropped once their receiver is.
+    subscribers: Vec<Sender<CommittedVersion>>,
+    // Which unreachable files `collect_garbage` deletes.
+    garbage_policy: GarbagePolicy,
+    // The table of contents written by `prepare_version`, until it is committed or aborted.
+    prepared_state: Option<AtomicStoreFileContents>,
+    _lock: StoreLock,
+}
+
+impl AtomicStore {
+    pub fn open(load_info: AtomicStoreLoader) -> Result<AtomicStore> {
+        let unclaimed_resources: HashMap<String, ResourceEntry> = load_info
+            .resource_files
+            .iter()
+            .filter(|(key, _)| !load_info.resources.contains_key(*key))
+            .map(|(key, entry)| (key.clone(), entry.clone()))
+            .collect();
+        if !unclaimed_resources.is_empty() {
+            match load_info.config.unclaimed_resource_policy {
+                UnclaimedResourcePolicy::CarryForward => {}
+                UnclaimedResourcePolicy::Warn => {
+                    for (key, entry) in unclaimed_resources.iter() {
+                        tracing::warn!(
+                            key,
+                            location = %entry.location,
+                            "resource was not loaded; carrying its last committed location forward",
+                        );
+                    }
+                }
+                UnclaimedResourcePolicy::Fail => {
+                    let mut keys: Vec<String> = unclaimed_resources.into_keys().collect();
+                    keys.sort();
+                    return Err(PersistenceError::UnclaimedResources { keys });
+                }
+            }
+        }
+
+        if !load_info.initial_run {
+            if let Some(retained_archives) = load_info.config.retained_archives {
+                // At startup, prune all existing archive files that fall outside the retaining
+                // window. This is necessary in case the `retained_archives` parameter has changed
+                // since the last time the store was opened. Once this is done, we know the number
+                // of archive files on disk is consistent with `retained_archives`, and we only need
+                // to check for at most one old archive each time we commit a new version.
+                prune_archives(
+                    load_info.backend.as_ref(),
+                    &load_info.file_path,
+                    &load_info.file_pattern,
+                    load_info.file_counter.saturating_sub(retained_archives),
+                )?;
+            }
+        }
+
+        let file_counter = if load_info.initial_run {
+            load_info.file_counter
+        } else {
+            load_info.file_counter + 1
+        };
+        let retired_resources = load_info.retired_resources;
+        let committed_state = match load_info.version_info {
+            Some(info) if !load_info.initial_run => Some(AtomicStoreFileContents {
+                file_counter: load_info.file_counter,
+                resource_files: load_info.resource_files,
+                metadata: info.metadata,
+                timestamp: info.timestamp,
+                retired_resources: retired_resources.clone(),
+            }),
+            _ => None,
+        };
+
+        Ok(AtomicStore {
+            backend: load_info.backend,
+            file_path: load_info.file_path,
+            file_pattern: load_info.file_pattern,
+            file_counter,
+            last_counter: if load_info.initial_run {
+                None
+            } else {
+                Some(load_info.file_counter)
+            },
+            resources: load_info.resources,
+            resource_descriptors: load_info.resource_descriptors,
+            unclaimed_resources,
+            commit_timeout: load_info.config.commit_timeout,
+            retained_archives: load_info.config.retained_archives,
+            quarantine_pending: load_info.supersedes_newer_versions,
+            committed_state,
+            retired_resources,
+            subscribers: Vec::new(),
+            garbage_policy: load_info.config.garbage_policy,
+            prepared_state: None,
+            _lock: load_info.lock,
+        })
+    }
+
+    /// Override the commit timeout of the config this store was loaded with; see
+    /// `AtomicStoreConfig::commit_timeout`.
+    #[deprecated(note = "use `AtomicStoreConfig::commit_timeout` instead")]
+    pub fn set_commit_timeout(&mut self, timeout: Duration) {
+        self.commit_timeout = timeout;
+    }
+
+    /// Receive a `CommittedVersion` for every version committed from now on, once it is durable.
+    pub fn subscribe(&mut self) -> Receiver<CommittedVersion> {
+        let (sender, receiver) = channel();
+        self.subscribers.push(sender);
+        receiver
+    }
+
+    /// Commit the version. Note that all logs and stores must call `.commit_version()` or `.skip_version()` before this function is called. If a log has committed several times since the last version, only its oldest commit point is recorded; the others are queued for the following versions.
+    ///
+    /// This will timeout after 100 milliseconds (configurable with `AtomicStoreConfig::commit_timeout`), returning `PersistenceError::TimedOut` with the keys of the resources that are still pending; nothing is committed, and the call can be retried. To disable this timeout, set it to `Duration::MAX`.
+    pub fn commit_version(&mut self) -> Result<()> {
+        self.commit_version_with_metadata(&[])
+    }
+
+    /// Decide on `branch` in every log of `logs`, which must belong to this store: append the
+    /// branch's resource instances to each log, commit each log's version and discard all of its
+    /// branches, and then commit the store's version, so that the table of contents only ever
+    /// records the decided branch. The other resources of the store must commit or skip their
+    /// versions, as for `commit_version`.
+    ///
+    /// If the branch cannot be appended to one of the logs, each log it was appended to is
+    /// reverted to its last commit, dropping anything stored since, the branches are kept, and
+    /// the error is returned. Likewise, if a log cannot commit its version, it and every log that
+    /// has not committed yet are reverted; the branches are only discarded once every log has
+    /// committed.
+    pub fn commit_branch(
+        &mut self,
+        branch: BranchId,
+        logs: &mut [&mut dyn BranchedLog],
+    ) -> Result<()> {
+        self.check_branched_logs(logs)?;
+        for i in 0..logs.len() {
+            if let Err(err) = logs[i].append_branch(branch) {
+                revert_branched_logs(&mut logs[..=i]);
+                return Err(err);
+            }
+        }
+        for i in 0..logs.len() {
+            if let Err(err) = logs[i].commit_version() {
+                revert_branched_logs(&mut logs[i..]);
+                return Err(err);
+            }
+        }
+        for log in logs.iter_mut() {
+            log.discard_branches()?;
+        }
+        self.commit_version()
+    }
+
+    /// Discard every branch of every log of `logs`, which must belong to this store, without
+    /// committing any of them.
+    pub fn discard_branches(&mut self, logs: &mut [&mut dyn BranchedLog]) -> Result<()> {
+        self.check_branched_logs(logs)?;
+        for log in logs.iter_mut() {
+            log.discard_branches()?;
+        }
+        Ok(())
+    }
+
+    fn check_branched_logs(&self, logs: &[&mut dyn BranchedLog]) -> Result<()> {
+        match logs
+            .iter()
+            .find(|log| !self.resources.contains_key(log.resource_key()))
+        {
+            Some(log) => Err(PersistenceError::FailedToFindExpectedResource {
+                key: log.resource_key().to_string(),
+            }),
+            None => Ok(()),
+        }
+    }
+
+    /// Commit a version for every commit point that all resources have queued, without waiting
+    /// for any more, and return how many versions were committed. Each resource's commit points
+    /// are recorded in the order they were queued, so a pipeline can commit its logs several times
+    /// before the store catches up.
+    pub fn commit_queued_versions(&mut self) -> Result<usize> {
+        let mut queued = usize::MAX;
+        for resource_store in self.resources.values() {
+            queued = queued.min(resource_store.read()?.queued_versions()?);
+        }
+        if queued == usize::MAX {
+            // With no resources, there is nothing to commit.
+            return Ok(0);
+        }
+        for _ in 0..queued {
+            self.commit_version()?;
+        }
+        Ok(queued)
+    }
+
+    /// Like `commit_version`, but records `metadata` with the version, such as the block height it
+    /// corresponds to. The metadata of a version can be read back with
+    /// `AtomicStoreLoader::read_version_info` without loading any logs.
+    pub fn commit_version_with_metadata(&mut self, metadata: &[u8]) -> Result<()> {
+        self.prepare_version(metadata)?;
+        self.finish_version()
+    }
+
+    /// Write the table of contents of the next version to the working file, without committing
+    /// it. The commit points of the resources stay queued until `finish_version`, so a prepared
+    /// version can be abandoned with `abort_version`.
+    pub(crate) fn prepare_version(&mut self, metadata: &[u8]) -> Result<()> {
+        if self.prepared_state.is_some() {
+            // A coordinated commit failed to install a version after deciding to commit it.
+            self.finish_version()?;
+            two_phase_commit::remove_prepared_marker(
+                self.backend.as_ref(),
+                &self.file_path,
+                &self.file_pattern,
+            )?;
+        }
+
+        // A single deadline for every resource, so the wait is bounded by the timeout no matter
+        // how many resources there are.
+        let deadline = Instant::now().checked_add(self.commit_timeout);
+        let mut pending = Vec::new();
+        for resource_store in self.resources.values() {
+            let store_access = resource_store.read()?;
+            if !store_access.wait_for_version_until(deadline)? {
+                pending.push(store_access.resource_key().to_string());
+            }
+        }
+        if !pending.is_empty() {
+            // No version has been started yet, so resources that were ready stay ready for the
+            // next attempt.
+            pending.sort();
+            return Err(PersistenceError::TimedOut { pending });
+        }
+
+        let mut collected_locations = self.unclaimed_resources.clone();
+        for (resource_key, resource_store) in self.resources.iter() {
+            if let Some(location_found) = resource_store.read()?.peek_version()? {
+                collected_locations.insert(
+                    resource_key.to_string(),
+                    ResourceEntry {
+                        location: location_found,
+                        descriptor: self.resource_descriptors.get(resource_key).cloned(),
+                    },
+                );
+            }
+        }
+
+        if self.quarantine_pending {
+            // `last_counter` is always set when opening an existing version.
+            if let Some(last_counter) = self.last_counter {
+                quarantine_versions_after(
+                    self.backend.as_ref(),
+                    &self.file_path,
+                    &self.file_pattern,
+                    last_counter,
+                )?;
+            }
+            self.quarantine_pending = false;
+        }
+
+        let temp_file_path = format_working_file_path(&self.file_path, &self.file_pattern);
+        let out_state = AtomicStoreFileContents {
+            file_counter: self.file_counter,
+            resource_files: collected_locations,
+            metadata: metadata.to_vec(),
+            timestamp: Some(unix_timestamp()),
+            retired_resources: self.retired_resources.clone(),
+        };
+        write_state(self.backend.as_ref(), &temp_file_path, &out_state)?;
+        self.prepared_state = Some(out_state);
+        Ok(())
+    }
+
+    /// Commit the version written by `prepare_version`, taking the commit points it recorded. If
+    /// the version cannot be installed, it stays prepared, so that this can be retried.
+    pub(crate) fn finish_version(&mut self) -> Result<()> {
+        let Some(out_state) = self.prepared_state.take() else {
+            return Ok(());
+        };
+        if let Err(err) = install_working_state(
+            self.backend.as_ref(),
+            &self.file_path,
+            &self.file_pattern,
+            self.last_counter,
+        ) {
+            self.prepared_state = Some(out_state);
+            return Err(err);
+        }
+        self.last_counter = Some(self.file_counter);
+        for resource_store in self.resources.values() {
+            resource_store.write()?.take_version()?;
+        }
+        self.notify_subscribers(&out_state);
+
+        // Prune an old archive if this commit has just pushed one outside of the retention window.
+        if let Some(retained_archives) = self.retained_archives {
+            if let Some(num) = self.file_counter.checked_sub(retained_archives + 1) {
+                let prune_path =
+                    format_archived_file_path(&self.file_path, &self.file_pattern, num);
+                if self.backend.exists(&prune_path) {
+                    if let Err(err) = self.backend.remove(&prune_path) {
+                        // If we fail to prune the old archive, we have still committed the version
+                        // update, so we shouldn't fail here. Just log a warning.
+                        tracing::warn!(
+                            %err,
+                            path = %prune_path.display(),
+                            "failed to prune old archive file",
+                        );
+                    }
+                }
+            }
+        }
+
+        self.delete_retired_resources();
+
+        self.committed_state = Some(out_state);
+        self.file_counter += 1; // advance for the next version
+        Ok(())
+    }
+
+    /// Abandon the version written by `prepare_version`, deleting its working file. The commit
+    /// points of the resources stay queued for the next version.
+    pub(crate) fn abort_version(&mut self) -> Result<()> {
+        if self.prepared_state.take().is_some() {
+            let temp_file_path = format_working_file_path(&self.file_path, &self.file_pattern);
+            if self.backend.exists(&temp_file_path) {
+                self.backend
+                    .remove(&temp_file_path)
+                    .context(StdIoDirOpsSnafu)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn backend(&self) -> &Arc<dyn StorageBackend> {
+        &self.backend
+    }
+
+    pub(crate) fn file_path(&self) -> &Path {
+        &self.file_path
+    }
+
+    pub(crate) fn file_pattern(&self) -> &str {
+        &self.file_pattern
+    }
+
+    /// Check the files of every resource in the last committed version against its table of
+    /// contents: each recorded location must lie inside an existing file, the header of each
+    /// `FixedAppendLog` index must match the recorded layout, the index of each `AppendLog` must
+    /// hold increasing locations inside its data files, and the entry count in the header of each
+    /// `RollingLog` file must match the entries in it. Every problem found is returned, rather than
+    /// only the first; errors are only returned if a file cannot be read at all.
+    ///
+    /// Resources migrated from the unversioned format are not checked until they have been
+    /// committed with a descriptor; each is reported as `InconsistencyKind::Unchecked`.
+    pub fn verify(&self) -> Result<Vec<Inconsistency>> {
+        match &self.committed_state {
+            Some(state) => verify_resources(
+                self.backend.as_ref(),
+                &self.file_path,
+                &state.resource_files,
+            ),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    /// Find every file in the store's directory that was written by a store or a log, but is not
+    /// reachable from any retained version of any store in the directory, and delete those that
+    /// `AtomicStoreConfig::garbage_policy` allows. These are backups of truncated log files,
+    /// temporary files left behind by interrupted commits of a store or of a log's index, and the
+    /// files of resources that no table of contents records.
+    ///
+    /// The files of another store in the directory are only looked at if its lock can be taken, so
+    /// a store that is open is never touched; while one is, no file can be considered orphaned.
+    /// The data files of a recorded resource are never collected, even past its last committed
+    /// location, as its log may still be writing them.
+    pub fn collect_garbage(&mut self) -> Result<Vec<GarbageFile>> {
+        let backend = self.backend.clone();
+
+        // Every resource recorded by a table of contents in the directory is reachable, as are the
+        // resources loaded or retired by this store.
+        let mut stores = directory_stores(backend.as_ref(), &self.file_path)?;
+        let own_store = stores.entry(self.file_pattern.clone()).or_default();
+        for (key, descriptor) in &self.resource_descriptors {
+            own_store.record(key, Some(descriptor.kind));
+        }
+        for key in self.resources.keys() {
+            own_store.record(key, None);
+        }
+        for (key, entry) in &self.unclaimed_resources {
+            let kind = entry.descriptor.as_ref().map(|descriptor| descriptor.kind);
+            own_store.record(key, kind);
+        }
+        for (key, retired) in &self.retired_resources {
+            own_store.record(key, retired.kind);
+        }
+
+        // Lock every other store for the rest of the scan, so that none can be opened meanwhile.
+        let mut locks = Vec::new();
+        let mut locked_patterns = vec![self.file_pattern.clone()];
+        let mut all_stores_locked = true;
+        for pattern in stores.keys() {
+            if *pattern == self.file_pattern {
+                continue;
+            }
+            match StoreLock::acquire(backend.as_ref(), &self.file_path, pattern) {
+                Ok(lock) => {
+                    locks.push(lock);
+                    locked_patterns.push(pattern.clone());
+                }
+                Err(PersistenceError::StoreLocked { .. }) => all_stores_locked = false,
+                Err(err) => return Err(err),
+            }
+        }
+        let all_tables_read = stores.values().all(|store| !store.unreadable_tables);
+
+        // The resources of the locked stores, and the keys of those of a store that is open.
+        let mut kinds: HashMap<String, Option<ResourceKind>> = HashMap::new();
+        let mut open_keys = HashSet::new();
+        for (pattern, store) in stores {
+            let locked = locked_patterns.contains(&pattern);
+            for (key, kind) in store.resources {
+                if !locked {
+                    open_keys.insert(key);
+                    continue;
+                }
+                let recorded = kinds.entry(key).or_default();
+                if recorded.is_none() {
+                    *recorded = kind;
+                }
+            }
+        }
+        let files = ResourceFiles::new(
+            kinds
+                .into_iter()
+                .chain(open_keys.iter().map(|key| (key.clone(), None))),
+        );
+        let toc_regex = any_table_of_contents_regex();
+        // A version this store failed to install after a coordinator decided to commit it is kept
+        // for the next attempt.
+        let store_files: Vec<PathBuf> = locked_patterns
+            .iter()
+            .filter(|pattern| **pattern != self.file_pattern || self.prepared_state.is_none())
+            .flat_map(|pattern| {
+                [
+                    format_working_file_path(&self.file_path, pattern),
+                    two_phase_commit::format_prepared_file_path(&self.file_path, pattern),
+                ]
+            })
+            .collect();
+
+        let resource_regex = any_resource_file_regex();
+        let now = unix_timestamp();
+        let mut garbage = Vec::new();
+        let mut deleted_any = false;
+        for name in backend.list(&self.file_path).context(StdIoDirOpsSnafu)? {
+            let path = self.file_path.join(&name);
+            let Ok(Some(EntryType::File { len })) = backend.stat(&path) else {
+                continue;
+            };
+            let mut backup_timestamp = None;
+            let kind = if toc_regex.is_match(&name) {
+                continue;
+            } else if store_files.contains(&path) {
+                // A version of this store can only be in the middle of being committed while the
+                // store is borrowed for it, and the other stores are locked.
+                GarbageKind::Working
+            } else if let Some(captures) = resource_regex.captures(&name) {
+                let key = files.owner(&name);
+                if key.is_some_and(|key| open_keys.contains(key)) {
+                    // A file of a store that is open.
+                    continue;
+                } else if let Some(timestamp) = captures.get(3) {
+                    backup_timestamp = timestamp.as_str().parse::<i64>().ok();
+                    GarbageKind::Backup
+                } else if let Some(key) = key {
+                    // Data files are never garbage while a table of contents records their
+                    // resource, as a log may still be writing them.
+                    if !fixed_append_log::is_leftover_index_file(
+                        backend.as_ref(),
+                        &self.file_path,
+                        key,
+                        &name,
+                    ) {
+                        continue;
+                    }
+                    GarbageKind::Working
+                } else if all_tables_read && all_stores_locked {
+                    GarbageKind::Orphaned
+                } else {
+                    continue;
+                }
+            } else {
+                // Not a file this library writes.
+                continue;
+            };
+
+            let deletable = match self.garbage_policy {
+                GarbagePolicy::Report => false,
+                GarbagePolicy::Delete { keep_backups_for } => match backup_timestamp {
+                    Some(timestamp) => {
+                        now.saturating_sub(timestamp)
+                            >= keep_backups_for.as_secs().try_into().unwrap_or(i64::MAX)
+                    }
+                    None => true,
+                },
+            };
+            let deleted = deletable
+                && match backend.remove(&path) {
+                    Ok(()) => true,
+                    Err(err) => {
+                        tracing::warn!(%err, path = %path.display(), "failed to delete garbage file");
+                        false
+                    }
+                };
+            deleted_any |= deleted;
+            garbage.push(GarbageFile {
+                path,
+                len,
+                kind,
+                deleted,
+            });
+        }
+        if deleted_any {
+            sync_dir(backend.as_ref(), &self.file_path)?;
+        }
+        garbage.sort_by(|a, b| a.path.cmp(&b.path));
+        Ok(garbage)
+    }
+
+    // Delete the files of removed resources that are not referred to by any retained version.
+    // They are forgotten by the next version committed; if we are interrupted, they are deleted
+    // again.
+    fn delete_retired_resources(&mut self) {
+        if self.retired_resources.is_empty() {
+            return;
+        }
+        // The version has been committed, so we shouldn't fail here; anything not deleted is
+        // retried after a later commit.
+        let archives = archive_files(self.backend.as_ref(), &self.file_path, &self.file_pattern)
+            .and_then(|archives| archives.collect::<Result<Vec<_>>>());
+        let oldest_version = match archives {
+            Ok(archives) => archives
+                .into_iter()
+                .map(|(_, num)| num)
+                .fold(self.file_counter, u32::min),
+            Err(err) => {
+                tracing::warn!(%err, "failed to list archives to delete removed resources");
+                return;
+            }
+        };
+        let deletable: Vec<String> = self
+            .retired_resources
+            .iter()
+            .filter(|(_, retired)| retired.first_version_without <= oldest_version)
+            .map(|(key, _)| key.clone())
+            .collect();
+        if deletable.is_empty() {
+            return;
+        }
+        let mut kinds = match directory_resource_kinds(self.backend.as_ref(), &self.file_path) {
+            Ok((kinds, _)) => kinds,
+            Err(err) => {
+                tracing::warn!(%err, "failed to list resources to delete removed resources");
+                return;
+            }
+        };
+        kinds.extend(
+            self.resource_descriptors
+                .iter()
+                .map(|(key, descriptor)| (key.clone(), Some(descriptor.kind))),
+        );
+        kinds.extend(entry_kinds(&self.unclaimed_resources));
+        kinds.extend(retired_kinds(&self.retired_resources));
+        let files = ResourceFiles::new(kinds);
+        for key in deletable {
+            // The files of a resource of unknown kind cannot be told apart with certainty, so they
+            // are left for `collect_garbage`.
+            if files.kind(&key).is_none() {
+                self.retired_resources.remove(&key);
+                continue;
+            }
+            match delete_resource_files(self.backend.as_ref(), &self.file_path, &files, &key) {
+                Ok(()) => {
+                    self.retired_resources.remove(&key);
+                }
+                Err(err) => {
+                    tracing::warn!(%err, key, "failed to delete files of removed resource");
+                }
+            }
+        }
+    }
+
+    fn notify_subscribers(&mut self, out_state: &AtomicStoreFileContents) {
+        if self.subscribers.is_empty() {
+            return;
+        }
+        let changed: HashMap<String, StorageLocation> = out_state
+            .resource_files
+            .iter()
+            .filter(|(key, entry)| {
+                self.committed_state
+                    .as_ref()
+                    .and_then(|state| state.resource_files.get(*key))
+                    .map(|previous| previous.location != entry.location)
+                    .unwrap_or(true)
+            })
+            .map(|(key, entry)| (key.clone(), entry.location))
+            .collect();
+        let version = CommittedVersion {
+            counter: out_state.file_counter,
+            changed,
+            metadata: out_state.metadata.clone(),
+            // Always set for versions committed by this store.
+            timestamp: out_state.timestamp.unwrap_or_default(),
+        };
+        self.subscribers
+            .retain(|subscriber| subscriber.send(version.clone()).is_ok());
+    }
+
+    /// Write a copy of the last committed version to `dest`, which can then be loaded like any
+    /// other store with the same file pattern. Logs may keep writing while the copy is made; data
+    /// files are hard linked where possible, and files which may still be appended to are copied
+    /// up to the committed location. Older archived versions are not copied. The layout of a
+    /// resource without a descriptor is unknown, so all of its files are copied, other than
+    /// backups and branches.
+    pub fn checkpoint(&self, dest: &Path) -> Result<()> {
+        let backend = self.backend.as_ref();
+        create_dir_all(backend, dest)?;
+        let latest_file_path = format_latest_file_path(dest, &self.file_pattern);
+        if backend.exists(&latest_file_path)
+            || archive_file_exists(backend, dest, &self.file_pattern)?
+        {
+            return Err(PersistenceError::StoreAlreadyExists {
+                path: dest.to_string_lossy().to_string(),
+            });
+        }
+        let state = match &self.committed_state {
+            Some(state) => state,
+            // Nothing committed yet; an empty directory loads as an empty store.
+            None => return Ok(()),
+        };
+
+        // Only needed for resources without a descriptor, which are not loaded by this store.
+        let mut untyped_files = None;
+        for (key, entry) in state.resource_files.iter() {
+            let descriptor = entry
+                .descriptor
+                .as_ref()
+                .or_else(|| self.resource_descriptors.get(key));
+            if let Some(descriptor) = descriptor {
+                copy_resource_files(
+                    backend,
+                    &self.file_path,
+                    key,
+                    dest,
+                    key,
+                    descriptor.kind,
+                    &entry.location,
+                )?;
+                continue;
+            }
+            let files = match &untyped_files {
+                Some(files) => files,
+                None => {
+                    let mut kinds = directory_resource_kinds(backend, &self.file_path)?.0;
+                    kinds.extend(entry_kinds(&state.resource_files));
+                    untyped_files.insert(ResourceFiles::new(kinds))
+                }
+            };
+            copy_untyped_resource_files(backend, &self.file_path, key, dest, files)?;
+        }
+
+        // The table of contents goes last, so that an interrupted checkpoint is never loadable.
+        let temp_file_path = format_working_file_path(dest, &self.file_pattern);
+        write_state(backend, &temp_file_path, state)?;
+        backend
+            .rename(&temp_file_path, &latest_file_path)
+            .context(StdIoDirOpsSnafu)?;
+        sync_dir(backend, dest)
+    }
+}
+
+#[test]
+fn test_atomic_store_log_timeout() {
+    use crate::load_store::BincodeLoadStore;
+
+    let prefix = "test_atomic_store_log_timeout";
+    let dir = tempfile::tempdir().expect("Could not create tempdir");
+    let mut loader =
+        AtomicStoreLoader::load(dir.path(), prefix).expect("Could not open an atomic store");
+    let mut log = crate::AppendLog::load(
+        &mut loader,
+        BincodeLoadStore::<u64>::default(),
+        prefix,
+        1024,
+    )
+    .expect("Could not create appendlog");
+    let mut store = AtomicStore::open(loader).expect("Could not open store");
+
+    // oops we forgot to commit log
+    if let Err(crate::error::PersistenceError::TimedOut { pending }) = store.commit_version() {
+        assert!(pending.contains(&prefix.to_string()));
+    } else {
+        panic!("Atomic store should've timed out");
+    }
+
+    // Committing again should work
+    log.commit_version().expect("Could not commit log");
+    store.commit_version().expect("Could not commit store");
+}
+
+#[test]
+fn test_archive_pruning() {
+    let dir = tempfile::tempdir().expect("Could not create tempdir");
+    let file_pattern = "test_archive_pruning";
+
+    let list_archives = || {
+        let mut versions = archive_files(
+            &crate::storage_backend::FileSystemBackend,
+            dir.path(),
+            file_pattern,
+        )
+        .unwrap()
+        .map(|res| res.unwrap().1)
+        .collect::<Vec<_>>();
+        versions.sort();
+        versions
+    };
+
+    // First create a store without any pruning and create several archive files.
+    {
+        let loader = AtomicStoreLoader::create(dir.path(), file_pattern)
+            .expect("Could not create an atomic store");
+        let mut store = AtomicStore::open(loader).expect("Could not open store");
+
+        for _ in 0..5 {
+            store.commit_version().expect("Could not commit store");
+        }
+        // There should be one archive for each committed version except the latest.
+        assert_eq!(list_archives(), vec![0, 1, 2, 3]);
+    }
+
+    // Now reopen the store with pruning turned on; ensure old versions get pruned immediately.
+    {
+        let config = AtomicStoreConfig::default().retain_archives(2);
+        let loader = AtomicStoreLoader::load_with_config(dir.path(), file_pattern, config)
+            .expect("Could not create an atomic store");
+        let mut store = AtomicStore::open(loader).expect("Could not open store");
+
+        assert_eq!(list_archives(), vec![2, 3]);
+
+        // Commit more versions, check that we always retain the two latest archives.
+        for i in 5..10 {
+            store.commit_version().expect("Could not commit store");
+            assert_eq!(list_archives(), vec![i - 2, i - 1])
+        }
+    }
+
+    // Check that we can delete the latest version and reload from an archive, even though some
+    // older archives have been pruned.
+    {
+        let latest = format_latest_file_path(dir.path(), file_pattern);
+        assert!(latest.exists());
+        std::fs::remove_file(latest).expect("Could not delete latest version");
+
+        let loader = AtomicStoreLoader::load(dir.path(), file_pattern)
+            .expect("Could not create an atomic store");
+        let store = AtomicStore::open(loader).expect("Could not open store");
+        assert_eq!(store.file_counter, 9);
+    }
+}
+
+#[test]
+fn test_unclaimed_resources() {
+    use crate::load_store::BincodeLoadStore;
+
+    let dir = tempfile::tempdir().expect("Could not create tempdir");
+    let file_pattern = "test_unclaimed_resources";
+
+    // Commit a version containing two logs.
+    {
+        let mut loader = AtomicStoreLoader::create(dir.path(), file_pattern)
+            .expect("Could not create an atomic store");
+        let mut log_a =
+            crate::AppendLog::create(&mut loader, BincodeLoadStore::<u64>::default(), "a", 1024)
+                .expect("Could not create appendlog");
+        let mut log_b =
+            crate::AppendLog::create(&mut loader, BincodeLoadStore::<u64>::default(), "b", 1024)
+                .expect("Could not create appendlog");
+        let mut store = AtomicStore::open(loader).expect("Could not open store");
+        log_a.store_resource(&1).expect("Could not store resource");
+        log_b.store_resource(&2).expect("Could not store resource");
+        log_a.commit_version().expect("Could not commit log");
+        log_b.commit_version().expect("Could not commit log");
+        store.commit_version().expect("Could not commit store");
+    }
+
+    // Reopen with only one of the logs and commit a few versions.
+    {
+        let mut loader = AtomicStoreLoader::load(dir.path(), file_pattern)
+            .expect("Could not load an atomic store");
+        let mut log_a =
+            crate::AppendLog::load(&mut loader, BincodeLoadStore::<u64>::default(), "a", 1024)
+                .expect("Could not load appendlog");
+        let mut store = AtomicStore::open(loader).expect("Could not open store");
+        for i in 0..3 {
+            log_a.store_resource(&i).expect("Could not store resource");
+            log_a.commit_version().expect("Could not commit log");
+            store.commit_version().expect("Could not commit store");
+        }
+    }
+
+    // The unclaimed log must still be recoverable.
+    {
+        let mut loader = AtomicStoreLoader::load(dir.path(), file_pattern)
+            .expect("Could not load an atomic store");
+        loader.set_unclaimed_resource_policy(UnclaimedResourcePolicy::Fail);
+        let log_b =
+            crate::AppendLog::load(&mut loader, BincodeLoadStore::<u64>::default(), "b", 1024)
+                .expect("Could not load appendlog");
+        assert_eq!(log_b.load_latest().expect("Could not load resource"), 2);
+
+        // `a` is unclaimed in this session, so the store must refuse to open.
+        match AtomicStore::open(loader) {
+            Err(PersistenceError::UnclaimedResources { keys }) => {
+                assert_eq!(keys, vec!["a".to_string(), "a_index".to_string()]);
+            }
+            _ => panic!("Atomic store should have refused unclaimed resources"),
+        }
+    }
+}
+
+#[test]
+fn test_resource_descriptor_mismatch() {
+    use crate::load_store::{BincodeLoadStore, LoadStore};
+
+    // Serializes a `u64` in a format with a declared identifier.
+    struct FormatLoadStore(&'static str);
+    impl LoadStore for FormatLoadStore {
+        type ParamType = u64;
+        fn load(&self, stream: &[u8]) -> Result<u64> {
+            BincodeLoadStore::<u64>::default().load(stream)
+        }
+        fn store(&mut self, param: &u64) -> Result<Vec<u8>> {
+            BincodeLoadStore::<u64>::default().store(param)
+        }
+        fn format_id(&self) -> Option<String> {
+            Some(self.0.to_string())
+        }
+    }
+
+    let dir = tempfile::tempdir().expect("Could not create tempdir");
+    let file_pattern = "test_resource_descriptor_mismatch";
+    {
+        let mut loader = AtomicStoreLoader::create(dir.path(), file_pattern)
+            .expect("Could not create an atomic store");
+        let mut log = crate::RollingLog::create(&mut loader, FormatLoadStore("u64 v1"), "r", 1024)
+            .expect("Could not create rollinglog");
+        let mut store = AtomicStore::open(loader).expect("Could not open store");
+        log.store_resource(&1).expect("Could not store resource");
+        log.commit_version().expect("Could not commit log");
+        store.commit_version().expect("Could not commit store");
+    }
+
+    let mut loader =
+        AtomicStoreLoader::load(dir.path(), file_pattern).expect("Could not load an atomic store");
+    // Wrong log type.
+    match crate::AppendLog::load(&mut loader, BincodeLoadStore::<u64>::default(), "r", 1024) {
+        Err(PersistenceError::ResourceFormatInconsistent { key }) => assert_eq!(key, "r"),
+        _ => panic!("Loading with the wrong log type should fail"),
+    }
+    // Wrong format.
+    match crate::RollingLog::load(&mut loader, FormatLoadStore("u64 v2"), "r", 1024) {
+        Err(PersistenceError::ResourceFormatInconsistent { key }) => assert_eq!(key, "r"),
+        _ => panic!("Loading with the wrong format should fail"),
+    }
+    // Wrong fill size.
+    match crate::RollingLog::load(&mut loader, FormatLoadStore("u64 v1"), "r", 512) {
+        Err(PersistenceError::ResourceFormatInconsistent { key }) => assert_eq!(key, "r"),
+        _ => panic!("Loading with the wrong file fill size should fail"),
+    }
+    let log = crate::RollingLog::load(&mut loader, FormatLoadStore("u64 v1"), "r", 1024)
+        .expect("Could not load rollinglog");
+    assert_eq!(log.load_latest().expect("Could not load resource"), 1);
+    drop(loader);
+
+    // An adaptor without a declared format is not checked, as the type name recorded for it can
+    // change between compiler versions.
+    let mut loader =
+        AtomicStoreLoader::load(dir.path(), file_pattern).expect("Could not load an atomic store");
+    let log = crate::RollingLog::load(&mut loader, BincodeLoadStore::<u64>::default(), "r", 1024)
+        .expect("Could not load rollinglog without a declared format");
+    assert_eq!(log.load_latest().expect("Could not load resource"), 1);
+}
+
+#[test]
+fn test_legacy_table_of_contents_migration() {
+    use crate::load_store::BincodeLoadStore;
+
+    let dir = tempfile::tempdir().expect("Could not create tempdir");
+    let file_pattern = "test_legacy_table_of_contents_migration";
+    let location = {
+        let mut loader = AtomicStoreLoader::create(dir.path(), file_pattern)
+            .expect("Could not create an atomic store");
+        let mut log =
+            crate::RollingLog::create(&mut loader, BincodeLoadStore::<u64>::default(), "r", 1024)
+                .expect("Could not create rollinglog");
+        let _store = AtomicStore::open(loader).expect("Could not open store");
+        let location = log.store_resource(&7).expect("Could not store resource");
+        log.commit_version().expect("Could not commit log");
+        location
+    };
+
+    // Write the table of contents in the unversioned format.
+    #[derive(Serialize)]
+    struct Legacy {
+        file_counter: u32,
+        resource_files: HashMap<String, StorageLocation>,
+    }
+    let legacy = Legacy {
+        file_counter: 0,
+        resource_files: [("r".to_string(), location)].into_iter().collect(),
+    };
+    std::fs::write(
+        format_latest_file_path(dir.path(), file_pattern),
+        bincode::serialize(&legacy).unwrap(),
+    )
+    .expect("Could not write legacy table of contents");
+
+    // The migrated entry has no descriptor yet, so it loads and records one on commit.
+    {
+        let mut loader = AtomicStoreLoader::load(dir.path(), file_pattern)
+            .expect("Could not load an atomic store");
+        let mut log =
+            crate::RollingLog::load(&mut loader, BincodeLoadStore::<u64>::default(), "r", 1024)
+                .expect("Could not load rollinglog");
+        let mut store = AtomicStore::open(loader).expect("Could not open store");
+        assert_eq!(log.load_latest().expect("Could not load resource"), 7);
+        log.skip_version().expect("Could not skip log version");
+        store.commit_version().expect("Could not commit store");
+    }
+
+    let state = load_state(
+        &crate::storage_backend::FileSystemBackend,
+        &format_latest_file_path(dir.path(), file_pattern),
+    )
+    .expect("Could not read table of contents");
+    assert_eq!(state.file_counter, 1);
+    let entry = &state.resource_files["r"];
+    assert_eq!(entry.location, location);
+    assert!(matches!(
+        entry.descriptor.as_ref().map(|d| d.kind),
+        Some(crate::resource_descriptor::ResourceKind::RollingLog {
+            file_fill_size: 1024
+        })
+    ));
+}
+
+#[test]
+fn test_load_version() {
+    use crate::load_store::BincodeLoadStore;
+
+    let dir = tempfile::tempdir().expect("Could not create tempdir");
+    let file_pattern = "test_load_version";
+
+    {
+        let mut loader = AtomicStoreLoader::create(dir.path(), file_pattern)
+            .expect("Could not create an atomic store");
+        let mut log =
+            crate::AppendLog::create(&mut loader, BincodeLoadStore::<u64>::default(), "a", 16)
+                .expect("Could not create appendlog");
+        let mut store = AtomicStore::open(loader).expect("Could not open store");
+        for i in 0..5 {
+            log.store_resource(&i).expect("Could not store resource");
+            log.commit_version().expect("Could not commit log");
+            store.commit_version().expect("Could not commit store");
+        }
+    }
+    assert_eq!(
+        AtomicStoreLoader::list_versions(dir.path(), file_pattern).unwrap(),
+        vec![0, 1, 2, 3, 4]
+    );
+    assert!(matches!(
+        AtomicStoreLoader::load_version(dir.path(), file_pattern, 7),
+        Err(PersistenceError::VersionNotFound { version: 7 })
+    ));
+
+    // Roll back to version 2 and commit a new version on top of it.
+    {
+        let mut loader = AtomicStoreLoader::load_version(dir.path(), file_pattern, 2)
+            .expect("Could not load version");
+        let mut log =
+            crate::AppendLog::load(&mut loader, BincodeLoadStore::<u64>::default(), "a", 16)
+                .expect("Could not load appendlog");
+        let mut store = AtomicStore::open(loader).expect("Could not open store");
+        assert_eq!(log.load_latest().unwrap(), 2);
+        assert_eq!(
+            log.iter().collect::<Result<Vec<_>>>().unwrap(),
+            vec![0, 1, 2]
+        );
+        log.store_resource(&100).expect("Could not store resource");
+        log.commit_version().expect("Could not commit log");
+        store.commit_version().expect("Could not commit store");
+    }
+    assert_eq!(
+        AtomicStoreLoader::list_versions(dir.path(), file_pattern).unwrap(),
+        vec![0, 1, 2, 3]
+    );
+    let quarantined = |name: &str| {
+        dir.path()
+            .join(format!("{}_quarantined_{}", file_pattern, name))
+    };
+    for name in ["3", "4"] {
+        assert!(quarantined(name).exists());
+    }
+
+    {
+        let mut loader = AtomicStoreLoader::load(dir.path(), file_pattern)
+            .expect("Could not load an atomic store");
+        let log = crate::AppendLog::load(&mut loader, BincodeLoadStore::<u64>::default(), "a", 16)
+            .expect("Could not load appendlog");
+        assert_eq!(
+            log.iter().collect::<Result<Vec<_>>>().unwrap(),
+            vec![0, 1, 2, 100]
+        );
+    }
+
+    // Rolling back to version 2 again quarantines the new version 3 alongside the old one.
+    let old_version_3 = std::fs::read(quarantined("3")).unwrap();
+    {
+        let mut loader = AtomicStoreLoader::load_version(dir.path(), file_pattern, 2)
+            .expect("Could not load version");
+        let mut log =
+            crate::AppendLog::load(&mut loader, BincodeLoadStore::<u64>::default(), "a", 16)
+                .expect("Could not load appendlog");
+        let mut store = AtomicStore::open(loader).expect("Could not open store");
+        log.store_resource(&200).expect("Could not store resource");
+        log.commit_version().expect("Could not commit log");
+        store.commit_version().expect("Could not commit store");
+    }
+    assert_eq!(std::fs::read(quarantined("3")).unwrap(), old_version_3);
+    assert!(quarantined("3.1").exists());
+    assert!(quarantined("4").exists());
+}
+
+#[test]
+fn test_corrupted_table_of_contents_fallback() {
+    use crate::load_store::BincodeLoadStore;
+
+    let dir = tempfile::tempdir().expect("Could not create tempdir");
+    let file_pattern = "test_corrupted_table_of_contents_fallback";
+    {
+        let mut loader = AtomicStoreLoader::create(dir.path(), file_pattern)
+            .expect("Could not create an atomic store");
+        let mut log =
+            crate::AppendLog::create(&mut loader, BincodeLoadStore::<u64>::default(), "a", 1024)
+                .expect("Could not create appendlog");
+        let mut store = AtomicStore::open(loader).expect("Could not open store");
+        for i in 0..4 {
+            log.store_resource(&i).expect("Could not store resource");
+            log.commit_version().expect("Could not commit log");
+            store.commit_version().expect("Could not commit store");
+        }
+    }
+
+    // Flip a bit in the latest version and truncate the most recent archive.
+    let latest = format_latest_file_path(dir.path(), file_pattern);
+    let mut bytes = std::fs::read(&latest).unwrap();
+    let last = bytes.len() - 1;
+    bytes[last] ^= 0x10;
+    std::fs::write(&latest, bytes).unwrap();
+    let archive = format_archived_file_path(dir.path(), file_pattern, 2);
+    let bytes = std::fs::read(&archive).unwrap();
+    std::fs::write(&archive, &bytes[..bytes.len() / 2]).unwrap();
+
+    {
+        let mut loader = AtomicStoreLoader::load(dir.path(), file_pattern)
+            .expect("Could not load an atomic store");
+        let skipped = loader
+            .skipped_versions()
+            .iter()
+            .map(|skipped| skipped.path.clone())
+            .collect::<Vec<_>>();
+        assert_eq!(skipped, vec![latest.clone(), archive.clone()]);
+        let mut log =
+            crate::AppendLog::load(&mut loader, BincodeLoadStore::<u64>::default(), "a", 1024)
+                .expect("Could not load appendlog");
         let mut store = AtomicStore::open(loader).expect("Could not open store");
+        assert_eq!(log.load_latest().unwrap(), 1);
+
+        log.store_resource(&10).expect("Could not store resource");
+        log.commit_version().expect("Could not commit log");
+        store.commit_version().expect("Could not commit store");
+    }
+
+    // The corrupted files are out of the way and the new version builds on the recovered one.
+    let mut loader =
+        AtomicStoreLoader::load(dir.path(), file_pattern).expect("Could not load an atomic store");
+    assert!(loader.skipped_versions().is_empty());
+    assert_eq!(
+        AtomicStoreLoader::list_versions(dir.path(), file_pattern).unwrap(),
+        vec![0, 1, 2]
+    );
+    let log = crate::AppendLog::load(&mut loader, BincodeLoadStore::<u64>::default(), "a", 1024)
+        .expect("Could not load appendlog");
+    assert_eq!(
+        log.iter().collect::<Result<Vec<_>>>().unwrap(),
+        vec![0, 1, 10]
+    );
+}
+
+#[test]
+fn test_store_lock() {
+    let dir = tempfile::tempdir().expect("Could not create tempdir");
+    let file_pattern = "test_store_lock";
+
+    let loader = AtomicStoreLoader::create(dir.path(), file_pattern)
+        .expect("Could not create an atomic store");
+    match AtomicStoreLoader::load(dir.path(), file_pattern) {
+        Err(PersistenceError::StoreLocked { pid, .. }) => {
+            assert_eq!(pid, Some(std::process::id()))
+        }
+        other => panic!("expected StoreLocked, got {:?}", other.map(|_| ())),
+    }
+    // A store with a different pattern in the same directory is independent.
+    AtomicStoreLoader::load(dir.path(), "test_store_lock_other")
+        .expect("Could not load an unrelated store");
+
+    // The store keeps holding the lock taken by its loader.
+    let store = AtomicStore::open(loader).expect("Could not open store");
+    assert!(matches!(
+        AtomicStoreLoader::create(dir.path(), file_pattern),
+        Err(PersistenceError::StoreLocked { .. })
+    ));
+    drop(store);
 
-        for _ in 0..5 {
-            store.commit_version().expect("Could not commit store");
+    AtomicStoreLoader::load(dir.path(), file_pattern).expect("Lock was not released");
+}
+
+// Create a store with `file_pattern` in `path`, and commit `value` to a log of its own.
+#[cfg(test)]
+fn create_test_store(config: AtomicStoreConfig, path: &Path, file_pattern: &str, value: u64) {
+    use crate::load_store::BincodeLoadStore;
+
+    let mut loader = AtomicStoreLoader::create_with_config(path, file_pattern, config).unwrap();
+    let key = format!("{}_log", file_pattern);
+    let mut log =
+        crate::AppendLog::create(&mut loader, BincodeLoadStore::<u64>::default(), &key, 1024)
+            .unwrap();
+    let mut store = AtomicStore::open(loader).unwrap();
+    log.store_resource(&value).unwrap();
+    log.commit_version().unwrap();
+    store.commit_version().unwrap();
+}
+
+// Load the log of a store made by `create_test_store`.
+#[cfg(test)]
+fn load_test_store(backend: &Arc<dyn StorageBackend>, path: &Path, file_pattern: &str) -> Vec<u64> {
+    use crate::load_store::BincodeLoadStore;
+
+    let mut loader = AtomicStoreLoader::load_with_config(
+        path,
+        file_pattern,
+        AtomicStoreConfig::default().backend(backend.clone()),
+    )
+    .unwrap();
+    let key = format!("{}_log", file_pattern);
+    let log = crate::AppendLog::load(&mut loader, BincodeLoadStore::<u64>::default(), &key, 1024)
+        .unwrap();
+    log.iter().collect::<Result<Vec<_>>>().unwrap()
+}
+
+#[test]
+fn test_existing_store_policy() {
+    use crate::memory_backend::MemoryBackend;
+
+    let backend: Arc<dyn StorageBackend> = Arc::new(MemoryBackend::new());
+    let path = Path::new("/store");
+    let config = || AtomicStoreConfig::default().backend(backend.clone());
+    create_test_store(config(), path, "a", 1);
+    create_test_store(config(), path, "b", 2);
+
+    assert!(matches!(
+        AtomicStoreLoader::create_with_config(
+            path,
+            "a",
+            config().existing_store(ExistingStorePolicy::Fail)
+        ),
+        Err(PersistenceError::StoreAlreadyExists { .. })
+    ));
+    assert_eq!(load_test_store(&backend, path, "a"), vec![1]);
+
+    // Wiping one store leaves the other alone.
+    create_test_store(
+        config().existing_store(ExistingStorePolicy::Wipe),
+        path,
+        "a",
+        3,
+    );
+    assert_eq!(load_test_store(&backend, path, "a"), vec![3]);
+    assert_eq!(load_test_store(&backend, path, "b"), vec![2]);
+
+    // Backing up one store moves only its files aside, even while the other is open.
+    let other = AtomicStoreLoader::load_with_config(
+        path,
+        "a",
+        AtomicStoreConfig::default().backend(backend.clone()),
+    )
+    .unwrap();
+    let backups = Path::new("/backups");
+    create_test_store(config().backup_dir(backups), path, "b", 4);
+    assert_eq!(load_test_store(&backend, path, "b"), vec![4]);
+    assert!(matches!(
+        AtomicStoreLoader::load_with_config(
+            path,
+            "a",
+            AtomicStoreConfig::default().backend(backend.clone())
+        ),
+        Err(PersistenceError::StoreLocked { .. })
+    ));
+    drop(other);
+    assert_eq!(load_test_store(&backend, path, "a"), vec![3]);
+    let backup = backups.join(&backend.list(backups).unwrap()[0]);
+    assert_eq!(load_test_store(&backend, &backup, "b"), vec![2]);
+    let mut names = backend.list(&backup).unwrap();
+    names.sort();
+    assert!(names.iter().all(|name| name.contains("b_")), "{:?}", names);
+}
+
+#[test]
+fn test_retain_backups() {
+    use crate::memory_backend::MemoryBackend;
+
+    let backend: Arc<dyn StorageBackend> = Arc::new(MemoryBackend::new());
+    // A store without a parent directory can still be backed up, within its own directory.
+    let path = Path::new("/");
+    let config = || {
+        AtomicStoreConfig::default()
+            .backend(backend.clone())
+            .retain_backups(2)
+    };
+    for value in 0..4 {
+        create_test_store(config(), path, "store", value);
+    }
+    let list_backups = || {
+        let mut backups = backend
+            .list(path)
+            .unwrap()
+            .into_iter()
+            .filter(|name| name.starts_with("backup."))
+            .collect::<Vec<_>>();
+        backups.sort();
+        backups
+    };
+    let backups = list_backups();
+    assert_eq!(backups.len(), 2);
+    // The newest backups are retained, and are not nested in each other.
+    assert_eq!(
+        load_test_store(&backend, &path.join(&backups[1]), "store"),
+        vec![2]
+    );
+    assert_eq!(
+        load_test_store(&backend, &path.join(&backups[0]), "store"),
+        vec![1]
+    );
+    assert_eq!(load_test_store(&backend, path, "store"), vec![3]);
+
+    // By default, the number of backups is bounded too.
+    for value in 4..10 {
+        let config = AtomicStoreConfig::default().backend(backend.clone());
+        create_test_store(config, path, "store", value);
+    }
+    assert_eq!(list_backups().len(), 3);
+}
+
+#[test]
+fn test_read_only_store() {
+    use crate::load_store::BincodeLoadStore;
+
+    let dir = tempfile::tempdir().expect("Could not create tempdir");
+    let file_pattern = "test_read_only_store";
+    let list_dir = || {
+        let mut entries = std::fs::read_dir(dir.path())
+            .unwrap()
+            .map(|entry| {
+                let entry = entry.unwrap();
+                (entry.file_name(), entry.metadata().unwrap().len())
+            })
+            .collect::<Vec<_>>();
+        entries.sort();
+        entries
+    };
+
+    let mut loader = AtomicStoreLoader::create(dir.path(), file_pattern)
+        .expect("Could not create an atomic store");
+    let mut append =
+        crate::AppendLog::create(&mut loader, BincodeLoadStore::<u64>::default(), "a", 16)
+            .expect("Could not create appendlog");
+    let mut fixed =
+        crate::FixedAppendLog::create(&mut loader, BincodeLoadStore::<u64>::default(), "f", 8, 4)
+            .expect("Could not create fixedappendlog");
+    let mut rolling =
+        crate::RollingLog::create(&mut loader, BincodeLoadStore::<u64>::default(), "r", 16)
+            .expect("Could not create rollinglog");
+    let mut store = AtomicStore::open(loader).expect("Could not open store");
+    let mut commit = |range: std::ops::Range<u64>| {
+        for i in range {
+            append.store_resource(&i).unwrap();
+            fixed.store_resource(&i).unwrap();
+            rolling.store_resource(&i).unwrap();
         }
-        // There should be one archive for each committed version except the latest.
-        assert_eq!(list_archives(), vec![0, 1, 2, 3]);
+        append.commit_version().unwrap();
+        fixed.commit_version().unwrap();
+        rolling.commit_version().unwrap();
+        store.commit_version().unwrap();
+    };
+    commit(0..3);
+
+    // Readers can open the store while the writer holds its lock, and never touch its files.
+    let before = list_dir();
+    let reader = AtomicStoreLoader::open_read_only(dir.path(), file_pattern)
+        .expect("Could not open store read-only");
+    let mut read_append =
+        crate::AppendLog::open_read_only(&reader, BincodeLoadStore::<u64>::default(), "a", 16)
+            .unwrap();
+    let mut read_fixed = crate::FixedAppendLog::open_read_only(
+        &reader,
+        BincodeLoadStore::<u64>::default(),
+        "f",
+        8,
+        4,
+    )
+    .unwrap();
+    let mut read_rolling =
+        crate::RollingLog::open_read_only(&reader, BincodeLoadStore::<u64>::default(), "r", 16)
+            .unwrap();
+    assert_eq!(read_append.load_latest().unwrap(), 2);
+    assert_eq!(
+        read_fixed.iter().collect::<Result<Vec<_>>>().unwrap(),
+        vec![0, 1, 2]
+    );
+    assert_eq!(read_rolling.load_latest().unwrap(), 2);
+    assert!(!read_append.refresh().unwrap());
+    assert_eq!(list_dir(), before);
+
+    // Newer versions are only visible after a refresh.
+    commit(3..10);
+    assert_eq!(
+        read_append.iter().collect::<Result<Vec<_>>>().unwrap(),
+        vec![0, 1, 2]
+    );
+    assert!(read_append.refresh().unwrap());
+    assert!(read_fixed.refresh().unwrap());
+    assert!(read_rolling.refresh().unwrap());
+    assert_eq!(
+        read_append.iter().collect::<Result<Vec<_>>>().unwrap(),
+        (0..10).collect::<Vec<_>>()
+    );
+    assert_eq!(read_fixed.load_at(7).unwrap(), 7);
+    assert_eq!(read_fixed.load_latest().unwrap(), 9);
+    assert_eq!(read_rolling.load_latest().unwrap(), 9);
+    assert_eq!(read_append.version(), read_fixed.version());
+}
+
+#[test]
+fn test_checkpoint() {
+    use crate::load_store::BincodeLoadStore;
+
+    let dir = tempfile::tempdir().expect("Could not create tempdir");
+    let dest = tempfile::tempdir().expect("Could not create tempdir");
+    let file_pattern = "test_checkpoint";
+
+    let mut loader = AtomicStoreLoader::create(dir.path(), file_pattern)
+        .expect("Could not create an atomic store");
+    let mut append =
+        crate::AppendLog::create(&mut loader, BincodeLoadStore::<u64>::default(), "a", 32)
+            .expect("Could not create appendlog");
+    let mut fixed =
+        crate::FixedAppendLog::create(&mut loader, BincodeLoadStore::<u64>::default(), "f", 8, 4)
+            .expect("Could not create fixedappendlog");
+    let mut rolling =
+        crate::RollingLog::create(&mut loader, BincodeLoadStore::<u64>::default(), "r", 32)
+            .expect("Could not create rollinglog");
+    let mut store = AtomicStore::open(loader).expect("Could not open store");
+    for i in 0..10 {
+        append.store_resource(&i).unwrap();
+        fixed.store_resource(&i).unwrap();
+        rolling.store_resource(&i).unwrap();
+        append.commit_version().unwrap();
+        fixed.commit_version().unwrap();
+        rolling.commit_version().unwrap();
+        store.commit_version().unwrap();
     }
+    // Committed by the logs, but not by the store.
+    for i in 10..15 {
+        append.store_resource(&i).unwrap();
+        fixed.store_resource(&i).unwrap();
+        rolling.store_resource(&i).unwrap();
+    }
+    append.commit_version().unwrap();
+    fixed.commit_version().unwrap();
+    rolling.commit_version().unwrap();
 
-    // Now reopen the store with pruning turned on; ensure old versions get pruned immediately.
+    store.checkpoint(dest.path()).expect("Could not checkpoint");
+    assert!(matches!(
+        store.checkpoint(dest.path()),
+        Err(PersistenceError::StoreAlreadyExists { .. })
+    ));
+    store.commit_version().unwrap();
+    drop(store);
+
+    let mut loader =
+        AtomicStoreLoader::load(dest.path(), file_pattern).expect("Could not load checkpoint");
+    let append = crate::AppendLog::load(&mut loader, BincodeLoadStore::<u64>::default(), "a", 32)
+        .expect("Could not load appendlog");
+    let fixed =
+        crate::FixedAppendLog::load(&mut loader, BincodeLoadStore::<u64>::default(), "f", 8, 4)
+            .expect("Could not load fixedappendlog");
+    let rolling = crate::RollingLog::load(&mut loader, BincodeLoadStore::<u64>::default(), "r", 32)
+        .expect("Could not load rollinglog");
+    loader.set_unclaimed_resource_policy(UnclaimedResourcePolicy::Fail);
+    let _store = AtomicStore::open(loader).expect("Could not open checkpoint");
+    let expected = (0..10).collect::<Vec<_>>();
+    assert_eq!(append.iter().collect::<Result<Vec<_>>>().unwrap(), expected);
+    assert_eq!(fixed.iter().collect::<Result<Vec<_>>>().unwrap(), expected);
+    assert_eq!(rolling.load_latest().unwrap(), 9);
+}
+
+#[test]
+fn test_checkpoint_without_descriptor() {
+    use crate::load_store::BincodeLoadStore;
+    use crate::memory_backend::MemoryBackend;
+
+    let backend = Arc::new(MemoryBackend::new());
+    let path = Path::new("/store");
+    let dest = Path::new("/checkpoint");
+    let file_pattern = "test_checkpoint_without_descriptor";
+    let adaptor = BincodeLoadStore::<u64>::default;
     {
-        let mut loader = AtomicStoreLoader::load(dir.path(), file_pattern)
-            .expect("Could not create an atomic store");
-        loader.retain_archives(2);
-        let mut store = AtomicStore::open(loader).expect("Could not open store");
+        let mut loader = AtomicStoreLoader::load_with_config(
+            path,
+            file_pattern,
+            AtomicStoreConfig::default().backend(backend.clone()),
+        )
+        .unwrap();
+        let mut a = crate::AppendLog::load(&mut loader, adaptor(), "a", 16).unwrap();
+        let mut a_1 = crate::AppendLog::load(&mut loader, adaptor(), "a_1", 16).unwrap();
+        let mut store = AtomicStore::open(loader).unwrap();
+        for i in 0..5u64 {
+            a.store_resource(&i).unwrap();
+            a_1.store_resource(&i).unwrap();
+            a.commit_version().unwrap();
+            a_1.commit_version().unwrap();
+            store.commit_version().unwrap();
+        }
+    }
+    // As if `a` had not been loaded since it was migrated from the unversioned format.
+    let latest_file_path = format_latest_file_path(path, file_pattern);
+    let mut state = load_state(backend.as_ref(), &latest_file_path).unwrap();
+    state.resource_files.get_mut("a").unwrap().descriptor = None;
+    write_state(backend.as_ref(), &latest_file_path, &state).unwrap();
+    for name in ["a_1.bak.1", "a_1_1.bak.1"] {
+        backend.open(&path.join(name), OpenMode::Create).unwrap();
+    }
 
-        assert_eq!(list_archives(), vec![2, 3]);
+    let mut loader = AtomicStoreLoader::load_with_config(
+        path,
+        file_pattern,
+        AtomicStoreConfig::default().backend(backend.clone()),
+    )
+    .unwrap();
+    let _a_1 = crate::AppendLog::load(&mut loader, adaptor(), "a_1", 16).unwrap();
+    let store = AtomicStore::open(loader).unwrap();
+    // Without a descriptor, the files of `a` cannot be checked.
+    assert_eq!(
+        store
+            .verify()
+            .unwrap()
+            .into_iter()
+            .map(|issue| (issue.key, issue.kind))
+            .collect::<Vec<_>>(),
+        vec![("a".to_string(), InconsistencyKind::Unchecked)]
+    );
+    store.checkpoint(dest).unwrap();
+    drop(store);
 
-        // Commit more versions, check that we always retain the two latest archives.
-        for i in 5..10 {
-            store.commit_version().expect("Could not commit store");
-            assert_eq!(list_archives(), vec![i - 2, i - 1])
+    // Backups are not copied, whichever key they belong to.
+    let mut names = backend.list(dest).unwrap();
+    names.sort();
+    assert!(
+        !names.iter().any(|name| name.contains(".bak.")),
+        "{:?}",
+        names
+    );
+
+    let mut loader = AtomicStoreLoader::load_with_config(
+        dest,
+        file_pattern,
+        AtomicStoreConfig::default().backend(backend.clone()),
+    )
+    .unwrap();
+    let a = crate::AppendLog::load(&mut loader, adaptor(), "a", 16).unwrap();
+    let a_1 = crate::AppendLog::load(&mut loader, adaptor(), "a_1", 16).unwrap();
+    loader.set_unclaimed_resource_policy(UnclaimedResourcePolicy::Fail);
+    let _store = AtomicStore::open(loader).unwrap();
+    let expected = (0..5).collect::<Vec<_>>();
+    assert_eq!(a.iter().collect::<Result<Vec<_>>>().unwrap(), expected);
+    assert_eq!(a_1.iter().collect::<Result<Vec<_>>>().unwrap(), expected);
+}
+
+#[test]
+fn test_subscribe() {
+    use crate::load_store::BincodeLoadStore;
+    use crate::memory_backend::MemoryBackend;
+
+    let backend = Arc::new(MemoryBackend::new());
+    let mut loader = AtomicStoreLoader::load_with_config(
+        Path::new("/store"),
+        "test_subscribe",
+        AtomicStoreConfig::default().backend(backend),
+    )
+    .unwrap();
+    let mut first =
+        crate::AppendLog::load(&mut loader, BincodeLoadStore::<u64>::default(), "a", 32).unwrap();
+    let mut second =
+        crate::RollingLog::load(&mut loader, BincodeLoadStore::<u64>::default(), "r", 32).unwrap();
+    let mut store = AtomicStore::open(loader).unwrap();
+    let receiver = store.subscribe();
+    let dropped = store.subscribe();
+    drop(dropped);
+
+    let first_location = first.store_resource(&1).unwrap();
+    let second_location = second.store_resource(&2).unwrap();
+    first.commit_version().unwrap();
+    second.commit_version().unwrap();
+    store.commit_version().unwrap();
+    let version = receiver.try_recv().unwrap();
+    assert_eq!(version.counter, 0);
+    // The append log's index is a resource of its own.
+    let mut keys = version.changed.keys().cloned().collect::<Vec<_>>();
+    keys.sort();
+    assert_eq!(keys, vec!["a", "a_index", "r"]);
+    assert_eq!(version.changed["a"], first_location);
+    assert_eq!(version.changed["r"], second_location);
+    assert!(version.timestamp > 0);
+    assert_eq!(store.subscribers.len(), 1);
+
+    // Only the resources whose location changed are reported.
+    let second_location = second.store_resource(&3).unwrap();
+    first.skip_version().unwrap();
+    second.commit_version().unwrap();
+    store.commit_version().unwrap();
+    let version = receiver.try_recv().unwrap();
+    assert_eq!(version.counter, 1);
+    assert_eq!(
+        version.changed,
+        HashMap::from([("r".to_string(), second_location)])
+    );
+    assert!(receiver.try_recv().is_err());
+}
+
+#[test]
+fn test_version_metadata() {
+    use crate::load_store::BincodeLoadStore;
+    use crate::memory_backend::MemoryBackend;
+
+    let backend = Arc::new(MemoryBackend::new());
+    let path = Path::new("/store");
+    let file_pattern = "test_version_metadata";
+    {
+        let mut loader = AtomicStoreLoader::load_with_config(
+            path,
+            file_pattern,
+            AtomicStoreConfig::default().backend(backend.clone()),
+        )
+        .unwrap();
+        assert!(loader.version_info().is_none());
+        let mut log =
+            crate::AppendLog::load(&mut loader, BincodeLoadStore::<u64>::default(), "a", 16)
+                .unwrap();
+        let mut store = AtomicStore::open(loader).unwrap();
+        let receiver = store.subscribe();
+        for height in 0..3u64 {
+            log.store_resource(&height).unwrap();
+            log.commit_version().unwrap();
+            store
+                .commit_version_with_metadata(&height.to_le_bytes())
+                .unwrap();
+            assert_eq!(receiver.try_recv().unwrap().metadata, height.to_le_bytes());
         }
+        log.skip_version().unwrap();
+        store.commit_version().unwrap();
     }
 
-    // Check that we can delete the latest version and reload from an archive, even though some
-    // older archives have been pruned.
+    let loader = AtomicStoreLoader::load_with_config(
+        path,
+        file_pattern,
+        AtomicStoreConfig::default().backend(backend.clone()),
+    )
+    .unwrap();
+    let info = loader.version_info().unwrap();
+    assert_eq!(info.counter, 3);
+    assert!(info.metadata.is_empty());
+    assert!(info.timestamp.is_some());
+    drop(loader);
+
+    for height in 0..3u64 {
+        let info = AtomicStoreLoader::read_version_info_with_config(
+            path,
+            file_pattern,
+            height as u32,
+            AtomicStoreConfig::default().backend(backend.clone()),
+        )
+        .unwrap();
+        assert_eq!(info.counter, height as u32);
+        assert_eq!(info.metadata, height.to_le_bytes());
+    }
+    let loader = AtomicStoreLoader::load_version_with_config(
+        path,
+        file_pattern,
+        1,
+        AtomicStoreConfig::default().backend(backend.clone()),
+    )
+    .unwrap();
+    assert_eq!(loader.version_info().unwrap().metadata, 1u64.to_le_bytes());
+    drop(loader);
+    assert!(matches!(
+        AtomicStoreLoader::read_version_info_with_config(
+            path,
+            file_pattern,
+            9,
+            AtomicStoreConfig::default().backend(backend.clone())
+        ),
+        Err(PersistenceError::VersionNotFound { version: 9 })
+    ));
+}
+
+#[test]
+fn test_find_version() {
+    use crate::load_store::BincodeLoadStore;
+    use crate::memory_backend::MemoryBackend;
+
+    let backend = Arc::new(MemoryBackend::new());
+    let path = Path::new("/store");
+    let file_pattern = "test_find_version";
+    let height_of = |info: &VersionInfo| u64::from_le_bytes(info.metadata[..].try_into().unwrap());
     {
-        let latest = format_latest_file_path(dir.path(), file_pattern);
-        assert!(latest.exists());
-        fs::remove_file(latest).expect("Could not delete latest version");
+        let mut loader = AtomicStoreLoader::load_with_config(
+            path,
+            file_pattern,
+            AtomicStoreConfig::default()
+                .backend(backend.clone())
+                .retain_archives(15),
+        )
+        .unwrap();
+        let mut log =
+            crate::AppendLog::load(&mut loader, BincodeLoadStore::<u64>::default(), "a", 64)
+                .unwrap();
+        let mut store = AtomicStore::open(loader).unwrap();
+        for i in 0..20u64 {
+            log.store_resource(&i).unwrap();
+            log.commit_version().unwrap();
+            store
+                .commit_version_with_metadata(&(i * 10).to_le_bytes())
+                .unwrap();
+        }
+    }
+    let find = |height: u64| {
+        AtomicStoreLoader::find_version_with_config(
+            path,
+            file_pattern,
+            |info| height_of(info) <= height,
+            AtomicStoreConfig::default().backend(backend.clone()),
+        )
+        .unwrap()
+        .map(|info| info.counter)
+    };
+    // Versions 0 to 3 have been pruned.
+    assert_eq!(find(0), None);
+    assert_eq!(find(39), None);
+    assert_eq!(find(40), Some(4));
+    assert_eq!(find(55), Some(5));
+    assert_eq!(find(190), Some(19));
+    assert_eq!(find(u64::MAX), Some(19));
 
-        let loader = AtomicStoreLoader::load(dir.path(), file_pattern)
-            .expect("Could not create an atomic store");
-        let store = AtomicStore::open(loader).expect("Could not open store");
-        assert_eq!(store.file_counter, 9);
+    let find_at_time = |timestamp| {
+        AtomicStoreLoader::find_version_at_time_with_config(
+            path,
+            file_pattern,
+            timestamp,
+            AtomicStoreConfig::default().backend(backend.clone()),
+        )
+        .unwrap()
+        .map(|info| info.counter)
+    };
+    assert_eq!(find_at_time(0), None);
+    assert_eq!(find_at_time(unix_timestamp() + 1), Some(19));
+
+    // Give each version its own commit time, to search between commits.
+    let set_timestamp = |counter: u32, timestamp: i64| {
+        for toc_path in [
+            format_archived_file_path(path, file_pattern, counter),
+            format_latest_file_path(path, file_pattern),
+        ] {
+            let Ok(mut state) = load_state(backend.as_ref(), &toc_path) else {
+                continue;
+            };
+            if state.file_counter == counter {
+                state.timestamp = Some(timestamp);
+                let mut file = backend.open(&toc_path, OpenMode::Create).unwrap();
+                file.write_all(&encode_state(&state).unwrap()).unwrap();
+            }
+        }
+    };
+    for counter in 4..20 {
+        set_timestamp(counter, 1000 + 10 * counter as i64);
+    }
+    assert_eq!(find_at_time(1039), None);
+    assert_eq!(find_at_time(1050), Some(5));
+    assert_eq!(find_at_time(1055), Some(5));
+    assert_eq!(find_at_time(1189), Some(18));
+
+    // The clock was set back before version 15 was committed.
+    set_timestamp(15, 1000);
+    assert_eq!(find_at_time(1020), Some(15));
+    assert_eq!(find_at_time(1145), Some(15));
+    assert_eq!(find_at_time(1185), Some(18));
+
+    // Unreadable archives are skipped.
+    backend
+        .open(
+            &format_archived_file_path(path, file_pattern, 18),
+            OpenMode::Create,
+        )
+        .unwrap()
+        .write_all(b"garbage")
+        .unwrap();
+    assert_eq!(find_at_time(1185), Some(17));
+    assert_eq!(find(185), Some(17));
+    assert_eq!(find(u64::MAX), Some(19));
+
+    // The version found can be loaded directly.
+    let mut loader = AtomicStoreLoader::load_version_with_config(
+        path,
+        file_pattern,
+        5,
+        AtomicStoreConfig::default().backend(backend.clone()),
+    )
+    .unwrap();
+    let log =
+        crate::AppendLog::load(&mut loader, BincodeLoadStore::<u64>::default(), "a", 64).unwrap();
+    assert_eq!(log.load_latest().unwrap(), 5);
+}
+
+#[test]
+fn test_remove_resource() {
+    use crate::load_store::BincodeLoadStore;
+    use crate::memory_backend::MemoryBackend;
+
+    let backend = Arc::new(MemoryBackend::new());
+    let path = Path::new("/store");
+    let file_pattern = "test_remove_resource";
+    let load = || {
+        let config = AtomicStoreConfig::default()
+            .backend(backend.clone())
+            .retain_archives(1);
+        AtomicStoreLoader::load_with_config(path, file_pattern, config).unwrap()
+    };
+    let files = ResourceFiles::new([
+        (
+            "a".to_string(),
+            Some(ResourceKind::AppendLog { file_fill_size: 16 }),
+        ),
+        (
+            "a_index".to_string(),
+            Some(ResourceKind::FixedAppendLog {
+                resource_size: 4,
+                file_size: 4096,
+            }),
+        ),
+        (
+            "f".to_string(),
+            Some(ResourceKind::FixedAppendLog {
+                resource_size: 8,
+                file_size: 2,
+            }),
+        ),
+        (
+            "r".to_string(),
+            Some(ResourceKind::RollingLog { file_fill_size: 16 }),
+        ),
+    ]);
+    let files_of = |key: &str| {
+        backend
+            .list(path)
+            .unwrap()
+            .into_iter()
+            .filter(|name| files.owner(name) == Some(key))
+            .count()
+    };
+    {
+        let mut loader = load();
+        let mut append =
+            crate::AppendLog::load(&mut loader, BincodeLoadStore::<u64>::default(), "a", 16)
+                .unwrap();
+        let mut fixed =
+            crate::FixedAppendLog::load(&mut loader, BincodeLoadStore::<u64>::default(), "f", 8, 2)
+                .unwrap();
+        let mut rolling =
+            crate::RollingLog::load(&mut loader, BincodeLoadStore::<u64>::default(), "r", 16)
+                .unwrap();
+        let mut store = AtomicStore::open(loader).unwrap();
+        for i in 0..5u64 {
+            append.store_resource(&i).unwrap();
+            fixed.store_resource(&i).unwrap();
+            rolling.store_resource(&i).unwrap();
+            append.commit_version().unwrap();
+            fixed.commit_version().unwrap();
+            rolling.commit_version().unwrap();
+            store.commit_version().unwrap();
+        }
+    }
+    assert!(files_of("a") > 0 && files_of("a_index") > 0 && files_of("f") > 0);
+
+    let mut loader = load();
+    let mut rolling =
+        crate::RollingLog::load(&mut loader, BincodeLoadStore::<u64>::default(), "r", 16).unwrap();
+    assert!(matches!(
+        loader.remove_resource("r"),
+        Err(PersistenceError::ResourceInUse { .. })
+    ));
+    assert!(matches!(
+        loader.remove_resource("missing"),
+        Err(PersistenceError::FailedToFindExpectedResource { .. })
+    ));
+    loader.remove_resource("a").unwrap();
+    loader.remove_resource("f").unwrap();
+    assert!(matches!(
+        crate::AppendLog::load(&mut loader, BincodeLoadStore::<u64>::default(), "a", 16),
+        Err(PersistenceError::ResourceRetired { .. })
+    ));
+    loader.set_unclaimed_resource_policy(UnclaimedResourcePolicy::Fail);
+    let mut store = AtomicStore::open(loader).unwrap();
+    rolling.skip_version().unwrap();
+    store.commit_version().unwrap();
+
+    // The retained archive still refers to the removed resources.
+    let state = load_state(
+        backend.as_ref(),
+        &format_latest_file_path(path, file_pattern),
+    )
+    .unwrap();
+    let mut keys = state.resource_files.keys().cloned().collect::<Vec<_>>();
+    keys.sort();
+    assert_eq!(keys, vec!["r"]);
+    assert_eq!(
+        state
+            .retired_resources
+            .iter()
+            .map(|(key, retired)| (key.clone(), retired.first_version_without))
+            .collect::<HashMap<_, _>>(),
+        HashMap::from([
+            ("a".to_string(), 5),
+            ("a_index".to_string(), 5),
+            ("f".to_string(), 5)
+        ])
+    );
+    assert!(state
+        .retired_resources
+        .values()
+        .all(|retired| retired.kind.is_some()));
+    assert!(files_of("a") > 0 && files_of("a_index") > 0 && files_of("f") > 0);
+
+    // Once it is pruned, the files are deleted, and the next version forgets the keys.
+    rolling.skip_version().unwrap();
+    store.commit_version().unwrap();
+    assert_eq!(files_of("a") + files_of("a_index") + files_of("f"), 0);
+    assert!(files_of("r") > 0);
+    rolling.skip_version().unwrap();
+    store.commit_version().unwrap();
+    let state = load_state(
+        backend.as_ref(),
+        &format_latest_file_path(path, file_pattern),
+    )
+    .unwrap();
+    assert!(state.retired_resources.is_empty());
+    drop(store);
+
+    // The keys can now be reused.
+    let mut loader = load();
+    let append =
+        crate::AppendLog::load(&mut loader, BincodeLoadStore::<u64>::default(), "a", 16).unwrap();
+    assert!(append.load_latest().is_err());
+}
+
+#[test]
+fn test_remove_resource_with_overlapping_names() {
+    use crate::load_store::BincodeLoadStore;
+    use crate::memory_backend::MemoryBackend;
+
+    let backend = Arc::new(MemoryBackend::new());
+    let path = Path::new("/store");
+    let file_pattern = "test_remove_overlapping";
+    let load = || {
+        let config = AtomicStoreConfig::default()
+            .backend(backend.clone())
+            .retain_archives(1);
+        AtomicStoreLoader::load_with_config(path, file_pattern, config).unwrap()
+    };
+    let adaptor = BincodeLoadStore::<u64>::default;
+    {
+        let mut loader = load();
+        let mut a = crate::AppendLog::load(&mut loader, adaptor(), "a", 16).unwrap();
+        let mut a_1 = crate::AppendLog::load(&mut loader, adaptor(), "a_1", 16).unwrap();
+        let mut f = crate::FixedAppendLog::load(&mut loader, adaptor(), "f", 8, 1).unwrap();
+        let mut f_1 = crate::AppendLog::load(&mut loader, adaptor(), "f_1", 16).unwrap();
+        let mut store = AtomicStore::open(loader).unwrap();
+        for i in 0..5u64 {
+            a.store_resource(&i).unwrap();
+            a_1.store_resource(&i).unwrap();
+            f.store_resource(&i).unwrap();
+            f_1.store_resource(&i).unwrap();
+            a.commit_version().unwrap();
+            a_1.commit_version().unwrap();
+            f.commit_version().unwrap();
+            f_1.commit_version().unwrap();
+            store.commit_version().unwrap();
+        }
+    }
+    // Backups of `a_1` and `f_1`, whose names could also be those of backups of `a` and `f`.
+    for name in ["a_1_3.bak.1", "f_1_2.bak.1"] {
+        backend.open(&path.join(name), OpenMode::Create).unwrap();
+    }
+    let files_before = backend.list(path).unwrap();
+
+    let mut loader = load();
+    let mut a_1 = crate::AppendLog::load(&mut loader, adaptor(), "a_1", 16).unwrap();
+    let mut f_1 = crate::AppendLog::load(&mut loader, adaptor(), "f_1", 16).unwrap();
+    loader.remove_resource("a").unwrap();
+    loader.remove_resource("f").unwrap();
+    let mut store = AtomicStore::open(loader).unwrap();
+    for _ in 0..2 {
+        a_1.skip_version().unwrap();
+        f_1.skip_version().unwrap();
+        store.commit_version().unwrap();
+    }
+
+    // Only the files of `a`, its index and `f` are deleted.
+    let files = ResourceFiles::new(
+        ["a", "a_1", "f_1"]
+            .into_iter()
+            .map(|key| {
+                (
+                    key.to_string(),
+                    Some(ResourceKind::AppendLog { file_fill_size: 16 }),
+                )
+            })
+            .chain(
+                ["a_index", "a_1_index", "f_1_index", "f"]
+                    .into_iter()
+                    .map(|key| {
+                        let kind = ResourceKind::FixedAppendLog {
+                            resource_size: 8,
+                            file_size: 1,
+                        };
+                        (key.to_string(), Some(kind))
+                    }),
+            ),
+    );
+    let files_after = backend.list(path).unwrap();
+    for name in files_before {
+        let Some(owner) = files.owner(&name) else {
+            continue;
+        };
+        let deleted = matches!(owner, "a" | "a_index" | "f");
+        assert_eq!(!files_after.contains(&name), deleted, "{}", name);
+    }
+    assert!(files_after.contains(&"a_1_3.bak.1".to_string()));
+    assert!(files_after.contains(&"f_1_2.bak.1".to_string()));
+}
+
+#[test]
+fn test_rename_resource() {
+    use crate::load_store::BincodeLoadStore;
+    use crate::memory_backend::MemoryBackend;
+
+    let backend = Arc::new(MemoryBackend::new());
+    let path = Path::new("/store");
+    let file_pattern = "test_rename_resource";
+    let load = || {
+        AtomicStoreLoader::load_with_config(
+            path,
+            file_pattern,
+            AtomicStoreConfig::default().backend(backend.clone()),
+        )
+        .unwrap()
+    };
+    let adaptor = BincodeLoadStore::<u64>::default;
+    {
+        let mut loader = load();
+        let mut append = crate::AppendLog::load(&mut loader, adaptor(), "a", 16).unwrap();
+        let mut fixed = crate::FixedAppendLog::load(&mut loader, adaptor(), "f", 8, 2).unwrap();
+        let mut rolling = crate::RollingLog::load(&mut loader, adaptor(), "r", 16).unwrap();
+        let mut store = AtomicStore::open(loader).unwrap();
+        for i in 0..5u64 {
+            append.store_resource(&i).unwrap();
+            fixed.store_resource(&i).unwrap();
+            rolling.store_resource(&i).unwrap();
+            append.commit_version().unwrap();
+            fixed.commit_version().unwrap();
+            rolling.commit_version().unwrap();
+            store.commit_version().unwrap();
+        }
+        // Not committed by the store.
+        append.store_resource(&100).unwrap();
+        fixed.store_resource(&100).unwrap();
+        append.commit_version().unwrap();
+        fixed.commit_version().unwrap();
+    }
+    backend
+        .open(&path.join("a_1.bak.7"), OpenMode::Create)
+        .unwrap()
+        .write_all(b"backup")
+        .unwrap();
+
+    // A rename that is never committed leaves the old keys in place.
+    {
+        let mut loader = load();
+        loader.rename_resource("a", "b").unwrap();
+        assert!(matches!(
+            loader.rename_resource("f", "b"),
+            Err(PersistenceError::DuplicateResourceKey { .. })
+        ));
+    }
+    let mut loader = load();
+    let mut rolling = crate::RollingLog::load(&mut loader, adaptor(), "r", 16).unwrap();
+    assert!(matches!(
+        loader.rename_resource("r", "s"),
+        Err(PersistenceError::ResourceInUse { .. })
+    ));
+    loader.rename_resource("a", "b").unwrap();
+    loader.rename_resource("f", "g").unwrap();
+    assert!(matches!(
+        crate::AppendLog::load(&mut loader, adaptor(), "a", 16),
+        Err(PersistenceError::ResourceRetired { .. })
+    ));
+    let mut append = crate::AppendLog::load(&mut loader, adaptor(), "b", 16).unwrap();
+    let mut fixed = crate::FixedAppendLog::load(&mut loader, adaptor(), "g", 8, 2).unwrap();
+    // Backups move to the new key too.
+    assert_eq!(
+        crate::utils::read_file(backend.as_ref(), &path.join("b_1.bak.7")).unwrap(),
+        b"backup"
+    );
+    loader.set_unclaimed_resource_policy(UnclaimedResourcePolicy::Fail);
+    let mut store = AtomicStore::open(loader).unwrap();
+    let expected = (0..5).collect::<Vec<_>>();
+    assert_eq!(append.iter().collect::<Result<Vec<_>>>().unwrap(), expected);
+    assert_eq!(fixed.iter().collect::<Result<Vec<_>>>().unwrap(), expected);
+    append.store_resource(&5).unwrap();
+    fixed.store_resource(&5).unwrap();
+    append.commit_version().unwrap();
+    fixed.commit_version().unwrap();
+    rolling.skip_version().unwrap();
+    store.commit_version().unwrap();
+    drop((append, fixed, rolling, store));
+
+    let mut loader = load();
+    let append = crate::AppendLog::load(&mut loader, adaptor(), "b", 16).unwrap();
+    let fixed = crate::FixedAppendLog::load(&mut loader, adaptor(), "g", 8, 2).unwrap();
+    let expected = (0..6).collect::<Vec<_>>();
+    assert_eq!(append.iter().collect::<Result<Vec<_>>>().unwrap(), expected);
+    assert_eq!(fixed.iter().collect::<Result<Vec<_>>>().unwrap(), expected);
+    drop(loader);
+
+    // The previous version can still be loaded with the old keys.
+    let mut loader = AtomicStoreLoader::load_version_with_config(
+        path,
+        file_pattern,
+        4,
+        AtomicStoreConfig::default().backend(backend.clone()),
+    )
+    .unwrap();
+    let append = crate::AppendLog::load(&mut loader, adaptor(), "a", 16).unwrap();
+    assert_eq!(
+        append.iter().collect::<Result<Vec<_>>>().unwrap(),
+        (0..5).collect::<Vec<_>>()
+    );
+    drop((append, loader));
+
+    // As if `b` and its index had been migrated from the unversioned format, their kinds must be
+    // passed in to rename them.
+    let latest_file_path = format_latest_file_path(path, file_pattern);
+    let mut state = load_state(backend.as_ref(), &latest_file_path).unwrap();
+    for key in ["b", "b_index"] {
+        state.resource_files.get_mut(key).unwrap().descriptor = None;
+    }
+    write_state(backend.as_ref(), &latest_file_path, &state).unwrap();
+    let mut loader = load();
+    assert!(matches!(
+        loader.rename_resource("b", "c"),
+        Err(PersistenceError::ResourceKindUnknown { .. })
+    ));
+    assert!(matches!(
+        loader.rename_resource_with_kind("g", "h", ResourceKind::RollingLog { file_fill_size: 16 }),
+        Err(PersistenceError::ResourceFormatInconsistent { .. })
+    ));
+    loader
+        .rename_resource_with_kind("b", "c", ResourceKind::AppendLog { file_fill_size: 16 })
+        .unwrap();
+    let append = crate::AppendLog::load(&mut loader, adaptor(), "c", 16).unwrap();
+    assert_eq!(
+        append.iter().collect::<Result<Vec<_>>>().unwrap(),
+        (0..6).collect::<Vec<_>>()
+    );
+}
+
+#[test]
+fn test_commit_timeout_names_pending_resources() {
+    use crate::load_store::BincodeLoadStore;
+    use crate::memory_backend::MemoryBackend;
+
+    let backend = Arc::new(MemoryBackend::new());
+    let timeout = Duration::from_millis(100);
+    let mut loader = AtomicStoreLoader::load_with_config(
+        Path::new("/store"),
+        "test_commit_timeout_names_pending_resources",
+        AtomicStoreConfig::default()
+            .backend(backend)
+            .commit_timeout(timeout),
+    )
+    .unwrap();
+    let mut logs = (0..4)
+        .map(|i| {
+            crate::RollingLog::load(
+                &mut loader,
+                BincodeLoadStore::<u64>::default(),
+                &format!("r{}", i),
+                64,
+            )
+            .unwrap()
+        })
+        .collect::<Vec<_>>();
+    let mut store = AtomicStore::open(loader).unwrap();
+
+    let location = logs[0].store_resource(&7).unwrap();
+    logs[0].commit_version().unwrap();
+    let start = Instant::now();
+    match store.commit_version() {
+        Err(PersistenceError::TimedOut { pending }) => {
+            assert_eq!(pending, vec!["r1", "r2", "r3"]);
+        }
+        res => panic!("expected a timeout, got {:?}", res),
+    }
+    // One deadline for the whole commit, rather than one timeout per pending resource.
+    assert!(start.elapsed() < timeout * 2);
+
+    // The resource that was ready stays ready for the next attempt.
+    for log in logs[1..].iter_mut() {
+        log.skip_version().unwrap();
+    }
+    let receiver = store.subscribe();
+    store.commit_version().unwrap();
+    assert_eq!(
+        receiver.try_recv().unwrap().changed,
+        HashMap::from([("r0".to_string(), location)])
+    );
+}
+
+#[test]
+fn test_queued_commit_points() {
+    use crate::load_store::BincodeLoadStore;
+    use crate::memory_backend::MemoryBackend;
+
+    let backend = Arc::new(MemoryBackend::new());
+    let path = Path::new("/store");
+    let file_pattern = "test_queued_commit_points";
+    let mut loader = AtomicStoreLoader::load_with_config(
+        path,
+        file_pattern,
+        AtomicStoreConfig::default().backend(backend.clone()),
+    )
+    .unwrap();
+    let mut append =
+        crate::AppendLog::load(&mut loader, BincodeLoadStore::<u64>::default(), "a", 32).unwrap();
+    let mut rolling =
+        crate::RollingLog::load(&mut loader, BincodeLoadStore::<u64>::default(), "r", 32).unwrap();
+    let mut store = AtomicStore::open(loader).unwrap();
+    let receiver = store.subscribe();
+
+    // Three versions are committed by the logs before the store catches up.
+    let mut rolling_locations = Vec::new();
+    for i in 0..3u64 {
+        append.store_resource(&i).unwrap();
+        append.commit_version().unwrap();
+        if i == 1 {
+            rolling.skip_version().unwrap();
+        } else {
+            rolling_locations.push(rolling.store_resource(&i).unwrap());
+            rolling.commit_version().unwrap();
+        }
+    }
+    // The logs read their own latest commits.
+    assert_eq!(append.load_latest().unwrap(), 2);
+    assert_eq!(rolling.load_latest().unwrap(), 2);
+
+    store.commit_version().unwrap();
+    assert_eq!(store.commit_queued_versions().unwrap(), 2);
+    assert_eq!(store.commit_queued_versions().unwrap(), 0);
+    let changed: Vec<_> = receiver.try_iter().map(|version| version.changed).collect();
+    assert_eq!(changed.len(), 3);
+    assert_eq!(changed[0]["r"], rolling_locations[0]);
+    // The skipped version leaves the rolling log unchanged.
+    assert!(!changed[1].contains_key("r"));
+    assert_eq!(changed[2]["r"], rolling_locations[1]);
+    drop(append);
+    drop(rolling);
+    drop(store);
+
+    // Each version records the commit point queued for it.
+    for version in 0..3u32 {
+        let mut loader = AtomicStoreLoader::load_version_with_config(
+            path,
+            file_pattern,
+            version,
+            AtomicStoreConfig::default().backend(backend.clone()),
+        )
+        .unwrap();
+        let append =
+            crate::AppendLog::load(&mut loader, BincodeLoadStore::<u64>::default(), "a", 32)
+                .unwrap();
+        assert_eq!(
+            append.iter().collect::<Result<Vec<_>>>().unwrap(),
+            (0..=version as u64).collect::<Vec<_>>()
+        );
+    }
+}
+
+#[test]
+fn test_speculative_branches() {
+    use crate::branch::BranchId;
+    use crate::load_store::BincodeLoadStore;
+    use crate::memory_backend::MemoryBackend;
+
+    let backend = Arc::new(MemoryBackend::new());
+    let path = Path::new("/store");
+    let file_pattern = "test_speculative_branches";
+    let branch_files = || {
+        backend
+            .list(path)
+            .unwrap()
+            .into_iter()
+            .filter(|name| name.contains(".branch."))
+            .count()
+    };
+    let load = || {
+        let mut loader = AtomicStoreLoader::load_with_config(
+            path,
+            file_pattern,
+            AtomicStoreConfig::default().backend(backend.clone()),
+        )
+        .unwrap();
+        let append =
+            crate::AppendLog::load(&mut loader, BincodeLoadStore::<u64>::default(), "a", 32)
+                .unwrap();
+        let fixed =
+            crate::FixedAppendLog::load(&mut loader, BincodeLoadStore::<u64>::default(), "f", 8, 4)
+                .unwrap();
+        (append, fixed, AtomicStore::open(loader).unwrap())
+    };
+
+    let (mut append, mut fixed, mut store) = load();
+    append.store_resource(&0).unwrap();
+    append.commit_version().unwrap();
+    fixed.store_resource(&0).unwrap();
+    fixed.commit_version().unwrap();
+    store.commit_version().unwrap();
+
+    // Two candidate forks, written to both logs.
+    for (branch, values) in [(BranchId(1), vec![10, 11]), (BranchId(2), vec![20])] {
+        for (i, value) in values.iter().enumerate() {
+            assert_eq!(
+                append.store_branch_resource(branch, value).unwrap(),
+                i as u64
+            );
+            assert_eq!(
+                fixed.store_branch_resource(branch, value).unwrap(),
+                i as u64
+            );
+        }
+    }
+    assert_eq!(append.branch_len(BranchId(1)), 2);
+    assert_eq!(fixed.branch_len(BranchId(3)), 0);
+    assert_eq!(append.load_branch_resource(BranchId(1), 1).unwrap(), 11);
+    assert_eq!(fixed.load_branch_resource(BranchId(2), 0).unwrap(), 20);
+    assert!(matches!(
+        append.load_branch_resource(BranchId(2), 1),
+        Err(PersistenceError::BranchEntryNotFound { .. })
+    ));
+    // Undecided branches are not part of the log.
+    assert_eq!(append.iter().collect::<Result<Vec<_>>>().unwrap(), vec![0]);
+    assert_eq!(branch_files(), 4);
+
+    // A branch that cannot be read in full is not appended to any of the logs.
+    backend.remove(&path.join(".f.branch.1")).unwrap();
+    assert!(store
+        .commit_branch(BranchId(1), &mut [&mut append, &mut fixed])
+        .is_err());
+    assert_eq!(append.iter().collect::<Result<Vec<_>>>().unwrap(), vec![0]);
+    assert_eq!(append.branch_len(BranchId(2)), 1);
+
+    store
+        .commit_branch(BranchId(2), &mut [&mut append, &mut fixed])
+        .unwrap();
+    assert_eq!(branch_files(), 0);
+    assert_eq!(append.branch_len(BranchId(1)), 0);
+    assert_eq!(
+        append.iter().collect::<Result<Vec<_>>>().unwrap(),
+        vec![0, 20]
+    );
+
+    append.store_branch_resource(BranchId(3), &30).unwrap();
+    store.discard_branches(&mut [&mut append]).unwrap();
+    assert_eq!(branch_files(), 0);
+
+    // Branches left undecided when the logs are closed are deleted when they are reopened.
+    append.store_branch_resource(BranchId(3), &30).unwrap();
+    fixed.store_branch_resource(BranchId(3), &30).unwrap();
+    drop((append, fixed, store));
+    assert_eq!(branch_files(), 2);
+    let (append, fixed, _store) = load();
+    assert_eq!(branch_files(), 0);
+    assert_eq!(
+        append.iter().collect::<Result<Vec<_>>>().unwrap(),
+        vec![0, 20]
+    );
+    assert_eq!(
+        fixed.iter().collect::<Result<Vec<_>>>().unwrap(),
+        vec![0, 20]
+    );
+}
+
+#[test]
+fn test_collect_garbage() {
+    use crate::config::GarbagePolicy;
+    use crate::load_store::BincodeLoadStore;
+    use crate::memory_backend::MemoryBackend;
+
+    let backend = Arc::new(MemoryBackend::new());
+    let path = Path::new("/store");
+    let file_pattern = "test_collect_garbage";
+    let touch = |name: &str| {
+        backend
+            .open(&path.join(name), OpenMode::Create)
+            .unwrap()
+            .write_all(b"garbage")
+            .unwrap();
+    };
+    let open = |policy: GarbagePolicy| {
+        let config = AtomicStoreConfig::default()
+            .backend(backend.clone())
+            .garbage_policy(policy);
+        let mut loader = AtomicStoreLoader::load_with_config(path, file_pattern, config).unwrap();
+        let append =
+            crate::AppendLog::load(&mut loader, BincodeLoadStore::<u64>::default(), "a", 1024)
+                .unwrap();
+        let fixed =
+            crate::FixedAppendLog::load(&mut loader, BincodeLoadStore::<u64>::default(), "f", 8, 4)
+                .unwrap();
+        (append, fixed, AtomicStore::open(loader).unwrap())
+    };
+
+    // Another store in the same directory, whose files are all reachable.
+    {
+        let mut loader = AtomicStoreLoader::load_with_config(
+            path,
+            "other",
+            AtomicStoreConfig::default().backend(backend.clone()),
+        )
+        .unwrap();
+        let mut log =
+            crate::AppendLog::load(&mut loader, BincodeLoadStore::<u64>::default(), "o", 1024)
+                .unwrap();
+        let mut store = AtomicStore::open(loader).unwrap();
+        log.store_resource(&1).unwrap();
+        log.commit_version().unwrap();
+        store.commit_version().unwrap();
+    }
+
+    {
+        let (mut append, mut fixed, mut store) = open(GarbagePolicy::Report);
+        append.store_resource(&1).unwrap();
+        append.commit_version().unwrap();
+        fixed.store_resource(&1).unwrap();
+        fixed.commit_version().unwrap();
+        store.commit_version().unwrap();
+        // Written, but never committed, so the append log's files are backed up when reopened.
+        append.store_resource(&2).unwrap();
+    }
+    let (mut append, mut fixed, mut store) = open(GarbagePolicy::Report);
+    append.store_resource(&3).unwrap();
+    append.commit_version().unwrap();
+    fixed.store_resource(&2).unwrap();
+    fixed.commit_version().unwrap();
+    store.commit_version().unwrap();
+    touch(&format!(".{}_working", file_pattern));
+    touch(".f_index_backup");
+    touch(".removed_0");
+    touch("notes.txt");
+    // Data files past the last committed location of their log, which it may still be writing.
+    touch(".a_5");
+    touch(".o_3");
+
+    // A store that is still open, whose files must not be touched.
+    let busy = {
+        let mut loader = AtomicStoreLoader::load_with_config(
+            path,
+            "busy",
+            AtomicStoreConfig::default().backend(backend.clone()),
+        )
+        .unwrap();
+        let mut log =
+            crate::AppendLog::load(&mut loader, BincodeLoadStore::<u64>::default(), "b", 1024)
+                .unwrap();
+        let mut store = AtomicStore::open(loader).unwrap();
+        log.store_resource(&1).unwrap();
+        log.commit_version().unwrap();
+        store.commit_version().unwrap();
+        (log, store)
+    };
+    touch(".b_4");
+    touch(".busy_working");
+
+    let kinds = |garbage: &[GarbageFile]| {
+        garbage
+            .iter()
+            .map(|file| {
+                (
+                    file.path.file_name().unwrap().to_string_lossy().to_string(),
+                    file.kind,
+                )
+            })
+            .filter(|(name, _)| !name.contains(".bak."))
+            .collect::<Vec<_>>()
+    };
+    // While a store is open, its files are not reported, and the files of new resources it may
+    // be writing cannot be told apart from orphaned ones.
+    let garbage = store.collect_garbage().unwrap();
+    assert_eq!(
+        kinds(&garbage),
+        vec![
+            // The indexes from before the last commit.
+            (".a_index_index_working".to_string(), GarbageKind::Working),
+            (".f_index_backup".to_string(), GarbageKind::Working),
+            (".f_index_working".to_string(), GarbageKind::Working),
+            (format!(".{}_working", file_pattern), GarbageKind::Working),
+        ]
+    );
+    drop(busy);
+
+    let garbage = store.collect_garbage().unwrap();
+    assert_eq!(
+        kinds(&garbage),
+        vec![
+            (".a_index_index_working".to_string(), GarbageKind::Working),
+            (".busy_working".to_string(), GarbageKind::Working),
+            (".f_index_backup".to_string(), GarbageKind::Working),
+            (".f_index_working".to_string(), GarbageKind::Working),
+            (".removed_0".to_string(), GarbageKind::Orphaned),
+            (format!(".{}_working", file_pattern), GarbageKind::Working),
+        ]
+    );
+    let backups = garbage
+        .iter()
+        .filter(|file| file.kind == GarbageKind::Backup)
+        .count();
+    // Both the data file and the index of the append log are truncated.
+    assert_eq!(backups, 2);
+    assert!(garbage.iter().all(|file| !file.deleted && file.len > 0));
+    // Files the store did not write are never reported.
+    assert!(!garbage.iter().any(|file| file.path.ends_with("notes.txt")));
+    drop((append, fixed, store));
+
+    // Recent backups are kept.
+    let (append, fixed, mut store) = open(GarbagePolicy::Delete {
+        keep_backups_for: Duration::from_secs(3600),
+    });
+    let garbage = store.collect_garbage().unwrap();
+    assert!(garbage
+        .iter()
+        .all(|file| file.deleted == (file.kind != GarbageKind::Backup)));
+    assert_eq!(
+        store
+            .collect_garbage()
+            .unwrap()
+            .iter()
+            .map(|file| file.kind)
+            .collect::<Vec<_>>(),
+        vec![GarbageKind::Backup; 2]
+    );
+    assert!(backend.exists(&path.join("notes.txt")));
+    for name in [".a_5", ".o_3", ".b_4"] {
+        assert!(backend.exists(&path.join(name)));
+    }
+    drop((append, fixed, store));
+
+    let (append, fixed, mut store) = open(GarbagePolicy::Delete {
+        keep_backups_for: Duration::ZERO,
+    });
+    assert_eq!(store.collect_garbage().unwrap().len(), 2);
+    assert!(store.collect_garbage().unwrap().is_empty());
+    assert_eq!(
+        append.iter().collect::<Result<Vec<_>>>().unwrap(),
+        vec![1, 3]
+    );
+    assert_eq!(
+        fixed.iter().collect::<Result<Vec<_>>>().unwrap(),
+        vec![1, 2]
+    );
+}
+
+#[test]
+fn test_verify() {
+    use crate::load_store::BincodeLoadStore;
+    use crate::memory_backend::MemoryBackend;
+
+    let backend = Arc::new(MemoryBackend::new());
+    let path = Path::new("/store");
+    let mut loader = AtomicStoreLoader::load_with_config(
+        path,
+        "test_verify",
+        AtomicStoreConfig::default().backend(backend.clone()),
+    )
+    .unwrap();
+    let mut append =
+        crate::AppendLog::load(&mut loader, BincodeLoadStore::<u64>::default(), "a", 1024).unwrap();
+    let mut fixed =
+        crate::FixedAppendLog::load(&mut loader, BincodeLoadStore::<u64>::default(), "f", 8, 4)
+            .unwrap();
+    let mut rolling =
+        crate::RollingLog::load(&mut loader, BincodeLoadStore::<u64>::default(), "r", 1024)
+            .unwrap();
+    let mut store = AtomicStore::open(loader).unwrap();
+    assert_eq!(store.verify().unwrap(), vec![]);
+    for value in 0..6u64 {
+        append.store_resource(&value).unwrap();
+        append.commit_version().unwrap();
+        fixed.store_resource(&value).unwrap();
+        fixed.commit_version().unwrap();
+        rolling.store_resource(&value).unwrap();
+        rolling.commit_version().unwrap();
+        store.commit_version().unwrap();
     }
+    assert_eq!(store.verify().unwrap(), vec![]);
+
+    let patch = |name: &str, offset: u64, bytes: &[u8]| {
+        let mut file = backend.open(&path.join(name), OpenMode::ReadWrite).unwrap();
+        file.seek(SeekFrom::Start(offset)).unwrap();
+        file.write_all(bytes).unwrap();
+    };
+    // Index entries are 16 bytes; the fourth now repeats the second.
+    let mut second_entry = vec![0u8; 16];
+    let mut index_file = backend
+        .open(&path.join("a_index_0_4096"), OpenMode::Read)
+        .unwrap();
+    index_file.seek(SeekFrom::Start(16)).unwrap();
+    index_file.read_exact(&mut second_entry).unwrap();
+    patch("a_index_0_4096", 48, &second_entry);
+    // Only the first four and a half entries of the append log remain.
+    let data = crate::utils::read_file(backend.as_ref(), &path.join(".a_0")).unwrap();
+    backend
+        .open(&path.join(".a_0"), OpenMode::Create)
+        .unwrap()
+        .write_all(&data[..36])
+        .unwrap();
+    patch("f_index", 0, &[0u8; 16]);
+    backend.remove(&path.join("f_0_4")).unwrap();
+    patch(".r_0", 0, &9u32.to_le_bytes());
+
+    let issues = store.verify().unwrap();
+    let found: Vec<(&str, String)> = issues
+        .iter()
+        .map(|issue| {
+            let name = issue.path.file_name().unwrap().to_string_lossy();
+            (issue.key.as_str(), name.to_string())
+        })
+        .collect();
+    assert_eq!(
+        found,
+        vec![
+            ("a", ".a_0".to_string()),
+            ("a", "a_index_0_4096".to_string()),
+            ("a", ".a_0".to_string()),
+            ("a", ".a_0".to_string()),
+            ("f", "f_index".to_string()),
+            ("f", "f_0_4".to_string()),
+            ("r", ".r_0".to_string()),
+        ]
+    );
+    let location = |file_counter, store_start| StorageLocation {
+        file_counter,
+        store_start,
+        store_length: 8,
+    };
+    assert_eq!(
+        issues[0].kind,
+        InconsistencyKind::LocationPastEnd {
+            location: location(0, 40),
+            index_entry: None,
+            len: 36,
+        }
+    );
+    assert_eq!(
+        issues[1].kind,
+        InconsistencyKind::IndexEntryOutOfOrder {
+            index_entry: 3,
+            location: location(0, 8),
+            previous: location(0, 16),
+        }
+    );
+    assert_eq!(
+        issues[4].kind,
+        InconsistencyKind::InvalidByteOrder { byte_order: 0 }
+    );
+    assert_eq!(issues[5].kind, InconsistencyKind::MissingFile);
+    assert_eq!(
+        issues[6].kind,
+        InconsistencyKind::EntryCountMismatch {
+            header: 9,
+            committed: Some(6),
+            held: 6,
+        }
+    );
+
+    // A read-only loader finds the same problems.
+    let read_only = AtomicStoreLoader::open_read_only_with_config(
+        path,
+        "test_verify",
+        AtomicStoreConfig::default().backend(backend.clone()),
+    )
+    .unwrap();
+    assert_eq!(read_only.verify().unwrap(), issues);
 }
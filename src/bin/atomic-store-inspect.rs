@@ -0,0 +1,401 @@
+// Copyright (c) 2022 Espresso Systems (espressosys.com)
+// This file is part of the AtomicStore library.
+
+// This program is free software: you can redistribute it and/or modify it under the terms of the GNU General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Inspect a store without modifying it: print its table of contents, list its versions, show the
+//! files of each resource, dump the raw entries of a log in hex, and check the files of every
+//! resource against the table of contents.
+//!
+//! The store is opened with `AtomicStoreLoader::open_read_only`, so this can be run against a
+//! store that a process has open for writing.
+
+use atomic_store::atomic_store::{InconsistencyKind, ReadOnlyLoader};
+use atomic_store::load_store::RawLoadStore;
+use atomic_store::resource_descriptor::ResourceKind;
+use atomic_store::storage_location::StorageLocation;
+use atomic_store::{AppendLog, AtomicStoreConfig, AtomicStoreLoader, FixedAppendLog, RollingLog};
+
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+
+const USAGE: &str = "\
+usage: atomic-store-inspect <directory> <file-pattern> <command> [options]
+
+commands:
+    toc [--version N]                  print the table of contents
+    versions                           list the committed versions
+    files [--version N]                show the files of each resource, with their sizes
+    dump <key> <entry> [--version N]   dump an entry of a log in hex, where <entry> is one of
+         [--kind KIND --size SIZE]         --index N
+                                           --location COUNTER:START:LENGTH
+                                           --latest
+                                       --kind and --size give the layout of a resource that has
+                                       none recorded: KIND is append, fixed or rolling, and SIZE
+                                       is the file fill size in bytes, or for a fixed log,
+                                       ENTRY_BYTES:ENTRIES_PER_FILE
+    verify [--version N]               check the files of every resource, exiting with an error
+                                       if any are inconsistent; resources with no recorded layout
+                                       are listed as unchecked";
+
+/// A failed command, with a message for the user.
+type CommandResult = Result<(), String>;
+
+struct Args {
+    storage_path: PathBuf,
+    file_pattern: String,
+    command: String,
+    // Positional arguments after the command.
+    operands: Vec<String>,
+    version: Option<u32>,
+    index: Option<u64>,
+    location: Option<StorageLocation>,
+    latest: bool,
+    kind: Option<String>,
+    size: Option<String>,
+}
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Args, String> {
+    let storage_path = PathBuf::from(args.next().ok_or("missing directory")?);
+    let file_pattern = args.next().ok_or("missing file pattern")?;
+    let command = args.next().ok_or("missing command")?;
+    let mut parsed = Args {
+        storage_path,
+        file_pattern,
+        command,
+        operands: Vec::new(),
+        version: None,
+        index: None,
+        location: None,
+        latest: false,
+        kind: None,
+        size: None,
+    };
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| args.next().ok_or(format!("{} requires a value", name));
+        match arg.as_str() {
+            "--version" => parsed.version = Some(parse_number(&value("--version")?)?),
+            "--index" => parsed.index = Some(parse_number(&value("--index")?)?),
+            "--location" => parsed.location = Some(parse_location(&value("--location")?)?),
+            "--latest" => parsed.latest = true,
+            "--kind" => parsed.kind = Some(value("--kind")?),
+            "--size" => parsed.size = Some(value("--size")?),
+            _ if arg.starts_with("--") => return Err(format!("unknown option {}", arg)),
+            _ => parsed.operands.push(arg),
+        }
+    }
+    Ok(parsed)
+}
+
+fn parse_number<T: std::str::FromStr>(value: &str) -> Result<T, String> {
+    value
+        .parse()
+        .map_err(|_| format!("'{}' is not a valid number", value))
+}
+
+fn parse_location(value: &str) -> Result<StorageLocation, String> {
+    let parts: Vec<&str> = value.split(':').collect();
+    let [file_counter, store_start, store_length] = parts[..] else {
+        return Err(format!(
+            "'{}' is not a location of the form COUNTER:START:LENGTH",
+            value
+        ));
+    };
+    Ok(StorageLocation {
+        file_counter: parse_number(file_counter)?,
+        store_start: parse_number(store_start)?,
+        store_length: parse_number(store_length)?,
+    })
+}
+
+// The layout given by `--kind` and `--size`, if any.
+fn parse_kind(args: &Args) -> Result<Option<ResourceKind>, String> {
+    let (kind, size) = match (&args.kind, &args.size) {
+        (None, None) => return Ok(None),
+        (Some(kind), Some(size)) => (kind, size),
+        _ => return Err("--kind and --size must be given together".to_string()),
+    };
+    let kind = match kind.as_str() {
+        "append" => ResourceKind::AppendLog {
+            file_fill_size: parse_number(size)?,
+        },
+        "rolling" => ResourceKind::RollingLog {
+            file_fill_size: parse_number(size)?,
+        },
+        "fixed" => {
+            let Some((resource_size, file_size)) = size.split_once(':') else {
+                return Err(format!(
+                    "'{}' is not a size of the form ENTRY_BYTES:ENTRIES_PER_FILE",
+                    size
+                ));
+            };
+            ResourceKind::FixedAppendLog {
+                resource_size: parse_number(resource_size)?,
+                file_size: parse_number(file_size)?,
+            }
+        }
+        kind => return Err(format!("unknown kind '{}'", kind)),
+    };
+    Ok(Some(kind))
+}
+
+fn open(args: &Args, config: AtomicStoreConfig) -> Result<ReadOnlyLoader, String> {
+    open_version(args, args.version, config)
+}
+
+fn open_version(
+    args: &Args,
+    version: Option<u32>,
+    config: AtomicStoreConfig,
+) -> Result<ReadOnlyLoader, String> {
+    let loader = match version {
+        Some(counter) => AtomicStoreLoader::open_read_only_version_with_config(
+            &args.storage_path,
+            &args.file_pattern,
+            counter,
+            config,
+        ),
+        None => AtomicStoreLoader::open_read_only_with_config(
+            &args.storage_path,
+            &args.file_pattern,
+            config,
+        ),
+    };
+    loader.map_err(|err| err.to_string())
+}
+
+fn print_toc(args: &Args) -> CommandResult {
+    let loader = open(args, AtomicStoreConfig::default())?;
+    let Some(info) = loader.version_info() else {
+        println!("nothing has been committed");
+        return Ok(());
+    };
+    println!("file_counter: {}", info.counter);
+    match info.timestamp {
+        Some(timestamp) => println!("timestamp: {}", timestamp),
+        None => println!("timestamp: unknown"),
+    }
+    if !info.metadata.is_empty() {
+        println!("metadata: {}", hex(&info.metadata));
+    }
+    println!("resources:");
+    for key in loader.resource_keys() {
+        // Every key listed has a location.
+        let location = loader.resource_location(key).unwrap();
+        match loader.resource_descriptor(key) {
+            Some(descriptor) => println!("    {} {} {}", key, location, descriptor),
+            None => println!("    {} {} (no recorded layout)", key, location),
+        }
+    }
+    Ok(())
+}
+
+fn list_versions(args: &Args) -> CommandResult {
+    let versions = AtomicStoreLoader::list_versions(&args.storage_path, &args.file_pattern)
+        .map_err(|err| err.to_string())?;
+    for counter in versions {
+        match AtomicStoreLoader::read_version_info(&args.storage_path, &args.file_pattern, counter)
+        {
+            Ok(info) => {
+                let timestamp = info
+                    .timestamp
+                    .map_or("unknown".to_string(), |timestamp| timestamp.to_string());
+                println!(
+                    "{} timestamp {} metadata {}",
+                    counter,
+                    timestamp,
+                    hex(&info.metadata)
+                );
+            }
+            Err(err) => println!("{} unreadable: {}", counter, err),
+        }
+    }
+    Ok(())
+}
+
+fn print_files(args: &Args) -> CommandResult {
+    let loader = open(args, AtomicStoreConfig::default())?;
+    for key in loader.resource_keys() {
+        let files = loader.resource_files(key).map_err(|err| err.to_string())?;
+        let total: u64 = files.iter().map(|(_, len)| len).sum();
+        println!("{} ({} files, {} bytes)", key, files.len(), total);
+        for (path, len) in files {
+            let name = path.file_name().unwrap_or(path.as_os_str());
+            println!("    {:>12} {}", len, name.to_string_lossy());
+        }
+    }
+    Ok(())
+}
+
+fn dump_entry(args: &Args) -> CommandResult {
+    let [key] = &args.operands[..] else {
+        return Err("dump takes exactly one resource key".to_string());
+    };
+    let selected = [args.index.is_some(), args.location.is_some(), args.latest];
+    if selected.iter().filter(|selected| **selected).count() != 1 {
+        return Err("dump takes exactly one of --index, --location and --latest".to_string());
+    }
+    let loader = open(args, AtomicStoreConfig::default())?;
+    let descriptor = loader.resource_descriptor(key);
+    let kind = match (parse_kind(args)?, descriptor) {
+        (Some(kind), _) => kind,
+        (None, Some(descriptor)) => descriptor.kind,
+        (None, None) => {
+            return Err(format!(
+                "resource '{}' has no recorded layout; give one with --kind and --size",
+                key
+            ))
+        }
+    };
+    // The format is only checked against a recorded descriptor.
+    let adaptor = RawLoadStore::new(descriptor.map_or("", |descriptor| &descriptor.format));
+    let entry = match kind {
+        ResourceKind::AppendLog { file_fill_size } => {
+            // The layout of the index is recorded with the index, which is a resource of its own.
+            let mut config = AtomicStoreConfig::default();
+            if let Some(ResourceKind::FixedAppendLog { file_size, .. }) = loader
+                .resource_descriptor(&format!("{}_index", key))
+                .map(|descriptor| descriptor.kind)
+            {
+                config = config.append_log_index_file_size(file_size);
+            }
+            // The same version, even if another has been committed since.
+            let loader = open_version(args, loader.version(), config)?;
+            let log = AppendLog::open_read_only(&loader, adaptor, key, file_fill_size)
+                .map_err(|err| err.to_string())?;
+            match (args.index, args.location) {
+                (Some(index), _) => {
+                    check_index(log.iter().len(), index)?;
+                    log.load_at(index)
+                }
+                (_, Some(location)) => log.load_specified(&location),
+                _ => log.load_latest(),
+            }
+        }
+        ResourceKind::FixedAppendLog {
+            resource_size,
+            file_size,
+        } => {
+            let log =
+                FixedAppendLog::open_read_only(&loader, adaptor, key, resource_size, file_size)
+                    .map_err(|err| err.to_string())?;
+            match (args.index, args.location) {
+                (Some(index), _) => {
+                    check_index(log.iter().len(), index)?;
+                    log.load_at(index)
+                }
+                (_, Some(location)) => log.load_specified(&location),
+                _ => log.load_latest(),
+            }
+        }
+        ResourceKind::RollingLog { file_fill_size } => {
+            let log = RollingLog::open_read_only(&loader, adaptor, key, file_fill_size)
+                .map_err(|err| err.to_string())?;
+            match (args.index, args.location) {
+                (Some(_), _) => {
+                    return Err("entries of a RollingLog cannot be read by index".to_string())
+                }
+                (_, Some(location)) => log.load_specified(&location),
+                _ => log.load_latest(),
+            }
+        }
+    };
+    let entry = entry.map_err(|err| err.to_string())?;
+    print!("{}", hex_dump(&entry));
+    Ok(())
+}
+
+fn verify(args: &Args) -> CommandResult {
+    let loader = open(args, AtomicStoreConfig::default())?;
+    let issues = loader.verify().map_err(|err| err.to_string())?;
+    for issue in issues.iter() {
+        println!("{}", issue);
+    }
+    // Resources without a recorded layout are listed, but are not known to be inconsistent.
+    let inconsistent = issues
+        .iter()
+        .filter(|issue| issue.kind != InconsistencyKind::Unchecked)
+        .count();
+    if inconsistent == 0 {
+        println!("no inconsistencies found");
+        Ok(())
+    } else {
+        Err(format!("{} inconsistencies found", inconsistent))
+    }
+}
+
+fn check_index(len: usize, index: u64) -> CommandResult {
+    if index < len as u64 {
+        Ok(())
+    } else {
+        Err(format!(
+            "index {} is out of range; the log has {} entries",
+            index, len
+        ))
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+// Format `bytes` sixteen to a line, with their offset and their printable characters.
+fn hex_dump(bytes: &[u8]) -> String {
+    let mut out = String::new();
+    for (line, chunk) in bytes.chunks(16).enumerate() {
+        let hex: Vec<String> = chunk.iter().map(|byte| format!("{:02x}", byte)).collect();
+        let text: String = chunk
+            .iter()
+            .map(|&byte| {
+                if byte.is_ascii_graphic() || byte == b' ' {
+                    byte as char
+                } else {
+                    '.'
+                }
+            })
+            .collect();
+        out.push_str(&format!(
+            "{:08x}  {:<47}  {}\n",
+            line * 16,
+            hex.join(" "),
+            text
+        ));
+    }
+    out
+}
+
+fn run(args: &Args) -> CommandResult {
+    if !Path::new(&args.storage_path).is_dir() {
+        return Err(format!(
+            "{} is not a directory",
+            args.storage_path.display()
+        ));
+    }
+    match args.command.as_str() {
+        "toc" => print_toc(args),
+        "versions" => list_versions(args),
+        "files" => print_files(args),
+        "dump" => dump_entry(args),
+        "verify" => verify(args),
+        command => Err(format!("unknown command '{}'", command)),
+    }
+}
+
+fn main() -> ExitCode {
+    let args = match parse_args(std::env::args().skip(1)) {
+        Ok(args) => args,
+        Err(err) => {
+            eprintln!("{}\n\n{}", err, USAGE);
+            return ExitCode::from(2);
+        }
+    };
+    match run(&args) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("error: {}", err);
+            ExitCode::FAILURE
+        }
+    }
+}
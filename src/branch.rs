@@ -0,0 +1,201 @@
+// Copyright (c) 2022 Espresso Systems (espressosys.com)
+// This file is part of the AtomicStore library.
+
+// This program is free software: you can redistribute it and/or modify it under the terms of the GNU General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Speculative branches of a log, for chained consensus.
+//!
+//! Entries for each candidate fork are written to a branch file of their own, `.<key>.branch.<id>`,
+//! beside the log's data files, and can be read back until a branch is decided. Committing a
+//! branch appends its entries to the log and commits the log's version, so that the next
+//! `AtomicStore::commit_version` records the decided branch; every other branch is discarded.
+//! `AtomicStore::commit_branch` decides on a branch of several logs of a store at once.
+//! Branch files are never referred to by the table of contents, so they do not survive the log
+//! being reopened.
+
+use crate::error::{
+    PersistenceError, StdIoDirOpsSnafu, StdIoOpenSnafu, StdIoReadSnafu, StdIoSeekSnafu,
+    StdIoWriteSnafu,
+};
+use crate::storage_backend::{OpenMode, StorageBackend, StorageFile};
+use crate::Result;
+
+use regex::Regex;
+use snafu::ResultExt;
+
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// Identifies a speculative branch of a log. The same ID can be used for the entries of one fork
+/// across several logs.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct BranchId(pub u64);
+
+impl fmt::Display for BranchId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+fn format_branch_file_path(root_path: &Path, file_pattern: &str, branch: BranchId) -> PathBuf {
+    let mut buf = root_path.to_path_buf();
+    buf.push(format!(".{}.branch.{}", file_pattern, branch));
+    buf
+}
+
+/// Matches the branch files of a log with `file_pattern`. Branch files are separated by dots rather
+/// than underscores, so they cannot be confused with the data files of another key.
+pub(crate) fn branch_file_regex(file_pattern: &str) -> Regex {
+    let pattern = regex::escape(file_pattern);
+    Regex::new(&format!(r"^\.{pattern}\.branch\.\d+$")).unwrap()
+}
+
+/// A log with speculative branches, so that `AtomicStore::commit_branch` can decide on a branch of
+/// several logs at once.
+pub trait BranchedLog {
+    /// The key of the log in its store.
+    fn resource_key(&self) -> &str;
+    /// Append the resource instances of `branch` to the log after any stored since the last
+    /// commit, without committing the version or discarding any branch.
+    fn append_branch(&mut self, branch: BranchId) -> Result<()>;
+    /// Commit the log's version, as the log's own `commit_version` does.
+    fn commit_version(&mut self) -> Result<()>;
+    /// Drop everything stored since the last commit, as the log's own `revert_version` does.
+    fn revert_version(&mut self) -> Result<()>;
+    /// Discard every branch without committing any of them.
+    fn discard_branches(&mut self) -> Result<()>;
+}
+
+#[derive(Debug)]
+struct BranchFile {
+    file: Box<dyn StorageFile>,
+    // The start and length of each entry, in the order they were written.
+    entries: Vec<(u64, usize)>,
+    len: u64,
+}
+
+/// The open branches of a log with `file_pattern`.
+#[derive(Debug)]
+pub(crate) struct Branches {
+    backend: Arc<dyn StorageBackend>,
+    file_path: PathBuf,
+    file_pattern: String,
+    branches: HashMap<BranchId, BranchFile>,
+}
+
+impl Branches {
+    /// Track the branches of a log without touching its files, for a log that is only read.
+    pub(crate) fn new(
+        backend: Arc<dyn StorageBackend>,
+        file_path: &Path,
+        file_pattern: &str,
+    ) -> Branches {
+        Branches {
+            backend,
+            file_path: file_path.to_path_buf(),
+            file_pattern: file_pattern.to_string(),
+            branches: HashMap::new(),
+        }
+    }
+
+    /// Track the branches of a log, deleting any branch files left behind when it was last open.
+    pub(crate) fn open(
+        backend: Arc<dyn StorageBackend>,
+        file_path: &Path,
+        file_pattern: &str,
+    ) -> Result<Branches> {
+        let branches = Self::new(backend, file_path, file_pattern);
+        branches.delete_files()?;
+        Ok(branches)
+    }
+
+    /// Append a serialized entry to `branch`, returning its index within the branch.
+    pub(crate) fn append(&mut self, branch: BranchId, serialized: &[u8]) -> Result<u64> {
+        let entry = match self.branches.entry(branch) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                let path = format_branch_file_path(&self.file_path, &self.file_pattern, branch);
+                let file = self
+                    .backend
+                    .open(&path, OpenMode::Create)
+                    .context(StdIoOpenSnafu)?;
+                entry.insert(BranchFile {
+                    file,
+                    entries: Vec::new(),
+                    len: 0,
+                })
+            }
+        };
+        entry.file.write_all(serialized).context(StdIoWriteSnafu)?;
+        entry.entries.push((entry.len, serialized.len()));
+        entry.len += serialized.len() as u64;
+        Ok(entry.entries.len() as u64 - 1)
+    }
+
+    /// The number of entries written to `branch`.
+    pub(crate) fn len(&self, branch: BranchId) -> u64 {
+        self.branches
+            .get(&branch)
+            .map(|entry| entry.entries.len() as u64)
+            .unwrap_or(0)
+    }
+
+    /// Read the serialized entry at `index` of `branch`.
+    pub(crate) fn read(&self, branch: BranchId, index: u64) -> Result<Vec<u8>> {
+        let (start, len) = self
+            .branches
+            .get(&branch)
+            .and_then(|entry| entry.entries.get(index as usize))
+            .copied()
+            .ok_or_else(|| PersistenceError::BranchEntryNotFound {
+                key: self.file_pattern.clone(),
+                branch: branch.0,
+                index,
+            })?;
+        let path = format_branch_file_path(&self.file_path, &self.file_pattern, branch);
+        let mut file = self
+            .backend
+            .open(&path, OpenMode::Read)
+            .context(StdIoOpenSnafu)?;
+        file.seek(SeekFrom::Start(start)).context(StdIoSeekSnafu)?;
+        let mut buffer = vec![0u8; len];
+        file.read_exact(&mut buffer).context(StdIoReadSnafu)?;
+        Ok(buffer)
+    }
+
+    /// Discard all branches and delete their files.
+    pub(crate) fn discard(&mut self) -> Result<()> {
+        if self.branches.is_empty() {
+            return Ok(());
+        }
+        self.branches.clear();
+        self.delete_files()
+    }
+
+    // The deletions are not synced; any branch files that reappear after a crash are deleted
+    // when the log is next opened.
+    fn delete_files(&self) -> Result<()> {
+        if !self.backend.is_dir(&self.file_path) {
+            return Ok(());
+        }
+        let regex = branch_file_regex(&self.file_pattern);
+        for name in self
+            .backend
+            .list(&self.file_path)
+            .context(StdIoDirOpsSnafu)?
+        {
+            if regex.is_match(&name) {
+                self.backend
+                    .remove(&self.file_path.join(&name))
+                    .context(StdIoDirOpsSnafu)?;
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,473 @@
+// Copyright (c) 2022 Espresso Systems (espressosys.com)
+// This file is part of the AtomicStore library.
+
+// This program is free software: you can redistribute it and/or modify it under the terms of the GNU General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Options controlling how a store and its logs persist their files.
+//!
+//! An `AtomicStoreConfig` is passed to the `_with_config` constructors of `AtomicStoreLoader`, and
+//! applies to the store and to every log loaded from that loader. The library does not read any
+//! of these options from the environment.
+
+use crate::atomic_store::UnclaimedResourcePolicy;
+use crate::storage_backend::{EntryType, FileSystemBackend, OpenMode, StorageBackend, StorageFile};
+
+use std::fs::TryLockError;
+use std::io;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Whether files and directories are flushed to durable storage.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub enum FsyncPolicy {
+    /// Sync every file and directory before it is referenced by a committed version, so that
+    /// committed versions survive a power loss.
+    #[default]
+    Always,
+    /// Never sync, leaving it to the operating system to write changes back. Committed versions
+    /// survive the process crashing, but not the machine; this is only suitable for data that can
+    /// be rebuilt, such as in tests.
+    Never,
+}
+
+/// What happens to the uncommitted tail of a log file when a log reopens it for writing.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub enum BackupPolicy {
+    /// Move the original file aside to a `.bak.<timestamp>` file, which is never deleted by the
+    /// store.
+    #[default]
+    Keep,
+    /// Delete the uncommitted data once the committed part of the file has been copied.
+    Discard,
+}
+
+/// What `AtomicStoreLoader::create` does with a store that already exists in its directory.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub enum ExistingStorePolicy {
+    /// Move the tables of contents of the store with the same file pattern, and the files of every
+    /// resource they record, to a new `backup.<timestamp>` directory, in the directory set by
+    /// `AtomicStoreConfig::backup_dir`. Other stores in the directory are left alone.
+    #[default]
+    Backup,
+    /// Fail with `PersistenceError::StoreAlreadyExists`, leaving the store untouched.
+    Fail,
+    /// Delete the tables of contents of the store with the same file pattern, and the files of
+    /// every resource they record, leaving the rest of the directory alone.
+    Wipe,
+}
+
+/// Which of the unreachable files found by `AtomicStore::collect_garbage` are deleted.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub enum GarbagePolicy {
+    /// Report unreachable files without deleting any.
+    #[default]
+    Report,
+    /// Delete unreachable files, except backups of log files made less than `keep_backups_for`
+    /// ago.
+    Delete { keep_backups_for: Duration },
+}
+
+/// Options for a store and the logs loaded with it. The defaults match the behaviour of the
+/// constructors that do not take a config.
+///
+/// ```
+/// # use atomic_store::{AtomicStoreConfig, AtomicStoreLoader};
+/// # use std::time::Duration;
+/// # let dir = tempfile::tempdir().unwrap();
+/// let config = AtomicStoreConfig::default()
+///     .commit_timeout(Duration::from_secs(1))
+///     .retain_archives(16);
+/// let loader = AtomicStoreLoader::load_with_config(dir.path(), "store", config).unwrap();
+/// ```
+#[derive(Clone, Debug)]
+pub struct AtomicStoreConfig {
+    pub(crate) backend: Arc<dyn StorageBackend>,
+    pub(crate) commit_timeout: Duration,
+    pub(crate) retained_archives: Option<u32>,
+    pub(crate) fsync: FsyncPolicy,
+    // Whether `backend` has already been wrapped to honour `FsyncPolicy::Never`.
+    pub(crate) fsync_applied: bool,
+    pub(crate) backups: BackupPolicy,
+    pub(crate) append_log_index_file_size: u64,
+    pub(crate) rolling_log_retained_entries: u32,
+    pub(crate) unclaimed_resource_policy: UnclaimedResourcePolicy,
+    pub(crate) existing_store: ExistingStorePolicy,
+    pub(crate) backup_dir: Option<PathBuf>,
+    pub(crate) retained_backups: Option<u32>,
+    pub(crate) garbage_policy: GarbagePolicy,
+}
+
+impl Default for AtomicStoreConfig {
+    fn default() -> Self {
+        AtomicStoreConfig {
+            backend: Arc::new(FileSystemBackend),
+            commit_timeout: Duration::from_millis(100),
+            retained_archives: None,
+            fsync: FsyncPolicy::default(),
+            fsync_applied: false,
+            backups: BackupPolicy::default(),
+            append_log_index_file_size: 4096,
+            rolling_log_retained_entries: 128,
+            unclaimed_resource_policy: UnclaimedResourcePolicy::default(),
+            existing_store: ExistingStorePolicy::default(),
+            backup_dir: None,
+            retained_backups: Some(3),
+            garbage_policy: GarbagePolicy::default(),
+        }
+    }
+}
+
+impl AtomicStoreConfig {
+    /// Keep the store in `backend`, rather than the local file system.
+    pub fn backend(mut self, backend: Arc<dyn StorageBackend>) -> Self {
+        self.backend = backend;
+        self.fsync_applied = false;
+        self
+    }
+
+    /// How long `AtomicStore::commit_version` waits for every resource to commit or skip the
+    /// version; 100 milliseconds by default. `Duration::MAX` waits indefinitely.
+    pub fn commit_timeout(mut self, timeout: Duration) -> Self {
+        self.commit_timeout = timeout;
+        self
+    }
+
+    /// How many archived tables of contents to retain besides the latest one. By default, all
+    /// archives are retained.
+    pub fn retain_archives(mut self, retained_archives: u32) -> Self {
+        self.retained_archives = Some(retained_archives);
+        self
+    }
+
+    /// Whether files and directories are synced; `FsyncPolicy::Always` by default.
+    pub fn fsync(mut self, policy: FsyncPolicy) -> Self {
+        self.fsync = policy;
+        self
+    }
+
+    /// Whether logs keep backups of the files they truncate; `BackupPolicy::Keep` by default.
+    pub fn backups(mut self, policy: BackupPolicy) -> Self {
+        self.backups = policy;
+        self
+    }
+
+    /// How many entries each file of the index of an `AppendLog` holds; 4096 by default. This is
+    /// recorded with the index, so it cannot be changed for existing logs.
+    pub fn append_log_index_file_size(mut self, entries: u64) -> Self {
+        self.append_log_index_file_size = entries;
+        self
+    }
+
+    /// How many entries a `RollingLog` retains until `set_retained_entries` is called; 128 by
+    /// default.
+    pub fn rolling_log_retained_entries(mut self, entries: u32) -> Self {
+        self.rolling_log_retained_entries = entries;
+        self
+    }
+
+    /// How resources that are not loaded before `AtomicStore::open` are treated;
+    /// `UnclaimedResourcePolicy::CarryForward` by default.
+    pub fn unclaimed_resource_policy(mut self, policy: UnclaimedResourcePolicy) -> Self {
+        self.unclaimed_resource_policy = policy;
+        self
+    }
+
+    /// What `AtomicStoreLoader::create` does with an existing store; `ExistingStorePolicy::Backup`
+    /// by default.
+    pub fn existing_store(mut self, policy: ExistingStorePolicy) -> Self {
+        self.existing_store = policy;
+        self
+    }
+
+    /// The directory in which `AtomicStoreLoader::create` puts backups of existing stores. By
+    /// default, backups are kept in the store's own directory.
+    pub fn backup_dir(mut self, dir: &Path) -> Self {
+        self.backup_dir = Some(dir.to_path_buf());
+        self
+    }
+
+    /// How many backups `AtomicStoreLoader::create` retains in the backup directory, including the
+    /// one it has just made; the oldest are deleted. By default, 3 backups are retained.
+    pub fn retain_backups(mut self, retained_backups: u32) -> Self {
+        self.retained_backups = Some(retained_backups);
+        self
+    }
+
+    /// Which unreachable files `AtomicStore::collect_garbage` deletes; `GarbagePolicy::Report` by
+    /// default.
+    pub fn garbage_policy(mut self, policy: GarbagePolicy) -> Self {
+        self.garbage_policy = policy;
+        self
+    }
+
+    /// Apply the fsync policy to the backend, so that everything accessing the store's files
+    /// through `backend` honours it.
+    pub(crate) fn apply_fsync_policy(mut self) -> Self {
+        if self.fsync == FsyncPolicy::Never && !self.fsync_applied {
+            self.backend = Arc::new(UnsyncedBackend {
+                inner: self.backend,
+            });
+            self.fsync_applied = true;
+        }
+        self
+    }
+}
+
+// Forwards everything to the wrapped backend except syncs, which do nothing.
+#[derive(Debug)]
+struct UnsyncedBackend {
+    inner: Arc<dyn StorageBackend>,
+}
+
+impl StorageBackend for UnsyncedBackend {
+    fn open(&self, path: &Path, mode: OpenMode) -> io::Result<Box<dyn StorageFile>> {
+        Ok(Box::new(UnsyncedFile {
+            inner: self.inner.open(path, mode)?,
+        }))
+    }
+
+    fn stat(&self, path: &Path) -> io::Result<Option<EntryType>> {
+        self.inner.stat(path)
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        self.inner.create_dir_all(path)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        self.inner.rename(from, to)
+    }
+
+    fn remove(&self, path: &Path) -> io::Result<()> {
+        self.inner.remove(path)
+    }
+
+    fn list(&self, path: &Path) -> io::Result<Vec<String>> {
+        self.inner.list(path)
+    }
+
+    fn sync_dir(&self, _path: &Path) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn hard_link(&self, from: &Path, to: &Path) -> io::Result<()> {
+        self.inner.hard_link(from, to)
+    }
+
+    fn remove_dir(&self, path: &Path) -> io::Result<()> {
+        self.inner.remove_dir(path)
+    }
+
+    fn copy(&self, from: &Path, to: &Path) -> io::Result<u64> {
+        self.inner.copy(from, to)
+    }
+}
+
+#[derive(Debug)]
+struct UnsyncedFile {
+    inner: Box<dyn StorageFile>,
+}
+
+impl Read for UnsyncedFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(buf)
+    }
+}
+
+impl Write for UnsyncedFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl Seek for UnsyncedFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.inner.seek(pos)
+    }
+}
+
+impl StorageFile for UnsyncedFile {
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        self.inner.set_len(len)
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        // Data still has to reach the operating system, even if it is not synced.
+        self.inner.flush()
+    }
+
+    fn try_lock(&self) -> Result<(), TryLockError> {
+        self.inner.try_lock()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fault_injection::FaultInjectingBackend;
+    use crate::memory_backend::MemoryBackend;
+    use crate::{load_store::BincodeLoadStore, AppendLog, AtomicStore, AtomicStoreLoader};
+
+    // Commit one entry to an append log, returning how many operations modified storage.
+    fn count_operations(policy: FsyncPolicy) -> usize {
+        let backend = FaultInjectingBackend::new(Arc::new(MemoryBackend::new()), None);
+        let config = AtomicStoreConfig::default()
+            .backend(Arc::new(backend.clone()))
+            .fsync(policy);
+        let mut loader =
+            AtomicStoreLoader::load_with_config(Path::new("/store"), "fsync", config).unwrap();
+        let mut log =
+            AppendLog::load(&mut loader, BincodeLoadStore::<u64>::default(), "log", 16).unwrap();
+        let mut store = AtomicStore::open(loader).unwrap();
+        log.store_resource(&1).unwrap();
+        log.commit_version().unwrap();
+        store.commit_version().unwrap();
+        backend.operations()
+    }
+
+    #[test]
+    fn fsync_never_skips_syncs() {
+        // Syncs are counted as operations, so skipping them must leave fewer.
+        assert!(count_operations(FsyncPolicy::Never) < count_operations(FsyncPolicy::Always));
+    }
+
+    #[test]
+    fn fsync_never_still_writes() {
+        // Without syncs the data still reaches the backend, so a reopened store sees the commit.
+        let backend = Arc::new(MemoryBackend::new());
+        let path = Path::new("/store");
+        let config = || {
+            AtomicStoreConfig::default()
+                .backend(backend.clone())
+                .fsync(FsyncPolicy::Never)
+        };
+        {
+            let mut loader = AtomicStoreLoader::load_with_config(path, "fsync", config()).unwrap();
+            let mut log =
+                AppendLog::load(&mut loader, BincodeLoadStore::<u64>::default(), "log", 16)
+                    .unwrap();
+            let mut store = AtomicStore::open(loader).unwrap();
+            log.store_resource(&7).unwrap();
+            log.commit_version().unwrap();
+            store.commit_version().unwrap();
+        }
+        let mut loader = AtomicStoreLoader::load_with_config(path, "fsync", config()).unwrap();
+        let log =
+            AppendLog::load(&mut loader, BincodeLoadStore::<u64>::default(), "log", 16).unwrap();
+        assert_eq!(log.load_latest().unwrap(), 7);
+    }
+
+    #[test]
+    fn fsync_policy_applies_once() {
+        let config = AtomicStoreConfig::default()
+            .backend(Arc::new(MemoryBackend::new()))
+            .fsync(FsyncPolicy::Never)
+            .apply_fsync_policy();
+        let reapplied = config.clone().apply_fsync_policy();
+        assert_eq!(reapplied.fsync, FsyncPolicy::Never);
+        assert!(Arc::ptr_eq(&config.backend, &reapplied.backend));
+    }
+
+    #[test]
+    fn backup_policy() {
+        let path = Path::new("/store");
+        for policy in [BackupPolicy::Keep, BackupPolicy::Discard] {
+            let backend = Arc::new(MemoryBackend::new());
+            let config = || {
+                AtomicStoreConfig::default()
+                    .backend(backend.clone())
+                    .backups(policy)
+            };
+            {
+                let mut loader =
+                    AtomicStoreLoader::load_with_config(path, "backups", config()).unwrap();
+                let mut log =
+                    AppendLog::load(&mut loader, BincodeLoadStore::<u64>::default(), "log", 1024)
+                        .unwrap();
+                let mut store = AtomicStore::open(loader).unwrap();
+                log.store_resource(&1).unwrap();
+                log.commit_version().unwrap();
+                store.commit_version().unwrap();
+                // Written, but never committed.
+                log.store_resource(&2).unwrap();
+            }
+            let mut loader =
+                AtomicStoreLoader::load_with_config(path, "backups", config()).unwrap();
+            let mut log =
+                AppendLog::load(&mut loader, BincodeLoadStore::<u64>::default(), "log", 1024)
+                    .unwrap();
+            let mut store = AtomicStore::open(loader).unwrap();
+            log.store_resource(&3).unwrap();
+            log.commit_version().unwrap();
+            store.commit_version().unwrap();
+            assert_eq!(
+                log.iter().collect::<crate::Result<Vec<_>>>().unwrap(),
+                vec![1, 3]
+            );
+
+            let backups = backend
+                .list(path)
+                .unwrap()
+                .into_iter()
+                .filter(|name| name.contains(".bak."))
+                .count();
+            // Both the data file and the index are truncated.
+            assert_eq!(backups, if policy == BackupPolicy::Keep { 2 } else { 0 });
+        }
+    }
+
+    #[test]
+    fn append_log_index_file_size() {
+        let backend = Arc::new(MemoryBackend::new());
+        let path = Path::new("/store");
+        let config = AtomicStoreConfig::default()
+            .backend(backend.clone())
+            .append_log_index_file_size(2);
+        let mut loader = AtomicStoreLoader::load_with_config(path, "index", config).unwrap();
+        let mut log =
+            AppendLog::load(&mut loader, BincodeLoadStore::<u64>::default(), "log", 1024).unwrap();
+        let mut store = AtomicStore::open(loader).unwrap();
+        for i in 0..5 {
+            log.store_resource(&i).unwrap();
+        }
+        log.commit_version().unwrap();
+        store.commit_version().unwrap();
+        for range in ["0_2", "2_4", "4_6"] {
+            assert!(backend.is_file(&path.join(format!("log_index_{}", range))));
+        }
+        drop(log);
+        drop(store);
+
+        // The index size is recorded, so the log cannot be reopened with a different one.
+        let config = AtomicStoreConfig::default().backend(backend.clone());
+        let mut loader = AtomicStoreLoader::load_with_config(path, "index", config).unwrap();
+        assert!(
+            AppendLog::load(&mut loader, BincodeLoadStore::<u64>::default(), "log", 1024).is_err()
+        );
+    }
+
+    #[test]
+    fn commit_timeout() {
+        let config = AtomicStoreConfig::default()
+            .backend(Arc::new(MemoryBackend::new()))
+            .commit_timeout(Duration::from_millis(10));
+        let mut loader =
+            AtomicStoreLoader::load_with_config(Path::new("/store"), "timeout", config).unwrap();
+        let _log =
+            AppendLog::load(&mut loader, BincodeLoadStore::<u64>::default(), "log", 1024).unwrap();
+        let mut store = AtomicStore::open(loader).unwrap();
+        // The log never commits or skips the version.
+        assert!(matches!(
+            store.commit_version(),
+            Err(crate::PersistenceError::TimedOut { .. })
+        ));
+    }
+}
@@ -59,6 +59,61 @@ pub enum PersistenceError {
         /// Resource key/file pattern
         key: String,
     },
+    /// Resources in the table of contents were not loaded before opening the store
+    #[snafu(display("Resources in the table of contents were not loaded: {keys:?}"))]
+    UnclaimedResources {
+        /// Keys of the unclaimed resources
+        keys: Vec<String>,
+    },
+    /// Requested version of the table of contents does not exist
+    #[snafu(display("Version {version} of the table of contents does not exist"))]
+    VersionNotFound {
+        /// The requested file counter
+        version: u32,
+    },
+    /// Another loader or store holds the lock on this store
+    #[snafu(display("Store is locked by another owner (process {pid:?}) holding '{path}'"))]
+    StoreLocked {
+        /// The lock file
+        path: String,
+        /// The process ID recorded by the holder of the lock, if it could be read
+        pid: Option<u32>,
+    },
+    /// A store with the same file pattern already exists at the destination
+    #[snafu(display("A store already exists at '{path}'"))]
+    StoreAlreadyExists {
+        /// The table of contents found at the destination
+        path: String,
+    },
+    /// A resource cannot be removed while a log has loaded it
+    #[snafu(display("Resource '{key}' is loaded, and cannot be removed"))]
+    ResourceInUse {
+        /// The key of the resource
+        key: String,
+    },
+    /// A removed resource's key cannot be reused until its files have been deleted
+    #[snafu(display("Resource '{key}' was removed, and its files have not been deleted yet"))]
+    ResourceRetired {
+        /// The key of the resource
+        key: String,
+    },
+    /// A resource migrated from the unversioned format has no recorded kind, which is needed to
+    /// find its files
+    #[snafu(display("The kind of resource '{key}' is not known"))]
+    ResourceKindUnknown {
+        /// The key of the resource
+        key: String,
+    },
+    /// A speculative branch of a log has no entry at the requested index
+    #[snafu(display("Branch {branch} of '{key}' has no entry at index {index}"))]
+    BranchEntryNotFound {
+        /// The key of the log
+        key: String,
+        /// The ID of the branch
+        branch: u64,
+        /// The requested index within the branch
+        index: u64,
+    },
     /// Unimplemented feature
     #[snafu(display("Feature not yet implemented: {description}"))]
     FeatureNotYetImplemented { description: String },
@@ -95,7 +150,13 @@ pub enum PersistenceError {
     /// Placeholder for PoisonError specializations
     SyncPoison { description: String },
     /// `AtomicStore::commit_version` took to long to wait for Log versions and timed out
-    TimedOut,
+    #[snafu(display(
+        "Timed out waiting for resources to commit or skip the version: {pending:?}"
+    ))]
+    TimedOut {
+        /// Keys of the resources that had not committed or skipped the version
+        pending: Vec<String>,
+    },
     #[snafu(display("location {stored_location:?} stored by log is older than location {expected_location:?} in global index"))]
     LocationOutOfDate {
         stored_location: StorageLocation,
@@ -0,0 +1,256 @@
+// Copyright (c) 2022 Espresso Systems (espressosys.com)
+// This file is part of the AtomicStore library.
+
+// This program is free software: you can redistribute it and/or modify it under the terms of the GNU General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! A `StorageBackend` that simulates a crash at a chosen I/O operation, for testing recovery.
+//!
+//! The operations that can modify storage are counted from zero: writes (including `set_len`),
+//! renames, removals, hard links and syncs of files or directories. When the chosen operation is
+//! reached the fault is injected, after which the process is considered to have crashed: every
+//! further operation fails, and the contents of the wrapped backend are what a restarted process
+//! would find.
+
+use crate::storage_backend::{EntryType, OpenMode, StorageBackend, StorageFile};
+
+use std::fs::TryLockError;
+use std::io;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+/// What happens to the operation a fault is injected at.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum FaultKind {
+    /// The operation fails without taking effect.
+    Fail,
+    /// A write only stores the first half of its data before failing. Other operations fail
+    /// without taking effect.
+    Truncate,
+    /// The operation reports success without taking effect, as if it was lost from a volatile
+    /// cache. The crash happens at the next sync, which fails, or immediately if the dropped
+    /// operation was itself a sync.
+    Drop,
+}
+
+/// A fault injected at the `op`th operation that can modify storage.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Fault {
+    pub op: usize,
+    pub kind: FaultKind,
+}
+
+#[derive(Debug, Default)]
+struct Progress {
+    ops: usize,
+    injected: bool,
+    crashed: bool,
+}
+
+#[derive(Debug)]
+struct FaultState {
+    fault: Option<Fault>,
+    progress: Mutex<Progress>,
+}
+
+// How to carry out an operation that is allowed to proceed.
+enum Outcome {
+    Apply,
+    Truncate,
+    Skip,
+}
+
+fn crashed() -> io::Error {
+    io::Error::other("injected fault")
+}
+
+impl FaultState {
+    fn check(&self) -> io::Result<()> {
+        if self.progress.lock().unwrap().crashed {
+            Err(crashed())
+        } else {
+            Ok(())
+        }
+    }
+
+    fn next_op(&self, is_sync: bool) -> io::Result<Outcome> {
+        let mut progress = self.progress.lock().unwrap();
+        if progress.crashed {
+            return Err(crashed());
+        }
+        let op = progress.ops;
+        progress.ops += 1;
+        if progress.injected && is_sync {
+            // The sync following a dropped operation.
+            progress.crashed = true;
+            return Err(crashed());
+        }
+        match self.fault {
+            Some(fault) if fault.op == op => {
+                progress.injected = true;
+                match fault.kind {
+                    FaultKind::Fail => {
+                        progress.crashed = true;
+                        Err(crashed())
+                    }
+                    FaultKind::Truncate => {
+                        progress.crashed = true;
+                        Ok(Outcome::Truncate)
+                    }
+                    FaultKind::Drop => {
+                        progress.crashed = is_sync;
+                        Ok(Outcome::Skip)
+                    }
+                }
+            }
+            _ => Ok(Outcome::Apply),
+        }
+    }
+
+    fn mutate(&self, is_sync: bool, op: impl FnOnce() -> io::Result<()>) -> io::Result<()> {
+        match self.next_op(is_sync)? {
+            Outcome::Apply => op(),
+            Outcome::Truncate => Err(crashed()),
+            Outcome::Skip => Ok(()),
+        }
+    }
+}
+
+/// Wraps a backend, injecting at most one fault. Clones share the same operation counter.
+#[derive(Clone, Debug)]
+pub struct FaultInjectingBackend {
+    inner: Arc<dyn StorageBackend>,
+    state: Arc<FaultState>,
+}
+
+impl FaultInjectingBackend {
+    pub fn new(inner: Arc<dyn StorageBackend>, fault: Option<Fault>) -> FaultInjectingBackend {
+        FaultInjectingBackend {
+            inner,
+            state: Arc::new(FaultState {
+                fault,
+                progress: Mutex::new(Progress::default()),
+            }),
+        }
+    }
+
+    /// The number of operations that could modify storage so far, including any that failed.
+    pub fn operations(&self) -> usize {
+        self.state.progress.lock().unwrap().ops
+    }
+
+    /// Whether the fault has been injected. Errors after this point are expected.
+    pub fn fault_injected(&self) -> bool {
+        self.state.progress.lock().unwrap().injected
+    }
+
+    /// Whether the simulated crash has happened, so that every operation fails.
+    pub fn has_crashed(&self) -> bool {
+        self.state.progress.lock().unwrap().crashed
+    }
+}
+
+impl StorageBackend for FaultInjectingBackend {
+    fn open(&self, path: &Path, mode: OpenMode) -> io::Result<Box<dyn StorageFile>> {
+        self.state.check()?;
+        Ok(Box::new(FaultInjectingFile {
+            inner: self.inner.open(path, mode)?,
+            state: self.state.clone(),
+        }))
+    }
+
+    fn stat(&self, path: &Path) -> io::Result<Option<EntryType>> {
+        self.state.check()?;
+        self.inner.stat(path)
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        self.state.check()?;
+        self.inner.create_dir_all(path)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        self.state.mutate(false, || self.inner.rename(from, to))
+    }
+
+    fn remove(&self, path: &Path) -> io::Result<()> {
+        self.state.mutate(false, || self.inner.remove(path))
+    }
+
+    fn list(&self, path: &Path) -> io::Result<Vec<String>> {
+        self.state.check()?;
+        self.inner.list(path)
+    }
+
+    fn sync_dir(&self, path: &Path) -> io::Result<()> {
+        self.state.mutate(true, || self.inner.sync_dir(path))
+    }
+
+    fn hard_link(&self, from: &Path, to: &Path) -> io::Result<()> {
+        self.state.mutate(false, || self.inner.hard_link(from, to))
+    }
+
+    fn remove_dir(&self, path: &Path) -> io::Result<()> {
+        self.state.mutate(false, || self.inner.remove_dir(path))
+    }
+}
+
+#[derive(Debug)]
+struct FaultInjectingFile {
+    inner: Box<dyn StorageFile>,
+    state: Arc<FaultState>,
+}
+
+impl Read for FaultInjectingFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.state.check()?;
+        self.inner.read(buf)
+    }
+}
+
+impl Write for FaultInjectingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self.state.next_op(false)? {
+            Outcome::Apply => self.inner.write(buf),
+            Outcome::Truncate => {
+                self.inner.write_all(&buf[..buf.len() / 2])?;
+                Err(crashed())
+            }
+            Outcome::Skip => {
+                // Later writes still land after the lost data.
+                self.inner.seek(SeekFrom::Current(buf.len() as i64))?;
+                Ok(buf.len())
+            }
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.state.check()?;
+        self.inner.flush()
+    }
+}
+
+impl Seek for FaultInjectingFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.state.check()?;
+        self.inner.seek(pos)
+    }
+}
+
+impl StorageFile for FaultInjectingFile {
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        let inner = &mut self.inner;
+        self.state.mutate(false, || inner.set_len(len))
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        let inner = &mut self.inner;
+        self.state.mutate(true, || inner.sync())
+    }
+
+    fn try_lock(&self) -> Result<(), TryLockError> {
+        self.inner.try_lock()
+    }
+}
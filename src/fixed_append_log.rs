@@ -5,22 +5,27 @@
 // This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
 // You should have received a copy of the GNU General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
 
-use crate::atomic_store::AtomicStoreLoader;
+use crate::atomic_store::{AtomicStoreLoader, Inconsistency, InconsistencyKind, ReadOnlyLoader};
+use crate::branch::{BranchId, BranchedLog, Branches};
+use crate::config::{AtomicStoreConfig, BackupPolicy};
 use crate::error::{
     BincodeDeSnafu, BincodeSerSnafu, LocationOutOfDateSnafu, PersistenceError, StdIoDirOpsSnafu,
     StdIoOpenSnafu, StdIoReadSnafu, StdIoSeekSnafu, StdIoWriteSnafu,
 };
-use crate::load_store::LoadStore;
+use crate::load_store::{recorded_format, LoadStore};
+use crate::resource_descriptor::{ResourceDescriptor, ResourceKind};
+use crate::storage_backend::{EntryType, OpenMode, StorageBackend, StorageFile};
 use crate::storage_location::StorageLocation;
-use crate::utils::unix_timestamp;
+use crate::utils::{
+    copy_prefix, discard_backup, file_len, link_or_copy, read_file, sync_dir, unix_timestamp,
+};
 use crate::version_sync::VersionSyncHandle;
 use crate::Result;
 
+use regex::Regex;
 use serde::{Deserialize, Serialize};
 use snafu::{ensure, ResultExt};
 
-use std::fs;
-use std::fs::{File, OpenOptions};
 use std::io::{Read, Seek, SeekFrom, Write};
 use std::path::{Path, PathBuf};
 use std::sync::{Arc, RwLock};
@@ -37,21 +42,28 @@ struct IndexContents {
 const BYTE_ORDER: u32 = 0x8001FEFFu32;
 const BYTE_DISORDER: u32 = 0xFFFE0180u32;
 
-fn load_existing_index(index_file_path: &Path) -> Result<IndexContents> {
-    if !index_file_path.is_file() {
-        return Err(PersistenceError::InvalidPathToFile {
-            path: index_file_path.to_string_lossy().to_string(),
-        });
-    }
-    let metadata = fs::metadata(index_file_path).context(StdIoOpenSnafu)?;
-    if metadata.len() < 16 {
+fn load_existing_index(
+    backend: &dyn StorageBackend,
+    index_file_path: &Path,
+) -> Result<IndexContents> {
+    let len = match backend.stat(index_file_path).context(StdIoOpenSnafu)? {
+        Some(EntryType::File { len }) => len,
+        _ => {
+            return Err(PersistenceError::InvalidPathToFile {
+                path: index_file_path.to_string_lossy().to_string(),
+            });
+        }
+    };
+    if len < 16 {
         // file doesn't contain a minimal IndexContents
         return Err(PersistenceError::InvalidFileContents {
             note: "file doesn't contain a minimal IndexContents".to_string(),
             path: index_file_path.to_string_lossy().to_string(),
         });
     }
-    let mut index_file = File::open(index_file_path).context(StdIoOpenSnafu)?;
+    let mut index_file = backend
+        .open(index_file_path, OpenMode::Read)
+        .context(StdIoOpenSnafu)?;
     let mut buffer = Vec::new();
     index_file
         .read_to_end(&mut buffer)
@@ -100,6 +112,109 @@ fn format_range_file_path(
     buf
 }
 
+/// Matches the files of a fixed append log with `file_pattern`, including its index and backups of
+/// replaced files.
+pub(crate) fn resource_file_regex(file_pattern: &str) -> Regex {
+    let pattern = regex::escape(file_pattern);
+    Regex::new(&format!(
+        r"^({pattern}_\d+_\d+(\.bak\.-?\d+)?|{pattern}_index|\.{pattern}_index_(working|backup))$"
+    ))
+    .unwrap()
+}
+
+/// Whether `name` is an index file of a log with `file_pattern` that is only written during a
+/// commit: the new index before it replaces the old one, or the old index once it has been
+/// replaced.
+pub(crate) fn is_leftover_index_file(
+    backend: &dyn StorageBackend,
+    root_path: &Path,
+    file_pattern: &str,
+    name: &str,
+) -> bool {
+    let path = root_path.join(name);
+    if path == format_working_index_file_path(root_path, file_pattern) {
+        return true;
+    }
+    // The old index is only read if the log is loaded without the new one.
+    path == format_backup_index_file_path(root_path, file_pattern)
+        && backend.exists(&format_index_file_path(root_path, file_pattern))
+}
+
+// The index one past the entry at `location`, or 0 if nothing has been committed.
+fn committed_index(location: &Option<StorageLocation>, resource_size: u64, file_size: u64) -> u64 {
+    match location {
+        Some(location) => {
+            location.file_counter as u64 * file_size + location.store_start / resource_size + 1
+        }
+        None => 0,
+    }
+}
+
+fn write_index_file(
+    backend: &dyn StorageBackend,
+    index_file_path: &Path,
+    resource_size: u64,
+    file_size: u64,
+    commit_index: u64,
+) -> Result<()> {
+    let contents = IndexContents {
+        byte_order: BYTE_ORDER,
+        chunk_size: resource_size as u32,
+        file_size: file_size as u32,
+        commit_index: commit_index as u32,
+    };
+
+    let serialized = bincode::serialize(&contents).context(BincodeSerSnafu)?;
+
+    let mut write_index_file = backend
+        .open(index_file_path, OpenMode::Create)
+        .context(StdIoOpenSnafu)?;
+    write_index_file
+        .write_all(&serialized)
+        .context(StdIoWriteSnafu)?;
+    write_index_file.sync().context(StdIoDirOpsSnafu) // drop is not guaranteed to report errors
+}
+
+// Copy the files of a log committed at `location` from `src_pattern` in `src_path` to
+// `dest_pattern` in `dest_path`, along with an index recording that location. The range file
+// containing `location` is truncated just past it.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn checkpoint_files(
+    backend: &dyn StorageBackend,
+    src_path: &Path,
+    src_pattern: &str,
+    dest_path: &Path,
+    dest_pattern: &str,
+    location: &StorageLocation,
+    resource_size: u64,
+    file_size: u64,
+) -> Result<()> {
+    let commit_index = committed_index(&Some(*location), resource_size, file_size);
+    let last_range_begin = (commit_index - 1) / file_size * file_size;
+    for range_begin in (0..last_range_begin).step_by(file_size as usize) {
+        let range_end = range_begin + file_size;
+        link_or_copy(
+            backend,
+            &format_range_file_path(src_path, src_pattern, range_begin, range_end),
+            &format_range_file_path(dest_path, dest_pattern, range_begin, range_end),
+        )?;
+    }
+    let range_end = last_range_begin + file_size;
+    copy_prefix(
+        backend,
+        &format_range_file_path(src_path, src_pattern, last_range_begin, range_end),
+        &format_range_file_path(dest_path, dest_pattern, last_range_begin, range_end),
+        (commit_index - last_range_begin) * resource_size,
+    )?;
+    write_index_file(
+        backend,
+        &format_index_file_path(dest_path, dest_pattern),
+        resource_size,
+        file_size,
+        commit_index,
+    )
+}
+
 fn compute_location(from_index: &IndexContents) -> StorageLocation {
     let commit_start = if from_index.commit_index == 0 {
         0
@@ -113,26 +228,179 @@ fn compute_location(from_index: &IndexContents) -> StorageLocation {
     }
 }
 
+// Check the index and the range files of a log with `file_pattern` committed at `location`,
+// recording anything inconsistent in `issues`.
+pub(crate) fn verify_files(
+    backend: &dyn StorageBackend,
+    root_path: &Path,
+    file_pattern: &str,
+    location: &StorageLocation,
+    resource_size: u64,
+    file_size: u64,
+    issues: &mut Vec<Inconsistency>,
+) -> Result<()> {
+    let mut issue = |path: &Path, kind: InconsistencyKind| {
+        issues.push(Inconsistency::new(file_pattern, path, kind))
+    };
+    if resource_size == 0 || file_size == 0 {
+        issue(root_path, InconsistencyKind::EmptyLayout);
+        return Ok(());
+    }
+
+    // As when the log is loaded, the backed up index is only read if there is no other.
+    let mut index_file_path = format_index_file_path(root_path, file_pattern);
+    if !backend.exists(&index_file_path) {
+        index_file_path = format_backup_index_file_path(root_path, file_pattern);
+    }
+    match file_len(backend, &index_file_path)? {
+        None => issue(&index_file_path, InconsistencyKind::MissingFile),
+        Some(len) if len < 16 => issue(&index_file_path, InconsistencyKind::IndexTooShort { len }),
+        Some(_) => {
+            let buffer = read_file(backend, &index_file_path)?;
+            let contents: IndexContents =
+                bincode::deserialize(&buffer[..]).context(BincodeDeSnafu)?;
+            if contents.byte_order == BYTE_DISORDER {
+                issue(&index_file_path, InconsistencyKind::OppositeByteOrder);
+            } else if contents.byte_order != BYTE_ORDER {
+                issue(
+                    &index_file_path,
+                    InconsistencyKind::InvalidByteOrder {
+                        byte_order: contents.byte_order,
+                    },
+                );
+            } else if contents.chunk_size as u64 != resource_size
+                || contents.file_size as u64 != file_size
+            {
+                issue(
+                    &index_file_path,
+                    InconsistencyKind::LayoutMismatch {
+                        index_resource_size: contents.chunk_size as u64,
+                        index_file_size: contents.file_size as u64,
+                        resource_size,
+                        file_size,
+                    },
+                );
+            } else if compute_location(&contents) < *location {
+                issue(
+                    &index_file_path,
+                    InconsistencyKind::IndexBehindLocation {
+                        committed: compute_location(&contents),
+                        location: *location,
+                    },
+                );
+            }
+        }
+    }
+
+    if location.store_length as u64 != resource_size
+        || !location.store_start.is_multiple_of(resource_size)
+        || location.store_start / resource_size >= file_size
+    {
+        let range_begin = location.file_counter as u64 * file_size;
+        issue(
+            &format_range_file_path(
+                root_path,
+                file_pattern,
+                range_begin,
+                range_begin + file_size,
+            ),
+            InconsistencyKind::LocationNotAnEntry {
+                location: *location,
+            },
+        );
+        return Ok(());
+    }
+    let commit_index = committed_index(&Some(*location), resource_size, file_size);
+    for range_begin in (0..commit_index).step_by(file_size as usize) {
+        let range_end = range_begin + file_size;
+        let path = format_range_file_path(root_path, file_pattern, range_begin, range_end);
+        let committed_len = (commit_index.min(range_end) - range_begin) * resource_size;
+        match file_len(backend, &path)? {
+            None => issue(&path, InconsistencyKind::MissingFile),
+            Some(len) if len < committed_len => issue(
+                &path,
+                InconsistencyKind::FileTooShort {
+                    len,
+                    committed: committed_len,
+                },
+            ),
+            Some(_) => {}
+        }
+    }
+    Ok(())
+}
+
+/// The serialized entry of a log, with the range file it was read from.
+pub(crate) type RawEntry = (PathBuf, Vec<u8>);
+
+// Read the entries of a log with `file_pattern` committed at `location`. Returns `None` if a range file is missing or too short, which
+// `verify_files` reports.
+pub(crate) fn read_committed_entries(
+    backend: &dyn StorageBackend,
+    root_path: &Path,
+    file_pattern: &str,
+    location: &StorageLocation,
+    resource_size: u64,
+    file_size: u64,
+) -> Result<Option<Vec<RawEntry>>> {
+    if resource_size == 0 || file_size == 0 {
+        return Ok(None);
+    }
+    let commit_index = committed_index(&Some(*location), resource_size, file_size);
+    let mut entries = Vec::new();
+    for range_begin in (0..commit_index).step_by(file_size as usize) {
+        let range_end = range_begin + file_size;
+        let path = format_range_file_path(root_path, file_pattern, range_begin, range_end);
+        if !backend.exists(&path) {
+            return Ok(None);
+        }
+        let contents = read_file(backend, &path)?;
+        let committed_len = ((commit_index.min(range_end) - range_begin) * resource_size) as usize;
+        if contents.len() < committed_len {
+            return Ok(None);
+        }
+        entries.extend(
+            contents[..committed_len]
+                .chunks(resource_size as usize)
+                .map(|entry| (path.clone(), entry.to_vec())),
+        );
+    }
+    Ok(Some(entries))
+}
+
 /// For now, this is implemented with a direct copy from memory to file, using native order, but the order is recorded in a header, and can be used to support transfer in the future.
 #[derive(Debug)]
 pub struct FixedAppendLog<ResourceAdaptor: LoadStore> {
     persisted_sync: Arc<RwLock<VersionSyncHandle>>,
+    backend: Arc<dyn StorageBackend>,
+    backups: BackupPolicy,
     file_path: PathBuf,
     file_pattern: String,
     resource_size: u64, // must match ResourceAdaptor::ParamType serialized size.
     file_size: u64, // number of ResourceAdaptor::ParamType serializations per file; must not change, will check on load.
-    write_to_file: Option<File>,
+    write_to_file: Option<Box<dyn StorageFile>>,
     commit_index: u64, // index one past the last commit
     write_index: u64,  // other indexes can be derived.
+    branches: Box<Branches>,
     adaptor: ResourceAdaptor,
 }
 
+/// A `FixedAppendLog` opened from a `ReadOnlyLoader`, which reads the version recorded in the
+/// table of contents it was opened or last refreshed with.
+#[derive(Debug)]
+pub struct ReadOnlyFixedAppendLog<ResourceAdaptor: LoadStore> {
+    log: FixedAppendLog<ResourceAdaptor>,
+    store_pattern: String,
+    version: Option<u32>,
+}
+
 pub struct Iter<'a, ResourceAdaptor: LoadStore> {
+    backend: Arc<dyn StorageBackend>,
     file_path: PathBuf,
     file_pattern: String,
     resource_size: u64,
     file_size: u64,
-    read_from_file: Option<File>,
+    read_from_file: Option<Box<dyn StorageFile>>,
     from_index: u64,
     end_index: u64,
     adaptor: &'a ResourceAdaptor,
@@ -140,6 +408,7 @@ pub struct Iter<'a, ResourceAdaptor: LoadStore> {
 
 impl<ResourceAdaptor: LoadStore + Default> FixedAppendLog<ResourceAdaptor> {
     pub(crate) fn open_impl(
+        config: &AtomicStoreConfig,
         adaptor: ResourceAdaptor,
         location: Option<StorageLocation>,
         file_path: &Path,
@@ -149,14 +418,15 @@ impl<ResourceAdaptor: LoadStore + Default> FixedAppendLog<ResourceAdaptor> {
     ) -> Result<FixedAppendLog<ResourceAdaptor>> {
         let index_file_path = format_index_file_path(file_path, file_pattern);
         let backup_file_path = format_backup_index_file_path(file_path, file_pattern);
+        let backend = &config.backend;
         let commit_index;
         let write_index;
         if let Some(location) = location {
             // expect the files to exist; if files do not exist, make an attempt to recover the backed up index. Do not attempt to open an abandoned working index file.
-            let index_contents = if index_file_path.exists() {
-                load_existing_index(&index_file_path)
-            } else if backup_file_path.exists() {
-                load_existing_index(&backup_file_path)
+            let index_contents = if backend.exists(&index_file_path) {
+                load_existing_index(backend.as_ref(), &index_file_path)
+            } else if backend.exists(&backup_file_path) {
+                load_existing_index(backend.as_ref(), &backup_file_path)
             } else {
                 Err(PersistenceError::FailedToResolvePath {
                     path: index_file_path.as_path().to_string_lossy().to_string(),
@@ -179,7 +449,10 @@ impl<ResourceAdaptor: LoadStore + Default> FixedAppendLog<ResourceAdaptor> {
                     stored_location: indexed_location,
                 }
             );
-            commit_index = index_contents.commit_index as u64;
+            // The index may have been committed past the location in the global index (if the store
+            // was not committed afterwards, or was reopened at an older version); only entries up to
+            // that location are part of the loaded version.
+            commit_index = committed_index(&Some(location), resource_size, file_size);
             write_index = commit_index;
         } else {
             commit_index = 0u64;
@@ -187,6 +460,8 @@ impl<ResourceAdaptor: LoadStore + Default> FixedAppendLog<ResourceAdaptor> {
         }
         Ok(FixedAppendLog {
             persisted_sync: Arc::new(RwLock::new(VersionSyncHandle::new(file_pattern, location))),
+            backend: backend.clone(),
+            backups: config.backups,
             file_path: file_path.to_path_buf(),
             file_pattern: file_pattern.to_string(),
             resource_size,
@@ -194,6 +469,7 @@ impl<ResourceAdaptor: LoadStore + Default> FixedAppendLog<ResourceAdaptor> {
             write_to_file: None,
             commit_index,
             write_index,
+            branches: Box::new(Branches::open(backend.clone(), file_path, file_pattern)?),
             adaptor,
         })
     }
@@ -204,15 +480,19 @@ impl<ResourceAdaptor: LoadStore + Default> FixedAppendLog<ResourceAdaptor> {
         resource_size: u64,
         file_size: u64,
     ) -> Result<FixedAppendLog<ResourceAdaptor>> {
+        let descriptor = Self::descriptor(&adaptor, resource_size, file_size);
+        let location =
+            loader.look_up_resource(file_pattern, &descriptor, adaptor.format_id().is_some())?;
         let created = Self::open_impl(
+            loader.config(),
             adaptor,
-            loader.look_up_resource(file_pattern),
+            location,
             loader.persistence_path(),
             file_pattern,
             resource_size,
             file_size,
         )?;
-        loader.add_sync_handle(file_pattern, created.persisted_sync.clone())?;
+        loader.add_sync_handle(file_pattern, created.persisted_sync.clone(), descriptor)?;
         Ok(created)
     }
     pub fn create(
@@ -222,7 +502,9 @@ impl<ResourceAdaptor: LoadStore + Default> FixedAppendLog<ResourceAdaptor> {
         resource_size: u64,
         file_size: u64,
     ) -> Result<FixedAppendLog<ResourceAdaptor>> {
+        let descriptor = Self::descriptor(&adaptor, resource_size, file_size);
         let created = Self::open_impl(
+            loader.config(),
             adaptor,
             None,
             loader.persistence_path(),
@@ -230,13 +512,65 @@ impl<ResourceAdaptor: LoadStore + Default> FixedAppendLog<ResourceAdaptor> {
             resource_size,
             file_size,
         )?;
-        loader.add_sync_handle(file_pattern, created.persisted_sync.clone())?;
+        loader.add_sync_handle(file_pattern, created.persisted_sync.clone(), descriptor)?;
         Ok(created)
     }
 
+    /// Open the log for reading at the version recorded in `loader`. Unlike `load`, the log's
+    /// files are not checked or modified, and the log is not registered with the loader.
+    pub fn open_read_only(
+        loader: &ReadOnlyLoader,
+        adaptor: ResourceAdaptor,
+        file_pattern: &str,
+        resource_size: u64,
+        file_size: u64,
+    ) -> Result<ReadOnlyFixedAppendLog<ResourceAdaptor>> {
+        let descriptor = Self::descriptor(&adaptor, resource_size, file_size);
+        let location =
+            loader.look_up_resource(file_pattern, &descriptor, adaptor.format_id().is_some())?;
+        let commit_index = committed_index(&location, resource_size, file_size);
+        let log = FixedAppendLog {
+            persisted_sync: Arc::new(RwLock::new(VersionSyncHandle::new(file_pattern, location))),
+            backend: loader.backend().clone(),
+            backups: loader.config().backups,
+            file_path: loader.persistence_path().to_path_buf(),
+            file_pattern: file_pattern.to_string(),
+            resource_size,
+            file_size,
+            write_to_file: None,
+            commit_index,
+            write_index: commit_index,
+            branches: Box::new(Branches::new(
+                loader.backend().clone(),
+                loader.persistence_path(),
+                file_pattern,
+            )),
+            adaptor,
+        };
+        Ok(ReadOnlyFixedAppendLog {
+            log,
+            store_pattern: loader.file_pattern().to_string(),
+            version: loader.version(),
+        })
+    }
+
+    pub(crate) fn descriptor(
+        adaptor: &ResourceAdaptor,
+        resource_size: u64,
+        file_size: u64,
+    ) -> ResourceDescriptor {
+        ResourceDescriptor {
+            kind: ResourceKind::FixedAppendLog {
+                resource_size,
+                file_size,
+            },
+            format: recorded_format(adaptor),
+        }
+    }
+
     fn location_to_index(&self, location: &StorageLocation) -> Result<u64> {
         if location.store_length as u64 != self.resource_size
-            || location.store_start % self.resource_size != 0
+            || !location.store_start.is_multiple_of(self.resource_size)
         {
             Err(PersistenceError::ResourceFormatInconsistent {
                 key: self.file_pattern.clone(),
@@ -262,15 +596,17 @@ impl<ResourceAdaptor: LoadStore + Default> FixedAppendLog<ResourceAdaptor> {
         let range_end = range_begin + self.file_size;
         let out_file_path =
             format_range_file_path(&self.file_path, &self.file_pattern, range_begin, range_end);
-        if out_file_path.exists() {
-            if !out_file_path.is_file() {
+        // Whether the directory entries change, and must be synced once the file is open.
+        let mut dir_changed = !self.backend.exists(&out_file_path);
+        if self.backend.exists(&out_file_path) {
+            if !self.backend.is_file(&out_file_path) {
                 return Err(PersistenceError::InvalidPathToFile {
                     path: out_file_path.to_string_lossy().to_string(),
                 });
             }
 
-            if let Ok(metadata) = fs::metadata(&out_file_path) {
-                if metadata.len() > write_pos {
+            if let Ok(Some(EntryType::File { len })) = self.backend.stat(&out_file_path) {
+                if len > write_pos {
                     let mut backup_path = self.file_path.clone();
                     backup_path.push(format!(
                         "{}_{}_{}.bak.{}",
@@ -279,22 +615,31 @@ impl<ResourceAdaptor: LoadStore + Default> FixedAppendLog<ResourceAdaptor> {
                         range_end,
                         unix_timestamp()
                     ));
+                    // The file is replaced rather than truncated in place, as it may be hard linked
+                    // by a checkpoint.
+                    self.backend
+                        .rename(&out_file_path, &backup_path)
+                        .context(StdIoDirOpsSnafu)?;
                     if file_index > 0 {
-                        fs::copy(&out_file_path, &backup_path).context(StdIoDirOpsSnafu)?;
-                    } else {
-                        fs::rename(&out_file_path, &backup_path).context(StdIoDirOpsSnafu)?;
+                        self.backend
+                            .copy(&backup_path, &out_file_path)
+                            .context(StdIoDirOpsSnafu)?;
                     }
+                    if self.backups == BackupPolicy::Discard {
+                        discard_backup(self.backend.as_ref(), &backup_path, &out_file_path)?;
+                    }
+                    dir_changed = true;
                 }
             }
         }
 
-        #[allow(clippy::suspicious_open_options)]
-        let mut file = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .open(out_file_path)
+        let mut file = self
+            .backend
+            .open(&out_file_path, OpenMode::ReadWrite)
             .context(StdIoOpenSnafu)?;
+        if dir_changed {
+            sync_dir(self.backend.as_ref(), &self.file_path)?;
+        }
         file.seek(SeekFrom::End(0)).context(StdIoSeekSnafu)?;
         if file.stream_position().context(StdIoSeekSnafu)? != write_pos {
             file.set_len(write_pos).context(StdIoWriteSnafu)?;
@@ -307,29 +652,31 @@ impl<ResourceAdaptor: LoadStore + Default> FixedAppendLog<ResourceAdaptor> {
     }
 
     // Writes out a resource instance; does not update the commit position, but in this version, does advance the pending commit position.
-    // In the future, we may support a queue of commit points, or even entire sequences of pre-written alternative future versions (for chained consensus), which would require a more complex interface.
     pub fn store_resource(
         &mut self,
         resource: &ResourceAdaptor::ParamType,
     ) -> Result<StorageLocation> {
+        let serialized = self.adaptor.store(resource)?;
+        self.store_serialized(&serialized)
+    }
+
+    fn store_serialized(&mut self, serialized: &[u8]) -> Result<StorageLocation> {
         if self.write_to_file.is_none() {
             self.open_write_file()?;
         }
-        let serialized = self.adaptor.store(resource)?;
         debug_assert_eq!(serialized.len() as u64, self.resource_size);
         self.write_to_file
-            .as_ref()
+            .as_mut()
             .unwrap()
-            .write_all(&serialized)
+            .write_all(serialized)
             .context(StdIoWriteSnafu)?;
 
         let location = self.index_to_location(self.write_index);
 
         self.write_index += 1;
-        if self.write_index % self.file_size == 0 {
+        if self.write_index.is_multiple_of(self.file_size) {
             if let Some(ref mut file) = self.write_to_file {
-                file.flush().context(StdIoWriteSnafu)?; // drop is not guaranteed to report errors
-                file.sync_all().context(StdIoDirOpsSnafu)?;
+                file.sync().context(StdIoDirOpsSnafu)?; // drop is not guaranteed to report errors
             }
             self.write_to_file = None;
         }
@@ -337,7 +684,7 @@ impl<ResourceAdaptor: LoadStore + Default> FixedAppendLog<ResourceAdaptor> {
         Ok(location)
     }
 
-    // This currenty won't have any effect if called again before the atomic store has processed the prior committed version. A more appropriate behavior might be to block. A version that supports queued writes could enqueue the commit points.
+    // If called again before the atomic store has processed the prior committed version, the commit points are queued, and recorded by successive versions of the store.
     pub fn commit_version(&mut self) -> Result<()> {
         let index_file_path = format_index_file_path(&self.file_path, &self.file_pattern);
         let backup_file_path = format_backup_index_file_path(&self.file_path, &self.file_pattern);
@@ -345,37 +692,91 @@ impl<ResourceAdaptor: LoadStore + Default> FixedAppendLog<ResourceAdaptor> {
 
         self.commit_index = self.write_index;
 
-        let contents = IndexContents {
-            byte_order: BYTE_ORDER,
-            chunk_size: self.resource_size as u32,
-            file_size: self.file_size as u32,
-            commit_index: self.commit_index as u32,
-        };
-
-        let serialized = bincode::serialize(&contents).context(BincodeSerSnafu)?;
-
         if let Some(ref mut file) = self.write_to_file {
-            file.flush().context(StdIoWriteSnafu)?; // drop is not guaranteed to report errors
-            file.sync_all().context(StdIoDirOpsSnafu)?;
+            file.sync().context(StdIoDirOpsSnafu)?; // drop is not guaranteed to report errors
         }
 
-        let mut write_index_file = File::create(&working_file_path).context(StdIoOpenSnafu)?;
-        write_index_file
-            .write_all(&serialized)
-            .context(StdIoWriteSnafu)?;
-        write_index_file.flush().context(StdIoWriteSnafu)?; // drop is not guaranteed to report errors
-        write_index_file.sync_all().context(StdIoDirOpsSnafu)?;
-        if index_file_path.exists() {
-            if backup_file_path.exists() {
-                fs::remove_file(&backup_file_path).context(StdIoDirOpsSnafu)?;
+        write_index_file(
+            self.backend.as_ref(),
+            &working_file_path,
+            self.resource_size,
+            self.file_size,
+            self.commit_index,
+        )?;
+        if self.backend.exists(&index_file_path) {
+            if self.backend.exists(&backup_file_path) {
+                self.backend
+                    .remove(&backup_file_path)
+                    .context(StdIoDirOpsSnafu)?;
             }
-            fs::rename(&index_file_path, &backup_file_path).context(StdIoDirOpsSnafu)?;
+            self.backend
+                .rename(&index_file_path, &backup_file_path)
+                .context(StdIoDirOpsSnafu)?;
         }
-        fs::rename(&working_file_path, &index_file_path).context(StdIoDirOpsSnafu)?;
+        self.backend
+            .rename(&working_file_path, &index_file_path)
+            .context(StdIoDirOpsSnafu)?;
+        sync_dir(self.backend.as_ref(), &self.file_path)?;
 
         self.persisted_sync.write()?.update_version()
     }
 
+    /// Write a resource instance to the speculative `branch`, returning its index within the
+    /// branch. The log itself is unchanged until the branch is committed.
+    pub fn store_branch_resource(
+        &mut self,
+        branch: BranchId,
+        resource: &ResourceAdaptor::ParamType,
+    ) -> Result<u64> {
+        let serialized = self.adaptor.store(resource)?;
+        debug_assert_eq!(serialized.len() as u64, self.resource_size);
+        self.branches.append(branch, &serialized)
+    }
+
+    /// Load the resource instance at `index` of the speculative `branch`.
+    pub fn load_branch_resource(
+        &self,
+        branch: BranchId,
+        index: u64,
+    ) -> Result<ResourceAdaptor::ParamType> {
+        self.adaptor.load(&self.branches.read(branch, index)?)
+    }
+
+    /// The number of resource instances written to `branch`; zero for a branch that has not been
+    /// written to.
+    pub fn branch_len(&self, branch: BranchId) -> u64 {
+        self.branches.len(branch)
+    }
+
+    /// Decide on `branch`: append its resource instances to the log after any stored since the
+    /// last commit, discard every branch, and commit the version. A branch that was never written
+    /// to commits no new instances. To decide on a branch of several logs of a store at once, use
+    /// `AtomicStore::commit_branch`.
+    ///
+    /// If an instance cannot be appended or the version cannot be committed, the log is reverted
+    /// to its last commit, dropping anything stored since, and the branches are kept.
+    pub fn commit_branch(&mut self, branch: BranchId) -> Result<()> {
+        if let Err(err) = self
+            .append_branch(branch)
+            .and_then(|()| self.commit_version())
+        {
+            if let Err(revert_err) = self.revert_version() {
+                tracing::warn!(
+                    err = %revert_err,
+                    key = %self.file_pattern,
+                    "failed to revert log after failing to commit a branch",
+                );
+            }
+            return Err(err);
+        }
+        self.branches.discard()
+    }
+
+    /// Discard every branch without committing any of them.
+    pub fn discard_branches(&mut self) -> Result<()> {
+        self.branches.discard()
+    }
+
     pub fn skip_version(&mut self) -> Result<()> {
         self.persisted_sync.write()?.skip_version()
     }
@@ -405,13 +806,16 @@ impl<ResourceAdaptor: LoadStore + Default> FixedAppendLog<ResourceAdaptor> {
     // this works like the LogLoader, but doesn't keep resources after the call completes.
     pub fn load_at(&self, index: u64) -> Result<ResourceAdaptor::ParamType> {
         let file_index = index % self.file_size;
-        let file_offset = index * self.resource_size;
+        let file_offset = file_index * self.resource_size;
         let range_begin = index - file_index;
         let range_end = range_begin + self.file_size;
         let read_file_path =
             format_range_file_path(&self.file_path, &self.file_pattern, range_begin, range_end);
 
-        let mut read_file = File::open(read_file_path).context(StdIoOpenSnafu)?;
+        let mut read_file = self
+            .backend
+            .open(&read_file_path, OpenMode::Read)
+            .context(StdIoOpenSnafu)?;
         read_file
             .seek(SeekFrom::Start(file_offset))
             .context(StdIoSeekSnafu)?;
@@ -421,8 +825,9 @@ impl<ResourceAdaptor: LoadStore + Default> FixedAppendLog<ResourceAdaptor> {
         self.adaptor.load(&buffer[..])
     }
 
-    pub fn iter(&self) -> Iter<ResourceAdaptor> {
+    pub fn iter(&self) -> Iter<'_, ResourceAdaptor> {
         Iter {
+            backend: self.backend.clone(),
             file_path: self.file_path.clone(),
             file_pattern: self.file_pattern.clone(),
             resource_size: self.resource_size,
@@ -435,6 +840,88 @@ impl<ResourceAdaptor: LoadStore + Default> FixedAppendLog<ResourceAdaptor> {
     }
 }
 
+impl<ResourceAdaptor: LoadStore + Default> BranchedLog for FixedAppendLog<ResourceAdaptor> {
+    fn resource_key(&self) -> &str {
+        &self.file_pattern
+    }
+
+    fn append_branch(&mut self, branch: BranchId) -> Result<()> {
+        for index in 0..self.branches.len(branch) {
+            let serialized = self.branches.read(branch, index)?;
+            self.store_serialized(&serialized)?;
+        }
+        Ok(())
+    }
+
+    fn commit_version(&mut self) -> Result<()> {
+        FixedAppendLog::commit_version(self)
+    }
+
+    fn revert_version(&mut self) -> Result<()> {
+        FixedAppendLog::revert_version(self)
+    }
+
+    fn discard_branches(&mut self) -> Result<()> {
+        FixedAppendLog::discard_branches(self)
+    }
+}
+
+impl<ResourceAdaptor: LoadStore + Default> ReadOnlyFixedAppendLog<ResourceAdaptor> {
+    /// Pick up the newest committed version of the store. Returns whether a different version of
+    /// the table of contents was read.
+    pub fn refresh(&mut self) -> Result<bool> {
+        let loader = AtomicStoreLoader::open_read_only_with_config(
+            &self.log.file_path,
+            &self.store_pattern,
+            AtomicStoreConfig::default().backend(self.log.backend.clone()),
+        )?;
+        if loader.version() == self.version {
+            return Ok(false);
+        }
+        self.update(&loader)?;
+        Ok(true)
+    }
+
+    pub(crate) fn update(&mut self, loader: &ReadOnlyLoader) -> Result<()> {
+        let log = &mut self.log;
+        let descriptor = FixedAppendLog::descriptor(&log.adaptor, log.resource_size, log.file_size);
+        let location = loader.look_up_resource(
+            &log.file_pattern,
+            &descriptor,
+            log.adaptor.format_id().is_some(),
+        )?;
+        log.commit_index = committed_index(&location, log.resource_size, log.file_size);
+        log.write_index = log.commit_index;
+        log.persisted_sync = Arc::new(RwLock::new(VersionSyncHandle::new(
+            &log.file_pattern,
+            location,
+        )));
+        self.version = loader.version();
+        Ok(())
+    }
+
+    /// The counter of the table of contents this log was last read from.
+    pub fn version(&self) -> Option<u32> {
+        self.version
+    }
+
+    pub fn load_latest(&self) -> Result<ResourceAdaptor::ParamType> {
+        self.log.load_latest()
+    }
+
+    pub fn load_specified(&self, location: &StorageLocation) -> Result<ResourceAdaptor::ParamType> {
+        self.log.load_specified(location)
+    }
+
+    pub fn load_at(&self, index: u64) -> Result<ResourceAdaptor::ParamType> {
+        self.log.load_at(index)
+    }
+
+    pub fn iter(&self) -> Iter<'_, ResourceAdaptor> {
+        self.log.iter()
+    }
+}
+
 impl<ResourceAdaptor: LoadStore> Iter<'_, ResourceAdaptor> {
     fn helper(&mut self) -> Result<ResourceAdaptor::ParamType> {
         let file_offset = self.from_index % self.file_size;
@@ -443,10 +930,14 @@ impl<ResourceAdaptor: LoadStore> Iter<'_, ResourceAdaptor> {
         if self.read_from_file.is_none() {
             let file_name =
                 format_range_file_path(&self.file_path, &self.file_pattern, range_begin, range_end);
-            self.read_from_file = Some(File::open(file_name).context(StdIoOpenSnafu)?);
+            self.read_from_file = Some(
+                self.backend
+                    .open(&file_name, OpenMode::Read)
+                    .context(StdIoOpenSnafu)?,
+            );
             if file_offset > 0 {
                 self.read_from_file
-                    .as_ref()
+                    .as_mut()
                     .unwrap()
                     .seek(SeekFrom::Start(file_offset * self.resource_size))
                     .context(StdIoSeekSnafu)?;
@@ -454,7 +945,7 @@ impl<ResourceAdaptor: LoadStore> Iter<'_, ResourceAdaptor> {
         }
         let mut reader = self
             .read_from_file
-            .as_ref()
+            .as_mut()
             .unwrap()
             .take(self.resource_size);
         let mut buffer = Vec::new();
@@ -473,7 +964,7 @@ impl<ResourceAdaptor: LoadStore> Iterator for Iter<'_, ResourceAdaptor> {
         }
         let resource = self.helper();
         self.from_index += 1;
-        if self.from_index % self.file_size == 0 {
+        if self.from_index.is_multiple_of(self.file_size) {
             self.read_from_file = None;
         }
         Some(resource)
@@ -544,4 +1035,35 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn load_at_later_range_file() -> Result<()> {
+        let mut test_path =
+            env::current_dir().map_err(|e| PersistenceError::StdIoDirOps { source: e })?;
+        test_path.push("testing_tmp");
+        let mut store_loader = AtomicStoreLoader::create(
+            test_path.as_path(),
+            "fixed_append_log_test_load_at_later_range_file",
+        )?;
+        let mut persisted_thing = FixedAppendLog::create(
+            &mut store_loader,
+            <BincodeLoadStore<Thing>>::default(),
+            "fixed_append_thing_load_at",
+            16,
+            2,
+        )?;
+        let mut atomic_store = AtomicStore::open(store_loader)?;
+        for t1 in 0..5 {
+            persisted_thing.store_resource(&Thing { t1, t2: -t1 })?;
+        }
+        persisted_thing.commit_version()?;
+        atomic_store.commit_version()?;
+
+        // Entries 2 and 3 are the first and second of the second range file.
+        for index in 0..5 {
+            let thing = persisted_thing.load_at(index)?;
+            assert_eq!((thing.t1, thing.t2), (index as i64, -(index as i64)));
+        }
+        Ok(())
+    }
 }
@@ -12,19 +12,28 @@ mod utils;
 
 pub mod append_log;
 pub mod atomic_store;
+pub mod branch;
+pub mod config;
 pub mod error;
+pub mod fault_injection;
 pub mod fixed_append_log;
 pub mod load_store;
+pub mod memory_backend;
+pub mod resource_descriptor;
 pub mod rolling_log;
+pub mod storage_backend;
 pub mod storage_location;
+pub mod two_phase_commit;
 pub mod version_sync;
 
 pub use crate::{
     append_log::AppendLog,
     atomic_store::{AtomicStore, AtomicStoreLoader},
+    config::AtomicStoreConfig,
     error::PersistenceError,
     fixed_append_log::FixedAppendLog,
     rolling_log::RollingLog,
+    two_phase_commit::CommitCoordinator,
 };
 
 /// Convenience type alias
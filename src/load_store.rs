@@ -20,6 +20,21 @@ pub trait LoadStore {
 
     fn load(&self, stream: &[u8]) -> Result<Self::ParamType>;
     fn store(&mut self, param: &Self::ParamType) -> Result<Vec<u8>>;
+
+    /// A stable identifier of the serialized format. If there is one, it is recorded in the table
+    /// of contents, and a resource recorded with a different format cannot be reopened with this
+    /// adaptor. Otherwise, the name of the adaptor type is recorded for information only, as type
+    /// names can change with the compiler version, or when a type is moved or renamed.
+    fn format_id(&self) -> Option<String> {
+        None
+    }
+}
+
+/// The format recorded in the table of contents for a resource serialized by `adaptor`.
+pub(crate) fn recorded_format<ResourceAdaptor: LoadStore>(adaptor: &ResourceAdaptor) -> String {
+    adaptor
+        .format_id()
+        .unwrap_or_else(|| std::any::type_name::<ResourceAdaptor>().to_string())
 }
 
 #[derive(Debug)]
@@ -75,6 +90,37 @@ impl<ParamType: CanonicalSerialize + CanonicalDeserialize> Default for ArkLoadSt
     }
 }
 
+/// Passes serialized resources through unchanged, for tools that read a resource without knowing
+/// its type. It reports the format it is created with, so that it can open a resource written by
+/// any adaptor.
+#[derive(Debug, Default)]
+pub struct RawLoadStore {
+    format: String,
+}
+
+impl RawLoadStore {
+    /// An adaptor for a resource written in `format`, as recorded in its `ResourceDescriptor`.
+    pub fn new(format: &str) -> Self {
+        RawLoadStore {
+            format: format.to_string(),
+        }
+    }
+}
+
+impl LoadStore for RawLoadStore {
+    type ParamType = Vec<u8>;
+
+    fn load(&self, stream: &[u8]) -> Result<Self::ParamType> {
+        Ok(stream.to_vec())
+    }
+    fn store(&mut self, param: &Self::ParamType) -> Result<Vec<u8>> {
+        Ok(param.clone())
+    }
+    fn format_id(&self) -> Option<String> {
+        Some(self.format.clone())
+    }
+}
+
 // #[derive(Debug, Default)]
 // pub struct StorageLocationLoadStore;
 
@@ -0,0 +1,610 @@
+// Copyright (c) 2022 Espresso Systems (espressosys.com)
+// This file is part of the AtomicStore library.
+
+// This program is free software: you can redistribute it and/or modify it under the terms of the GNU General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! A `StorageBackend` that keeps all files in memory, for tests that simulate restarts and power
+//! loss rather than needing real durability.
+
+use crate::storage_backend::{EntryType, OpenMode, StorageBackend, StorageFile};
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::TryLockError;
+use std::io;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+type FileContents = Arc<Mutex<Vec<u8>>>;
+
+#[derive(Debug, Clone)]
+enum Entry {
+    // Hard links share their contents.
+    File(FileContents),
+    Directory,
+}
+
+/// Keeps a directory tree in memory. Clones share the same tree, so a store can be loaded again
+/// from the files left by a previous instance; `reopen` takes an independent copy instead, as if
+/// the process had restarted, and `lose_power` one with only the directory entries that were
+/// synced, as if the machine had lost power.
+///
+/// Writes to files are durable as soon as they are made. A file locked with `try_lock` stays
+/// locked until the file is closed, for every clone of the backend; the copies made by `reopen`
+/// and `lose_power` start without any locks, like a restarted process.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryBackend {
+    entries: Arc<Mutex<BTreeMap<PathBuf, Entry>>>,
+    // The entries as of the last `sync_dir` of their directories. Always locked after `entries`.
+    synced_entries: Arc<Mutex<BTreeMap<PathBuf, Entry>>>,
+    locks: FileLocks,
+}
+
+// The contents of the files currently locked, identified by address. A locked file is kept alive
+// by the `MemoryFile` holding the lock, so its address cannot be reused while it is in the set.
+type FileLocks = Arc<Mutex<HashSet<usize>>>;
+
+fn not_found() -> io::Error {
+    io::ErrorKind::NotFound.into()
+}
+
+fn lock_contents(contents: &FileContents) -> MutexGuard<'_, Vec<u8>> {
+    // A panic while holding the lock cannot leave the contents partially updated.
+    contents
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+impl MemoryBackend {
+    pub fn new() -> MemoryBackend {
+        MemoryBackend::default()
+    }
+
+    /// Copy the current contents of every file into a new backend. Files that are open through
+    /// this backend, such as those held by a store that was never shut down, cannot modify the
+    /// copy.
+    pub fn reopen(&self) -> MemoryBackend {
+        MemoryBackend::from_entries(&self.lock())
+    }
+
+    /// Like `reopen`, but files created, renamed or removed since their directory was last synced
+    /// with `sync_dir` are as they were at that sync, as if the machine had lost power. The
+    /// contents of files are kept.
+    pub fn lose_power(&self) -> MemoryBackend {
+        let _entries = self.lock();
+        let synced = self.lock_synced();
+        // An entry only survives if every directory above it does.
+        let surviving = synced
+            .iter()
+            .filter(|(path, _)| {
+                path.ancestors()
+                    .skip(1)
+                    .filter(|ancestor| !is_root(ancestor))
+                    .all(|ancestor| matches!(synced.get(ancestor), Some(Entry::Directory)))
+            })
+            .map(|(path, entry)| (path.clone(), entry.clone()))
+            .collect();
+        MemoryBackend::from_entries(&surviving)
+    }
+
+    fn from_entries(entries: &BTreeMap<PathBuf, Entry>) -> MemoryBackend {
+        // Copy each file once, so that hard links still share their contents in the copy.
+        let mut copied: HashMap<*const Mutex<Vec<u8>>, FileContents> = HashMap::new();
+        let new_entries: BTreeMap<PathBuf, Entry> = entries
+            .iter()
+            .map(|(path, entry)| {
+                let entry = match entry {
+                    Entry::File(contents) => Entry::File(
+                        copied
+                            .entry(Arc::as_ptr(contents))
+                            .or_insert_with(|| {
+                                Arc::new(Mutex::new(lock_contents(contents).clone()))
+                            })
+                            .clone(),
+                    ),
+                    Entry::Directory => Entry::Directory,
+                };
+                (path.clone(), entry)
+            })
+            .collect();
+        // Everything that survived a restart is durable.
+        MemoryBackend {
+            synced_entries: Arc::new(Mutex::new(new_entries.clone())),
+            entries: Arc::new(Mutex::new(new_entries)),
+            locks: FileLocks::default(),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, BTreeMap<PathBuf, Entry>> {
+        self.entries
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn lock_synced(&self) -> MutexGuard<'_, BTreeMap<PathBuf, Entry>> {
+        self.synced_entries
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+// The root of the tree, and paths without a parent, always exist as directories.
+fn parent_exists(entries: &BTreeMap<PathBuf, Entry>, path: &Path) -> bool {
+    match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() && parent.parent().is_some() => {
+            matches!(entries.get(parent), Some(Entry::Directory))
+        }
+        _ => true,
+    }
+}
+
+fn is_root(path: &Path) -> bool {
+    path.as_os_str().is_empty() || path.parent().is_none()
+}
+
+impl StorageBackend for MemoryBackend {
+    fn open(&self, path: &Path, mode: OpenMode) -> io::Result<Box<dyn StorageFile>> {
+        let mut entries = self.lock();
+        let contents = match (entries.get(path), mode) {
+            (Some(Entry::Directory), _) => return Err(io::ErrorKind::IsADirectory.into()),
+            (Some(Entry::File(contents)), OpenMode::Create) => {
+                lock_contents(contents).clear();
+                contents.clone()
+            }
+            (Some(Entry::File(contents)), _) => contents.clone(),
+            (None, OpenMode::Read) => return Err(not_found()),
+            (None, _) => {
+                if !parent_exists(&entries, path) {
+                    return Err(not_found());
+                }
+                let contents = FileContents::default();
+                entries.insert(path.to_path_buf(), Entry::File(contents.clone()));
+                contents
+            }
+        };
+        Ok(Box::new(MemoryFile {
+            contents,
+            position: 0,
+            writable: mode != OpenMode::Read,
+            locks: self.locks.clone(),
+            locked: AtomicBool::new(false),
+        }))
+    }
+
+    fn stat(&self, path: &Path) -> io::Result<Option<EntryType>> {
+        if is_root(path) {
+            return Ok(Some(EntryType::Directory));
+        }
+        Ok(self.lock().get(path).map(|entry| match entry {
+            Entry::File(contents) => EntryType::File {
+                len: lock_contents(contents).len() as u64,
+            },
+            Entry::Directory => EntryType::Directory,
+        }))
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        let mut entries = self.lock();
+        for ancestor in path.ancestors().filter(|ancestor| !is_root(ancestor)) {
+            match entries.get(ancestor) {
+                Some(Entry::Directory) => break,
+                Some(Entry::File(_)) => return Err(io::ErrorKind::AlreadyExists.into()),
+                None => {
+                    entries.insert(ancestor.to_path_buf(), Entry::Directory);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let mut entries = self.lock();
+        if !parent_exists(&entries, to) {
+            return Err(not_found());
+        }
+        match entries.get(from).cloned() {
+            None => Err(not_found()),
+            Some(Entry::File(contents)) => {
+                if let Some(Entry::Directory) = entries.get(to) {
+                    return Err(io::ErrorKind::IsADirectory.into());
+                }
+                entries.remove(from);
+                entries.insert(to.to_path_buf(), Entry::File(contents));
+                Ok(())
+            }
+            Some(Entry::Directory) => {
+                if entries.contains_key(to) || to.starts_with(from) {
+                    return Err(io::ErrorKind::AlreadyExists.into());
+                }
+                let moved = entries
+                    .keys()
+                    .filter(|path| path.starts_with(from))
+                    .cloned()
+                    .collect::<Vec<_>>();
+                for path in moved {
+                    let entry = entries.remove(&path).unwrap();
+                    // `path` starts with `from`, so this cannot fail.
+                    let relative = path.strip_prefix(from).unwrap();
+                    entries.insert(to.join(relative), entry);
+                }
+                Ok(())
+            }
+        }
+    }
+
+    fn remove(&self, path: &Path) -> io::Result<()> {
+        let mut entries = self.lock();
+        match entries.get(path) {
+            Some(Entry::File(_)) => {
+                entries.remove(path);
+                Ok(())
+            }
+            Some(Entry::Directory) => Err(io::ErrorKind::IsADirectory.into()),
+            None => Err(not_found()),
+        }
+    }
+
+    fn remove_dir(&self, path: &Path) -> io::Result<()> {
+        let mut entries = self.lock();
+        match entries.get(path) {
+            Some(Entry::Directory) => {
+                if entries.keys().any(|entry| entry.parent() == Some(path)) {
+                    return Err(io::ErrorKind::DirectoryNotEmpty.into());
+                }
+                entries.remove(path);
+                Ok(())
+            }
+            Some(Entry::File(_)) => Err(io::ErrorKind::NotADirectory.into()),
+            None => Err(not_found()),
+        }
+    }
+
+    fn list(&self, path: &Path) -> io::Result<Vec<String>> {
+        let entries = self.lock();
+        if !is_root(path) && !matches!(entries.get(path), Some(Entry::Directory)) {
+            return Err(not_found());
+        }
+        Ok(entries
+            .keys()
+            .filter(|entry| entry.parent() == Some(path))
+            .filter_map(|entry| entry.file_name())
+            .map(|name| name.to_string_lossy().to_string())
+            .collect())
+    }
+
+    fn sync_dir(&self, path: &Path) -> io::Result<()> {
+        let entries = self.lock();
+        if !is_root(path) && !matches!(entries.get(path), Some(Entry::Directory)) {
+            return Err(not_found());
+        }
+        let mut synced = self.lock_synced();
+        synced.retain(|entry, _| entry.parent() != Some(path));
+        synced.extend(
+            entries
+                .iter()
+                .filter(|(entry, _)| entry.parent() == Some(path))
+                .map(|(entry, contents)| (entry.clone(), contents.clone())),
+        );
+        Ok(())
+    }
+
+    fn hard_link(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let mut entries = self.lock();
+        if entries.contains_key(to) {
+            return Err(io::ErrorKind::AlreadyExists.into());
+        }
+        if !parent_exists(&entries, to) {
+            return Err(not_found());
+        }
+        match entries.get(from).cloned() {
+            Some(Entry::File(contents)) => {
+                entries.insert(to.to_path_buf(), Entry::File(contents));
+                Ok(())
+            }
+            Some(Entry::Directory) => Err(io::ErrorKind::IsADirectory.into()),
+            None => Err(not_found()),
+        }
+    }
+}
+
+/// A file opened through a `MemoryBackend`. Like a file descriptor, it keeps the contents it was
+/// opened with, even if the file is later renamed or removed.
+#[derive(Debug)]
+struct MemoryFile {
+    contents: FileContents,
+    position: u64,
+    writable: bool,
+    locks: FileLocks,
+    // Whether this file holds the lock on its contents.
+    locked: AtomicBool,
+}
+
+impl MemoryFile {
+    fn lock_id(&self) -> usize {
+        Arc::as_ptr(&self.contents) as usize
+    }
+
+    fn lock_locks(&self) -> MutexGuard<'_, HashSet<usize>> {
+        self.locks
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl Drop for MemoryFile {
+    fn drop(&mut self) {
+        if self.locked.load(Ordering::SeqCst) {
+            let id = self.lock_id();
+            self.lock_locks().remove(&id);
+        }
+    }
+}
+
+impl Read for MemoryFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let contents = lock_contents(&self.contents);
+        let start = (self.position as usize).min(contents.len());
+        let read = buf.len().min(contents.len() - start);
+        buf[..read].copy_from_slice(&contents[start..start + read]);
+        self.position += read as u64;
+        Ok(read)
+    }
+}
+
+impl Write for MemoryFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if !self.writable {
+            return Err(io::ErrorKind::PermissionDenied.into());
+        }
+        let mut contents = lock_contents(&self.contents);
+        let start = self.position as usize;
+        let end = start + buf.len();
+        if contents.len() < end {
+            contents.resize(end, 0);
+        }
+        contents[start..end].copy_from_slice(buf);
+        self.position = end as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for MemoryFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let (base, offset) = match pos {
+            SeekFrom::Start(offset) => {
+                self.position = offset;
+                return Ok(offset);
+            }
+            SeekFrom::End(offset) => (lock_contents(&self.contents).len() as u64, offset),
+            SeekFrom::Current(offset) => (self.position, offset),
+        };
+        match base.checked_add_signed(offset) {
+            Some(position) => {
+                self.position = position;
+                Ok(position)
+            }
+            None => Err(io::ErrorKind::InvalidInput.into()),
+        }
+    }
+}
+
+impl StorageFile for MemoryFile {
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        if !self.writable {
+            return Err(io::ErrorKind::PermissionDenied.into());
+        }
+        lock_contents(&self.contents).resize(len as usize, 0);
+        Ok(())
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn try_lock(&self) -> Result<(), TryLockError> {
+        if self.locked.load(Ordering::SeqCst) {
+            return Ok(());
+        }
+        if !self.lock_locks().insert(self.lock_id()) {
+            return Err(TryLockError::WouldBlock);
+        }
+        self.locked.store(true, Ordering::SeqCst);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        load_store::BincodeLoadStore, AppendLog, AtomicStore, AtomicStoreConfig, AtomicStoreLoader,
+        FixedAppendLog, Result, RollingLog,
+    };
+
+    struct Logs {
+        append: AppendLog<BincodeLoadStore<u64>>,
+        fixed: FixedAppendLog<BincodeLoadStore<u64>>,
+        rolling: RollingLog<BincodeLoadStore<u64>>,
+        store: AtomicStore,
+    }
+
+    fn open(backend: &MemoryBackend) -> Result<Logs> {
+        let mut loader = AtomicStoreLoader::load_with_config(
+            Path::new("/memory/store"),
+            "memory",
+            AtomicStoreConfig::default().backend(Arc::new(backend.clone())),
+        )?;
+        let append = AppendLog::load(&mut loader, Default::default(), "append", 32)?;
+        let fixed = FixedAppendLog::load(&mut loader, Default::default(), "fixed", 8, 4)?;
+        let rolling = RollingLog::load(&mut loader, Default::default(), "rolling", 32)?;
+        let store = AtomicStore::open(loader)?;
+        Ok(Logs {
+            append,
+            fixed,
+            rolling,
+            store,
+        })
+    }
+
+    impl Logs {
+        fn commit(&mut self, values: std::ops::Range<u64>) -> Result<()> {
+            for value in values {
+                self.append.store_resource(&value)?;
+                self.fixed.store_resource(&value)?;
+                self.rolling.store_resource(&value)?;
+            }
+            self.append.commit_version()?;
+            self.fixed.commit_version()?;
+            self.rolling.commit_version()?;
+            self.store.commit_version()
+        }
+
+        fn check(&self, committed: u64) {
+            let expected = (0..committed).collect::<Vec<_>>();
+            assert_eq!(
+                self.append.iter().collect::<Result<Vec<_>>>().unwrap(),
+                expected
+            );
+            assert_eq!(
+                self.fixed.iter().collect::<Result<Vec<_>>>().unwrap(),
+                expected
+            );
+            assert_eq!(self.rolling.load_latest().unwrap(), committed - 1);
+        }
+    }
+
+    #[test]
+    fn reload_after_shutdown() {
+        let backend = MemoryBackend::new();
+        let mut logs = open(&backend).unwrap();
+        logs.commit(0..10).unwrap();
+        drop(logs);
+
+        let mut logs = open(&backend).unwrap();
+        logs.check(10);
+        logs.commit(10..12).unwrap();
+        drop(logs);
+        open(&backend).unwrap().check(12);
+    }
+
+    #[test]
+    fn reopen_after_crash() {
+        let backend = MemoryBackend::new();
+        let mut logs = open(&backend).unwrap();
+        logs.commit(0..10).unwrap();
+        // Written and committed by the logs, but not by the store.
+        for value in 10..20 {
+            logs.append.store_resource(&value).unwrap();
+            logs.fixed.store_resource(&value).unwrap();
+            logs.rolling.store_resource(&value).unwrap();
+        }
+        logs.append.commit_version().unwrap();
+
+        // The old instance is still alive, but cannot affect the reopened image.
+        let restarted = backend.reopen();
+        let mut reopened = open(&restarted).unwrap();
+        logs.fixed.commit_version().unwrap();
+        logs.rolling.commit_version().unwrap();
+        logs.store.commit_version().unwrap();
+        reopened.check(10);
+        reopened.commit(10..11).unwrap();
+        reopened.check(11);
+        logs.check(20);
+    }
+
+    #[test]
+    fn store_lock() {
+        let backend = MemoryBackend::new();
+        let logs = open(&backend).unwrap();
+        assert!(matches!(
+            open(&backend),
+            Err(crate::PersistenceError::StoreLocked { .. })
+        ));
+        // A restarted process does not inherit the locks of the old one.
+        drop(open(&backend.reopen()).unwrap());
+        drop(logs);
+        open(&backend).unwrap();
+    }
+
+    #[test]
+    fn lose_power_after_commit() {
+        let backend = MemoryBackend::new();
+        let mut logs = open(&backend).unwrap();
+        // Enough versions for every log to roll over to new files.
+        for value in 0..20 {
+            logs.commit(value..value + 1).unwrap();
+            // Every committed version must survive without relying on directory entries that
+            // were never synced.
+            open(&backend.lose_power()).unwrap().check(value + 1);
+        }
+    }
+
+    #[test]
+    fn lose_power_semantics() {
+        let backend = MemoryBackend::new();
+        let dir = Path::new("/a");
+        backend.create_dir_all(dir).unwrap();
+        backend.open(&dir.join("file"), OpenMode::Create).unwrap();
+        assert!(!backend.lose_power().exists(dir));
+        backend.sync_dir(Path::new("/")).unwrap();
+        assert!(backend.lose_power().is_dir(dir));
+        assert!(!backend.lose_power().exists(&dir.join("file")));
+
+        backend.sync_dir(dir).unwrap();
+        backend
+            .rename(&dir.join("file"), &dir.join("moved"))
+            .unwrap();
+        let restarted = backend.lose_power();
+        assert!(restarted.is_file(&dir.join("file")));
+        assert!(!restarted.exists(&dir.join("moved")));
+        backend.sync_dir(dir).unwrap();
+        let restarted = backend.lose_power();
+        assert!(!restarted.exists(&dir.join("file")));
+        assert!(restarted.is_file(&dir.join("moved")));
+    }
+
+    #[test]
+    fn file_semantics() {
+        let backend = MemoryBackend::new();
+        let dir = Path::new("/a/b");
+        backend.create_dir_all(dir).unwrap();
+        assert!(backend.is_dir(Path::new("/a")));
+        assert!(backend.open(Path::new("/c/d"), OpenMode::Create).is_err());
+
+        let path = dir.join("file");
+        let mut file = backend.open(&path, OpenMode::ReadWrite).unwrap();
+        file.write_all(b"hello").unwrap();
+        file.seek(SeekFrom::Start(8)).unwrap();
+        file.write_all(b"!").unwrap();
+        assert_eq!(
+            backend.stat(&path).unwrap(),
+            Some(EntryType::File { len: 9 })
+        );
+        backend.hard_link(&path, &dir.join("link")).unwrap();
+        backend.rename(&path, &dir.join("moved")).unwrap();
+        file.set_len(5).unwrap();
+
+        let mut buf = Vec::new();
+        let mut link = backend.open(&dir.join("link"), OpenMode::Read).unwrap();
+        link.read_to_end(&mut buf).unwrap();
+        assert_eq!(buf, b"hello");
+        assert!(link.write_all(b"x").is_err());
+
+        let mut names = backend.list(dir).unwrap();
+        names.sort();
+        assert_eq!(names, vec!["link", "moved"]);
+
+        backend.rename(Path::new("/a"), Path::new("/e")).unwrap();
+        assert!(backend.is_file(Path::new("/e/b/moved")));
+        assert!(!backend.exists(Path::new("/a/b/moved")));
+        backend.remove(Path::new("/e/b/moved")).unwrap();
+        assert_eq!(backend.list(Path::new("/e/b")).unwrap(), vec!["link"]);
+    }
+}
@@ -0,0 +1,51 @@
+// Copyright (c) 2022 Espresso Systems (espressosys.com)
+// This file is part of the AtomicStore library.
+
+// This program is free software: you can redistribute it and/or modify it under the terms of the GNU General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+use serde::{Deserialize, Serialize};
+
+use std::fmt;
+
+/// The type of log managing a resource, along with the parameters that determine its file layout.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ResourceKind {
+    AppendLog { file_fill_size: u64 },
+    FixedAppendLog { resource_size: u64, file_size: u64 },
+    RollingLog { file_fill_size: u64 },
+}
+
+/// Describes how a resource was written, so that it cannot be reopened with a different log type
+/// or serialization format.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ResourceDescriptor {
+    pub kind: ResourceKind,
+    /// The `LoadStore::format_id` of the adaptor used to serialize the resource, or the name of the
+    /// adaptor type if it has none.
+    pub format: String,
+}
+
+impl fmt::Display for ResourceKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ResourceKind::AppendLog { file_fill_size } => {
+                write!(f, "AppendLog(fill {})", file_fill_size)
+            }
+            ResourceKind::FixedAppendLog {
+                resource_size,
+                file_size,
+            } => write!(f, "FixedAppendLog({} x {} bytes)", file_size, resource_size),
+            ResourceKind::RollingLog { file_fill_size } => {
+                write!(f, "RollingLog(fill {})", file_fill_size)
+            }
+        }
+    }
+}
+
+impl fmt::Display for ResourceDescriptor {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} of {}", self.kind, self.format)
+    }
+}
@@ -5,39 +5,52 @@
 // This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
 // You should have received a copy of the GNU General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
 
-use crate::atomic_store::AtomicStoreLoader;
+use crate::atomic_store::{AtomicStoreLoader, Inconsistency, InconsistencyKind, ReadOnlyLoader};
+use crate::config::{AtomicStoreConfig, BackupPolicy};
 use crate::error::{
     PersistenceError, StdIoDirOpsSnafu, StdIoOpenSnafu, StdIoReadSnafu, StdIoSeekSnafu,
     StdIoWriteSnafu,
 };
-use crate::load_store::LoadStore;
+use crate::load_store::{recorded_format, LoadStore};
+use crate::resource_descriptor::{ResourceDescriptor, ResourceKind};
+use crate::storage_backend::{EntryType, OpenMode, StorageBackend, StorageFile};
 use crate::storage_location::StorageLocation;
-use crate::utils::unix_timestamp;
+use crate::utils::{
+    copy_prefix, discard_backup, link_or_copy, read_file, sync_dir, unix_timestamp,
+};
 use crate::version_sync::VersionSyncHandle;
 use crate::Result;
 
+use regex::Regex;
 use snafu::ResultExt;
 
-use std::fs;
-use std::fs::{File, OpenOptions};
 use std::io::{Read, Seek, SeekFrom, Write};
 use std::path::{Path, PathBuf};
 use std::sync::{Arc, RwLock};
 
-const DEFAULT_RETAINED_ENTRIES: u32 = 128;
-
 #[derive(Debug)]
 pub struct RollingLog<ResourceAdaptor: LoadStore> {
     persisted_sync: Arc<RwLock<VersionSyncHandle>>,
+    backend: Arc<dyn StorageBackend>,
     file_path: PathBuf,
     file_pattern: String,
     file_fill_size: u64,
-    write_to_file: Option<File>,
+    write_to_file: Option<Box<dyn StorageFile>>,
     write_pos: u64,
     file_entries: u32,
     write_file_counter: u32,
     adaptor: ResourceAdaptor,
     retained_entries: u32,
+    backups: BackupPolicy,
+}
+
+/// A `RollingLog` opened from a `ReadOnlyLoader`, which reads the version recorded in the table of
+/// contents it was opened or last refreshed with.
+#[derive(Debug)]
+pub struct ReadOnlyRollingLog<ResourceAdaptor: LoadStore> {
+    log: RollingLog<ResourceAdaptor>,
+    store_pattern: String,
+    version: Option<u32>,
 }
 
 fn format_nth_file_path(root_path: &Path, file_pattern: &str, file_count: u32) -> PathBuf {
@@ -46,8 +59,14 @@ fn format_nth_file_path(root_path: &Path, file_pattern: &str, file_count: u32) -
     buf
 }
 
+/// Matches the files of a rolling log with `file_pattern`, including backups of replaced files.
+pub(crate) fn resource_file_regex(file_pattern: &str) -> Regex {
+    let pattern = regex::escape(file_pattern);
+    Regex::new(&format!(r"^(\.{pattern}_\d+|{pattern}_\d+\.bak\.-?\d+)$")).unwrap()
+}
+
 fn load_from_file<ResourceAdaptor: LoadStore>(
-    read_file: &mut File,
+    read_file: &mut dyn StorageFile,
     adaptor: &ResourceAdaptor,
     location: &StorageLocation,
 ) -> Result<ResourceAdaptor::ParamType> {
@@ -75,18 +94,155 @@ fn get_next_write_position(location: &Option<StorageLocation>, file_fill_size: u
     }
 }
 
+// Copy the retained files of a log committed at `location` from `src_pattern` in `src_path` to
+// `dest_pattern` in `dest_path`; the file containing `location` is truncated just past it.
+pub(crate) fn checkpoint_files(
+    backend: &dyn StorageBackend,
+    src_path: &Path,
+    src_pattern: &str,
+    dest_path: &Path,
+    dest_pattern: &str,
+    location: &StorageLocation,
+) -> Result<()> {
+    copy_prefix(
+        backend,
+        &format_nth_file_path(src_path, src_pattern, location.file_counter),
+        &format_nth_file_path(dest_path, dest_pattern, location.file_counter),
+        location.store_start + location.store_length as u64,
+    )?;
+    // Older files are pruned from the oldest up.
+    for file_counter in (0..location.file_counter).rev() {
+        let path = format_nth_file_path(src_path, src_pattern, file_counter);
+        if !backend.exists(&path) {
+            break;
+        }
+        link_or_copy(
+            backend,
+            &path,
+            &format_nth_file_path(dest_path, dest_pattern, file_counter),
+        )?;
+    }
+    Ok(())
+}
+
+// Check the retained files of a log with `file_pattern` committed at `location`, recording anything
+// inconsistent in `issues`: the location must be an entry of its file, and the entry count in the
+// header of each file must match the entries framed in it. Entries written to the last file after
+// `location` may or may not have been counted yet.
+pub(crate) fn verify_files(
+    backend: &dyn StorageBackend,
+    root_path: &Path,
+    file_pattern: &str,
+    location: &StorageLocation,
+    issues: &mut Vec<Inconsistency>,
+) -> Result<()> {
+    let mut issue = |path: &Path, kind: InconsistencyKind| {
+        issues.push(Inconsistency::new(file_pattern, path, kind))
+    };
+    for file_counter in (0..=location.file_counter).rev() {
+        let path = format_nth_file_path(root_path, file_pattern, file_counter);
+        if !backend.exists(&path) {
+            if file_counter == location.file_counter {
+                issue(&path, InconsistencyKind::MissingFile);
+            }
+            // Older files are pruned from the oldest up.
+            break;
+        }
+        let contents = read_file(backend, &path)?;
+        if contents.len() < 4 {
+            issue(&path, InconsistencyKind::MissingHeader);
+            continue;
+        }
+        let header = u32::from_le_bytes(contents[..4].try_into().unwrap());
+        // The start and length of each complete entry.
+        let mut entries = Vec::new();
+        let mut read_position = 4usize;
+        while read_position + 4 <= contents.len() {
+            let entry_size = u32::from_le_bytes(
+                contents[read_position..read_position + 4]
+                    .try_into()
+                    .unwrap(),
+            );
+            let entry_start = read_position + 4;
+            if entry_start + entry_size as usize > contents.len() {
+                break;
+            }
+            entries.push((entry_start as u64, entry_size));
+            read_position = entry_start + entry_size as usize;
+        }
+
+        if file_counter < location.file_counter {
+            if read_position != contents.len() {
+                issue(
+                    &path,
+                    InconsistencyKind::PartialEntry {
+                        offset: read_position as u64,
+                    },
+                );
+            }
+            if header as usize != entries.len() {
+                issue(
+                    &path,
+                    InconsistencyKind::EntryCountMismatch {
+                        header: header as u64,
+                        committed: None,
+                        held: entries.len() as u64,
+                    },
+                );
+            }
+            continue;
+        }
+        match entries
+            .iter()
+            .position(|entry| *entry == (location.store_start, location.store_length))
+        {
+            Some(last_committed) => {
+                let committed = last_committed + 1;
+                if (header as usize) < committed || header as usize > entries.len() {
+                    issue(
+                        &path,
+                        InconsistencyKind::EntryCountMismatch {
+                            header: header as u64,
+                            committed: Some(committed as u64),
+                            held: entries.len() as u64,
+                        },
+                    );
+                }
+            }
+            None if location.store_start + location.store_length as u64 > contents.len() as u64 => {
+                issue(
+                    &path,
+                    InconsistencyKind::LocationPastEnd {
+                        location: *location,
+                        index_entry: None,
+                        len: contents.len() as u64,
+                    },
+                );
+            }
+            None => issue(
+                &path,
+                InconsistencyKind::LocationNotAnEntry {
+                    location: *location,
+                },
+            ),
+        }
+    }
+    Ok(())
+}
+
 impl<ResourceAdaptor: LoadStore> RollingLog<ResourceAdaptor> {
     pub(crate) fn open_impl(
+        config: &AtomicStoreConfig,
         adaptor: ResourceAdaptor,
         location: Option<StorageLocation>,
         file_path: &Path,
         file_pattern: &str,
         file_fill_size: u64,
-        retained_entries: u32,
     ) -> Result<RollingLog<ResourceAdaptor>> {
         let (write_pos, counter) = get_next_write_position(&location, file_fill_size);
         Ok(RollingLog {
             persisted_sync: Arc::new(RwLock::new(VersionSyncHandle::new(file_pattern, location))),
+            backend: config.backend.clone(),
             file_path: file_path.to_path_buf(),
             file_pattern: String::from(file_pattern),
             file_fill_size,
@@ -95,7 +251,8 @@ impl<ResourceAdaptor: LoadStore> RollingLog<ResourceAdaptor> {
             file_entries: 0,
             write_file_counter: counter,
             adaptor,
-            retained_entries,
+            retained_entries: config.rolling_log_retained_entries,
+            backups: config.backups,
         })
     }
 
@@ -105,17 +262,19 @@ impl<ResourceAdaptor: LoadStore> RollingLog<ResourceAdaptor> {
         file_pattern: &str,
         file_fill_size: u64,
     ) -> Result<RollingLog<ResourceAdaptor>> {
-        let resource = loader.look_up_resource(file_pattern);
+        let descriptor = Self::descriptor(&adaptor, file_fill_size);
+        let resource =
+            loader.look_up_resource(file_pattern, &descriptor, adaptor.format_id().is_some())?;
         let path = loader.persistence_path().to_path_buf();
         let created = Self::open_impl(
+            loader.config(),
             adaptor,
             resource,
             &path,
             file_pattern,
             file_fill_size,
-            DEFAULT_RETAINED_ENTRIES,
         )?;
-        loader.add_sync_handle(file_pattern, created.persisted_sync.clone())?;
+        loader.add_sync_handle(file_pattern, created.persisted_sync.clone(), descriptor)?;
         Ok(created)
     }
     pub fn create(
@@ -124,32 +283,68 @@ impl<ResourceAdaptor: LoadStore> RollingLog<ResourceAdaptor> {
         file_pattern: &str,
         file_fill_size: u64,
     ) -> Result<RollingLog<ResourceAdaptor>> {
+        let descriptor = Self::descriptor(&adaptor, file_fill_size);
         let path = loader.persistence_path().to_path_buf();
         let created = Self::open_impl(
+            loader.config(),
             adaptor,
             None,
             &path,
             file_pattern,
             file_fill_size,
-            DEFAULT_RETAINED_ENTRIES,
         )?;
-        loader.add_sync_handle(file_pattern, created.persisted_sync.clone())?;
+        loader.add_sync_handle(file_pattern, created.persisted_sync.clone(), descriptor)?;
         Ok(created)
     }
 
+    /// Open the log for reading at the version recorded in `loader`. Unlike `load`, the log's
+    /// files are not modified, and the log is not registered with the loader.
+    pub fn open_read_only(
+        loader: &ReadOnlyLoader,
+        adaptor: ResourceAdaptor,
+        file_pattern: &str,
+        file_fill_size: u64,
+    ) -> Result<ReadOnlyRollingLog<ResourceAdaptor>> {
+        let descriptor = Self::descriptor(&adaptor, file_fill_size);
+        let resource =
+            loader.look_up_resource(file_pattern, &descriptor, adaptor.format_id().is_some())?;
+        let log = Self::open_impl(
+            loader.config(),
+            adaptor,
+            resource,
+            loader.persistence_path(),
+            file_pattern,
+            file_fill_size,
+        )?;
+        Ok(ReadOnlyRollingLog {
+            log,
+            store_pattern: loader.file_pattern().to_string(),
+            version: loader.version(),
+        })
+    }
+
+    fn descriptor(adaptor: &ResourceAdaptor, file_fill_size: u64) -> ResourceDescriptor {
+        ResourceDescriptor {
+            kind: ResourceKind::RollingLog { file_fill_size },
+            format: recorded_format(adaptor),
+        }
+    }
+
     fn open_write_file(&mut self) -> Result<()> {
         let out_file_path =
             format_nth_file_path(&self.file_path, &self.file_pattern, self.write_file_counter);
 
-        if out_file_path.exists() {
-            if !out_file_path.is_file() {
+        // Whether the directory entries change, and must be synced once the file is open.
+        let mut dir_changed = !self.backend.exists(&out_file_path);
+        if self.backend.exists(&out_file_path) {
+            if !self.backend.is_file(&out_file_path) {
                 return Err(PersistenceError::InvalidPathToFile {
                     path: out_file_path.to_string_lossy().to_string(),
                 });
             }
 
-            if let Ok(metadata) = fs::metadata(&out_file_path) {
-                if metadata.len() > self.write_pos {
+            if let Ok(Some(EntryType::File { len })) = self.backend.stat(&out_file_path) {
+                if len > self.write_pos {
                     let mut backup_path = self.file_path.clone();
                     backup_path.push(format!(
                         "{}_{}.bak.{}",
@@ -157,25 +352,36 @@ impl<ResourceAdaptor: LoadStore> RollingLog<ResourceAdaptor> {
                         self.write_file_counter,
                         unix_timestamp()
                     ));
+                    // The file is replaced rather than truncated in place, as it may be hard linked
+                    // by a checkpoint.
+                    self.backend
+                        .rename(&out_file_path, &backup_path)
+                        .context(StdIoDirOpsSnafu)?;
                     if self.write_pos > 0 {
-                        fs::copy(&out_file_path, &backup_path).context(StdIoDirOpsSnafu)?;
-                    } else {
-                        fs::rename(&out_file_path, &backup_path).context(StdIoDirOpsSnafu)?;
+                        self.backend
+                            .copy(&backup_path, &out_file_path)
+                            .context(StdIoDirOpsSnafu)?;
+                    }
+                    if self.backups == BackupPolicy::Discard {
+                        discard_backup(self.backend.as_ref(), &backup_path, &out_file_path)?;
                     }
+                    dir_changed = true;
                 }
             }
         }
 
-        #[allow(clippy::suspicious_open_options)]
-        let mut file = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .open(out_file_path.clone())
+        let mut file = self
+            .backend
+            .open(&out_file_path, OpenMode::ReadWrite)
             .context(StdIoOpenSnafu)?;
+        if dir_changed {
+            sync_dir(self.backend.as_ref(), &self.file_path)?;
+        }
         file.seek(SeekFrom::End(0)).context(StdIoSeekSnafu)?;
         self.file_entries = 0;
-        if file.stream_position().context(StdIoSeekSnafu)? == 0 {
+        // A new file, or one whose header write was interrupted.
+        if file.stream_position().context(StdIoSeekSnafu)? < 4 {
+            file.seek(SeekFrom::Start(0)).context(StdIoSeekSnafu)?;
             file.write_all(&[0u8; 4]).context(StdIoWriteSnafu)?;
         }
         if file.stream_position().context(StdIoSeekSnafu)? < self.write_pos {
@@ -242,12 +448,12 @@ impl<ResourceAdaptor: LoadStore> RollingLog<ResourceAdaptor> {
         let resource_length = serialized.len() as u32;
         assert_eq!(resource_length.to_le_bytes().len(), 4);
         self.write_to_file
-            .as_ref()
+            .as_mut()
             .unwrap()
             .write_all(&resource_length.to_le_bytes())
             .context(StdIoWriteSnafu)?;
         self.write_to_file
-            .as_ref()
+            .as_mut()
             .unwrap()
             .write_all(&serialized)
             .context(StdIoWriteSnafu)?;
@@ -268,8 +474,7 @@ impl<ResourceAdaptor: LoadStore> RollingLog<ResourceAdaptor> {
                 write_to_file
                     .write_all(&self.file_entries.to_le_bytes())
                     .context(StdIoWriteSnafu)?;
-                write_to_file.flush().context(StdIoWriteSnafu)?;
-                write_to_file.sync_all().context(StdIoDirOpsSnafu)?;
+                write_to_file.sync().context(StdIoDirOpsSnafu)?;
             }
             self.write_pos = 4;
             self.file_entries = 0;
@@ -280,7 +485,7 @@ impl<ResourceAdaptor: LoadStore> RollingLog<ResourceAdaptor> {
         Ok(location)
     }
 
-    // This currenty won't have any effect if called again before the atomic store has processed the prior committed version. A more appropriate behavior might be to block. A version that supports queued writes could enqueue the commit points.
+    // If called again before the atomic store has processed the prior committed version, the commit points are queued, and recorded by successive versions of the store.
     pub fn commit_version(&mut self) -> Result<()> {
         if let Some(write_to_file) = self.write_to_file.as_mut() {
             let _lines = write_to_file
@@ -292,8 +497,7 @@ impl<ResourceAdaptor: LoadStore> RollingLog<ResourceAdaptor> {
             let _lines = write_to_file
                 .seek(SeekFrom::Start(self.write_pos))
                 .context(StdIoSeekSnafu)?;
-            write_to_file.flush().context(StdIoWriteSnafu)?;
-            write_to_file.sync_all().context(StdIoDirOpsSnafu)?;
+            write_to_file.sync().context(StdIoDirOpsSnafu)?;
         }
         self.persisted_sync.write()?.update_version()
     }
@@ -326,8 +530,11 @@ impl<ResourceAdaptor: LoadStore> RollingLog<ResourceAdaptor> {
     pub fn load_specified(&self, location: &StorageLocation) -> Result<ResourceAdaptor::ParamType> {
         let read_file_path =
             format_nth_file_path(&self.file_path, &self.file_pattern, location.file_counter);
-        let mut read_file = File::open(read_file_path.as_path()).context(StdIoOpenSnafu)?;
-        load_from_file::<ResourceAdaptor>(&mut read_file, &self.adaptor, location)
+        let mut read_file = self
+            .backend
+            .open(&read_file_path, OpenMode::Read)
+            .context(StdIoOpenSnafu)?;
+        load_from_file::<ResourceAdaptor>(read_file.as_mut(), &self.adaptor, location)
     }
 
     pub fn set_retained_entries(&mut self, retained_entries: u32) {
@@ -336,6 +543,7 @@ impl<ResourceAdaptor: LoadStore> RollingLog<ResourceAdaptor> {
 
     // Prune write files after the total number of entries exceeds the retained number
     pub fn prune_file_entries(&self) -> Result<()> {
+        let mut pruned = false;
         if let Some(commit_pos) = self.persisted_sync.read()?.last_location() {
             let mut file_index = commit_pos.file_counter;
             let mut retained_counter = self
@@ -348,12 +556,16 @@ impl<ResourceAdaptor: LoadStore> RollingLog<ResourceAdaptor> {
                 file_index -= 1;
 
                 let path = format_nth_file_path(&self.file_path, &self.file_pattern, file_index);
-                if !path.exists() {
+                if !self.backend.exists(&path) {
                     break;
                 } else if retained_counter == 0 {
-                    fs::remove_file(path).context(StdIoDirOpsSnafu)?;
+                    self.backend.remove(&path).context(StdIoDirOpsSnafu)?;
+                    pruned = true;
                 } else {
-                    let mut read_file = File::open(path).context(StdIoOpenSnafu)?;
+                    let mut read_file = self
+                        .backend
+                        .open(&path, OpenMode::Read)
+                        .context(StdIoOpenSnafu)?;
                     let mut buffer = [0u8; 4];
                     read_file.read_exact(&mut buffer).context(StdIoReadSnafu)?;
                     let store_length = u32::from_le_bytes(buffer);
@@ -361,10 +573,54 @@ impl<ResourceAdaptor: LoadStore> RollingLog<ResourceAdaptor> {
                 }
             }
         }
+        if pruned {
+            sync_dir(self.backend.as_ref(), &self.file_path)?;
+        }
         Ok(())
     }
 }
 
+impl<ResourceAdaptor: LoadStore> ReadOnlyRollingLog<ResourceAdaptor> {
+    /// Pick up the newest committed version of the store. Returns whether a different version of
+    /// the table of contents was read.
+    pub fn refresh(&mut self) -> Result<bool> {
+        let loader = AtomicStoreLoader::open_read_only_with_config(
+            &self.log.file_path,
+            &self.store_pattern,
+            AtomicStoreConfig::default().backend(self.log.backend.clone()),
+        )?;
+        if loader.version() == self.version {
+            return Ok(false);
+        }
+        let log = &mut self.log;
+        let descriptor = RollingLog::descriptor(&log.adaptor, log.file_fill_size);
+        let location = loader.look_up_resource(
+            &log.file_pattern,
+            &descriptor,
+            log.adaptor.format_id().is_some(),
+        )?;
+        log.persisted_sync = Arc::new(RwLock::new(VersionSyncHandle::new(
+            &log.file_pattern,
+            location,
+        )));
+        self.version = loader.version();
+        Ok(true)
+    }
+
+    /// The counter of the table of contents this log was last read from.
+    pub fn version(&self) -> Option<u32> {
+        self.version
+    }
+
+    pub fn load_latest(&self) -> Result<ResourceAdaptor::ParamType> {
+        self.log.load_latest()
+    }
+
+    pub fn load_specified(&self, location: &StorageLocation) -> Result<ResourceAdaptor::ParamType> {
+        self.log.load_specified(location)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
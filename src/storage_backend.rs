@@ -0,0 +1,289 @@
+// Copyright (c) 2022 Espresso Systems (espressosys.com)
+// This file is part of the AtomicStore library.
+
+// This program is free software: you can redistribute it and/or modify it under the terms of the GNU General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! The medium that stores and logs persist their files to.
+//!
+//! All file and directory access goes through a `StorageBackend`, so that stores can be kept on
+//! media other than the local file system. Paths are interpreted by the backend; the store only
+//! ever joins file names onto the storage path it was opened with.
+
+use std::fmt::Debug;
+use std::fs;
+use std::fs::{File, OpenOptions, TryLockError};
+use std::io;
+use std::io::{Read, Seek, Write};
+use std::path::Path;
+
+/// How `StorageBackend::open` opens a file.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum OpenMode {
+    /// Open an existing file for reading.
+    Read,
+    /// Open a file for reading and writing, creating it if it does not exist; its contents are
+    /// kept.
+    ReadWrite,
+    /// Create a file for writing, truncating it if it already exists.
+    Create,
+}
+
+/// The type of an entry found by `StorageBackend::stat`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum EntryType {
+    File { len: u64 },
+    Directory,
+}
+
+/// An open file of a `StorageBackend`.
+pub trait StorageFile: Read + Write + Seek + Send + Debug {
+    /// Truncate or extend the file to `len` bytes.
+    fn set_len(&mut self, len: u64) -> io::Result<()>;
+
+    /// Flush the contents of the file to durable storage.
+    fn sync(&mut self) -> io::Result<()>;
+
+    /// Try to take an exclusive lock on the file, held until the file is closed. Backends that
+    /// cannot be shared between owners need not support locking.
+    fn try_lock(&self) -> Result<(), TryLockError> {
+        Ok(())
+    }
+}
+
+/// The operations used by stores and logs to persist their files.
+///
+/// Operations on paths that are not a directory, such as `rename` and `remove`, only modify the
+/// containing directory, and must be followed by `sync_dir` to be durable.
+pub trait StorageBackend: Send + Sync + Debug {
+    /// Open the file at `path`.
+    fn open(&self, path: &Path, mode: OpenMode) -> io::Result<Box<dyn StorageFile>>;
+
+    /// Find the type of the entry at `path`, or `None` if there is no entry.
+    fn stat(&self, path: &Path) -> io::Result<Option<EntryType>>;
+
+    /// Create the directory at `path`, along with any missing parents.
+    fn create_dir_all(&self, path: &Path) -> io::Result<()>;
+
+    /// Rename a file or directory, replacing `to` if it is an existing file.
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()>;
+
+    /// Remove the file at `path`.
+    fn remove(&self, path: &Path) -> io::Result<()>;
+
+    /// List the names of the entries in the directory at `path`.
+    fn list(&self, path: &Path) -> io::Result<Vec<String>>;
+
+    /// Flush the entries of the directory at `path` to durable storage.
+    fn sync_dir(&self, path: &Path) -> io::Result<()>;
+
+    /// Make `to` refer to the same contents as `from`, without copying them. Backends that cannot
+    /// share contents between files return an `Unsupported` error, and the file is copied instead.
+    fn hard_link(&self, _from: &Path, _to: &Path) -> io::Result<()> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+
+    /// Remove the empty directory at `path`. Backends that cannot remove directories return an
+    /// `Unsupported` error.
+    fn remove_dir(&self, _path: &Path) -> io::Result<()> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        matches!(self.stat(path), Ok(Some(_)))
+    }
+
+    fn is_file(&self, path: &Path) -> bool {
+        matches!(self.stat(path), Ok(Some(EntryType::File { .. })))
+    }
+
+    fn is_dir(&self, path: &Path) -> bool {
+        matches!(self.stat(path), Ok(Some(EntryType::Directory)))
+    }
+
+    /// Copy the contents of the file at `from` to a new file at `to`.
+    fn copy(&self, from: &Path, to: &Path) -> io::Result<u64> {
+        let mut source = self.open(from, OpenMode::Read)?;
+        let mut dest = self.open(to, OpenMode::Create)?;
+        io::copy(&mut source, &mut dest)
+    }
+}
+
+/// Stores files in the local file system.
+#[derive(Debug, Default, Copy, Clone)]
+pub struct FileSystemBackend;
+
+impl StorageFile for File {
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        File::set_len(self, len)
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        self.flush()?; // drop is not guaranteed to report errors
+        self.sync_all()
+    }
+
+    fn try_lock(&self) -> Result<(), TryLockError> {
+        File::try_lock(self)
+    }
+}
+
+impl StorageBackend for FileSystemBackend {
+    fn open(&self, path: &Path, mode: OpenMode) -> io::Result<Box<dyn StorageFile>> {
+        let file = match mode {
+            OpenMode::Read => File::open(path)?,
+            OpenMode::ReadWrite => OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(path)?,
+            OpenMode::Create => File::create(path)?,
+        };
+        Ok(Box::new(file))
+    }
+
+    fn stat(&self, path: &Path) -> io::Result<Option<EntryType>> {
+        match fs::metadata(path) {
+            Ok(metadata) if metadata.is_dir() => Ok(Some(EntryType::Directory)),
+            Ok(metadata) => Ok(Some(EntryType::File {
+                len: metadata.len(),
+            })),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        fs::create_dir_all(path)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        fs::rename(from, to)
+    }
+
+    fn remove(&self, path: &Path) -> io::Result<()> {
+        fs::remove_file(path)
+    }
+
+    fn remove_dir(&self, path: &Path) -> io::Result<()> {
+        fs::remove_dir(path)
+    }
+
+    fn list(&self, path: &Path) -> io::Result<Vec<String>> {
+        fs::read_dir(path)?
+            .map(|entry| Ok(entry?.file_name().to_string_lossy().to_string()))
+            .collect()
+    }
+
+    #[cfg(unix)]
+    fn sync_dir(&self, path: &Path) -> io::Result<()> {
+        File::open(path)?.sync_all()
+    }
+
+    /// Directories cannot be opened as files on this platform; metadata changes are made durable
+    /// by the file system itself.
+    #[cfg(not(unix))]
+    fn sync_dir(&self, _path: &Path) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn hard_link(&self, from: &Path, to: &Path) -> io::Result<()> {
+        fs::hard_link(from, to)
+    }
+
+    fn copy(&self, from: &Path, to: &Path) -> io::Result<u64> {
+        fs::copy(from, to)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        load_store::BincodeLoadStore, AppendLog, AtomicStore, AtomicStoreConfig, AtomicStoreLoader,
+    };
+    use std::path::PathBuf;
+    use std::sync::Arc;
+
+    // Keeps files under a real directory, but only accepts paths under a root that does not exist
+    // in the file system, so anything bypassing the backend fails.
+    #[derive(Debug)]
+    struct RebasedBackend {
+        root: PathBuf,
+        inner: FileSystemBackend,
+    }
+
+    impl RebasedBackend {
+        fn rebase(&self, path: &Path) -> io::Result<PathBuf> {
+            let relative = path
+                .strip_prefix("/nonexistent_store_root")
+                .map_err(|_| io::Error::from(io::ErrorKind::NotFound))?;
+            Ok(self.root.join(relative))
+        }
+    }
+
+    impl StorageBackend for RebasedBackend {
+        fn open(&self, path: &Path, mode: OpenMode) -> io::Result<Box<dyn StorageFile>> {
+            self.inner.open(&self.rebase(path)?, mode)
+        }
+        fn stat(&self, path: &Path) -> io::Result<Option<EntryType>> {
+            self.inner.stat(&self.rebase(path)?)
+        }
+        fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+            self.inner.create_dir_all(&self.rebase(path)?)
+        }
+        fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+            self.inner.rename(&self.rebase(from)?, &self.rebase(to)?)
+        }
+        fn remove(&self, path: &Path) -> io::Result<()> {
+            self.inner.remove(&self.rebase(path)?)
+        }
+        fn list(&self, path: &Path) -> io::Result<Vec<String>> {
+            self.inner.list(&self.rebase(path)?)
+        }
+        fn sync_dir(&self, path: &Path) -> io::Result<()> {
+            self.inner.sync_dir(&self.rebase(path)?)
+        }
+    }
+
+    #[test]
+    fn all_access_goes_through_backend() {
+        let dir = tempfile::tempdir().unwrap();
+        let backend = Arc::new(RebasedBackend {
+            root: dir.path().to_path_buf(),
+            inner: FileSystemBackend,
+        });
+        let path = Path::new("/nonexistent_store_root/store");
+        for i in 0..3u64 {
+            let mut loader = AtomicStoreLoader::load_with_config(
+                path,
+                "rebased",
+                AtomicStoreConfig::default().backend(backend.clone()),
+            )
+            .unwrap();
+            let mut log =
+                AppendLog::load(&mut loader, BincodeLoadStore::<u64>::default(), "log", 16)
+                    .unwrap();
+            let mut store = AtomicStore::open(loader).unwrap();
+            assert_eq!(
+                log.iter().collect::<crate::Result<Vec<_>>>().unwrap(),
+                (0..i).collect::<Vec<_>>()
+            );
+            log.store_resource(&i).unwrap();
+            log.commit_version().unwrap();
+            store.commit_version().unwrap();
+        }
+        assert!(dir.path().join("store").join("rebased_latest").is_file());
+        assert_eq!(
+            AtomicStoreLoader::list_versions_with_config(
+                path,
+                "rebased",
+                AtomicStoreConfig::default().backend(backend.clone())
+            )
+            .unwrap(),
+            vec![0, 1, 2]
+        );
+    }
+}
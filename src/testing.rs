@@ -11,15 +11,21 @@
 use crate::{
     append_log::AppendLog,
     atomic_store::{AtomicStore, AtomicStoreLoader},
+    config::AtomicStoreConfig,
+    fault_injection::{Fault, FaultInjectingBackend, FaultKind},
     load_store::BincodeLoadStore,
+    memory_backend::MemoryBackend,
     rolling_log::RollingLog,
+    storage_backend::{FileSystemBackend, StorageBackend},
     storage_location::StorageLocation,
-    Result,
+    PersistenceError, Result,
 };
 use core::iter::once;
 use rand::Rng;
 use rand_chacha::ChaChaRng;
 use rand_core::SeedableRng;
+use std::path::PathBuf;
+use std::sync::Arc;
 use tempfile::TempDir;
 
 fn poisson_uni<R: Rng>(prng: &mut R, lambda: u16) -> u16 {
@@ -83,7 +89,7 @@ impl quickcheck::Arbitrary for SizeDistribution {
         g.choose(&options).unwrap().clone()
     }
 
-    fn shrink(&self) -> Box<(dyn Iterator<Item = Self> + 'static)> {
+    fn shrink(&self) -> Box<dyn Iterator<Item = Self> + 'static> {
         match self {
             Self::Constant(c) => Box::new(c.shrink().map(Self::Constant)),
 
@@ -177,7 +183,7 @@ impl quickcheck::Arbitrary for DataDistribution {
         g.choose(&options).unwrap().clone()
     }
 
-    fn shrink(&self) -> Box<(dyn Iterator<Item = Self> + 'static)> {
+    fn shrink(&self) -> Box<dyn Iterator<Item = Self> + 'static> {
         match self {
             // Self::RandomNoise => Box::new(std::iter::empty()),
             Self::Byte(v, vs) => {
@@ -271,7 +277,7 @@ impl quickcheck::Arbitrary for LogDescription {
         }
     }
 
-    fn shrink(&self) -> Box<(dyn Iterator<Item = Self> + 'static)> {
+    fn shrink(&self) -> Box<dyn Iterator<Item = Self> + 'static> {
         Box::new(
             self.file_fill_size
                 .shrink()
@@ -339,7 +345,7 @@ impl quickcheck::Arbitrary for StoreDescription {
         Self { logs }
     }
 
-    fn shrink(&self) -> Box<(dyn Iterator<Item = Self> + 'static)> {
+    fn shrink(&self) -> Box<dyn Iterator<Item = Self> + 'static> {
         Box::new(self.logs.shrink().map(|logs| Self { logs }))
     }
 }
@@ -365,7 +371,6 @@ enum StorageAction {
         i1: u32,
         i2: u32,
     },
-    // TODO: metadata corruption?
 }
 
 impl quickcheck::Arbitrary for StorageAction {
@@ -396,7 +401,7 @@ impl quickcheck::Arbitrary for StorageAction {
         g.choose(&options).unwrap().clone()
     }
 
-    fn shrink(&self) -> Box<(dyn Iterator<Item = Self> + 'static)> {
+    fn shrink(&self) -> Box<dyn Iterator<Item = Self> + 'static> {
         use StorageAction::*;
 
         match self {
@@ -490,16 +495,29 @@ struct IdealLog {
     num_pending_items: usize,
 }
 
+// The items held by each log as of some commit.
+type CommittedItems = Vec<Vec<(StorageLocation, Vec<u8>)>>;
+
 struct StorageRunner {
     store: AtomicStore,
     logs: Vec<IdealLog>,
-    directory: TempDir,
+    backend: Arc<dyn StorageBackend>,
+    path: PathBuf,
+    directory: Option<TempDir>,
 }
 
 impl StorageRunner {
-    fn new_with_path(desc: StoreDescription, directory: TempDir) -> Result<Self> {
-        let test_path = directory.path();
-        let mut store_loader = AtomicStoreLoader::load(test_path, "storage_runner_store").unwrap();
+    fn new_with_backend(
+        desc: StoreDescription,
+        backend: Arc<dyn StorageBackend>,
+        path: PathBuf,
+        directory: Option<TempDir>,
+    ) -> Result<Self> {
+        let mut store_loader = AtomicStoreLoader::load_with_config(
+            &path,
+            "storage_runner_store",
+            AtomicStoreConfig::default().backend(backend.clone()),
+        )?;
 
         let logs = desc
             .logs
@@ -511,24 +529,18 @@ impl StorageRunner {
                 let name = name.replace('/', "_");
                 let name = name[..core::cmp::min(10, name.len())].to_string();
                 let log = match log_desc.log_type {
-                    StorageType::Append => Log::Append(
-                        AppendLog::load(
-                            &mut store_loader,
-                            <BincodeLoadStore<Vec<u8>>>::default(),
-                            &name,
-                            log_desc.file_fill_size as u64,
-                        )
-                        .unwrap(),
-                    ),
-                    StorageType::Rolling => Log::Rolling(
-                        RollingLog::load(
-                            &mut store_loader,
-                            <BincodeLoadStore<Vec<u8>>>::default(),
-                            &name,
-                            log_desc.file_fill_size as u64,
-                        )
-                        .unwrap(),
-                    ),
+                    StorageType::Append => Log::Append(AppendLog::load(
+                        &mut store_loader,
+                        <BincodeLoadStore<Vec<u8>>>::default(),
+                        &name,
+                        log_desc.file_fill_size as u64,
+                    )?),
+                    StorageType::Rolling => Log::Rolling(RollingLog::load(
+                        &mut store_loader,
+                        <BincodeLoadStore<Vec<u8>>>::default(),
+                        &name,
+                        log_desc.file_fill_size as u64,
+                    )?),
                 };
 
                 Ok(IdealLog {
@@ -540,41 +552,83 @@ impl StorageRunner {
                     num_pending_items: 0,
                 })
             })
-            .collect::<Result<Vec<_>>>()
-            .unwrap();
+            .collect::<Result<Vec<_>>>()?;
 
         let store = AtomicStore::open(store_loader)?;
 
         Ok(Self {
             store,
             logs,
+            backend,
+            path,
             directory,
         })
     }
 
     fn new(desc: StoreDescription) -> Result<Self> {
-        Self::new_with_path(
+        let directory = tempfile::Builder::new()
+            .prefix("atomicstore_test")
+            .tempdir()
+            .unwrap();
+        Self::new_with_backend(
             desc,
-            tempfile::Builder::new()
-                .prefix("atomicstore_test")
-                .tempdir()
-                .unwrap(),
+            Arc::new(FileSystemBackend),
+            directory.path().to_path_buf(),
+            Some(directory),
         )
     }
 
-    fn run_action(mut self, action: StorageAction) -> Self {
+    fn committed_items(&self) -> CommittedItems {
+        self.logs
+            .iter()
+            .map(|log| log.stored_items[..log.stored_items.len() - log.num_pending_items].to_vec())
+            .collect()
+    }
+
+    fn all_items(&self) -> CommittedItems {
+        self.logs
+            .iter()
+            .map(|log| log.stored_items.clone())
+            .collect()
+    }
+
+    // Whether the logs hold exactly the `committed` items. Rolling logs only keep their latest
+    // item, so only that is compared.
+    fn holds(&self, committed: &CommittedItems) -> Result<bool> {
+        for (log, items) in self.logs.iter().zip(committed) {
+            let values = items.iter().map(|(_, val)| val.clone()).collect::<Vec<_>>();
+            let holds = match &log.log {
+                Log::Append(append) => append.iter().collect::<Result<Vec<_>>>()? == values,
+                Log::Rolling(rolling) => match rolling.load_latest() {
+                    Ok(latest) => values.last() == Some(&latest),
+                    Err(PersistenceError::FailedToFindExpectedResource { .. }) => values.is_empty(),
+                    Err(err) => return Err(err),
+                },
+            };
+            if !holds {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    fn run_action(self, action: StorageAction) -> Self {
+        self.try_run_action(action).unwrap()
+    }
+
+    fn try_run_action(mut self, action: StorageAction) -> Result<Self> {
         use StorageAction::*;
         match action {
             Commit => {
                 for log in self.logs.iter_mut() {
                     if log.num_pending_items > 0 {
-                        log.log.commit_version().unwrap();
+                        log.log.commit_version()?;
                     } else {
-                        log.log.skip_version().unwrap();
+                        log.log.skip_version()?;
                     }
                     log.num_pending_items = 0;
                 }
-                self.store.commit_version().unwrap();
+                self.store.commit_version()?;
             }
 
             Revert { which_log } => {
@@ -588,7 +642,7 @@ impl StorageRunner {
                         log.stored_items.pop();
                     }
 
-                    log.log.revert_version().unwrap();
+                    log.log.revert_version()?;
                 }
             }
 
@@ -610,8 +664,13 @@ impl StorageRunner {
 
                 drop(self.store);
 
-                self = Self::new_with_path(StoreDescription { logs }, self.directory).unwrap();
-                for (lg, stored_items) in self.logs.iter_mut().zip(log_stored_items.into_iter()) {
+                self = Self::new_with_backend(
+                    StoreDescription { logs },
+                    self.backend,
+                    self.path,
+                    self.directory,
+                )?;
+                for (lg, stored_items) in self.logs.iter_mut().zip(log_stored_items) {
                     lg.stored_items = stored_items;
                 }
             }
@@ -639,7 +698,7 @@ impl StorageRunner {
                         let mut buf = vec![0; buf_size];
                         log.desc.data_dist.sample(&mut log_prng, &mut buf);
 
-                        let loc = log.log.store_resource(&buf).unwrap();
+                        let loc = log.log.store_resource(&buf)?;
                         log.stored_items.push((loc, buf));
                         log.num_pending_items += 1;
                     }
@@ -661,9 +720,7 @@ impl StorageRunner {
                         Err(crate::PersistenceError::FailedToFindExpectedResource { .. }) => {
                             assert!(log.stored_items.len() <= log.num_pending_items);
                         }
-                        Err(e) => {
-                            panic!("load_latest error {:?}", e);
-                        }
+                        Err(e) => return Err(e),
                     }
                 }
             }
@@ -699,6 +756,14 @@ impl StorageRunner {
                             Err(crate::PersistenceError::FailedToFindExpectedResource { .. }),
                             Ok(hv),
                         ) if lo < hi && &hv == hi_val => {}
+                        (Err(e), _) | (_, Err(e))
+                            if !matches!(
+                                e,
+                                PersistenceError::FailedToFindExpectedResource { .. }
+                            ) =>
+                        {
+                            return Err(e)
+                        }
                         e => {
                             panic!(
                                 "load_specified {:?}, {:?} failed: {:?}",
@@ -712,7 +777,7 @@ impl StorageRunner {
             }
         }
 
-        self
+        Ok(self)
     }
 }
 
@@ -835,3 +900,166 @@ fn store_test_scenario_regressions() {
         },
     );
 }
+
+const CRASH_TEST_PATH: &str = "/crash_test/store";
+
+// Run `actions` on a store kept in `backend` until the injected fault makes one of them fail.
+// Returns the committed states the store may recover to, and the number of actions attempted.
+fn run_until_crash(
+    actions: &[StorageAction],
+    desc: &StoreDescription,
+    backend: &FaultInjectingBackend,
+) -> (Vec<CommittedItems>, usize) {
+    let empty = vec![vec![]; desc.logs.len()];
+    let mut runner = match StorageRunner::new_with_backend(
+        desc.clone(),
+        Arc::new(backend.clone()),
+        PathBuf::from(CRASH_TEST_PATH),
+        None,
+    ) {
+        Ok(runner) => runner,
+        Err(err) => {
+            assert!(backend.fault_injected(), "unexpected error {:?}", err);
+            return (vec![empty], 0);
+        }
+    };
+    for (i, action) in actions.iter().enumerate() {
+        // A commit interrupted after its table of contents was renamed into place is durable.
+        let mut candidates = vec![runner.committed_items()];
+        if let StorageAction::Commit = action {
+            candidates.push(runner.all_items());
+        }
+        match runner.try_run_action(action.clone()) {
+            Ok(next) => runner = next,
+            Err(err) => {
+                assert!(backend.fault_injected(), "unexpected error {:?}", err);
+                return (candidates, i + 1);
+            }
+        }
+    }
+    (vec![runner.committed_items()], actions.len())
+}
+
+// Reload the store from `image` after a crash, check that it recovered one of the `candidates`,
+// and make sure it remains usable by running the `remaining` actions.
+fn recover(
+    image: &MemoryBackend,
+    desc: &StoreDescription,
+    candidates: &[CommittedItems],
+    remaining: &[StorageAction],
+    fault: Option<Fault>,
+) {
+    let mut runner = StorageRunner::new_with_backend(
+        desc.clone(),
+        Arc::new(image.reopen()),
+        PathBuf::from(CRASH_TEST_PATH),
+        None,
+    )
+    .unwrap_or_else(|err| panic!("failed to recover from {:?}: {:?}", fault, err));
+    let committed = candidates
+        .iter()
+        .find(|committed| runner.holds(committed).unwrap())
+        .unwrap_or_else(|| panic!("{:?} lost or corrupted committed data", fault));
+    for (log, items) in runner.logs.iter_mut().zip(committed) {
+        log.stored_items = items.clone();
+    }
+    runner = runner.run_action(StorageAction::LoadLatest);
+    for action in remaining {
+        runner = runner.run_action(action.clone());
+    }
+}
+
+// Replay `actions` once for every operation that modifies storage and every kind of fault,
+// crashing at that operation, and check that each crash recovers to a committed state.
+fn crash_test_scenario(actions: Vec<StorageAction>, desc: StoreDescription) {
+    let operations = {
+        let backend = FaultInjectingBackend::new(Arc::new(MemoryBackend::new()), None);
+        let (candidates, _) = run_until_crash(&actions, &desc, &backend);
+        assert_eq!(candidates.len(), 1);
+        backend.operations()
+    };
+    println!(
+        "crashing {} actions at {} operations, {} logs",
+        actions.len(),
+        operations,
+        desc.logs.len()
+    );
+
+    for op in 0..operations {
+        for kind in [FaultKind::Fail, FaultKind::Truncate, FaultKind::Drop] {
+            let fault = Some(Fault { op, kind });
+            let image = MemoryBackend::new();
+            let backend = FaultInjectingBackend::new(Arc::new(image.clone()), fault);
+            let (candidates, attempted) = run_until_crash(&actions, &desc, &backend);
+            recover(&image, &desc, &candidates, &actions[attempted..], fault);
+        }
+    }
+}
+
+#[test]
+fn crash_test_scenario_quickcheck() {
+    fn property(mut actions: Vec<StorageAction>, mut desc: StoreDescription) {
+        // The actions are replayed once per operation and fault, so the number of replays grows
+        // with the square of the scenario's length; keep the scenarios small enough to crash at
+        // every operation.
+        actions.truncate(3);
+        desc.logs.truncate(2);
+        crash_test_scenario(actions, desc);
+    }
+    quickcheck::QuickCheck::new()
+        .tests(4)
+        .quickcheck(property as fn(Vec<StorageAction>, StoreDescription))
+}
+
+// Longer scenarios, crashed at every operation. Run with `cargo test -- --ignored`.
+#[test]
+#[ignore]
+fn crash_test_scenario_quickcheck_long() {
+    fn property(mut actions: Vec<StorageAction>, desc: StoreDescription) {
+        actions.truncate(5);
+        crash_test_scenario(actions, desc);
+    }
+    quickcheck::QuickCheck::new()
+        .tests(4)
+        .quickcheck(property as fn(Vec<StorageAction>, StoreDescription))
+}
+
+#[test]
+fn crash_test_scenario_regressions() {
+    use DataDistribution::*;
+    use SizeDistribution::*;
+    use StorageAction::*;
+    use StorageType::*;
+
+    let log = |log_type, file_fill_size| LogDescription {
+        file_fill_size,
+        log_type,
+        data_rate: Constant(2),
+        size_dist: Constant(3),
+        data_dist: Byte(7, vec![1, 2]),
+    };
+    let write = |seed| WriteData {
+        seed,
+        max_num: 4,
+        max_buf_size: 4,
+    };
+    crash_test_scenario(
+        vec![
+            write(0),
+            Commit,
+            write(1),
+            Commit,
+            Reconstruct,
+            write(2),
+            Revert { which_log: 0 },
+            Commit,
+            ReadData { i1: 0, i2: 3 },
+        ],
+        StoreDescription {
+            logs: vec![
+                ("append".to_string(), log(Append, 24)),
+                ("rolling".to_string(), log(Rolling, 16)),
+            ],
+        },
+    );
+}
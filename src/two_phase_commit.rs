@@ -0,0 +1,605 @@
+// Copyright (c) 2022 Espresso Systems (espressosys.com)
+// This file is part of the AtomicStore library.
+
+// This program is free software: you can redistribute it and/or modify it under the terms of the GNU General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Versions committed atomically across several stores.
+//!
+//! A `CommitCoordinator` commits a version in each of several `AtomicStore`s, which may be in
+//! different directories, so that either all of the versions are committed or none are. Each store
+//! first writes the table of contents of its next version to its working file, along with a
+//! `.<pattern>_prepared` file naming the transaction. Once every store is prepared, the coordinator
+//! durably records its decision to commit, and only then does each store make its new version the
+//! latest.
+//!
+//! If the process is interrupted, `AtomicStoreLoader::load` resolves a prepared version when the
+//! store is next loaded: it is committed if the decision record names its transaction, and
+//! discarded otherwise. The record keeps each committed transaction until every store that took
+//! part in it has finished it, which the coordinator checks when it starts the next transaction by
+//! looking for the stores' prepared markers through its own backend.
+
+use crate::atomic_store::AtomicStore;
+use crate::error::{
+    BincodeDeSnafu, BincodeSerSnafu, StdIoDirOpsSnafu, StdIoOpenSnafu, StdIoWriteSnafu,
+};
+use crate::storage_backend::{FileSystemBackend, OpenMode, StorageBackend};
+use crate::utils::{create_dir_all, read_file, sync_dir, sync_parent_dir};
+use crate::Result;
+
+use serde::{Deserialize, Serialize};
+use snafu::ResultExt;
+
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+pub(crate) fn format_prepared_file_path(root_path: &Path, file_pattern: &str) -> PathBuf {
+    let mut buf = root_path.to_path_buf();
+    buf.push(format!(".{}_prepared", file_pattern));
+    buf
+}
+
+/// Written beside the working file of a store that has prepared a version for a transaction.
+#[derive(Debug, Serialize, Deserialize)]
+struct PreparedMarker {
+    record_path: PathBuf,
+    transaction: u64,
+}
+
+/// A transaction a coordinator decided to commit, and the stores that prepared a version for it.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct CommittedTransaction {
+    transaction: u64,
+    /// The directory and file pattern of each store.
+    stores: Vec<(PathBuf, String)>,
+}
+
+/// The last transaction a coordinator started, and the committed transactions that a store may not
+/// have finished yet.
+#[derive(Debug, Serialize, Deserialize)]
+struct DecisionRecord {
+    /// Recorded before any store prepares a version for the transaction, so that its ID is never
+    /// handed out again, even if the process is interrupted.
+    started: u64,
+    committed: Vec<CommittedTransaction>,
+}
+
+// Write `contents` to a new file at `path`, and sync it.
+fn write_file(backend: &dyn StorageBackend, path: &Path, contents: &[u8]) -> Result<()> {
+    let mut file = backend
+        .open(path, OpenMode::Create)
+        .context(StdIoOpenSnafu)?;
+    file.write_all(contents).context(StdIoWriteSnafu)?;
+    file.sync().context(StdIoDirOpsSnafu)
+}
+
+fn read_record(backend: &dyn StorageBackend, record_path: &Path) -> Result<Option<DecisionRecord>> {
+    if !backend.exists(record_path) {
+        return Ok(None);
+    }
+    let buf = read_file(backend, record_path)?;
+    Ok(Some(bincode::deserialize(&buf).context(BincodeDeSnafu)?))
+}
+
+// Replace the decision record by writing it to a working file and renaming it into place, so that
+// a crash leaves either the old decision or the new one.
+fn write_record(
+    backend: &dyn StorageBackend,
+    record_path: &Path,
+    record: &DecisionRecord,
+) -> Result<()> {
+    let mut temp_path = record_path.as_os_str().to_owned();
+    temp_path.push(".working");
+    let temp_path = PathBuf::from(temp_path);
+    let serialized = bincode::serialize(record).context(BincodeSerSnafu)?;
+    write_file(backend, &temp_path, &serialized)?;
+    backend
+        .rename(&temp_path, record_path)
+        .context(StdIoDirOpsSnafu)?;
+    sync_parent_dir(backend, record_path)
+}
+
+/// Whether the store at `root_path` with `file_pattern` has a version prepared by a transaction,
+/// and if so, whether the transaction was committed. Returns `None` if nothing is prepared.
+pub(crate) fn prepared_outcome(
+    backend: &dyn StorageBackend,
+    root_path: &Path,
+    file_pattern: &str,
+) -> Result<Option<bool>> {
+    let marker_path = format_prepared_file_path(root_path, file_pattern);
+    if !backend.exists(&marker_path) {
+        return Ok(None);
+    }
+    let marker = read_marker(backend, &marker_path)?;
+    // Transaction IDs are never reused, so the ID alone identifies the stores prepared for it.
+    let committed = read_record(backend, &marker.record_path)?.is_some_and(|record| {
+        record
+            .committed
+            .iter()
+            .any(|committed| committed.transaction == marker.transaction)
+    });
+    Ok(Some(committed))
+}
+
+fn read_marker(backend: &dyn StorageBackend, marker_path: &Path) -> Result<PreparedMarker> {
+    bincode::deserialize(&read_file(backend, marker_path)?).context(BincodeDeSnafu)
+}
+
+// Whether the store at `root_path` with `file_pattern` has finished `transaction`: its marker is
+// removed once the prepared version is committed, and a store only prepares another version once
+// it has.
+fn has_finished(
+    backend: &dyn StorageBackend,
+    root_path: &Path,
+    file_pattern: &str,
+    transaction: u64,
+) -> Result<bool> {
+    let marker_path = format_prepared_file_path(root_path, file_pattern);
+    if !backend.exists(&marker_path) {
+        return Ok(true);
+    }
+    Ok(read_marker(backend, &marker_path)?.transaction != transaction)
+}
+
+/// Delete the prepared marker of the store at `root_path` with `file_pattern`, if there is one.
+pub(crate) fn remove_prepared_marker(
+    backend: &dyn StorageBackend,
+    root_path: &Path,
+    file_pattern: &str,
+) -> Result<()> {
+    let marker_path = format_prepared_file_path(root_path, file_pattern);
+    if backend.exists(&marker_path) {
+        backend.remove(&marker_path).context(StdIoDirOpsSnafu)?;
+        sync_dir(backend, root_path)?;
+    }
+    Ok(())
+}
+
+/// Commits versions in several stores atomically, recording each decision at a path of its own.
+#[derive(Debug)]
+pub struct CommitCoordinator {
+    backend: Arc<dyn StorageBackend>,
+    record_path: PathBuf,
+    last_transaction: u64,
+    // Committed transactions that some store may not have finished.
+    unfinished: Vec<CommittedTransaction>,
+}
+
+impl CommitCoordinator {
+    /// Create a coordinator that records its decisions at `record_path`, continuing from the last
+    /// transaction recorded there, if any. The directory containing the record is created if it
+    /// does not exist.
+    pub fn new(record_path: &Path) -> Result<CommitCoordinator> {
+        Self::new_with_backend(Arc::new(FileSystemBackend), record_path)
+    }
+
+    /// Like `new`, but for a record kept in `backend`.
+    pub fn new_with_backend(
+        backend: Arc<dyn StorageBackend>,
+        record_path: &Path,
+    ) -> Result<CommitCoordinator> {
+        if let Some(parent) = record_path.parent() {
+            create_dir_all(backend.as_ref(), parent)?;
+        }
+        let (last_transaction, unfinished) = read_record(backend.as_ref(), record_path)?
+            .map(|record| (record.started, record.committed))
+            .unwrap_or_default();
+        Ok(CommitCoordinator {
+            backend,
+            record_path: record_path.to_path_buf(),
+            last_transaction,
+            unfinished,
+        })
+    }
+
+    /// The ID of the last transaction attempted by this coordinator.
+    pub fn last_transaction(&self) -> u64 {
+        self.last_transaction
+    }
+
+    /// Commit the next version of every store in `stores` with `metadata`, or none of them. As with
+    /// `AtomicStore::commit_version`, every resource of every store must have committed or skipped
+    /// its version first.
+    ///
+    /// If any store cannot prepare its version, every prepared version is discarded, the commit
+    /// points of the resources stay queued, and the error is returned. Once the decision has been
+    /// recorded, the versions are committed even if this is interrupted: every store is asked to
+    /// commit its version, and the first error is returned, but any store left with a prepared
+    /// version commits it when it is next loaded, or before it prepares its next version.
+    pub fn commit(&mut self, stores: &mut [&mut AtomicStore], metadata: &[u8]) -> Result<()> {
+        let transaction = self.start_transaction()?;
+        let marker = PreparedMarker {
+            record_path: self.record_path.clone(),
+            transaction,
+        };
+        for i in 0..stores.len() {
+            if let Err(err) = Self::prepare(stores[i], &marker, metadata) {
+                Self::abort(stores);
+                return Err(err);
+            }
+        }
+
+        let mut committed = self.unfinished.clone();
+        committed.push(CommittedTransaction {
+            transaction,
+            stores: stores
+                .iter()
+                .map(|store| {
+                    (
+                        store.file_path().to_path_buf(),
+                        store.file_pattern().to_string(),
+                    )
+                })
+                .collect(),
+        });
+        let record = DecisionRecord {
+            started: transaction,
+            committed,
+        };
+        if let Err(err) = write_record(self.backend.as_ref(), &self.record_path, &record) {
+            Self::abort(stores);
+            return Err(err);
+        }
+        self.unfinished = record.committed;
+
+        let mut result = Ok(());
+        for store in stores.iter_mut() {
+            let finished = store.finish_version().and_then(|()| {
+                remove_prepared_marker(
+                    store.backend().as_ref(),
+                    store.file_path(),
+                    store.file_pattern(),
+                )
+            });
+            if let Err(err) = finished {
+                tracing::warn!(
+                    %err,
+                    path = %store.file_path().display(),
+                    "failed to commit decided version",
+                );
+                if result.is_ok() {
+                    result = Err(err);
+                }
+            }
+        }
+        result
+    }
+
+    // Hand out the next transaction ID, recording it first, so that a decision can never be
+    // mistaken for one made about an earlier attempt, even one interrupted before it was decided.
+    // Committed transactions that every store has finished are forgotten at the same time.
+    fn start_transaction(&mut self) -> Result<u64> {
+        let mut unfinished = Vec::new();
+        for committed in &self.unfinished {
+            let mut finished = true;
+            for (root_path, file_pattern) in &committed.stores {
+                finished &= has_finished(
+                    self.backend.as_ref(),
+                    root_path,
+                    file_pattern,
+                    committed.transaction,
+                )?;
+            }
+            if !finished {
+                unfinished.push(committed.clone());
+            }
+        }
+        let record = DecisionRecord {
+            started: self.last_transaction + 1,
+            committed: unfinished,
+        };
+        write_record(self.backend.as_ref(), &self.record_path, &record)?;
+        self.last_transaction += 1;
+        self.unfinished = record.committed;
+        Ok(self.last_transaction)
+    }
+
+    fn prepare(store: &mut AtomicStore, marker: &PreparedMarker, metadata: &[u8]) -> Result<()> {
+        store.prepare_version(metadata)?;
+        let backend = store.backend().clone();
+        let marker_path = format_prepared_file_path(store.file_path(), store.file_pattern());
+        let serialized = bincode::serialize(marker).context(BincodeSerSnafu)?;
+        write_file(backend.as_ref(), &marker_path, &serialized)?;
+        // Both the working file and the marker must be durable before the decision is recorded.
+        sync_dir(backend.as_ref(), store.file_path())
+    }
+
+    // Discard the prepared versions of a transaction that will not be committed. Failures are only
+    // logged, as the prepared versions are discarded when the stores are next loaded in any case.
+    fn abort(stores: &mut [&mut AtomicStore]) {
+        for store in stores.iter_mut() {
+            let result = store.abort_version().and_then(|()| {
+                remove_prepared_marker(
+                    store.backend().as_ref(),
+                    store.file_path(),
+                    store.file_pattern(),
+                )
+            });
+            if let Err(err) = result {
+                tracing::warn!(
+                    %err,
+                    path = %store.file_path().display(),
+                    "failed to discard prepared version",
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fault_injection::{Fault, FaultInjectingBackend, FaultKind};
+    use crate::memory_backend::MemoryBackend;
+    use crate::{
+        load_store::BincodeLoadStore, AppendLog, AtomicStoreConfig, AtomicStoreLoader,
+        PersistenceError,
+    };
+
+    type Log = AppendLog<BincodeLoadStore<u64>>;
+
+    const STORES: [&str; 2] = ["/a", "/b"];
+
+    fn open(backend: &Arc<dyn StorageBackend>, dir: &str) -> (Log, AtomicStore) {
+        open_with_config(dir, AtomicStoreConfig::default().backend(backend.clone()))
+    }
+
+    fn open_with_config(dir: &str, config: AtomicStoreConfig) -> (Log, AtomicStore) {
+        let mut loader =
+            AtomicStoreLoader::load_with_config(Path::new(dir), "store", config).unwrap();
+        let log = Log::load(&mut loader, BincodeLoadStore::default(), "log", 16).unwrap();
+        (log, AtomicStore::open(loader).unwrap())
+    }
+
+    // The committed contents of the log in each store, after reopening the backend as if the
+    // process had crashed.
+    fn committed_after_crash(backend: &MemoryBackend) -> Vec<Vec<u64>> {
+        let backend: Arc<dyn StorageBackend> = Arc::new(backend.reopen());
+        STORES
+            .iter()
+            .map(|dir| {
+                let (log, _store) = open(&backend, dir);
+                let root = Path::new(dir);
+                assert!(!backend.exists(&format_prepared_file_path(root, "store")));
+                assert!(!backend.exists(&root.join(".store_working")));
+                log.iter().collect::<Result<Vec<_>>>().unwrap()
+            })
+            .collect()
+    }
+
+    // The IDs of the committed transactions kept in the decision record at `record_path`.
+    fn committed_transactions(backend: &Arc<dyn StorageBackend>, record_path: &Path) -> Vec<u64> {
+        read_record(backend.as_ref(), record_path)
+            .unwrap()
+            .unwrap()
+            .committed
+            .iter()
+            .map(|committed| committed.transaction)
+            .collect()
+    }
+
+    // Open both stores, and commit `value` to each of their logs.
+    fn stage(backend: &Arc<dyn StorageBackend>, value: u64) -> Vec<(Log, AtomicStore)> {
+        STORES
+            .iter()
+            .map(|dir| {
+                let (mut log, store) = open(backend, dir);
+                log.store_resource(&value).unwrap();
+                log.commit_version().unwrap();
+                (log, store)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn commits_every_store() {
+        let memory = MemoryBackend::new();
+        let backend: Arc<dyn StorageBackend> = Arc::new(memory.clone());
+        let record_path = Path::new("/coordinator/decision");
+        let mut coordinator =
+            CommitCoordinator::new_with_backend(backend.clone(), record_path).unwrap();
+        let mut staged = stage(&backend, 1);
+        let mut stores = staged
+            .iter_mut()
+            .map(|(_, store)| store)
+            .collect::<Vec<_>>();
+        coordinator.commit(&mut stores, b"first").unwrap();
+        assert_eq!(coordinator.last_transaction(), 1);
+        drop(staged);
+
+        assert_eq!(committed_after_crash(&memory), vec![vec![1], vec![1]]);
+        let coordinator = CommitCoordinator::new_with_backend(backend, record_path).unwrap();
+        assert_eq!(coordinator.last_transaction(), 1);
+    }
+
+    #[test]
+    fn failed_prepare_commits_nothing() {
+        let memory = MemoryBackend::new();
+        let backend: Arc<dyn StorageBackend> = Arc::new(memory.clone());
+        let mut coordinator =
+            CommitCoordinator::new_with_backend(backend.clone(), Path::new("/decision")).unwrap();
+        let (mut log_a, mut store_a) = open(&backend, "/a");
+        let (mut log_b, mut store_b) = open_with_config(
+            "/b",
+            AtomicStoreConfig::default()
+                .backend(backend.clone())
+                .commit_timeout(std::time::Duration::from_millis(10)),
+        );
+        log_a.store_resource(&1).unwrap();
+        log_a.commit_version().unwrap();
+        log_b.store_resource(&1).unwrap();
+
+        // The log in the second store has not committed, so neither store commits.
+        assert!(matches!(
+            coordinator.commit(&mut [&mut store_a, &mut store_b], &[]),
+            Err(PersistenceError::TimedOut { .. })
+        ));
+        assert!(!backend.exists(&format_prepared_file_path(Path::new("/a"), "store")));
+        assert!(!backend.exists(Path::new("/a/.store_working")));
+        assert_eq!(committed_after_crash(&memory), vec![vec![], vec![]]);
+
+        // The first log's commit point is still queued for the next attempt.
+        log_b.commit_version().unwrap();
+        coordinator
+            .commit(&mut [&mut store_a, &mut store_b], &[])
+            .unwrap();
+        assert_eq!(coordinator.last_transaction(), 2);
+        assert_eq!(committed_after_crash(&memory), vec![vec![1], vec![1]]);
+    }
+
+    #[test]
+    fn interrupted_before_decision_is_discarded() {
+        let memory = MemoryBackend::new();
+        let backend: Arc<dyn StorageBackend> = Arc::new(memory.clone());
+        let marker = PreparedMarker {
+            record_path: PathBuf::from("/decision"),
+            transaction: 1,
+        };
+        let mut staged = stage(&backend, 1);
+        for (_, store) in staged.iter_mut() {
+            CommitCoordinator::prepare(store, &marker, &[]).unwrap();
+        }
+        assert_eq!(committed_after_crash(&memory), vec![vec![], vec![]]);
+    }
+
+    #[test]
+    fn interrupted_after_decision_is_committed() {
+        let memory = MemoryBackend::new();
+        let backend: Arc<dyn StorageBackend> = Arc::new(memory.clone());
+        let record_path = Path::new("/decision");
+        let mut staged = stage(&backend, 1);
+        let mut coordinator =
+            CommitCoordinator::new_with_backend(backend.clone(), record_path).unwrap();
+        let mut stores = staged
+            .iter_mut()
+            .map(|(_, store)| store)
+            .collect::<Vec<_>>();
+        coordinator.commit(&mut stores, &[]).unwrap();
+        drop(staged);
+
+        // Prepare the second transaction, record the decision, and finish only the first store.
+        let mut staged = stage(&backend, 2);
+        let marker = PreparedMarker {
+            record_path: record_path.to_path_buf(),
+            transaction: 2,
+        };
+        for (_, store) in staged.iter_mut() {
+            CommitCoordinator::prepare(store, &marker, &[]).unwrap();
+        }
+        let record = DecisionRecord {
+            started: 2,
+            committed: vec![CommittedTransaction {
+                transaction: 2,
+                stores: STORES
+                    .iter()
+                    .map(|dir| (PathBuf::from(dir), "store".to_string()))
+                    .collect(),
+            }],
+        };
+        write_record(backend.as_ref(), record_path, &record).unwrap();
+        staged[0].1.finish_version().unwrap();
+        assert_eq!(committed_after_crash(&memory), vec![vec![1, 2], vec![1, 2]]);
+    }
+
+    #[test]
+    fn interrupted_transaction_id_is_not_reused() {
+        let memory = MemoryBackend::new();
+        let backend: Arc<dyn StorageBackend> = Arc::new(memory.clone());
+        let record_path = Path::new("/decision");
+        let mut coordinator =
+            CommitCoordinator::new_with_backend(backend.clone(), record_path).unwrap();
+
+        // Both stores prepare the first transaction, but the process stops before the decision.
+        let mut staged = stage(&backend, 1);
+        let marker = PreparedMarker {
+            record_path: record_path.to_path_buf(),
+            transaction: coordinator.start_transaction().unwrap(),
+        };
+        for (_, store) in staged.iter_mut() {
+            CommitCoordinator::prepare(store, &marker, &[]).unwrap();
+        }
+        drop(staged);
+        let memory = memory.reopen();
+        let backend: Arc<dyn StorageBackend> = Arc::new(memory.clone());
+
+        // A new coordinator commits only the first store, which discards its prepared version
+        // when loaded. The second store's prepared version must not be taken for this transaction.
+        let mut coordinator =
+            CommitCoordinator::new_with_backend(backend.clone(), record_path).unwrap();
+        assert_eq!(coordinator.last_transaction(), 1);
+        let (mut log, mut store) = open(&backend, "/a");
+        log.store_resource(&2).unwrap();
+        log.commit_version().unwrap();
+        coordinator.commit(&mut [&mut store], &[]).unwrap();
+        assert_eq!(coordinator.last_transaction(), 2);
+        drop((log, store));
+        assert_eq!(committed_after_crash(&memory), vec![vec![2], vec![]]);
+    }
+
+    #[test]
+    fn unfinished_decision_outlives_the_next_commit() {
+        let record_path = Path::new("/decision");
+        // Count the operations of the second store up to its prepared version, so that the
+        // install that follows can be made to fail.
+        let prepared_ops = {
+            let faulty = Arc::new(FaultInjectingBackend::new(
+                Arc::new(MemoryBackend::new()),
+                None,
+            ));
+            let backend: Arc<dyn StorageBackend> = faulty.clone();
+            let (mut log, mut store) = open(&backend, "/b");
+            log.store_resource(&1).unwrap();
+            log.commit_version().unwrap();
+            let marker = PreparedMarker {
+                record_path: record_path.to_path_buf(),
+                transaction: 1,
+            };
+            CommitCoordinator::prepare(&mut store, &marker, &[]).unwrap();
+            faulty.operations()
+        };
+
+        let memory = MemoryBackend::new();
+        let backend: Arc<dyn StorageBackend> = Arc::new(memory.clone());
+        let faulty: Arc<dyn StorageBackend> = Arc::new(FaultInjectingBackend::new(
+            backend.clone(),
+            Some(Fault {
+                op: prepared_ops,
+                kind: FaultKind::Fail,
+            }),
+        ));
+        let mut coordinator =
+            CommitCoordinator::new_with_backend(backend.clone(), record_path).unwrap();
+        let (mut log_a, mut store_a) = open(&backend, "/a");
+        let (mut log_b, mut store_b) = open(&faulty, "/b");
+        log_a.store_resource(&1).unwrap();
+        log_a.commit_version().unwrap();
+        log_b.store_resource(&1).unwrap();
+        log_b.commit_version().unwrap();
+
+        // The second store fails to install the decided version, but the first store still does.
+        coordinator
+            .commit(&mut [&mut store_b, &mut store_a], &[])
+            .unwrap_err();
+        assert!(!backend.exists(&format_prepared_file_path(Path::new("/a"), "store")));
+
+        // Committing another transaction must not forget the first, which the second store has
+        // not finished.
+        log_a.store_resource(&2).unwrap();
+        log_a.commit_version().unwrap();
+        coordinator.commit(&mut [&mut store_a], &[]).unwrap();
+        assert_eq!(committed_transactions(&backend, record_path), vec![1, 2]);
+        drop((log_a, store_a, log_b, store_b));
+        let memory = memory.reopen();
+        assert_eq!(committed_after_crash(&memory), vec![vec![1, 2], vec![1]]);
+
+        // Once every store has finished the first transaction, it is forgotten.
+        let backend: Arc<dyn StorageBackend> = Arc::new(memory.clone());
+        drop(open(&backend, "/b"));
+        let mut coordinator =
+            CommitCoordinator::new_with_backend(backend.clone(), record_path).unwrap();
+        coordinator.commit(&mut [], &[]).unwrap();
+        assert_eq!(committed_transactions(&backend, record_path), vec![3]);
+    }
+}
@@ -1,3 +1,11 @@
+use crate::error::{StdIoDirOpsSnafu, StdIoOpenSnafu, StdIoReadSnafu, StdIoWriteSnafu};
+use crate::storage_backend::{EntryType, OpenMode, StorageBackend};
+use crate::Result;
+
+use snafu::ResultExt;
+
+use std::io::Read;
+use std::path::Path;
 use std::time::SystemTime;
 
 /// Get the unix timestamp
@@ -9,3 +17,101 @@ pub fn unix_timestamp() -> i64 {
         Err(e) => -(e.duration().as_secs() as i64),
     }
 }
+
+/// Flush the entries of a directory to durable storage.
+///
+/// Renaming, creating or removing a file only modifies the containing directory, so syncing the
+/// file itself is not enough for the change to survive a power loss.
+pub fn sync_dir(backend: &dyn StorageBackend, dir_path: &Path) -> Result<()> {
+    backend.sync_dir(dir_path).context(StdIoDirOpsSnafu)
+}
+
+/// Flush the directory containing `path`, if it has one.
+pub fn sync_parent_dir(backend: &dyn StorageBackend, path: &Path) -> Result<()> {
+    match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => sync_dir(backend, parent),
+        _ => Ok(()),
+    }
+}
+
+/// Read the whole of the file at `path`.
+pub fn read_file(backend: &dyn StorageBackend, path: &Path) -> Result<Vec<u8>> {
+    let mut file = backend.open(path, OpenMode::Read).context(StdIoOpenSnafu)?;
+    let mut buf = Vec::new();
+    file.read_to_end(&mut buf).context(StdIoReadSnafu)?;
+    Ok(buf)
+}
+
+/// The size of the file at `path` in bytes, or `None` if there is no file there.
+pub fn file_len(backend: &dyn StorageBackend, path: &Path) -> Result<Option<u64>> {
+    match backend.stat(path).context(StdIoOpenSnafu)? {
+        Some(EntryType::File { len }) => Ok(Some(len)),
+        _ => Ok(None),
+    }
+}
+
+/// Create the directory at `path` along with any missing ancestors, and sync the directory
+/// containing each one created, so that none of them can be lost.
+pub fn create_dir_all(backend: &dyn StorageBackend, path: &Path) -> Result<()> {
+    let missing: Vec<&Path> = path
+        .ancestors()
+        .take_while(|dir| !dir.as_os_str().is_empty() && !backend.exists(dir))
+        .collect();
+    if missing.is_empty() {
+        return Ok(());
+    }
+    backend.create_dir_all(path).context(StdIoDirOpsSnafu)?;
+    for dir in missing.into_iter().rev() {
+        sync_parent_dir(backend, dir)?;
+    }
+    Ok(())
+}
+
+/// Make `dest` a copy of a file that will no longer be modified, by hard linking it if possible.
+pub fn link_or_copy(backend: &dyn StorageBackend, src: &Path, dest: &Path) -> Result<()> {
+    if backend.hard_link(src, dest).is_err() {
+        copy_prefix(backend, src, dest, u64::MAX)?;
+    }
+    Ok(())
+}
+
+/// Copy at most the first `len` bytes of `src` to a new file at `dest`, and sync it.
+pub fn copy_prefix(backend: &dyn StorageBackend, src: &Path, dest: &Path, len: u64) -> Result<()> {
+    let source = backend.open(src, OpenMode::Read).context(StdIoOpenSnafu)?;
+    let mut dest_file = backend
+        .open(dest, OpenMode::Create)
+        .context(StdIoOpenSnafu)?;
+    std::io::copy(&mut source.take(len), &mut dest_file).context(StdIoWriteSnafu)?;
+    dest_file.sync().context(StdIoDirOpsSnafu)
+}
+
+/// Delete `backup_path`, the original of a file that has been replaced by a copy of its committed
+/// prefix at `copy_path`. The copy is synced first, as the backup may be the only durable copy of
+/// the committed data until then.
+pub fn discard_backup(
+    backend: &dyn StorageBackend,
+    backup_path: &Path,
+    copy_path: &Path,
+) -> Result<()> {
+    if backend.exists(copy_path) {
+        backend
+            .open(copy_path, OpenMode::ReadWrite)
+            .context(StdIoOpenSnafu)?
+            .sync()
+            .context(StdIoDirOpsSnafu)?;
+    }
+    backend.remove(backup_path).context(StdIoDirOpsSnafu)
+}
+
+/// Delete the directory at `path` and everything in it.
+pub fn remove_dir_all(backend: &dyn StorageBackend, path: &Path) -> Result<()> {
+    for name in backend.list(path).context(StdIoDirOpsSnafu)? {
+        let entry_path = path.join(name);
+        if backend.is_dir(&entry_path) {
+            remove_dir_all(backend, &entry_path)?;
+        } else {
+            backend.remove(&entry_path).context(StdIoDirOpsSnafu)?;
+        }
+    }
+    backend.remove_dir(path).context(StdIoDirOpsSnafu)
+}
@@ -5,23 +5,30 @@
 // This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
 // You should have received a copy of the GNU General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
 
-// boy oh boy did clippy get this one wrong
-#![allow(clippy::mutex_atomic)]
-
 use crate::storage_location::StorageLocation;
 use crate::Result;
 
 use std::{
+    collections::VecDeque,
     sync::{Arc, Condvar, Mutex},
     time::{Duration, Instant},
 };
 
+/// Commit points of a resource that have not been taken by the `AtomicStore` yet.
+#[derive(Debug)]
+struct PendingVersions {
+    // The location to record for each version, oldest first.
+    queue: VecDeque<Option<StorageLocation>>,
+    // The location recorded for the last version taken.
+    taken: Option<StorageLocation>,
+}
+
 #[derive(Debug)]
 pub struct VersionSyncHandle {
     last_version_location: Option<StorageLocation>,
     next_version_location: Option<StorageLocation>,
-    version_pending: Arc<(Mutex<bool>, Condvar)>,
-    _resource_key: String,
+    version_pending: Arc<(Mutex<PendingVersions>, Condvar)>,
+    resource_key: String,
 }
 
 impl VersionSyncHandle {
@@ -29,72 +36,105 @@ impl VersionSyncHandle {
         VersionSyncHandle {
             last_version_location,
             next_version_location: last_version_location,
-            version_pending: Arc::new((Mutex::new(false), Condvar::new())),
-            _resource_key: key.to_string(),
+            version_pending: Arc::new((
+                Mutex::new(PendingVersions {
+                    queue: VecDeque::new(),
+                    taken: last_version_location,
+                }),
+                Condvar::new(),
+            )),
+            resource_key: key.to_string(),
         }
     }
+    pub fn resource_key(&self) -> &str {
+        &self.resource_key
+    }
+    /// The location of the last version committed by the resource, which may not have been taken
+    /// by the store yet.
     pub fn last_location(&self) -> &Option<StorageLocation> {
         &self.last_version_location
     }
     // pub(crate) fn next_location(&self) -> &Option<StorageLocation> {
     //     &self.next_version_location
     // }
-    pub fn start_version(&mut self) -> Result<()> {
+    /// Take the oldest queued commit point for the store's next version, returning the location
+    /// to record. If nothing is queued, the location of the last version taken is returned again.
+    pub fn take_version(&mut self) -> Result<Option<StorageLocation>> {
         let (mtx, _) = &*self.version_pending;
-        let mut version_ready = mtx.lock()?;
-        *version_ready = false;
-        Ok(())
+        let mut pending = mtx.lock()?;
+        if let Some(location) = pending.queue.pop_front() {
+            pending.taken = location;
+        }
+        Ok(pending.taken)
+    }
+    /// The location `take_version` would return, without taking it.
+    pub fn peek_version(&self) -> Result<Option<StorageLocation>> {
+        let (mtx, _) = &*self.version_pending;
+        let pending = mtx.lock()?;
+        Ok(pending.queue.front().copied().unwrap_or(pending.taken))
+    }
+    /// How many commit points are queued for the store.
+    pub fn queued_versions(&self) -> Result<usize> {
+        let (mtx, _) = &*self.version_pending;
+        Ok(mtx.lock()?.queue.len())
     }
     pub fn advance_next(&mut self, next_version_location: Option<StorageLocation>) {
         self.next_version_location = next_version_location;
     }
+    /// Queue the location written so far as the resource's commit point for the next version.
     pub fn update_version(&mut self) -> Result<()> {
         let (mtx, cv) = &*self.version_pending;
-        let mut version_ready = mtx.lock()?;
-        if !*version_ready {
-            self.last_version_location = self.next_version_location;
-            *version_ready = true;
-            cv.notify_one();
-        }
+        let mut pending = mtx.lock()?;
+        self.last_version_location = self.next_version_location;
+        pending.queue.push_back(self.last_version_location);
+        cv.notify_one();
         Ok(())
     }
+    /// Queue a commit point that leaves the resource unchanged from the previous one.
     pub fn skip_version(&mut self) -> Result<()> {
         let (mtx, cv) = &*self.version_pending;
-        let mut version_ready = mtx.lock()?;
-        if !*version_ready {
-            *version_ready = true;
-            cv.notify_one();
-        }
+        let mut pending = mtx.lock()?;
+        let location = pending.queue.back().copied().unwrap_or(pending.taken);
+        pending.queue.push_back(location);
+        cv.notify_one();
         Ok(())
     }
     pub fn revert_version(&mut self) -> Result<()> {
         let (mtx, _cv) = &*self.version_pending;
-        let _version_ready = mtx.lock()?;
+        let _pending = mtx.lock()?;
         self.next_version_location = self.last_version_location;
         Ok(())
     }
 
     pub fn wait_for_version_with_timeout(&self, timeout: Duration) -> Result<()> {
+        if self.wait_for_version_until(Instant::now().checked_add(timeout))? {
+            Ok(())
+        } else {
+            Err(crate::error::PersistenceError::TimedOut {
+                pending: vec![self.resource_key.clone()],
+            })
+        }
+    }
+
+    /// Wait until a commit point is queued or `deadline` passes, returning whether one is queued.
+    /// With no deadline, waits indefinitely.
+    pub fn wait_for_version_until(&self, deadline: Option<Instant>) -> Result<bool> {
         let version_pending = Arc::clone(&self.version_pending);
         let (mtx, cv) = &*version_pending;
-        let mut version_ready = mtx.lock()?;
-        // Get the current time, to make sure that `mtx` won't constantly update with `false` and this function won't
-        // ever time out
-        let start_time = Instant::now();
-        while !*version_ready {
-            // Make sure the `timeout` has not elapsed since the beginning of this function
-            let elapsed = start_time.elapsed();
-            if elapsed >= timeout {
-                return Err(crate::error::PersistenceError::TimedOut);
-            }
-
-            let remaining_time = timeout - elapsed;
-            let (guard, result) = cv.wait_timeout(version_ready, remaining_time)?;
-            if result.timed_out() {
-                return Err(crate::error::PersistenceError::TimedOut);
-            }
-            version_ready = guard;
+        let mut pending = mtx.lock()?;
+        while pending.queue.is_empty() {
+            pending = match deadline {
+                Some(deadline) => {
+                    // Checked on every wakeup, so that spurious wakeups don't extend the wait.
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return Ok(false);
+                    }
+                    cv.wait_timeout(pending, deadline - now)?.0
+                }
+                None => cv.wait(pending)?,
+            };
         }
-        Ok(())
+        Ok(true)
     }
 }
@@ -0,0 +1,157 @@
+// Copyright (c) 2022 Espresso Systems (espressosys.com)
+// This file is part of the AtomicStore library.
+
+// This program is free software: you can redistribute it and/or modify it under the terms of the GNU General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! These tests run the writer in a child process (this test binary, re-executed) which aborts
+//! without unwinding or dropping anything, then check from the parent that the last committed
+//! version can be recovered.
+//!
+//! Aborting keeps everything the process wrote in the page cache, so these tests do not show that
+//! anything was synced; `MemoryBackend::lose_power` is used to test that instead.
+
+use atomic_store::{
+    append_log::AppendLog,
+    atomic_store::{AtomicStore, AtomicStoreLoader},
+    fixed_append_log::FixedAppendLog,
+    load_store::BincodeLoadStore,
+    rolling_log::RollingLog,
+    Result,
+};
+
+use std::env;
+use std::path::Path;
+use std::process::Command;
+
+// Set in the child process to the directory it should write to.
+const CRASH_DIR_VAR: &str = "ATOMIC_STORE_CRASH_TEST_DIR";
+
+struct Logs {
+    append: AppendLog<BincodeLoadStore<u64>>,
+    fixed: FixedAppendLog<BincodeLoadStore<u64>>,
+    rolling: RollingLog<BincodeLoadStore<u64>>,
+    store: AtomicStore,
+}
+
+fn open(dir: &Path) -> Result<Logs> {
+    let mut loader = AtomicStoreLoader::load(dir, "crash_store")?;
+    let append = AppendLog::load(&mut loader, Default::default(), "append", 32)?;
+    let fixed = FixedAppendLog::load(&mut loader, Default::default(), "fixed", 8, 4)?;
+    let rolling = RollingLog::load(&mut loader, Default::default(), "rolling", 32)?;
+    let store = AtomicStore::open(loader)?;
+    Ok(Logs {
+        append,
+        fixed,
+        rolling,
+        store,
+    })
+}
+
+impl Logs {
+    fn store(&mut self, value: u64) -> Result<()> {
+        self.append.store_resource(&value)?;
+        self.fixed.store_resource(&value)?;
+        self.rolling.store_resource(&value)?;
+        Ok(())
+    }
+
+    fn commit_logs(&mut self) -> Result<()> {
+        self.append.commit_version()?;
+        self.fixed.commit_version()?;
+        self.rolling.commit_version()
+    }
+}
+
+/// Run the test named `name` in a child process writing to `dir`, and wait for it to crash.
+fn run_crashing_child(name: &str, dir: &Path) {
+    let status = Command::new(env::current_exe().unwrap())
+        .args([name, "--exact", "--test-threads=1"])
+        .env(CRASH_DIR_VAR, dir)
+        .status()
+        .expect("Could not run child process");
+    assert!(!status.success(), "child process should have aborted");
+}
+
+fn check_recovered(dir: &Path, committed: u64) {
+    let logs = open(dir).expect("Could not reopen store");
+    let expected = (0..committed).collect::<Vec<_>>();
+    assert_eq!(
+        logs.append.iter().collect::<Result<Vec<_>>>().unwrap(),
+        expected
+    );
+    assert_eq!(
+        logs.fixed.iter().collect::<Result<Vec<_>>>().unwrap(),
+        expected
+    );
+    assert_eq!(logs.rolling.load_latest().unwrap(), committed - 1);
+}
+
+#[test]
+fn crash_after_commit() {
+    if let Ok(dir) = env::var(CRASH_DIR_VAR) {
+        let mut logs = open(Path::new(&dir)).unwrap();
+        for i in 0..20 {
+            logs.store(i).unwrap();
+            logs.commit_logs().unwrap();
+            logs.store.commit_version().unwrap();
+        }
+        std::process::abort();
+    }
+
+    let dir = tempfile::tempdir().unwrap();
+    run_crashing_child("crash_after_commit", dir.path());
+    check_recovered(dir.path(), 20);
+}
+
+#[test]
+fn crash_between_log_and_store_commit() {
+    if let Ok(dir) = env::var(CRASH_DIR_VAR) {
+        let mut logs = open(Path::new(&dir)).unwrap();
+        for i in 0..10 {
+            logs.store(i).unwrap();
+            logs.commit_logs().unwrap();
+            logs.store.commit_version().unwrap();
+        }
+        // The logs commit these entries, but the store never does.
+        for i in 10..15 {
+            logs.store(i).unwrap();
+        }
+        logs.commit_logs().unwrap();
+        std::process::abort();
+    }
+
+    let dir = tempfile::tempdir().unwrap();
+    run_crashing_child("crash_between_log_and_store_commit", dir.path());
+    check_recovered(dir.path(), 10);
+
+    // The recovered store must accept new versions.
+    let mut logs = open(dir.path()).unwrap();
+    logs.store(10).unwrap();
+    logs.commit_logs().unwrap();
+    logs.store.commit_version().unwrap();
+    drop(logs);
+    check_recovered(dir.path(), 11);
+}
+
+#[test]
+fn crash_with_uncommitted_writes() {
+    if let Ok(dir) = env::var(CRASH_DIR_VAR) {
+        let mut logs = open(Path::new(&dir)).unwrap();
+        for i in 0..5 {
+            logs.store(i).unwrap();
+            logs.commit_logs().unwrap();
+            logs.store.commit_version().unwrap();
+        }
+        // Enough uncommitted entries to roll over to new files.
+        for i in 5..50 {
+            logs.store(i).unwrap();
+        }
+        std::process::abort();
+    }
+
+    let dir = tempfile::tempdir().unwrap();
+    run_crashing_child("crash_with_uncommitted_writes", dir.path());
+    check_recovered(dir.path(), 5);
+}
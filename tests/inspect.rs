@@ -0,0 +1,142 @@
+// Copyright (c) 2022 Espresso Systems (espressosys.com)
+// This file is part of the AtomicStore library.
+
+// This program is free software: you can redistribute it and/or modify it under the terms of the GNU General Public License as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU General Public License for more details.
+// You should have received a copy of the GNU General Public License along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+use atomic_store::{
+    append_log::AppendLog,
+    atomic_store::{AtomicStore, AtomicStoreLoader, InconsistencyKind},
+    fixed_append_log::FixedAppendLog,
+    load_store::BincodeLoadStore,
+    rolling_log::RollingLog,
+};
+
+use std::path::Path;
+use std::process::Command;
+
+fn inspect(dir: &Path, args: &[&str]) -> (bool, String) {
+    let output = Command::new(env!("CARGO_BIN_EXE_atomic-store-inspect"))
+        .arg(dir)
+        .arg("inspect")
+        .args(args)
+        .output()
+        .unwrap();
+    (
+        output.status.success(),
+        String::from_utf8(output.stdout).unwrap(),
+    )
+}
+
+#[test]
+fn inspect_store() {
+    let dir = tempfile::tempdir().unwrap();
+    {
+        let mut loader = AtomicStoreLoader::create(dir.path(), "inspect").unwrap();
+        let mut append =
+            AppendLog::create(&mut loader, BincodeLoadStore::<u32>::default(), "a", 1024).unwrap();
+        let mut fixed =
+            FixedAppendLog::create(&mut loader, BincodeLoadStore::<u32>::default(), "f", 4, 16)
+                .unwrap();
+        let mut rolling =
+            RollingLog::create(&mut loader, BincodeLoadStore::<u32>::default(), "r", 1024).unwrap();
+        let mut store = AtomicStore::open(loader).unwrap();
+        for value in [0x11223344u32, 0x55667788] {
+            append.store_resource(&value).unwrap();
+            append.commit_version().unwrap();
+            fixed.store_resource(&value).unwrap();
+            fixed.commit_version().unwrap();
+            rolling.store_resource(&value).unwrap();
+            rolling.commit_version().unwrap();
+            store.commit_version().unwrap();
+        }
+    }
+
+    let (ok, toc) = inspect(dir.path(), &["toc"]);
+    assert!(ok);
+    assert!(toc.contains("file_counter: 1"));
+    assert!(toc.contains("    a @0{4+4} AppendLog(fill 1024)"));
+    assert!(toc.contains("    f @0{4+4} FixedAppendLog(16 x 4 bytes)"));
+    assert!(toc.contains("    r "));
+
+    let (ok, versions) = inspect(dir.path(), &["versions"]);
+    assert!(ok);
+    assert_eq!(
+        versions.lines().map(|line| &line[..2]).collect::<Vec<_>>(),
+        vec!["0 ", "1 "]
+    );
+    let (ok, toc) = inspect(dir.path(), &["toc", "--version", "0"]);
+    assert!(ok);
+    assert!(toc.contains("    a @0{0+4} AppendLog(fill 1024)"));
+
+    let (ok, files) = inspect(dir.path(), &["files"]);
+    assert!(ok);
+    assert!(files.contains("a (1 files, 8 bytes)"));
+    assert!(files.contains(" .a_0\n"));
+
+    // Entries are little-endian bincode.
+    let (ok, dump) = inspect(dir.path(), &["dump", "a", "--index", "1"]);
+    assert!(ok);
+    assert_eq!(dump, format!("00000000  88 77 66 55{:36}  .wfU\n", ""));
+    let (ok, dump) = inspect(dir.path(), &["dump", "f", "--location", "0:0:4"]);
+    assert!(ok);
+    assert!(dump.starts_with("00000000  44 33 22 11"));
+    let (ok, dump) = inspect(dir.path(), &["dump", "r", "--latest"]);
+    assert!(ok);
+    assert!(dump.starts_with("00000000  88 77 66 55"));
+
+    // A layout given on the command line must agree with the recorded one.
+    let (ok, dump) = inspect(
+        dir.path(),
+        &[
+            "dump", "a", "--index", "0", "--kind", "append", "--size", "1024",
+        ],
+    );
+    assert!(ok);
+    assert!(dump.starts_with("00000000  44 33 22 11"));
+    let (ok, dump) = inspect(
+        dir.path(),
+        &["dump", "f", "--latest", "--kind", "fixed", "--size", "4:16"],
+    );
+    assert!(ok);
+    assert!(dump.starts_with("00000000  88 77 66 55"));
+    assert!(
+        !inspect(
+            dir.path(),
+            &["dump", "a", "--latest", "--kind", "fixed", "--size", "4:16"]
+        )
+        .0
+    );
+    assert!(!inspect(dir.path(), &["dump", "a", "--latest", "--kind", "append"]).0);
+
+    assert!(inspect(dir.path(), &["verify"]).0);
+    // Claim a third entry in the header of the rolling log's file.
+    let rolling_file = dir.path().join(".r_0");
+    let mut contents = std::fs::read(&rolling_file).unwrap();
+    contents[..4].copy_from_slice(&3u32.to_le_bytes());
+    std::fs::write(&rolling_file, contents).unwrap();
+    let (ok, report) = inspect(dir.path(), &["verify"]);
+    assert!(!ok);
+    // One line for the one inconsistency, naming the resource and its file.
+    let lines: Vec<&str> = report.lines().collect();
+    assert_eq!(lines.len(), 1);
+    assert!(lines[0].starts_with(&format!("r: {}: ", rolling_file.display())));
+    let issues = AtomicStoreLoader::open_read_only(dir.path(), "inspect")
+        .unwrap()
+        .verify()
+        .unwrap();
+    assert_eq!(issues.len(), 1);
+    assert_eq!(
+        issues[0].kind,
+        InconsistencyKind::EntryCountMismatch {
+            header: 3,
+            committed: Some(2),
+            held: 2,
+        }
+    );
+
+    assert!(!inspect(dir.path(), &["dump", "a", "--index", "2"]).0);
+    assert!(!inspect(dir.path(), &["dump", "r", "--index", "0"]).0);
+    assert!(!inspect(dir.path(), &["bogus"]).0);
+}